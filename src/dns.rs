@@ -0,0 +1,584 @@
+#[cfg(feature = "async")]
+use core::future::Future;
+#[cfg(feature = "async")]
+use core::pin::Pin;
+#[cfg(feature = "async")]
+use core::ptr;
+#[cfg(feature = "async")]
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Instant;
+
+use ::log::*;
+
+use esp_idf_hal::mutex;
+
+use esp_idf_sys::*;
+
+use crate::netif::tcpip_exec;
+
+use crate::private::waitable::*;
+
+const DNS_PORT: u16 = 53;
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+
+// The queries of the asynchronous lookups with an explicit server are made one at a time,
+// by a single task started on first use
+static QUERIES: mutex::Mutex<Option<mpsc::Sender<Query>>> = mutex::Mutex::new(None);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+    // IPv4 addresses come first. The lookups done by lwIP return a single address though: the
+    // IPv4 one, or the IPv6 one if the name has no IPv4 address
+    Any,
+}
+
+impl Default for AddressFamily {
+    fn default() -> Self {
+        AddressFamily::Ipv4
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolveOptions {
+    pub timeout: Duration,
+    // Queried directly instead of the servers of the netifs, bypassing the lwIP cache
+    pub server: Option<IpAddr>,
+    pub family: AddressFamily,
+}
+
+impl Default for ResolveOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            server: None,
+            family: Default::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lookup {
+    result: Option<Result<Vec<IpAddr>, EspError>>,
+    #[cfg(feature = "async")]
+    waker: Option<Waker>,
+}
+
+impl Lookup {
+    fn complete(lookup: &Waitable<Lookup>, result: Result<Vec<IpAddr>, EspError>) {
+        lookup.modify(|lookup| {
+            // The lookups which timed out might still complete later
+            if lookup.result.is_some() {
+                return (false, ());
+            }
+
+            lookup.result = Some(result);
+
+            #[cfg(feature = "async")]
+            if let Some(waker) = lookup.waker.take() {
+                waker.wake();
+            }
+
+            (true, ())
+        });
+    }
+}
+
+pub fn resolve(hostname: &str) -> Result<Vec<IpAddr>, EspError> {
+    resolve_with(hostname, &Default::default())
+}
+
+pub fn resolve_with(hostname: &str, options: &ResolveOptions) -> Result<Vec<IpAddr>, EspError> {
+    // Explicit servers are queried by the calling task
+    if let Some(server) = options
+        .server
+        .filter(|_| hostname.parse::<IpAddr>().is_err())
+    {
+        return query(
+            hostname,
+            server,
+            options.family,
+            Instant::now() + options.timeout,
+        );
+    }
+
+    let lookup = start(hostname, options)?;
+
+    let (timed_out, result) = lookup.wait_timeout_while_and_get(
+        options.timeout,
+        |lookup| lookup.result.is_none(),
+        |lookup| lookup.result.clone(),
+    );
+
+    if timed_out {
+        info!("Timeout while resolving {}", hostname);
+
+        esp!(ESP_ERR_TIMEOUT as i32)?;
+    }
+
+    result.unwrap()
+}
+
+// Completes once the name is resolved, the lookup fails or the timeout of the options elapses
+#[cfg(feature = "async")]
+pub fn resolve_async(hostname: &str, options: &ResolveOptions) -> EspDnsResolve {
+    EspDnsResolve(start(hostname, options).and_then(|lookup| {
+        let deadline = if lookup.get(|lookup| lookup.result.is_some()) {
+            None
+        } else {
+            Some(Deadline::start(hostname, &lookup, options.timeout)?)
+        };
+
+        Ok((lookup, deadline))
+    }))
+}
+
+#[cfg(feature = "async")]
+pub struct EspDnsResolve(Result<(Arc<Waitable<Lookup>>, Option<Deadline>), EspError>);
+
+#[cfg(feature = "async")]
+impl Future for EspDnsResolve {
+    type Output = Result<Vec<IpAddr>, EspError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let lookup = match &self.0 {
+            Ok((lookup, _)) => lookup,
+            Err(err) => return Poll::Ready(Err(err.clone())),
+        };
+
+        let result = lookup.modify(|lookup| {
+            if lookup.result.is_none() {
+                lookup.waker = Some(cx.waker().clone());
+            }
+
+            (false, lookup.result.clone())
+        });
+
+        if let Some(result) = result {
+            Poll::Ready(result)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+fn start(hostname: &str, options: &ResolveOptions) -> Result<Arc<Waitable<Lookup>>, EspError> {
+    // Literal addresses need no lookup
+    if let Ok(ip) = hostname.parse::<IpAddr>() {
+        return Ok(Arc::new(Waitable::new(Lookup {
+            result: Some(Ok(vec![ip])),
+            ..Default::default()
+        })));
+    }
+
+    let lookup = Arc::new(Waitable::new(Lookup::default()));
+
+    if let Some(server) = options.server {
+        submit(Query {
+            hostname: hostname.into(),
+            server,
+            family: options.family,
+            deadline: Instant::now() + options.timeout,
+            lookup: lookup.clone(),
+        })?;
+    } else {
+        start_lwip(hostname, options.family, lookup.clone())?;
+    }
+
+    Ok(lookup)
+}
+
+fn start_lwip(
+    hostname: &str,
+    family: AddressFamily,
+    lookup: Arc<Waitable<Lookup>>,
+) -> Result<(), EspError> {
+    let c_hostname =
+        CString::new(hostname).map_err(|_| EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap())?;
+
+    let mut addr: ip_addr_t = Default::default();
+
+    // Owned by the callback, as it might be called after the caller gave up waiting
+    let arg = Arc::into_raw(lookup.clone());
+
+    let mut err: err_t = 0;
+
+    tcpip_exec(|| {
+        err = unsafe {
+            #[cfg(esp_idf_lwip_ipv6)]
+            let err = dns_gethostbyname_addrtype(
+                c_hostname.as_ptr(),
+                &mut addr,
+                Some(on_found),
+                arg as *mut _,
+                match family {
+                    AddressFamily::Ipv4 => LWIP_DNS_ADDRTYPE_IPV4,
+                    AddressFamily::Ipv6 => LWIP_DNS_ADDRTYPE_IPV6,
+                    AddressFamily::Any => LWIP_DNS_ADDRTYPE_IPV4_IPV6,
+                } as _,
+            );
+
+            #[cfg(not(esp_idf_lwip_ipv6))]
+            let err = dns_gethostbyname(
+                c_hostname.as_ptr(),
+                &mut addr,
+                Some(on_found),
+                arg as *mut _,
+            );
+
+            err
+        };
+
+        err_enum_t_ERR_OK as _
+    })
+    .map_err(|err| {
+        unsafe { Arc::from_raw(arg) };
+
+        err
+    })?;
+
+    if err == err_enum_t_ERR_INPROGRESS as err_t {
+        return Ok(());
+    }
+
+    // The callback is only called for the lookups in progress
+    unsafe { Arc::from_raw(arg) };
+
+    let result = if err == err_enum_t_ERR_OK as err_t {
+        Ok(vec![from_ip_addr(&addr)])
+    } else {
+        info!("Failed to resolve {}: {}", hostname, err);
+
+        Err(EspError::from(ESP_ERR_NOT_FOUND as i32).unwrap())
+    };
+
+    Lookup::complete(&lookup, result);
+
+    Ok(())
+}
+
+unsafe extern "C" fn on_found(
+    _name: *const c_types::c_char,
+    addr: *const ip_addr_t,
+    arg: *mut c_types::c_void,
+) {
+    let lookup = Arc::from_raw(arg as *const Waitable<Lookup>);
+
+    let result = if let Some(addr) = addr.as_ref() {
+        Ok(vec![from_ip_addr(addr)])
+    } else {
+        Err(EspError::from(ESP_ERR_NOT_FOUND as i32).unwrap())
+    };
+
+    Lookup::complete(&lookup, result);
+}
+
+#[cfg(esp_idf_lwip_ipv6)]
+fn from_ip_addr(addr: &ip_addr_t) -> IpAddr {
+    if addr.type_ as u32 == lwip_ip_addr_type_IPADDR_TYPE_V6 {
+        let words = unsafe { addr.u_addr.ip6.addr };
+
+        let mut octets = [0_u8; 16];
+        for (index, word) in words.iter().enumerate() {
+            octets[index * 4..index * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        IpAddr::V6(Ipv6Addr::from(octets))
+    } else {
+        IpAddr::V4(Ipv4Addr::from(
+            unsafe { addr.u_addr.ip4.addr }.to_le_bytes(),
+        ))
+    }
+}
+
+#[cfg(not(esp_idf_lwip_ipv6))]
+fn from_ip_addr(addr: &ip_addr_t) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(addr.addr.to_le_bytes()))
+}
+
+// Fails the lookup with `ESP_ERR_TIMEOUT` unless it completes in time, as nothing waits for it
+// with a timeout when resolving asynchronously
+#[cfg(feature = "async")]
+struct Deadline(esp_timer_handle_t, *mut (String, Arc<Waitable<Lookup>>));
+
+#[cfg(feature = "async")]
+unsafe impl Send for Deadline {}
+
+#[cfg(feature = "async")]
+impl Deadline {
+    fn start(
+        hostname: &str,
+        lookup: &Arc<Waitable<Lookup>>,
+        timeout: Duration,
+    ) -> Result<Self, EspError> {
+        // Owned by the callback once the timer expires
+        let arg = Box::into_raw(Box::new((String::from(hostname), lookup.clone())));
+
+        let mut timer: esp_timer_handle_t = ptr::null_mut();
+
+        let result = esp!(unsafe {
+            esp_timer_create(
+                &esp_timer_create_args_t {
+                    callback: Some(Self::expire),
+                    name: b"dns_timeout\0" as *const _ as *const _,
+                    arg: arg as *mut _,
+                    dispatch_method: esp_timer_dispatch_t_ESP_TIMER_TASK,
+                    skip_unhandled_events: false,
+                },
+                &mut timer as *mut _,
+            )
+        })
+        .and_then(|_| {
+            esp!(unsafe { esp_timer_start_once(timer, timeout.as_micros() as _) }).map_err(|err| {
+                unsafe { esp_timer_delete(timer) };
+
+                err
+            })
+        });
+
+        if let Err(err) = result {
+            unsafe { drop(Box::from_raw(arg)) };
+
+            return Err(err);
+        }
+
+        Ok(Self(timer, arg))
+    }
+
+    unsafe extern "C" fn expire(arg: *mut c_types::c_void) {
+        let (hostname, lookup) = *Box::from_raw(arg as *mut (String, Arc<Waitable<Lookup>>));
+
+        if !lookup.get(|lookup| lookup.result.is_some()) {
+            info!("Timeout while resolving {}", hostname);
+
+            Lookup::complete(
+                &lookup,
+                Err(EspError::from(ESP_ERR_TIMEOUT as i32).unwrap()),
+            );
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        unsafe {
+            // The argument is freed by the callback if the timer already expired
+            if esp_timer_stop(self.0) == ESP_OK {
+                drop(Box::from_raw(self.1));
+            }
+
+            esp_timer_delete(self.0);
+        }
+    }
+}
+
+struct Query {
+    hostname: String,
+    server: IpAddr,
+    family: AddressFamily,
+    deadline: Instant,
+    lookup: Arc<Waitable<Lookup>>,
+}
+
+fn submit(request: Query) -> Result<(), EspError> {
+    let mut queries = QUERIES.lock();
+
+    if queries.is_none() {
+        let (sender, receiver) = mpsc::channel::<Query>();
+
+        thread::Builder::new()
+            .name("dns_query".into())
+            .stack_size(4096)
+            .spawn(move || {
+                for next in receiver {
+                    let result = query(&next.hostname, next.server, next.family, next.deadline);
+
+                    Lookup::complete(&next.lookup, result);
+                }
+            })
+            .map_err(|_| EspError::from(ESP_ERR_NO_MEM as i32).unwrap())?;
+
+        *queries = Some(sender);
+    }
+
+    queries
+        .as_ref()
+        .unwrap()
+        .send(request)
+        .map_err(|_| EspError::from(ESP_FAIL).unwrap())
+}
+
+// A plain DNS query over UDP, for the lookups with an explicit server
+fn query(
+    hostname: &str,
+    server: IpAddr,
+    family: AddressFamily,
+    deadline: Instant,
+) -> Result<Vec<IpAddr>, EspError> {
+    let types: &[u16] = match family {
+        AddressFamily::Ipv4 => &[DNS_TYPE_A],
+        AddressFamily::Ipv6 => &[DNS_TYPE_AAAA],
+        AddressFamily::Any => &[DNS_TYPE_A, DNS_TYPE_AAAA],
+    };
+
+    let io_err = |err: std::io::Error| {
+        info!("DNS query for {} failed: {}", hostname, err);
+
+        EspError::from(ESP_FAIL).unwrap()
+    };
+
+    let local: SocketAddr = match server {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).map_err(io_err)?;
+    socket.connect((server, DNS_PORT)).map_err(io_err)?;
+
+    let mut addresses = Vec::new();
+
+    for qtype in types {
+        let mut id = [0_u8; 2];
+        unsafe { esp_fill_random(id.as_mut_ptr() as *mut _, id.len() as _) };
+
+        let request = build_query(u16::from_be_bytes(id), hostname, *qtype)?;
+
+        socket.send(&request).map_err(io_err)?;
+
+        let mut response = [0_u8; 512];
+
+        // Stray responses to earlier queries are skipped, until the deadline of the whole lookup
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining == Duration::from_secs(0) {
+                info!("Timeout while resolving {}", hostname);
+
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
+
+            socket.set_read_timeout(Some(remaining)).map_err(io_err)?;
+
+            let len = socket.recv(&mut response).map_err(|err| {
+                if err.kind() == std::io::ErrorKind::WouldBlock
+                    || err.kind() == std::io::ErrorKind::TimedOut
+                {
+                    EspError::from(ESP_ERR_TIMEOUT as i32).unwrap()
+                } else {
+                    io_err(err)
+                }
+            })?;
+
+            if len >= 2 && response[..2] == id {
+                parse_response(&response[..len], &mut addresses)?;
+                break;
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        esp!(ESP_ERR_NOT_FOUND as i32)?;
+    }
+
+    Ok(addresses)
+}
+
+fn build_query(id: u16, hostname: &str, qtype: u16) -> Result<Vec<u8>, EspError> {
+    let mut request = Vec::with_capacity(hostname.len() + 18);
+
+    request.extend_from_slice(&id.to_be_bytes());
+    request.extend_from_slice(&[0x01, 0x00]); // Recursion desired
+    request.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // One question
+
+    for label in hostname.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+
+    request.push(0);
+    request.extend_from_slice(&qtype.to_be_bytes());
+    request.extend_from_slice(&[0, 1]); // Class IN
+
+    Ok(request)
+}
+
+fn parse_response(response: &[u8], addresses: &mut Vec<IpAddr>) -> Result<(), EspError> {
+    let malformed = || EspError::from(ESP_ERR_INVALID_RESPONSE as i32).unwrap();
+
+    if response.len() < 12 {
+        return Err(malformed());
+    }
+
+    // NXDOMAIN and the other errors leave the answer empty
+    if response[3] & 0x0f != 0 {
+        return Ok(());
+    }
+
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+
+    let mut offset = 12;
+
+    for _ in 0..questions {
+        offset = skip_name(response, offset).ok_or_else(malformed)? + 4;
+    }
+
+    for _ in 0..answers {
+        offset = skip_name(response, offset).ok_or_else(malformed)?;
+
+        let header = response.get(offset..offset + 10).ok_or_else(malformed)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+
+        offset += 10;
+
+        let data = response.get(offset..offset + len).ok_or_else(malformed)?;
+
+        match (rtype, len) {
+            (DNS_TYPE_A, 4) => addresses.push(IpAddr::V4(Ipv4Addr::new(
+                data[0], data[1], data[2], data[3],
+            ))),
+            (DNS_TYPE_AAAA, 16) => {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(data);
+
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            // E.g. the CNAME records preceding the addresses
+            _ => (),
+        }
+
+        offset += len;
+    }
+
+    Ok(())
+}
+
+// Returns the offset right after the (possibly compressed) name at the given offset
+fn skip_name(response: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *response.get(offset)? as usize;
+
+        if len == 0 {
+            return Some(offset + 1);
+        } else if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+
+        offset += len + 1;
+    }
+}
@@ -0,0 +1,277 @@
+use core::{mem, ptr, time::Duration};
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use ::log::*;
+
+use esp_idf_hal::mutex;
+
+use esp_idf_sys::*;
+
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventFetchData, EspEventSubscribeMetadata};
+use crate::wifi::EspWifi;
+
+use crate::private::cstr::*;
+use crate::private::waitable::*;
+
+// The DPP supplicant reports through a callback without a user argument, so the state of the
+// (single) active enrollee is kept here
+static STATE: mutex::Mutex<Option<usize>> = mutex::Mutex::new(None);
+
+// ESP-IDF does not post DPP events on the system event loop, so they are re-posted with this base
+static DPP_EVENT: &str = "DPP_EVENT\0";
+
+const DPP_EVENT_URI_READY: i32 = 0;
+const DPP_EVENT_CREDENTIALS_RECEIVED: i32 = 1;
+const DPP_EVENT_FAILED: i32 = 2;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DppCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+impl From<&wifi_sta_config_t> for DppCredentials {
+    fn from(conf: &wifi_sta_config_t) -> Self {
+        Self {
+            ssid: from_cstr(&conf.ssid).into(),
+            password: from_cstr(&conf.password).into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DppEvent {
+    UriReady,
+    CredentialsReceived(DppCredentials),
+    Failed(esp_err_t),
+    Other(i32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for DppEvent {
+    fn source() -> *const c_types::c_char {
+        DPP_EVENT.as_ptr() as *const _
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for DppEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        match data.event_id {
+            DPP_EVENT_URI_READY => DppEvent::UriReady,
+            DPP_EVENT_CREDENTIALS_RECEIVED => {
+                let conf: wifi_sta_config_t = unsafe { data.as_payload() };
+
+                DppEvent::CredentialsReceived((&conf).into())
+            }
+            DPP_EVENT_FAILED => DppEvent::Failed(unsafe { data.as_payload() }),
+            other => DppEvent::Other(other),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DppState {
+    uri: Option<String>,
+    credentials: Option<DppCredentials>,
+    error: Option<esp_err_t>,
+}
+
+pub struct EspDppEnrollee<'a> {
+    _wifi: &'a mut EspWifi,
+    state: Box<Waitable<DppState>>,
+    // Whether the supplicant has to be deinitialized when dropped
+    initialized: bool,
+}
+
+impl<'a> EspDppEnrollee<'a> {
+    pub fn new(
+        wifi: &'a mut EspWifi,
+        channels: &[u8],
+        key: Option<&str>,
+        info: Option<&str>,
+    ) -> Result<Self, EspError> {
+        let mut active = STATE.lock();
+
+        if active.is_some() {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        wifi.start_sta_idle()?;
+
+        let mut enrollee = Self {
+            _wifi: wifi,
+            state: Box::new(Waitable::new(Default::default())),
+            initialized: false,
+        };
+
+        *active = Some(&*enrollee.state as *const _ as usize);
+
+        drop(active);
+
+        esp!(unsafe { esp_supp_dpp_init(Some(Self::event_callback)) })?;
+
+        enrollee.initialized = true;
+
+        let channels = channels
+            .iter()
+            .map(|channel| channel.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut rcs = RawCstrs::new();
+
+        esp!(unsafe {
+            esp_supp_dpp_bootstrap_gen(
+                rcs.as_ptr(channels),
+                esp_supp_dpp_bootstrap_t_DPP_BOOTSTRAP_QR_CODE,
+                rcs.as_nptr(key),
+                rcs.as_nptr(info),
+            )
+        })?;
+
+        info!("DPP bootstrapping requested");
+
+        Ok(enrollee)
+    }
+
+    // The QR code bootstrapping URI, to be scanned by the configurator
+    pub fn uri(&self, timeout: Option<Duration>) -> Result<String, EspError> {
+        self.wait(timeout, |state| state.uri.clone())
+    }
+
+    pub fn start_listen(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_supp_dpp_start_listen() })?;
+
+        info!("DPP listening for the configurator");
+
+        Ok(())
+    }
+
+    pub fn stop_listen(&mut self) {
+        unsafe { esp_supp_dpp_stop_listen() };
+    }
+
+    pub fn wait_for_credentials(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<DppCredentials, EspError> {
+        self.wait(timeout, |state| state.credentials.clone())
+    }
+
+    fn wait<Q>(
+        &self,
+        timeout: Option<Duration>,
+        getter: impl Fn(&DppState) -> Option<Q>,
+    ) -> Result<Q, EspError> {
+        let condition = |state: &DppState| getter(state).is_none() && state.error.is_none();
+        let get = |state: &DppState| getter(state).ok_or_else(|| state.error.unwrap());
+
+        let result = if let Some(timeout) = timeout {
+            let (timed_out, result) = self
+                .state
+                .wait_timeout_while_and_get(timeout, condition, get);
+
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
+
+            result
+        } else {
+            self.state.wait_while_and_get(condition, get)
+        };
+
+        // The supplicant might report a failure without an error code
+        result
+            .map_err(|err| EspError::from(err).unwrap_or_else(|| EspError::from(ESP_FAIL).unwrap()))
+    }
+
+    #[allow(non_upper_case_globals)]
+    unsafe extern "C" fn event_callback(event: esp_supp_dpp_event_t, data: *mut c_types::c_void) {
+        let active = STATE.lock();
+
+        let state_ref = if let Some(state) = *active {
+            (state as *const Waitable<DppState>).as_ref().unwrap()
+        } else {
+            return;
+        };
+
+        let event_id = state_ref.modify(|state| match event {
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_URI_READY => {
+                let uri: String = from_cstr_ptr(data as *const _).into();
+
+                info!("DPP URI ready: {}", uri);
+
+                state.uri = Some(uri);
+
+                (true, Some(DPP_EVENT_URI_READY))
+            }
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_CFG_RECVD => {
+                let conf = &(data as *const wifi_config_t).as_ref().unwrap().sta;
+
+                let credentials: DppCredentials = conf.into();
+
+                info!("DPP credentials received for SSID {}", credentials.ssid);
+
+                state.credentials = Some(credentials);
+
+                (true, Some(DPP_EVENT_CREDENTIALS_RECEIVED))
+            }
+            esp_supp_dpp_event_t_ESP_SUPP_DPP_FAIL => {
+                // The error code is passed in place of the data pointer
+                warn!("DPP failed: {}", data as esp_err_t);
+
+                state.error = Some(data as esp_err_t);
+
+                (true, Some(DPP_EVENT_FAILED))
+            }
+            _ => (false, None),
+        });
+
+        if let Some(event_id) = event_id {
+            let err = data as esp_err_t;
+
+            let (payload, payload_len) = match event_id {
+                DPP_EVENT_CREDENTIALS_RECEIVED => (
+                    &(*(data as *const wifi_config_t)).sta as *const _ as *mut c_types::c_void,
+                    mem::size_of::<wifi_sta_config_t>(),
+                ),
+                DPP_EVENT_FAILED => (
+                    &err as *const _ as *mut c_types::c_void,
+                    mem::size_of::<esp_err_t>(),
+                ),
+                _ => (ptr::null_mut(), 0),
+            };
+
+            if let Err(err) = esp!(esp_event_post(
+                DPP_EVENT.as_ptr() as *const _,
+                event_id,
+                payload,
+                payload_len as _,
+                0
+            )) {
+                warn!("Failed to post DPP event {}: {}", event_id, err);
+            }
+        }
+    }
+}
+
+impl<'a> Drop for EspDppEnrollee<'a> {
+    fn drop(&mut self) {
+        if self.initialized {
+            unsafe {
+                esp_supp_dpp_stop_listen();
+                esp_supp_dpp_deinit();
+            }
+        }
+
+        *STATE.lock() = None;
+
+        info!("DPP enrollee dropped");
+    }
+}
@@ -1,7 +1,9 @@
 use core::{convert::TryInto, ptr, time::Duration};
 
 extern crate alloc;
+use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 
 use ::log::*;
@@ -143,6 +145,10 @@ pub struct EspEth<P> {
 
     netif: Option<EspNetif>,
 
+    hostname: Option<String>,
+    dhcp_client: Option<DhcpClientConfiguration>,
+    route_priority: Option<u32>,
+
     shared: Box<Waitable<Shared>>,
 }
 
@@ -521,6 +527,9 @@ impl<P> EspEth<P> {
             handle,
             glue_handle: glue_handle as *mut _,
             netif: None,
+            hostname: None,
+            dhcp_client: None,
+            route_priority: None,
             shared,
         };
 
@@ -543,6 +552,75 @@ impl<P> EspEth<P> {
         f(self.netif.as_mut())
     }
 
+    pub fn get_mac(&self) -> Result<[u8; 6], EspError> {
+        let mut mac = [0_u8; 6];
+
+        esp!(unsafe {
+            esp_eth_ioctl(
+                self.handle,
+                esp_eth_io_cmd_t_ETH_CMD_G_MAC_ADDR,
+                mac.as_mut_ptr() as *mut _,
+            )
+        })?;
+
+        Ok(mac)
+    }
+
+    pub fn set_mac(&mut self, mac: &[u8; 6]) -> Result<(), EspError> {
+        check_mac(mac)?;
+
+        esp!(unsafe {
+            esp_eth_ioctl(
+                self.handle,
+                esp_eth_io_cmd_t_ETH_CMD_S_MAC_ADDR,
+                mac.as_ptr() as *mut _,
+            )
+        })?;
+
+        if let Some(netif) = self.netif.as_mut() {
+            netif.set_mac(mac)?;
+        }
+
+        info!("MAC set to {:?}", mac);
+
+        Ok(())
+    }
+
+    pub fn get_hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+
+    // Applied right away if the interface exists, and kept across configuration changes
+    pub fn set_hostname(&mut self, hostname: Option<&str>) -> Result<(), EspError> {
+        if let (Some(netif), Some(hostname)) = (self.netif.as_ref(), hostname) {
+            netif.set_hostname(hostname)?;
+        }
+
+        self.hostname = hostname.map(|hostname| hostname.to_owned());
+
+        Ok(())
+    }
+
+    // The priority of the configured interface kind if not overridden
+    pub fn get_route_priority(&self) -> Option<u32> {
+        self.route_priority
+    }
+
+    // Interfaces with a higher priority are preferred as the default route. Takes effect the
+    // next time the configuration is set
+    pub fn set_route_priority(&mut self, route_priority: Option<u32>) {
+        self.route_priority = route_priority;
+    }
+
+    pub fn get_dhcp_client_conf(&self) -> Option<&DhcpClientConfiguration> {
+        self.dhcp_client.as_ref()
+    }
+
+    // Applied to the netif if it uses DHCP. Takes effect the next time the configuration is set
+    pub fn set_dhcp_client_conf(&mut self, conf: Option<DhcpClientConfiguration>) {
+        self.dhcp_client = conf;
+    }
+
     fn set_ip_conf(&mut self, conf: &Configuration) -> Result<(), EspError> {
         Self::netif_unbind(self.netif.as_mut())?;
 
@@ -566,8 +644,22 @@ impl<P> EspEth<P> {
             _ => None,
         };
 
-        if let Some(iconf) = iconf {
-            let netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+        if let Some(mut iconf) = iconf {
+            iconf.hostname = self.hostname.clone();
+
+            if let Some(route_priority) = self.route_priority {
+                iconf.route_priority = route_priority;
+            }
+
+            let mut netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+
+            if let (
+                InterfaceIpConfiguration::Client(ipv4::ClientConfiguration::DHCP(_)),
+                Some(dhcp_client),
+            ) = (&iconf.ip_configuration, self.dhcp_client.as_ref())
+            {
+                netif.set_dhcp_client_conf(dhcp_client)?;
+            }
 
             esp!(unsafe { esp_netif_attach(netif.1, self.glue_handle) })?;
 
@@ -1,12 +1,23 @@
-use core::fmt::{Debug, Display};
+use core::any::Any;
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::fmt::{self, Debug, Display};
+use core::future::Future;
 use core::marker::PhantomData;
 use core::mem;
+use core::pin::Pin;
 use core::ptr;
 use core::result::Result;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
 use core::time::Duration;
 
 extern crate alloc;
-use alloc::sync::Arc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use futures_core::Stream;
 
 use ::log::*;
 
@@ -18,7 +29,10 @@ use esp_idf_hal::mutex;
 
 use esp_idf_sys::*;
 
+use crate::netif::IpEvent;
 use crate::private::cstr::RawCstrs;
+use crate::private::waitable::*;
+use crate::wifi::WifiEvent;
 
 pub type EspSystemSubscription = EspSubscription<System>;
 pub type EspBackgroundSubscription = EspSubscription<User<Background>>;
@@ -51,6 +65,125 @@ impl<'a> Default for BackgroundLoopConfiguration<'a> {
     }
 }
 
+impl<'a> BackgroundLoopConfiguration<'a> {
+    pub fn builder() -> BackgroundLoopConfigurationBuilder<'a> {
+        BackgroundLoopConfigurationBuilder(Default::default())
+    }
+
+    pub fn validate(&self) -> Result<(), BackgroundLoopConfigurationError> {
+        if self.queue_size == 0 {
+            return Err(BackgroundLoopConfigurationError::QueueSize(self.queue_size));
+        }
+
+        // The task name is truncated by FreeRTOS rather than rejected, but a silently
+        // truncated name is confusing when looking at task dumps
+        if self.task_name.len() >= configMAX_TASK_NAME_LEN as usize {
+            return Err(BackgroundLoopConfigurationError::TaskName(
+                self.task_name.len(),
+            ));
+        }
+
+        if self.task_priority as u32 >= configMAX_PRIORITIES as u32 {
+            return Err(BackgroundLoopConfigurationError::TaskPriority(
+                self.task_priority,
+            ));
+        }
+
+        if self.task_stack_size < configMINIMAL_STACK_SIZE as usize {
+            return Err(BackgroundLoopConfigurationError::TaskStackSize(
+                self.task_stack_size,
+            ));
+        }
+
+        #[cfg(esp_idf_freertos_unicore)]
+        if !matches!(self.task_pin_to_core, Core::Core0) {
+            return Err(BackgroundLoopConfigurationError::TaskPinToCore);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackgroundLoopConfigurationError {
+    QueueSize(usize),
+    TaskName(usize),
+    TaskPriority(u8),
+    TaskStackSize(usize),
+    TaskPinToCore,
+}
+
+impl Display for BackgroundLoopConfigurationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QueueSize(size) => write!(f, "Queue size {} is invalid, must be > 0", size),
+            Self::TaskName(len) => write!(
+                f,
+                "Task name length {} is invalid, must be < {}",
+                len, configMAX_TASK_NAME_LEN
+            ),
+            Self::TaskPriority(priority) => write!(
+                f,
+                "Task priority {} is invalid, must be < {}",
+                priority, configMAX_PRIORITIES
+            ),
+            Self::TaskStackSize(size) => write!(
+                f,
+                "Task stack size {} is invalid, must be >= {}",
+                size, configMINIMAL_STACK_SIZE
+            ),
+            Self::TaskPinToCore => write!(f, "Task can only be pinned to core 0 on this chip"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BackgroundLoopConfigurationError {}
+
+impl From<BackgroundLoopConfigurationError> for EspError {
+    fn from(_: BackgroundLoopConfigurationError) -> Self {
+        EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct BackgroundLoopConfigurationBuilder<'a>(BackgroundLoopConfiguration<'a>);
+
+impl<'a> BackgroundLoopConfigurationBuilder<'a> {
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.0.queue_size = queue_size;
+        self
+    }
+
+    pub fn task_name(mut self, task_name: &'a str) -> Self {
+        self.0.task_name = task_name;
+        self
+    }
+
+    pub fn task_priority(mut self, task_priority: u8) -> Self {
+        self.0.task_priority = task_priority;
+        self
+    }
+
+    pub fn task_stack_size(mut self, task_stack_size: usize) -> Self {
+        self.0.task_stack_size = task_stack_size;
+        self
+    }
+
+    pub fn task_pin_to_core(mut self, task_pin_to_core: Core) -> Self {
+        self.0.task_pin_to_core = task_pin_to_core;
+        self
+    }
+
+    pub fn build(
+        self,
+    ) -> Result<BackgroundLoopConfiguration<'a>, BackgroundLoopConfigurationError> {
+        self.0.validate()?;
+
+        Ok(self.0)
+    }
+}
+
 impl<'a> From<&BackgroundLoopConfiguration<'a>> for (esp_event_loop_args_t, RawCstrs) {
     fn from(conf: &BackgroundLoopConfiguration<'a>) -> Self {
         let mut rcs = RawCstrs::new();
@@ -89,6 +222,8 @@ impl From<&ExplicitLoopConfiguration> for esp_event_loop_args_t {
 
 static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
 
+static SYSTEM: mutex::Mutex<Option<Weak<EventLoopHandle<System>>>> = mutex::Mutex::new(None);
+
 #[derive(Clone)]
 pub struct System;
 #[derive(Clone)]
@@ -116,6 +251,14 @@ impl<T> EspEventLoopType for User<T> {
     }
 }
 
+// Loops dispatched by their own task, on which another task can block while
+// waiting for an event (but not one of the loop's own handlers)
+pub trait EspDispatchedEventLoopType: EspEventLoopType {}
+
+impl EspDispatchedEventLoopType for System {}
+
+impl EspDispatchedEventLoopType for User<Background> {}
+
 pub trait EspEventSubscribeMetadata {
     fn source() -> *const c_types::c_char;
 
@@ -124,6 +267,215 @@ pub trait EspEventSubscribeMetadata {
     }
 }
 
+#[derive(Copy, Clone)]
+pub struct EspEventBase(&'static [u8]);
+
+impl EspEventBase {
+    /// # Safety
+    ///
+    /// `name` must be NUL-terminated valid UTF-8 and must be the address of a `static` owned by
+    /// this event base only, because ESP-IDF compares event bases by pointer.
+    /// Use the `esp_event_base!` macro rather than calling this function directly.
+    pub const unsafe fn new_unchecked(name: &'static [u8]) -> Self {
+        Self(name)
+    }
+
+    pub fn as_ptr(&self) -> *const c_types::c_char {
+        self.0.as_ptr() as *const _
+    }
+
+    pub fn name(&self) -> &'static str {
+        let name = self.as_str_with_nul();
+
+        &name[..name.len() - 1]
+    }
+
+    pub fn as_str_with_nul(&self) -> &'static str {
+        unsafe { core::str::from_utf8_unchecked(self.0) }
+    }
+
+    #[doc(hidden)]
+    pub const fn to_array<const N: usize>(name: &str) -> [u8; N] {
+        let bytes = name.as_bytes();
+        let mut array = [0; N];
+
+        let mut index = 0;
+        while index < N {
+            array[index] = bytes[index];
+            index += 1;
+        }
+
+        array
+    }
+}
+
+impl PartialEq for EspEventBase {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ptr() == other.0.as_ptr()
+    }
+}
+
+impl Eq for EspEventBase {}
+
+impl PartialEq<esp_event_base_t> for EspEventBase {
+    fn eq(&self, other: &esp_event_base_t) -> bool {
+        self.as_ptr() == *other
+    }
+}
+
+impl Debug for EspEventBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("EspEventBase").field(&self.name()).finish()
+    }
+}
+
+impl From<EspEventBase> for *const c_types::c_char {
+    fn from(base: EspEventBase) -> Self {
+        base.as_ptr()
+    }
+}
+
+#[macro_export]
+macro_rules! esp_event_base {
+    ($name:literal) => {{
+        const NAME: &str = concat!($name, "\0");
+
+        // A dedicated static rather than the literal itself, as identical literals might be merged
+        static BASE: [u8; NAME.len()] = $crate::eventloop::EspEventBase::to_array(NAME);
+
+        unsafe { $crate::eventloop::EspEventBase::new_unchecked(&BASE) }
+    }};
+}
+
+/// # Safety
+///
+/// `source()` must return a NUL-terminated string with a `'static` address which is unique
+/// to the implementing type, because ESP-IDF compares event bases by pointer.
+/// Use the `esp_typed_event!` macro rather than implementing this trait by hand.
+pub unsafe trait EspTypedEvent: Copy {
+    fn source() -> &'static str;
+
+    fn event_id() -> i32 {
+        0
+    }
+}
+
+impl<P> EspEventSubscribeMetadata for P
+where
+    P: EspTypedEvent,
+{
+    fn source() -> *const c_types::c_char {
+        <P as EspTypedEvent>::source().as_ptr() as *const _
+    }
+
+    fn event_id() -> i32 {
+        <P as EspTypedEvent>::event_id()
+    }
+}
+
+impl<'a, P> From<&'a P> for EspEventPostData<'a>
+where
+    P: EspTypedEvent,
+{
+    fn from(payload: &'a P) -> Self {
+        unsafe {
+            EspEventPostData::new(
+                <P as EspEventSubscribeMetadata>::source(),
+                <P as EspEventSubscribeMetadata>::event_id(),
+                payload,
+            )
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! esp_typed_event {
+    ($event:ty) => {
+        $crate::esp_typed_event!($event, 0);
+    };
+    ($event:ty, $base:expr, $event_id:expr) => {
+        unsafe impl $crate::eventloop::EspTypedEvent for $event {
+            fn source() -> &'static str {
+                $base.as_str_with_nul()
+            }
+
+            fn event_id() -> i32 {
+                $event_id
+            }
+        }
+
+        impl From<$crate::eventloop::EspEventFetchData> for $event {
+            fn from(data: $crate::eventloop::EspEventFetchData) -> Self {
+                unsafe { data.as_payload() }
+            }
+        }
+    };
+    ($event:ty, $event_id:expr) => {
+        unsafe impl $crate::eventloop::EspTypedEvent for $event {
+            fn source() -> &'static str {
+                static SOURCE: &str = concat!(module_path!(), "::", stringify!($event), "\0");
+
+                SOURCE
+            }
+
+            fn event_id() -> i32 {
+                $event_id
+            }
+        }
+
+        impl From<$crate::eventloop::EspEventFetchData> for $event {
+            fn from(data: $crate::eventloop::EspEventFetchData) -> Self {
+                unsafe { data.as_payload() }
+            }
+        }
+    };
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EspEventLoopDiagnostics {
+    pub posted: u32,
+    pub post_timeouts: u32,
+    pub post_errors: u32,
+    pub isr_posted: u32,
+    pub isr_post_failures: u32,
+    // The most events seen waiting in the queue right after a post. Only tracked for user loops,
+    // as ESP-IDF posts to the system loop without going through `post_raw`
+    pub queue_high_watermark: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EspSubscriptionDiagnostics {
+    pub invocations: u32,
+    pub errors: u32,
+    pub total_time: Duration,
+    pub max_time: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SystemEvent {
+    Wifi(WifiEvent),
+    Ip(IpEvent),
+    Other(i32),
+}
+
+impl EspEventSubscribeMetadata for SystemEvent {
+    fn source() -> *const c_types::c_char {
+        ptr::null() // ESP_EVENT_ANY_BASE
+    }
+}
+
+impl From<EspEventFetchData> for SystemEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        if data.source == unsafe { WIFI_EVENT } {
+            SystemEvent::Wifi(data.into())
+        } else if data.source == unsafe { IP_EVENT } {
+            SystemEvent::Ip(data.into())
+        } else {
+            SystemEvent::Other(data.event_id)
+        }
+    }
+}
+
 pub struct EspEventPostData<'a> {
     pub source: *const c_types::c_char,
     pub event_id: i32,
@@ -148,6 +500,104 @@ impl<'a> EspEventPostData<'a> {
     }
 }
 
+// Encoded payloads are prefixed with their length, as event handlers only get the payload pointer
+#[cfg(all(feature = "serde", feature = "postcard"))]
+const ENCODED_LEN_PREFIX: usize = mem::size_of::<u32>();
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+fn encode<P>(payload: &P) -> Result<Vec<u8>, postcard::Error>
+where
+    P: serde::Serialize,
+{
+    let encoded = postcard::to_allocvec(payload)?;
+
+    let mut buf = Vec::with_capacity(ENCODED_LEN_PREFIX + encoded.len());
+    buf.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&encoded);
+
+    Ok(buf)
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+unsafe fn decode<P>(data: &EspEventFetchData) -> Result<P, postcard::Error>
+where
+    P: serde::de::DeserializeOwned,
+{
+    let len = u32::from_le_bytes(data.as_payload::<[u8; ENCODED_LEN_PREFIX]>()) as usize;
+
+    let buf = core::slice::from_raw_parts((data.payload as *const u8).add(ENCODED_LEN_PREFIX), len);
+
+    postcard::from_bytes(buf)
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+pub struct EspEncodedEvent<P> {
+    payload: P,
+    encoded: Vec<u8>,
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+impl<P> EspEncodedEvent<P>
+where
+    P: serde::Serialize,
+{
+    pub fn new(payload: P) -> Result<Self, postcard::Error> {
+        let encoded = encode(&payload)?;
+
+        Ok(Self { payload, encoded })
+    }
+
+    pub fn payload(&self) -> &P {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> P {
+        self.payload
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+impl<P> EspEventSubscribeMetadata for EspEncodedEvent<P>
+where
+    P: EspEventSubscribeMetadata,
+{
+    fn source() -> *const c_types::c_char {
+        P::source()
+    }
+
+    fn event_id() -> i32 {
+        P::event_id()
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+impl<'a, P> From<&'a EspEncodedEvent<P>> for EspEventPostData<'a>
+where
+    P: EspEventSubscribeMetadata,
+{
+    fn from(event: &'a EspEncodedEvent<P>) -> Self {
+        Self {
+            source: P::source(),
+            event_id: P::event_id(),
+            payload: event.encoded.as_ptr() as *const _,
+            payload_len: event.encoded.len(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+impl<P> core::convert::TryFrom<EspEventFetchData> for EspEncodedEvent<P>
+where
+    P: serde::Serialize + serde::de::DeserializeOwned + EspEventSubscribeMetadata,
+{
+    type Error = postcard::Error;
+
+    fn try_from(data: EspEventFetchData) -> Result<Self, Self::Error> {
+        Self::new(unsafe { decode(&data) }?)
+    }
+}
+
 pub struct EspEventFetchData {
     pub source: *const c_types::c_char,
     pub event_id: i32,
@@ -160,6 +610,13 @@ impl EspEventFetchData {
 
         *payload
     }
+
+    /// # Safety
+    ///
+    /// The payload of the event must be a valid `P`
+    pub unsafe fn as_payload_ref<P>(&self) -> &P {
+        (self.payload as *const P).as_ref().unwrap()
+    }
 }
 
 struct UnsafeCallback(*mut Box<dyn FnMut(EspEventFetchData) + 'static>);
@@ -189,9 +646,9 @@ where
     T: EspEventLoopType,
 {
     event_loop_handle: Arc<EventLoopHandle<T>>,
-    handler_instance: esp_event_handler_instance_t,
-    source: *const c_types::c_char,
-    event_id: i32,
+    registrations: Vec<(*const c_types::c_char, i32, esp_event_handler_instance_t)>,
+    diagnostics: Arc<mutex::Mutex<EspSubscriptionDiagnostics>>,
+    paused: Arc<AtomicBool>,
     _callback: Box<Box<dyn FnMut(EspEventFetchData) + 'static>>,
 }
 
@@ -221,175 +678,874 @@ unsafe impl Send for EspSubscription<System> {}
 unsafe impl Send for EspSubscription<User<Background>> {}
 unsafe impl Send for EspSubscription<User<Explicit>> {}
 
-impl<T> Drop for EspSubscription<T>
+impl<T> EspSubscription<T>
 where
     T: EspEventLoopType,
 {
-    fn drop(&mut self) {
-        if T::is_system() {
-            unsafe {
-                esp!(esp_event_handler_instance_unregister(
-                    self.source,
-                    self.event_id,
-                    self.handler_instance
-                ))
-                .unwrap();
-            }
-        } else {
-            unsafe {
-                let handle: &T = &self.event_loop_handle.0;
-                let user: &User<Background> = mem::transmute(handle);
-
-                esp!(esp_event_handler_instance_unregister_with(
-                    user.0,
-                    self.source,
-                    self.event_id,
-                    self.handler_instance
-                ))
-                .unwrap();
-            }
-        }
+    pub fn diagnostics(&self) -> EspSubscriptionDiagnostics {
+        *self.diagnostics.lock()
     }
-}
-
-struct EventLoopHandle<T>(T)
-where
-    T: EspEventLoopType;
-
-impl EventLoopHandle<System> {
-    fn new() -> Result<Self, EspError> {
-        let mut taken = TAKEN.lock();
 
-        if *taken {
-            esp!(ESP_ERR_INVALID_STATE as i32)?;
-        }
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
 
-        esp!(unsafe { esp_event_loop_create_default() })?;
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
 
-        *taken = true;
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
 
-        Ok(Self(System))
+    pub fn detach(mut self) -> Result<(), EspError> {
+        self.unsubscribe()
     }
-}
 
-impl<T> EventLoopHandle<User<T>> {
-    fn new_internal(conf: &esp_event_loop_args_t) -> Result<Self, EspError> {
-        let mut handle: esp_event_loop_handle_t = ptr::null_mut();
+    fn unsubscribe(&mut self) -> Result<(), EspError> {
+        while let Some((source, event_id, handler_instance)) = self.registrations.last().copied() {
+            if T::is_system() {
+                esp!(unsafe {
+                    esp_event_handler_instance_unregister(source, event_id, handler_instance)
+                })?;
+            } else {
+                esp!(unsafe {
+                    esp_event_handler_instance_unregister_with(
+                        self.event_loop_handle.raw(),
+                        source,
+                        event_id,
+                        handler_instance,
+                    )
+                })?;
+            }
 
-        esp!(unsafe { esp_event_loop_create(conf as *const _, &mut handle as _) })?;
+            self.registrations.pop();
+        }
 
-        Ok(Self(User(handle, PhantomData)))
+        Ok(())
     }
 }
 
-impl EventLoopHandle<User<Background>> {
-    fn new(conf: &BackgroundLoopConfiguration) -> Result<Self, EspError> {
-        let (nconf, _rcs) = conf.into();
-
-        Self::new_internal(&nconf)
+impl<T> Drop for EspSubscription<T>
+where
+    T: EspEventLoopType,
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.unsubscribe() {
+            error!(
+                "Failed to unregister the event handler, leaking it: {}",
+                err
+            );
+
+            // The handler might still be invoked, so its callback must outlive the subscription
+            mem::forget(mem::replace(
+                &mut self._callback,
+                Box::new(Box::new(|_| ())),
+            ));
+        }
     }
 }
 
-impl EventLoopHandle<User<Explicit>> {
-    fn new(conf: &ExplicitLoopConfiguration) -> Result<Self, EspError> {
-        Self::new_internal(&conf.into())
-    }
+struct StreamState<P> {
+    queue: VecDeque<P>,
+    queue_size: usize,
+    waker: Option<Waker>,
 }
 
-impl EventLoopHandle<User<Pinned>> {
-    fn new(conf: &ExplicitLoopConfiguration) -> Result<Self, EspError> {
-        Self::new_internal(&conf.into())
-    }
+pub struct EspSubscriptionStream<T, P>
+where
+    T: EspEventLoopType,
+{
+    _subscription: EspSubscription<T>,
+    state: Arc<mutex::Mutex<StreamState<P>>>,
 }
 
-impl<T> Drop for EventLoopHandle<T>
+impl<T, P> Stream for EspSubscriptionStream<T, P>
 where
     T: EspEventLoopType,
 {
-    fn drop(&mut self) {
-        if T::is_system() {
-            let mut taken = TAKEN.lock();
+    type Item = P;
 
-            unsafe {
-                esp!(esp_event_loop_delete_default()).unwrap();
-            }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.lock();
 
-            *taken = false;
+        if let Some(payload) = state.queue.pop_front() {
+            Poll::Ready(Some(payload))
         } else {
-            unsafe {
-                let handle: &T = &self.0;
-                let user: &User<Background> = mem::transmute(handle);
+            state.waker = Some(cx.waker().clone());
 
-                esp!(esp_event_loop_delete(user.0)).unwrap();
-            }
+            Poll::Pending
         }
-
-        info!("Dropped");
     }
 }
 
-pub struct EspEventLoop<T>(Arc<EventLoopHandle<T>>)
-where
-    T: EspEventLoopType;
+// Last payload seen for each (source, event_id) pair which has retention enabled
+type RetainedEvents = BTreeMap<(usize, i32), RetainedPayload>;
 
-impl<T> EspEventLoop<T>
-where
-    T: EspEventLoopType,
-{
-    pub fn subscribe_raw<E>(
-        &mut self,
+#[derive(Copy, Clone)]
+#[repr(C, align(16))]
+struct RetainedChunk([u8; 16]);
+
+// A copy of a payload in storage aligned enough for the replay to read it back as any `P`
+#[derive(Clone)]
+struct RetainedPayload {
+    chunks: Vec<RetainedChunk>,
+    len: usize,
+}
+
+impl RetainedPayload {
+    unsafe fn copy_from(payload: *const c_types::c_void, len: usize) -> Self {
+        if payload.is_null() {
+            return Self {
+                chunks: Vec::new(),
+                len: 0,
+            };
+        }
+
+        let chunk_size = mem::size_of::<RetainedChunk>();
+        let mut chunks = vec![RetainedChunk([0; 16]); (len + chunk_size - 1) / chunk_size];
+
+        ptr::copy_nonoverlapping(payload as *const u8, chunks.as_mut_ptr() as *mut u8, len);
+
+        Self { chunks, len }
+    }
+
+    fn as_ptr(&self) -> *const c_types::c_void {
+        if self.len == 0 {
+            ptr::null()
+        } else {
+            self.chunks.as_ptr() as *const _
+        }
+    }
+}
+
+// Payloads posted with `post_boxed`, waiting to be claimed by the first matching `subscribe_boxed`
+// subscription. Only their ID travels through the event loop queue, so a payload is never freed
+// twice, however many subscriptions, replays or bridges see its event
+#[derive(Default)]
+struct BoxedPayloads {
+    next_id: u32,
+    payloads: BTreeMap<u32, ((usize, i32), Box<dyn Any + Send>)>,
+    // The number of `subscribe_boxed` subscriptions for each (source, event_id) pattern
+    subscribers: BTreeMap<(usize, i32), usize>,
+}
+
+impl BoxedPayloads {
+    fn is_subscribed(&self, key: (usize, i32)) -> bool {
+        self.subscribers
+            .keys()
+            .any(|pattern| matches_event(*pattern, key))
+    }
+}
+
+// Whether an event is matched by a subscription, where a null source stands for ESP_EVENT_ANY_BASE
+fn matches_event(pattern: (usize, i32), key: (usize, i32)) -> bool {
+    (pattern.0 == 0 || pattern.0 == key.0) && (pattern.1 == ESP_EVENT_ANY_ID || pattern.1 == key.1)
+}
+
+// Owned by the callback of a `subscribe_boxed` subscription. Once the last subscription matching
+// a payload goes away, the payload can no longer be claimed, so it is dropped
+struct BoxedSubscriber<T>
+where
+    T: EspEventLoopType,
+{
+    handle: Arc<EventLoopHandle<T>>,
+    pattern: (usize, i32),
+}
+
+impl<T> Drop for BoxedSubscriber<T>
+where
+    T: EspEventLoopType,
+{
+    fn drop(&mut self) {
+        let unclaimed = {
+            let mut boxed = self.handle.boxed.lock();
+
+            if let Some(count) = boxed.subscribers.get_mut(&self.pattern) {
+                *count -= 1;
+
+                if *count == 0 {
+                    boxed.subscribers.remove(&self.pattern);
+                }
+            }
+
+            let ids = boxed
+                .payloads
+                .iter()
+                .filter(|(_, (key, _))| !boxed.is_subscribed(*key))
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+
+            ids.into_iter()
+                .filter_map(|id| boxed.payloads.remove(&id))
+                .collect::<Vec<_>>()
+        };
+
+        // Dropped outside of the lock, as their destructors might use the event loop
+        drop(unclaimed);
+    }
+}
+
+type ErrorHandler = Arc<dyn Fn(*const c_types::c_char, i32, &dyn Display) + Send + Sync + 'static>;
+
+// Atomics rather than a mutex, as they are also updated from ISRs
+#[derive(Default)]
+struct LoopCounters {
+    posted: AtomicU32,
+    post_timeouts: AtomicU32,
+    post_errors: AtomicU32,
+    isr_posted: AtomicU32,
+    isr_post_failures: AtomicU32,
+    dispatched: AtomicU32,
+    queue_high_watermark: AtomicU32,
+}
+
+impl LoopCounters {
+    fn record_queued(&self) {
+        let queued = self
+            .posted
+            .load(Ordering::Relaxed)
+            .wrapping_add(self.isr_posted.load(Ordering::Relaxed))
+            .wrapping_sub(self.dispatched.load(Ordering::Relaxed));
+
+        // The dispatched counter is read last, so it might be ahead of the posted ones
+        if (queued as i32) > 0 {
+            self.queue_high_watermark
+                .fetch_max(queued, Ordering::Relaxed);
+        }
+    }
+}
+
+struct EventLoopHandle<T>
+where
+    T: EspEventLoopType,
+{
+    event_loop: T,
+    retained: mutex::Mutex<RetainedEvents>,
+    boxed: mutex::Mutex<BoxedPayloads>,
+    // Boxed, as its address is registered with ESP-IDF before the handle is moved
+    counters: Box<LoopCounters>,
+    diagnostics_enabled: AtomicBool,
+    error_handler: mutex::Mutex<Option<ErrorHandler>>,
+    waker: mutex::Mutex<Option<Waker>>,
+    // Whether the loop is deleted on drop
+    owned: bool,
+}
+
+impl<T> EventLoopHandle<T>
+where
+    T: EspEventLoopType,
+{
+    fn with(event_loop: T) -> Self {
+        Self {
+            event_loop,
+            retained: mutex::Mutex::new(BTreeMap::new()),
+            boxed: mutex::Mutex::new(Default::default()),
+            counters: Box::new(Default::default()),
+            diagnostics_enabled: AtomicBool::new(false),
+            error_handler: mutex::Mutex::new(None),
+            waker: mutex::Mutex::new(None),
+            owned: true,
+        }
+    }
+
+    // The ESP-IDF handle of a user loop, or null for the system loop
+    fn raw(&self) -> esp_event_loop_handle_t {
+        if T::is_system() {
+            ptr::null_mut()
+        } else {
+            let handle: &T = &self.event_loop;
+            let user: &User<Background> = unsafe { mem::transmute(handle) };
+
+            user.0
+        }
+    }
+
+    extern "C" fn count_dispatched(
+        event_handler_arg: *mut c_types::c_void,
+        _event_base: esp_event_base_t,
+        _event_id: i32,
+        _event_data: *mut c_types::c_void,
+    ) {
+        let counters = unsafe { (event_handler_arg as *const LoopCounters).as_ref() }.unwrap();
+
+        counters.dispatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Returns `None` when the payload has already been claimed, or is not a `P`
+    fn take_boxed<P>(&self, id: u32) -> Option<Box<P>>
+    where
+        P: Send + 'static,
+    {
+        let mut boxed = self.boxed.lock();
+
+        let (key, payload) = boxed.payloads.remove(&id)?;
+
+        match payload.downcast::<P>() {
+            Ok(payload) => Some(payload),
+            Err(payload) => {
+                boxed.payloads.insert(id, (key, payload));
+
+                None
+            }
+        }
+    }
+
+    fn dispatched(&self) -> u32 {
+        self.counters.dispatched.load(Ordering::Relaxed)
+    }
+
+    fn handle_error(&self, source: *const c_types::c_char, event_id: i32, error: &dyn Display) {
+        // Called outside of the lock, so that the handler can replace itself
+        let error_handler = self.error_handler.lock().clone();
+
+        if let Some(error_handler) = error_handler {
+            error_handler(source, event_id, error);
+        } else {
+            error!(
+                "Event handler for source {:?}, event ID {} failed: {}",
+                source, event_id, error
+            );
+        }
+    }
+
+    fn retained(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+    ) -> Vec<((usize, i32), RetainedPayload)> {
+        self.retained
+            .lock()
+            .iter()
+            .filter(|(key, _)| matches_event((source as usize, event_id), **key))
+            .map(|(key, payload)| (*key, payload.clone()))
+            .collect()
+    }
+}
+
+impl EventLoopHandle<System> {
+    // With `adopt`, a default loop created by another component is used rather than failing,
+    // and is then left alone when the handle is dropped
+    fn new(adopt: bool) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let result = unsafe { esp_event_loop_create_default() };
+
+        let owned = if adopt && result == ESP_ERR_INVALID_STATE as i32 {
+            info!("Using the default event loop created elsewhere");
+
+            false
+        } else {
+            esp!(result)?;
+
+            true
+        };
+
+        *taken = true;
+
+        let mut this = Self::with(System);
+        this.owned = owned;
+
+        Ok(this)
+    }
+}
+
+impl<T> EventLoopHandle<User<T>> {
+    fn new_internal(conf: &esp_event_loop_args_t) -> Result<Self, EspError> {
+        let mut handle: esp_event_loop_handle_t = ptr::null_mut();
+
+        esp!(unsafe { esp_event_loop_create(conf as *const _, &mut handle as _) })?;
+
+        // If the registration below fails, dropping the handle deletes the loop
+        let this = Self::with(User(handle, PhantomData));
+
+        // Counts every dispatched event; the handler is released together with the loop
+        esp!(unsafe {
+            esp_event_handler_instance_register_with(
+                handle,
+                ptr::null(), // ESP_EVENT_ANY_BASE
+                ESP_EVENT_ANY_ID,
+                Some(Self::count_dispatched),
+                &*this.counters as *const _ as *mut _,
+                ptr::null_mut(),
+            )
+        })?;
+
+        Ok(this)
+    }
+}
+
+impl EventLoopHandle<User<Background>> {
+    fn new(conf: &BackgroundLoopConfiguration) -> Result<Self, EspError> {
+        if let Err(err) = conf.validate() {
+            error!("Invalid background loop configuration: {}", err);
+
+            return Err(err.into());
+        }
+
+        let (nconf, _rcs) = conf.into();
+
+        Self::new_internal(&nconf)
+    }
+}
+
+impl EventLoopHandle<User<Explicit>> {
+    fn new(conf: &ExplicitLoopConfiguration) -> Result<Self, EspError> {
+        Self::new_internal(&conf.into())
+    }
+}
+
+impl EventLoopHandle<User<Pinned>> {
+    fn new(conf: &ExplicitLoopConfiguration) -> Result<Self, EspError> {
+        Self::new_internal(&conf.into())
+    }
+}
+
+impl<T> Drop for EventLoopHandle<T>
+where
+    T: EspEventLoopType,
+{
+    fn drop(&mut self) {
+        if T::is_system() {
+            let mut taken = TAKEN.lock();
+
+            if self.owned {
+                unsafe {
+                    esp!(esp_event_loop_delete_default()).unwrap();
+                }
+            }
+
+            *taken = false;
+        } else {
+            unsafe {
+                esp!(esp_event_loop_delete(self.raw())).unwrap();
+            }
+        }
+
+        info!("Dropped");
+    }
+}
+
+pub struct EspEventLoop<T>(Arc<EventLoopHandle<T>>)
+where
+    T: EspEventLoopType;
+
+fn micros_since_boot() -> u64 {
+    unsafe { esp_timer_get_time() as _ }
+}
+
+impl<T> EspEventLoop<T>
+where
+    T: EspEventLoopType,
+{
+    pub fn subscribe_raw<E>(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        callback: impl FnMut(EspEventFetchData) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.subscribe_raw_multi(&[(source, event_id)], callback)
+    }
+
+    pub fn subscribe_raw_multi<E>(
+        &self,
+        events: &[(*const c_types::c_char, i32)],
+        mut callback: impl FnMut(EspEventFetchData) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        let diagnostics = Arc::new(mutex::Mutex::new(EspSubscriptionDiagnostics::default()));
+        let callback_diagnostics = diagnostics.clone();
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let callback_paused = paused.clone();
+
+        let handle = self.0.clone();
+
+        let callback: Box<dyn FnMut(EspEventFetchData) + 'static> = Box::new(move |data| {
+            // Events arriving while the subscription is paused are dropped
+            if callback_paused.load(Ordering::SeqCst) {
+                return;
+            }
+
+            let (source, event_id) = (data.source, data.event_id);
+
+            if !handle.diagnostics_enabled.load(Ordering::Relaxed) {
+                if let Err(error) = callback(data) {
+                    handle.handle_error(source, event_id, &error);
+                }
+
+                return;
+            }
+
+            let start = micros_since_boot();
+
+            let result = callback(data);
+
+            let elapsed = Duration::from_micros(micros_since_boot() - start);
+
+            if let Err(error) = &result {
+                handle.handle_error(source, event_id, error);
+            }
+
+            let mut diagnostics = callback_diagnostics.lock();
+
+            diagnostics.invocations += 1;
+            if result.is_err() {
+                diagnostics.errors += 1;
+            }
+            diagnostics.total_time += elapsed;
+            diagnostics.max_time = diagnostics.max_time.max(elapsed);
+        });
+        let mut callback = Box::new(callback);
+
+        let unsafe_callback = UnsafeCallback::from(&mut callback);
+
+        // Replay retained events before registering, so that the callback is never invoked
+        // concurrently from the subscribing task and the event loop task
+        for (source, event_id) in events {
+            let retained = self.0.retained(*source, *event_id);
+
+            for ((retained_source, retained_event_id), payload) in retained {
+                unsafe {
+                    unsafe_callback.call(EspEventFetchData {
+                        source: retained_source as *const _,
+                        event_id: retained_event_id,
+                        payload: payload.as_ptr(),
+                    });
+                }
+            }
+        }
+
+        // If any of the registrations fails, dropping the subscription unregisters the ones done so far
+        let mut subscription = EspSubscription {
+            event_loop_handle: self.0.clone(),
+            registrations: Vec::with_capacity(events.len()),
+            diagnostics,
+            paused,
+            _callback: callback,
+        };
+
+        for (source, event_id) in events {
+            let mut handler_instance: esp_event_handler_instance_t = ptr::null_mut();
+
+            if T::is_system() {
+                esp!(unsafe {
+                    esp_event_handler_instance_register(
+                        *source,
+                        *event_id,
+                        Some(EspSubscription::<System>::handle),
+                        unsafe_callback.as_ptr(),
+                        &mut handler_instance as *mut _,
+                    )
+                })?;
+            } else {
+                esp!(unsafe {
+                    esp_event_handler_instance_register_with(
+                        self.0.raw(),
+                        *source,
+                        *event_id,
+                        Some(EspSubscription::<User<T>>::handle),
+                        unsafe_callback.as_ptr(),
+                        &mut handler_instance as *mut _,
+                    )
+                })?;
+            }
+
+            subscription
+                .registrations
+                .push((*source, *event_id, handler_instance));
+        }
+
+        Ok(subscription)
+    }
+
+    // Only for the typed events, whose payloads all have the size of `P`. For the others, use
+    // `retain_last_raw` with the size of their largest payload
+    pub fn retain_last<P>(&self) -> Result<EspSubscription<T>, EspError>
+    where
+        P: EspTypedEvent,
+    {
+        unsafe {
+            self.retain_last_raw(
+                <P as EspEventSubscribeMetadata>::source(),
+                <P as EspEventSubscribeMetadata>::event_id(),
+                mem::size_of::<P>(),
+            )
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The payload of every event matching `source` and `event_id` must either be null, or be
+    /// at least `payload_len` bytes long
+    pub unsafe fn retain_last_raw(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        payload_len: usize,
+    ) -> Result<EspSubscription<T>, EspError> {
+        let handle = self.0.clone();
+
+        self.subscribe_raw(source, event_id, move |data| {
+            let payload = RetainedPayload::copy_from(data.payload, payload_len);
+
+            handle
+                .retained
+                .lock()
+                .insert((data.source as usize, data.event_id), payload);
+
+            Ok::<_, Infallible>(())
+        })
+    }
+
+    pub fn subscriber<P>(&self) -> EspSubscriber<'_, T, P>
+    where
+        P: From<EspEventFetchData> + EspEventSubscribeMetadata + 'static,
+    {
+        self.subscriber_raw(P::source(), P::event_id()).map(P::from)
+    }
+
+    pub fn subscriber_raw(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+    ) -> EspSubscriber<'_, T, EspEventFetchData> {
+        EspSubscriber {
+            event_loop: self,
+            source,
+            event_id,
+            chain: Box::new(Some),
+        }
+    }
+
+    pub fn subscribe_async<P>(
+        &self,
+        queue_size: usize,
+    ) -> Result<EspSubscriptionStream<T, P>, EspError>
+    where
+        P: From<EspEventFetchData> + EspEventSubscribeMetadata + Send + 'static,
+    {
+        let state = Arc::new(mutex::Mutex::new(StreamState {
+            queue: VecDeque::with_capacity(queue_size),
+            queue_size,
+            waker: None,
+        }));
+
+        let callback_state = state.clone();
+
+        let subscription = self.subscribe_raw(P::source(), P::event_id(), move |data| {
+            let mut state = callback_state.lock();
+
+            if state.queue.len() < state.queue_size {
+                state.queue.push_back(P::from(data));
+            } else {
+                warn!("Subscription stream queue is full, dropping event");
+            }
+
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+
+            Ok::<_, Infallible>(())
+        })?;
+
+        Ok(EspSubscriptionStream {
+            _subscription: subscription,
+            state,
+        })
+    }
+
+    pub fn diagnostics(&self) -> EspEventLoopDiagnostics {
+        let counters = &self.0.counters;
+
+        EspEventLoopDiagnostics {
+            posted: counters.posted.load(Ordering::Relaxed),
+            post_timeouts: counters.post_timeouts.load(Ordering::Relaxed),
+            post_errors: counters.post_errors.load(Ordering::Relaxed),
+            isr_posted: counters.isr_posted.load(Ordering::Relaxed),
+            isr_post_failures: counters.isr_post_failures.load(Ordering::Relaxed),
+            queue_high_watermark: counters.queue_high_watermark.load(Ordering::Relaxed),
+        }
+    }
+
+    // Enables the invocation counts and timings of the subscriptions, which are off by default
+    // as they cost a timer read and a lock for every handled event
+    pub fn set_diagnostics_enabled(&self, enabled: bool) {
+        self.0.diagnostics_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_error_handler(
+        &self,
+        error_handler: impl Fn(*const c_types::c_char, i32, &dyn Display) + Send + Sync + 'static,
+    ) {
+        *self.0.error_handler.lock() = Some(Arc::new(error_handler));
+    }
+
+    pub fn clear_error_handler(&self) {
+        *self.0.error_handler.lock() = None;
+    }
+
+    #[cfg(esp_idf_esp_event_loop_profiling)]
+    pub fn dump() -> Result<(), EspError> {
+        // Dumps the profiling information of all event loops to stdout
+        esp!(unsafe { esp_event_dump(stdout) })
+    }
+
+    /// # Safety
+    ///
+    /// The payload of every event matching `P::source()` and `P::event_id()` must be a valid `P`
+    pub unsafe fn subscribe_ref<P, E>(
+        &self,
+        mut callback: impl for<'b> FnMut(&'b P) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        P: EspEventSubscribeMetadata,
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.subscribe_raw(P::source(), P::event_id(), move |data| {
+            callback(data.as_payload_ref())
+        })
+    }
+
+    #[cfg(all(feature = "serde", feature = "postcard"))]
+    pub fn post_encoded<P>(&self, payload: &P, wait: Option<Duration>) -> Result<bool, EspError>
+    where
+        P: serde::Serialize + EspEventSubscribeMetadata,
+    {
+        let encoded = encode(payload).map_err(|err| {
+            error!("Failed to encode event payload: {}", err);
+
+            EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap()
+        })?;
+
+        self.post_raw(
+            &EspEventPostData {
+                source: P::source(),
+                event_id: P::event_id(),
+                payload: encoded.as_ptr() as *const _,
+                payload_len: encoded.len(),
+                phantom: PhantomData,
+            },
+            wait,
+        )
+    }
+
+    #[cfg(all(feature = "serde", feature = "postcard"))]
+    pub fn subscribe_decoded<P, E>(
+        &self,
+        mut callback: impl FnMut(P) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        P: serde::de::DeserializeOwned + EspEventSubscribeMetadata,
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        let handle = self.0.clone();
+
+        self.subscribe_raw(P::source(), P::event_id(), move |data| {
+            match unsafe { decode(&data) } {
+                Ok(payload) => callback(payload),
+                Err(err) => {
+                    handle.handle_error(data.source, data.event_id, &err);
+
+                    Ok(())
+                }
+            }
+        })
+    }
+
+    // When several subscriptions match an event, only the first one to run gets its payload
+    pub fn subscribe_boxed<P, E>(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        mut callback: impl FnMut(Box<P>) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        P: Send + 'static,
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        let pattern = (source as usize, event_id);
+
+        *self.0.boxed.lock().subscribers.entry(pattern).or_insert(0) += 1;
+
+        let subscriber = BoxedSubscriber {
+            handle: self.0.clone(),
+            pattern,
+        };
+
+        self.subscribe_raw(source, event_id, move |data| {
+            if data.payload.is_null() {
+                return Ok(());
+            }
+
+            match subscriber
+                .handle
+                .take_boxed::<P>(unsafe { data.as_payload::<u32>() })
+            {
+                Some(payload) => callback(payload),
+                None => Ok(()),
+            }
+        })
+    }
+
+    pub fn post_boxed<P>(
+        &self,
         source: *const c_types::c_char,
         event_id: i32,
-        mut callback: impl FnMut(EspEventFetchData) -> Result<(), E> + 'static,
-    ) -> Result<EspSubscription<T>, EspError>
+        payload: Box<P>,
+        wait: Option<Duration>,
+    ) -> Result<bool, EspError>
     where
-        E: Display + Debug + Send + Sync + 'static,
+        P: Send + 'static,
     {
-        let mut handler_instance: esp_event_handler_instance_t = ptr::null_mut();
+        let key = (source as usize, event_id);
 
-        let callback: Box<dyn FnMut(EspEventFetchData) + 'static> =
-            Box::new(move |data| callback(data).unwrap());
-        let mut callback = Box::new(callback);
+        let (id, unclaimable) = {
+            let mut boxed = self.0.boxed.lock();
 
-        let unsafe_callback = UnsafeCallback::from(&mut callback);
+            let id = boxed.next_id;
+            boxed.next_id = id.wrapping_add(1);
 
-        if T::is_system() {
-            esp!(unsafe {
-                esp_event_handler_instance_register(
-                    source,
-                    event_id,
-                    Some(EspSubscription::<System>::handle),
-                    unsafe_callback.as_ptr(),
-                    &mut handler_instance as *mut _,
-                )
-            })?;
-        } else {
-            esp!(unsafe {
-                let handle: &T = &self.0 .0;
-                let user: &User<Background> = mem::transmute(handle);
-
-                esp_event_handler_instance_register_with(
-                    user.0,
-                    source,
-                    event_id,
-                    Some(EspSubscription::<User<T>>::handle),
-                    unsafe_callback.as_ptr(),
-                    &mut handler_instance as *mut _,
-                )
-            })?;
+            if boxed.is_subscribed(key) {
+                boxed
+                    .payloads
+                    .insert(id, (key, payload as Box<dyn Any + Send>));
+
+                (id, None)
+            } else {
+                // Nobody could ever claim it, so the event is posted without it
+                (id, Some(payload))
+            }
+        };
+
+        drop(unclaimable);
+
+        let result = self.post_raw(
+            &unsafe { EspEventPostData::new(source, event_id, &id) },
+            wait,
+        );
+
+        if !matches!(result, Ok(true)) {
+            // The event was never queued
+            let payload = self.0.boxed.lock().payloads.remove(&id);
+
+            drop(payload);
         }
 
-        Ok(EspSubscription {
-            event_loop_handle: self.0.clone(),
-            handler_instance,
-            source,
-            event_id,
-            _callback: callback,
-        })
+        result
     }
 
     pub fn post_raw(
-        &mut self,
+        &self,
         data: &EspEventPostData,
         wait: Option<Duration>,
     ) -> Result<bool, EspError> {
@@ -407,11 +1563,8 @@ where
             }
         } else {
             unsafe {
-                let handle: &T = &self.0 .0;
-                let user: &User<Background> = mem::transmute(handle);
-
                 esp_event_post_to(
-                    user.0,
+                    self.0.raw(),
                     data.source,
                     data.event_id,
                     data.payload as *const _ as *mut _,
@@ -421,58 +1574,229 @@ where
             }
         };
 
+        let counters = &self.0.counters;
+
+        match result {
+            ESP_OK => {
+                counters.posted.fetch_add(1, Ordering::Relaxed);
+
+                if !T::is_system() {
+                    counters.record_queued();
+                }
+            }
+            ESP_ERR_TIMEOUT => {
+                counters.post_timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                counters.post_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
         if result == ESP_ERR_TIMEOUT {
             Ok(false)
         } else {
             esp!(result)?;
 
+            // Only posts done via this method wake up a loop driven by `run_async`
+            if let Some(waker) = self.0.waker.lock().take() {
+                waker.wake();
+            }
+
             Ok(true)
         }
     }
 
     #[cfg(esp_idf_esp_event_post_from_isr)]
-    pub fn isr_post_raw(&mut self, data: &EspEventPostData) -> Result<bool, EspError> {
+    pub fn isr_post_raw(&self, data: &EspEventPostData) -> Result<bool, EspError> {
+        self.isr_post_raw_task_woken(data).map(|(posted, _)| posted)
+    }
+
+    #[cfg(esp_idf_esp_event_post_from_isr)]
+    pub fn isr_post_raw_task_woken(
+        &self,
+        data: &EspEventPostData,
+    ) -> Result<(bool, bool), EspError> {
+        unsafe { self.isr_postbox() }.isr_post_task_woken(data)
+    }
+
+    /// # Safety
+    ///
+    /// The returned postbox does not keep the event loop alive, so it must not be used
+    /// after the last clone of this event loop is dropped
+    #[cfg(esp_idf_esp_event_post_from_isr)]
+    pub unsafe fn isr_postbox(&self) -> EspIsrPostbox {
+        EspIsrPostbox(self.0.raw(), &*self.0.counters)
+    }
+}
+
+#[cfg(esp_idf_esp_event_post_from_isr)]
+#[derive(Copy, Clone)]
+pub struct EspIsrPostbox(esp_event_loop_handle_t, *const LoopCounters);
+
+#[cfg(esp_idf_esp_event_post_from_isr)]
+unsafe impl Send for EspIsrPostbox {}
+#[cfg(esp_idf_esp_event_post_from_isr)]
+unsafe impl Sync for EspIsrPostbox {}
+
+#[cfg(esp_idf_esp_event_post_from_isr)]
+impl EspIsrPostbox {
+    pub fn isr_post(&self, data: &EspEventPostData) -> Result<bool, EspError> {
+        self.isr_post_task_woken(data).map(|(posted, _)| posted)
+    }
+
+    pub fn isr_post_task_woken(&self, data: &EspEventPostData) -> Result<(bool, bool), EspError> {
+        // The second flag is set when posting unblocked a task of higher priority than the
+        // interrupted one, in which case the ISR should request a context switch on exit
         // TODO: Handle the case where data size is < 4 as an optimization
 
-        let result = if T::is_system() {
+        let mut task_woken: BaseType_t = 0;
+
+        // A null handle stands for the default (system) event loop
+        let result = if self.0.is_null() {
             unsafe {
                 esp_event_isr_post(
                     data.source,
                     data.event_id,
                     data.payload as *const _ as *mut _,
                     data.payload_len as _,
-                    ptr::null_mut(),
+                    &mut task_woken as *mut _,
                 )
             }
         } else {
             unsafe {
-                let handle: &T = &self.0 .0;
-                let user: &User<Background> = mem::transmute(handle);
-
                 esp_event_isr_post_to(
-                    user.0,
+                    self.0,
                     data.source,
                     data.event_id,
                     data.payload as *const _ as *mut _,
                     data.payload_len as _,
-                    ptr::null_mut(),
+                    &mut task_woken as *mut _,
                 )
             }
         };
 
+        let counters = unsafe { self.1.as_ref() }.unwrap();
+
+        if result == ESP_OK {
+            counters.isr_posted.fetch_add(1, Ordering::Relaxed);
+
+            if !self.0.is_null() {
+                counters.record_queued();
+            }
+        } else {
+            counters.isr_post_failures.fetch_add(1, Ordering::Relaxed);
+        }
+
         if result == ESP_FAIL {
-            Ok(false)
+            Ok((false, task_woken != 0))
         } else {
             esp!(result)?;
 
-            Ok(true)
+            Ok((true, task_woken != 0))
+        }
+    }
+}
+
+impl<T> EspEventLoop<T>
+where
+    T: EspDispatchedEventLoopType,
+{
+    pub fn wait_for<P>(
+        &self,
+        predicate: impl Fn(&P) -> bool + Send + 'static,
+        timeout: Option<Duration>,
+    ) -> Result<P, EspError>
+    where
+        P: From<EspEventFetchData> + EspEventSubscribeMetadata + Send + 'static,
+    {
+        let state = Arc::new(Waitable::new(None));
+        let callback_state = state.clone();
+
+        let _subscription = self.subscribe_raw(P::source(), P::event_id(), move |data| {
+            let payload = P::from(data);
+
+            if predicate(&payload) {
+                callback_state.modify(|state| {
+                    *state = Some(payload);
+
+                    (true, ())
+                });
+            }
+
+            Ok::<_, Infallible>(())
+        })?;
+
+        if let Some(timeout) = timeout {
+            let (timed_out, _) =
+                state.wait_timeout_while_and_get(timeout, |state| state.is_none(), |_| ());
+
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT)?;
+            }
+        } else {
+            state.wait_while(|state| state.is_none());
+        }
+
+        Ok(state.modify(|state| (false, state.take())).unwrap())
+    }
+}
+
+impl<T> EspEventLoop<User<T>> {
+    pub fn spin_counting(&self, duration: Option<Duration>) -> Result<u32, EspError> {
+        let deadline = duration.map(|duration| micros_since_boot() + duration.as_micros() as u64);
+
+        let start = self.0.dispatched();
+
+        loop {
+            let remaining = deadline.map(|deadline| {
+                Duration::from_micros(deadline.saturating_sub(micros_since_boot()))
+            });
+
+            let dispatched = self.0.dispatched();
+
+            esp!(unsafe { esp_event_loop_run(self.0.raw(), TickType::from(remaining).0) })?;
+
+            // Either the loop stayed idle for the remaining time, or the deadline has passed
+            if self.0.dispatched() == dispatched
+                || deadline
+                    .map(|deadline| micros_since_boot() >= deadline)
+                    .unwrap_or(false)
+            {
+                break;
+            }
         }
+
+        Ok(self.0.dispatched().wrapping_sub(start))
     }
 }
 
 impl EspEventLoop<System> {
     pub fn new() -> Result<Self, EspError> {
-        Ok(Self(Arc::new(EventLoopHandle::<System>::new()?)))
+        let mut system = SYSTEM.lock();
+
+        let handle = Arc::new(EventLoopHandle::<System>::new(false)?);
+
+        *system = Some(Arc::downgrade(&handle));
+
+        Ok(Self(handle))
+    }
+
+    pub fn take() -> Result<Self, EspError> {
+        Self::get_or_create()
+    }
+
+    pub fn get_or_create() -> Result<Self, EspError> {
+        let mut system = SYSTEM.lock();
+
+        if let Some(handle) = system.as_ref().and_then(Weak::upgrade) {
+            Ok(Self(handle))
+        } else {
+            let handle = Arc::new(EventLoopHandle::<System>::new(true)?);
+
+            *system = Some(Arc::downgrade(&handle));
+
+            Ok(Self(handle))
+        }
     }
 }
 
@@ -490,12 +1814,253 @@ impl EspEventLoop<User<Explicit>> {
             conf,
         )?)))
     }
+
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope EspScopedSubscriptions<'env, User<Explicit>>) -> R,
+    {
+        let scope = EspScopedSubscriptions::new(self);
+
+        f(&scope)
+    }
 }
 
 impl EspEventLoop<User<Pinned>> {
     pub fn new(conf: &ExplicitLoopConfiguration) -> Result<Self, EspError> {
         Ok(Self(Arc::new(EventLoopHandle::<User<Pinned>>::new(conf)?)))
     }
+
+    pub fn tick(&self, waker: &Waker) -> Result<(), EspError> {
+        // Register the waker before dispatching, so that an event posted while dispatching is not missed
+        *self.0.waker.lock() = Some(waker.clone());
+
+        // With no ticks to run, `esp_event_loop_run` dispatches at most one event per call
+        loop {
+            let dispatched = self.0.dispatched();
+
+            esp!(unsafe { esp_event_loop_run(self.0.raw(), 0) })?;
+
+            if self.0.dispatched() == dispatched {
+                break Ok(());
+            }
+        }
+    }
+
+    pub fn run_async(&self) -> EspEventLoopRun<'_> {
+        EspEventLoopRun(self)
+    }
+
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope EspScopedSubscriptions<'env, User<Pinned>>) -> R,
+    {
+        let scope = EspScopedSubscriptions::new(self);
+
+        f(&scope)
+    }
+}
+
+pub struct EspSubscriber<'a, T, P>
+where
+    T: EspEventLoopType,
+{
+    event_loop: &'a EspEventLoop<T>,
+    source: *const c_types::c_char,
+    event_id: i32,
+    chain: Box<dyn FnMut(EspEventFetchData) -> Option<P>>,
+}
+
+impl<'a, T, P> EspSubscriber<'a, T, P>
+where
+    T: EspEventLoopType,
+    P: 'static,
+{
+    pub fn filter(mut self, mut predicate: impl FnMut(&P) -> bool + 'static) -> Self {
+        let mut chain = self.chain;
+
+        self.chain = Box::new(move |data| chain(data).filter(|payload| predicate(payload)));
+        self
+    }
+
+    pub fn map<Q>(self, mut f: impl FnMut(P) -> Q + 'static) -> EspSubscriber<'a, T, Q> {
+        let mut chain = self.chain;
+
+        EspSubscriber {
+            event_loop: self.event_loop,
+            source: self.source,
+            event_id: self.event_id,
+            chain: Box::new(move |data| chain(data).map(&mut f)),
+        }
+    }
+
+    pub fn filter_map<Q>(
+        self,
+        mut f: impl FnMut(P) -> Option<Q> + 'static,
+    ) -> EspSubscriber<'a, T, Q> {
+        let mut chain = self.chain;
+
+        EspSubscriber {
+            event_loop: self.event_loop,
+            source: self.source,
+            event_id: self.event_id,
+            chain: Box::new(move |data| chain(data).and_then(&mut f)),
+        }
+    }
+
+    // Drops the events which arrive less than `interval` after the last one let through
+    pub fn throttle(self, interval: Duration) -> Self {
+        let mut last: Option<u64> = None;
+
+        self.filter(move |_| {
+            let now = micros_since_boot();
+
+            if last
+                .map(|last| now - last < interval.as_micros() as u64)
+                .unwrap_or(false)
+            {
+                false
+            } else {
+                last = Some(now);
+                true
+            }
+        })
+    }
+
+    // Drops the events equal to the last one let through
+    pub fn dedup(self) -> Self
+    where
+        P: Clone + PartialEq,
+    {
+        let mut last: Option<P> = None;
+
+        self.filter(move |payload| {
+            if last.as_ref() == Some(payload) {
+                false
+            } else {
+                last = Some(payload.clone());
+                true
+            }
+        })
+    }
+
+    pub fn subscribe<E>(
+        self,
+        mut callback: impl FnMut(P) -> Result<(), E> + 'static,
+    ) -> Result<EspSubscription<T>, EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        let mut chain = self.chain;
+
+        self.event_loop
+            .subscribe_raw(self.source, self.event_id, move |data| {
+                if let Some(payload) = chain(data) {
+                    callback(payload)
+                } else {
+                    Ok(())
+                }
+            })
+    }
+}
+
+pub struct EspEventLoopRun<'a>(&'a EspEventLoop<User<Pinned>>);
+
+impl<'a> Future for EspEventLoopRun<'a> {
+    type Output = Result<(), EspError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.0.tick(cx.waker()) {
+            Ok(()) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+pub struct EspScopedSubscriptions<'env, T>
+where
+    T: EspEventLoopType,
+{
+    event_loop: EspEventLoop<T>,
+    subscriptions: RefCell<Vec<EspSubscription<T>>>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'env, T> EspScopedSubscriptions<'env, T>
+where
+    T: EspEventLoopType,
+{
+    fn new(event_loop: &EspEventLoop<T>) -> Self {
+        Self {
+            event_loop: event_loop.clone(),
+            subscriptions: RefCell::new(Vec::new()),
+            _env: PhantomData,
+        }
+    }
+
+    fn subscribe_boxed_callback<E>(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        callback: Box<dyn FnMut(EspEventFetchData) -> Result<(), E> + 'env>,
+    ) -> Result<(), EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        // Safe, because the subscription is unregistered when the scope ends, which is before 'env ends
+        let callback: Box<dyn FnMut(EspEventFetchData) -> Result<(), E> + 'static> =
+            unsafe { mem::transmute(callback) };
+
+        let subscription = self.event_loop.subscribe_raw(source, event_id, callback)?;
+
+        self.subscriptions.borrow_mut().push(subscription);
+
+        Ok(())
+    }
+}
+
+impl<'env> EspScopedSubscriptions<'env, User<Explicit>> {
+    pub fn subscribe_raw<E>(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        callback: impl FnMut(EspEventFetchData) -> Result<(), E> + Send + 'env,
+    ) -> Result<(), EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.subscribe_boxed_callback(source, event_id, Box::new(callback))
+    }
+}
+
+impl<'env> EspScopedSubscriptions<'env, User<Pinned>> {
+    pub fn subscribe_raw<E>(
+        &self,
+        source: *const c_types::c_char,
+        event_id: i32,
+        callback: impl FnMut(EspEventFetchData) -> Result<(), E> + 'env,
+    ) -> Result<(), EspError>
+    where
+        E: Display + Debug + Send + Sync + 'static,
+    {
+        self.subscribe_boxed_callback(source, event_id, Box::new(callback))
+    }
+}
+
+impl<'env, T> Drop for EspScopedSubscriptions<'env, T>
+where
+    T: EspEventLoopType,
+{
+    fn drop(&mut self) {
+        // Unregistering takes the event loop lock, which ESP-IDF holds while dispatching,
+        // so once this is done, none of the callbacks is running or will ever run again.
+        // A handler left registered would call a callback borrowing from the ended scope,
+        // so unlike with `EspSubscription`, leaking it is not an option
+        for subscription in self.subscriptions.get_mut().drain(..) {
+            if let Err(err) = subscription.detach() {
+                panic!("Failed to unregister a scoped event handler: {}", err);
+            }
+        }
+    }
 }
 
 impl<T> Clone for EspEventLoop<T>
@@ -516,6 +2081,71 @@ unsafe impl Sync for EspEventLoop<User<Background>> {}
 unsafe impl Send for EspEventLoop<User<Explicit>> {}
 unsafe impl Sync for EspEventLoop<User<Explicit>> {}
 
+pub struct EspEventBridge<S>
+where
+    S: EspEventLoopType,
+{
+    _subscriptions: Vec<EspSubscription<S>>,
+}
+
+impl<S> EspEventBridge<S>
+where
+    S: EspEventLoopType,
+{
+    /// # Safety
+    ///
+    /// Each route is a (source, event_id, payload_len) triple, as ESP-IDF does not pass the
+    /// payload length to the event handlers. The payload of every event matching a route must
+    /// either be null, or be at least `payload_len` bytes long
+    pub unsafe fn new<D>(
+        from: &EspEventLoop<S>,
+        to: &EspEventLoop<D>,
+        routes: &[(*const c_types::c_char, i32, usize)],
+    ) -> Result<Self, EspError>
+    where
+        D: EspEventLoopType + 'static,
+    {
+        // Bridged events would be posted back to the loop they came from, forever
+        if S::is_system() == D::is_system() && from.0.raw() == to.0.raw() {
+            error!("Cannot bridge an event loop to itself");
+
+            return Err(EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap());
+        }
+
+        let mut subscriptions = Vec::with_capacity(routes.len());
+
+        for (source, event_id, payload_len) in routes {
+            let payload_len = *payload_len;
+            let to = to.clone();
+
+            subscriptions.push(from.subscribe_raw(*source, *event_id, move |data| {
+                let post_data = EspEventPostData {
+                    source: data.source,
+                    event_id: data.event_id,
+                    payload: data.payload,
+                    payload_len: if data.payload.is_null() {
+                        0
+                    } else {
+                        payload_len
+                    },
+                    phantom: PhantomData,
+                };
+
+                // Never block the source loop; drop the event if the destination queue is full
+                if !to.post_raw(&post_data, Some(Duration::from_millis(0)))? {
+                    warn!("Destination event loop queue is full, dropping bridged event");
+                }
+
+                Ok::<_, EspError>(())
+            })?);
+        }
+
+        Ok(Self {
+            _subscriptions: subscriptions,
+        })
+    }
+}
+
 impl<T> service::Service for EspEventLoop<T>
 where
     T: EspEventLoopType,
@@ -525,7 +2155,7 @@ where
 
 impl<T> event_bus::Spin for EspEventLoop<User<T>> {
     fn spin(&mut self, duration: Option<Duration>) -> Result<(), EspError> {
-        esp!(unsafe { esp_event_loop_run(self.0 .0 .0, TickType::from(duration).0,) })
+        esp!(unsafe { esp_event_loop_run(self.0.raw(), TickType::from(duration).0,) })
     }
 }
 
@@ -594,3 +2224,66 @@ where
         Ok(self.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Temperature(f32);
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Humidity(u8);
+
+    crate::esp_typed_event!(Temperature);
+    crate::esp_typed_event!(Humidity, 7);
+
+    #[test]
+    fn typed_event_sources() {
+        let temperature = <Temperature as EspTypedEvent>::source();
+        let humidity = <Humidity as EspTypedEvent>::source();
+
+        assert!(temperature.ends_with("::Temperature\0"));
+        assert!(humidity.ends_with("::Humidity\0"));
+        assert_eq!(temperature.find('\0'), Some(temperature.len() - 1));
+
+        assert_ne!(
+            <Temperature as EspEventSubscribeMetadata>::source(),
+            <Humidity as EspEventSubscribeMetadata>::source()
+        );
+
+        // Compared by address in ESP-IDF, so it has to be stable
+        assert_eq!(
+            <Temperature as EspEventSubscribeMetadata>::source(),
+            <Temperature as EspEventSubscribeMetadata>::source()
+        );
+    }
+
+    #[test]
+    fn typed_event_ids() {
+        assert_eq!(<Temperature as EspEventSubscribeMetadata>::event_id(), 0);
+        assert_eq!(<Humidity as EspEventSubscribeMetadata>::event_id(), 7);
+    }
+
+    #[test]
+    fn typed_event_payloads() {
+        let humidity = Humidity(42);
+
+        let post_data: EspEventPostData = (&humidity).into();
+
+        assert_eq!(
+            post_data.source,
+            <Humidity as EspEventSubscribeMetadata>::source()
+        );
+        assert_eq!(post_data.event_id, 7);
+        assert_eq!(post_data.payload_len, mem::size_of::<Humidity>());
+
+        let fetch_data = EspEventFetchData {
+            source: post_data.source,
+            event_id: post_data.event_id,
+            payload: post_data.payload,
+        };
+
+        assert_eq!(Humidity::from(fetch_data), humidity);
+    }
+}
@@ -1,4 +1,15 @@
 #[cfg(esp_idf_comp_esp_http_client_enabled)]
 pub mod client;
+#[cfg(esp_idf_comp_esp_http_client_enabled)]
+pub mod cookies;
+#[cfg(any(
+    esp_idf_comp_esp_http_client_enabled,
+    all(esp_idf_comp_esp_http_server_enabled, feature = "std")
+))]
+mod date;
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+pub mod router;
 #[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
 pub mod server;
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+pub mod static_files;
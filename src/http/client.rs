@@ -1,8 +1,23 @@
+use core::cell::{Cell, RefCell};
+#[cfg(all(feature = "async", feature = "std"))]
+use core::future::Future;
+#[cfg(all(feature = "async", feature = "std"))]
+use core::pin::Pin;
+use core::ptr;
+#[cfg(all(feature = "async", feature = "std"))]
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
 extern crate alloc;
 use alloc::borrow::Cow;
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::vec::Vec;
+
+#[cfg(all(feature = "async", feature = "std"))]
+use std::sync::{mpsc, Arc};
 
 use ::log::*;
 
@@ -14,8 +29,14 @@ use esp_idf_sys::*;
 
 use uncased::{Uncased, UncasedStr};
 
+use crate::http::cookies::{has_scheme, resolve_url, CookieJar};
 use crate::private::common::Newtype;
 use crate::private::cstr::*;
+#[cfg(all(feature = "async", feature = "std"))]
+use crate::private::waitable::*;
+
+// The values of the headers sent more than once, like `Set-Cookie` or `Link`, in order
+type HeaderMap = BTreeMap<Uncased<'static>, Vec<String>>;
 
 impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     fn from(method: Method) -> Self {
@@ -45,6 +66,17 @@ impl From<Method> for Newtype<(esp_http_client_method_t, ())> {
     }
 }
 
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub enum HttpAuthType {
+    // Sent with every request
+    Basic,
+    // Sent once the server asks for it with a 401 response, which also allows Basic
+    Challenge,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[cfg_attr(feature = "std", derive(Hash))]
 pub enum FollowRedirectsPolicy {
@@ -59,20 +91,87 @@ impl Default for FollowRedirectsPolicy {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransferDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Progress {
+    pub direction: TransferDirection,
+    // Of the body of the current request or response
+    pub transferred: usize,
+    pub total: Option<usize>,
+}
+
+pub type ProgressCallback = Box<dyn FnMut(Progress)>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TcpKeepAlive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub count: u32,
+}
+
+impl Default for TcpKeepAlive {
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(5),
+            interval: Duration::from_secs(5),
+            count: 3,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct EspHttpClientConfiguration {
     pub buffer_size: Option<usize>,
+    // ESP-IDF applies the same timeout to connecting and to each read and write
+    pub timeout: Option<Duration>,
+    #[cfg(not(esp_idf_version = "4.3"))]
+    pub keep_alive: Option<TcpKeepAlive>,
+    // By default the connection is kept open for the next request if the server allows it and the
+    // request goes to the same host, so that e.g. the TLS handshake is not repeated when polling
+    pub new_connection_per_request: bool,
     pub follow_redirects_policy: FollowRedirectsPolicy,
+    // Defaults to 10
+    pub max_redirects: Option<usize>,
+    // Keep the method on 301 and 302 redirects too, rather than switching to GET. The method is
+    // always kept on 307 and 308, and such redirects are not followed if the request had a body
+    pub preserve_method_on_redirect: bool,
+    // Follow redirects between HTTP and HTTPS
+    pub allow_cross_scheme_redirects: bool,
 
     pub use_global_ca_store: bool,
+    // Set to `Some(esp_crt_bundle_attach)` to verify servers with the built-in certificate bundle
     #[cfg(not(esp_idf_version = "4.3"))]
     pub crt_bundle_attach: Option<unsafe extern "C" fn(conf: *mut c_types::c_void) -> esp_err_t>,
+    // A root certificate to verify the server with, either PEM - which has to be NUL-terminated -
+    // or DER. Skipping the verification altogether is only possible with
+    // CONFIG_ESP_TLS_SKIP_SERVER_CERT_VERIFY, when none of the options above is set
+    pub server_certificate: Option<&'static [u8]>,
+    pub skip_cert_common_name_check: bool,
+
+    // For mutual TLS, in the same formats as the server certificate. Pre-shared keys are not
+    // supported, as the ESP-IDF HTTP client does not expose them
+    pub client_certificate: Option<&'static [u8]>,
+    pub private_key: Option<&'static [u8]>,
+    #[cfg(not(esp_idf_version = "4.3"))]
+    pub private_key_password: Option<&'static [u8]>,
 }
 
 #[allow(clippy::type_complexity)]
 pub struct EspHttpClient {
     raw: esp_http_client_handle_t,
     follow_redirects_policy: FollowRedirectsPolicy,
+    max_redirects: usize,
+    preserve_method_on_redirect: bool,
+    allow_cross_scheme_redirects: bool,
+    new_connection_per_request: bool,
+    has_credentials: bool,
+    progress_callback: RefCell<Option<ProgressCallback>>,
+    cookie_jar: Option<CookieJar>,
     event_handler: Box<Option<Box<dyn Fn(&esp_http_client_event_t) -> esp_err_t>>>,
 }
 
@@ -94,14 +193,63 @@ impl EspHttpClient {
             use_global_ca_store: configuration.use_global_ca_store,
             #[cfg(not(esp_idf_version = "4.3"))]
             crt_bundle_attach: configuration.crt_bundle_attach,
+            skip_cert_common_name_check: configuration.skip_cert_common_name_check,
 
             ..Default::default()
         };
 
+        if let Some(certificate) = configuration.server_certificate {
+            let (data, _len) = x509(certificate, "server certificate")?;
+
+            native_config.cert_pem = data;
+            #[cfg(not(esp_idf_version = "4.3"))]
+            {
+                native_config.cert_len = _len as _;
+            }
+        }
+
+        if let Some(certificate) = configuration.client_certificate {
+            let (data, _len) = x509(certificate, "client certificate")?;
+
+            native_config.client_cert_pem = data;
+            #[cfg(not(esp_idf_version = "4.3"))]
+            {
+                native_config.client_cert_len = _len as _;
+            }
+        }
+
+        if let Some(key) = configuration.private_key {
+            let (data, _len) = x509(key, "private key")?;
+
+            native_config.client_key_pem = data;
+            #[cfg(not(esp_idf_version = "4.3"))]
+            {
+                native_config.client_key_len = _len as _;
+            }
+        }
+
+        #[cfg(not(esp_idf_version = "4.3"))]
+        if let Some(password) = configuration.private_key_password {
+            native_config.client_key_password = password.as_ptr() as *const _;
+            native_config.client_key_password_len = password.len() as _;
+        }
+
         if let Some(buffer_size) = configuration.buffer_size {
             native_config.buffer_size = buffer_size as _;
         };
 
+        if let Some(timeout) = configuration.timeout {
+            native_config.timeout_ms = timeout.as_millis() as _;
+        }
+
+        #[cfg(not(esp_idf_version = "4.3"))]
+        if let Some(keep_alive) = configuration.keep_alive {
+            native_config.keep_alive_enable = true;
+            native_config.keep_alive_idle = keep_alive.idle.as_secs() as _;
+            native_config.keep_alive_interval = keep_alive.interval.as_secs() as _;
+            native_config.keep_alive_count = keep_alive.count as _;
+        }
+
         let raw = unsafe { esp_http_client_init(&native_config) };
         if raw.is_null() {
             Err(EspError::from(ESP_FAIL).unwrap())
@@ -109,11 +257,101 @@ impl EspHttpClient {
             Ok(Self {
                 raw,
                 follow_redirects_policy: configuration.follow_redirects_policy,
+                max_redirects: configuration.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+                preserve_method_on_redirect: configuration.preserve_method_on_redirect,
+                allow_cross_scheme_redirects: configuration.allow_cross_scheme_redirects,
+                new_connection_per_request: configuration.new_connection_per_request,
+                has_credentials: false,
+                progress_callback: RefCell::new(None),
+                cookie_jar: None,
                 event_handler,
             })
         }
     }
 
+    // Digest authentication uses `HttpAuthType::Challenge`, as it needs the nonce from the server
+    pub fn set_credentials(
+        &mut self,
+        auth_type: HttpAuthType,
+        username: impl AsRef<str>,
+        password: impl AsRef<str>,
+    ) -> Result<(), EspError> {
+        let c_username = CString::new(username.as_ref()).unwrap();
+        let c_password = CString::new(password.as_ref()).unwrap();
+
+        // Both are copied by the client
+        esp!(unsafe { esp_http_client_set_username(self.raw, c_username.as_ptr()) })?;
+        esp!(unsafe { esp_http_client_set_password(self.raw, c_password.as_ptr() as *mut _) })?;
+        esp!(unsafe {
+            esp_http_client_set_authtype(
+                self.raw,
+                match auth_type {
+                    HttpAuthType::Basic => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_BASIC,
+                    HttpAuthType::Challenge => esp_http_client_auth_type_t_HTTP_AUTH_TYPE_NONE,
+                },
+            )
+        })?;
+
+        self.has_credentials = true;
+
+        Ok(())
+    }
+
+    // With a jar, the cookies set by the servers are sent with the subsequent requests
+    pub fn set_cookie_jar(&mut self, cookie_jar: Option<CookieJar>) {
+        self.cookie_jar = cookie_jar;
+    }
+
+    pub fn cookie_jar(&self) -> Option<&CookieJar> {
+        self.cookie_jar.as_ref()
+    }
+
+    pub fn cookie_jar_mut(&mut self) -> Option<&mut CookieJar> {
+        self.cookie_jar.as_mut()
+    }
+
+    fn apply_cookies(&mut self, url: &str) -> Result<(), EspError> {
+        // The headers of the client persist across requests
+        unsafe { esp_http_client_delete_header(self.raw, b"Cookie\0".as_ptr() as *const _) };
+
+        if let Some(cookies) = self.cookie_jar.as_mut().and_then(|jar| jar.header(url)) {
+            let c_cookies = CString::new(cookies).unwrap();
+
+            esp!(unsafe {
+                esp_http_client_set_header(
+                    self.raw,
+                    b"Cookie\0".as_ptr() as *const _,
+                    c_cookies.as_ptr(),
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Called after each write of the request body and each read of the response body
+    pub fn set_progress_callback(&mut self, callback: Option<ProgressCallback>) {
+        *self.progress_callback.borrow_mut() = callback;
+    }
+
+    fn progress(&self, progress: Progress) {
+        if let Some(callback) = self.progress_callback.borrow_mut().as_mut() {
+            callback(progress);
+        }
+    }
+
+    pub fn clear_credentials(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_http_client_set_username(self.raw, ptr::null()) })?;
+        esp!(unsafe { esp_http_client_set_password(self.raw, ptr::null_mut()) })?;
+        esp!(unsafe {
+            esp_http_client_set_authtype(self.raw, esp_http_client_auth_type_t_HTTP_AUTH_TYPE_NONE)
+        })?;
+
+        self.has_credentials = false;
+
+        Ok(())
+    }
+
     extern "C" fn on_events(event: *mut esp_http_client_event_t) -> esp_err_t {
         match unsafe { event.as_mut() } {
             Some(event) => {
@@ -151,7 +389,15 @@ impl Client for EspHttpClient {
     ) -> Result<Self::Request<'_>, Self::Error> {
         let c_url = CString::new(url.as_ref()).unwrap();
 
+        // A connection with an unread response body cannot be reused either
+        if self.new_connection_per_request
+            || !unsafe { esp_http_client_is_complete_data_received(self.raw) }
+        {
+            esp!(unsafe { esp_http_client_close(self.raw) })?;
+        }
+
         esp!(unsafe { esp_http_client_set_url(self.raw, c_url.as_ptr() as _) })?;
+        self.apply_cookies(url.as_ref())?;
         esp!(unsafe {
             esp_http_client_set_method(
                 self.raw,
@@ -167,6 +413,8 @@ impl Client for EspHttpClient {
 
         Ok(EspHttpRequest {
             client: self,
+            url: url.as_ref().into(),
+            method,
             follow_redirects,
         })
     }
@@ -174,6 +422,8 @@ impl Client for EspHttpClient {
 
 pub struct EspHttpRequest<'a> {
     client: &'a mut EspHttpClient,
+    url: String,
+    method: Method,
     follow_redirects: bool,
 }
 
@@ -187,8 +437,33 @@ impl<'a> Request<'a> for EspHttpRequest<'a> {
 
         Ok(Self::Write::<'a> {
             client: self.client,
+            url: self.url,
+            method: self.method,
             follow_redirects: self.follow_redirects,
             size,
+            chunked: false,
+            sent: 0,
+            redirects: Vec::new(),
+        })
+    }
+}
+
+impl<'a> EspHttpRequest<'a> {
+    // For bodies of unknown length, sent with chunked transfer encoding: each write becomes a chunk
+    pub fn into_chunked_writer(self) -> Result<EspHttpRequestWrite<'a>, EspError> {
+        // A negative length makes the ESP-IDF client send the `Transfer-Encoding: chunked` header,
+        // the chunks themselves are framed by the writer
+        esp!(unsafe { esp_http_client_open(self.client.raw, -1) })?;
+
+        Ok(EspHttpRequestWrite {
+            client: self.client,
+            url: self.url,
+            method: self.method,
+            follow_redirects: self.follow_redirects,
+            size: 0,
+            chunked: true,
+            sent: 0,
+            redirects: Vec::new(),
         })
     }
 }
@@ -215,27 +490,77 @@ impl<'a> SendHeaders<'a> for EspHttpRequest<'a> {
 
 pub struct EspHttpRequestWrite<'a> {
     client: &'a mut EspHttpClient,
+    url: String,
+    method: Method,
     follow_redirects: bool,
     size: usize,
+    chunked: bool,
+    sent: usize,
+    redirects: Vec<String>,
 }
 
 impl<'a> EspHttpRequestWrite<'a> {
-    fn fetch_headers(&mut self) -> Result<BTreeMap<Uncased<'static>, String>, EspError> {
-        let mut headers = BTreeMap::new();
+    // Sends everything from the reader, returning the number of bytes sent
+    pub fn write_from<R>(&mut self, mut read: R) -> Result<usize, EspError>
+    where
+        R: Read,
+        R::Error: core::fmt::Debug,
+    {
+        let mut buf = [0_u8; 512];
+        let mut total = 0;
+
+        loop {
+            let len = read.do_read(&mut buf).map_err(|err| {
+                warn!("Failed to read the request body: {:?}", err);
+
+                EspError::from(ESP_FAIL).unwrap()
+            })?;
+
+            if len == 0 {
+                break;
+            }
+
+            self.do_write(&buf[..len])?;
+            total += len;
+        }
+
+        Ok(total)
+    }
+
+    fn write_all_raw(&mut self, mut data: &[u8]) -> Result<(), EspError> {
+        while !data.is_empty() {
+            let result = unsafe {
+                esp_http_client_write(self.client.raw, data.as_ptr() as _, data.len() as _)
+            };
+            if result <= 0 {
+                esp!(if result < 0 { result } else { ESP_FAIL })?;
+            }
+
+            data = &data[result as usize..];
+        }
+
+        Ok(())
+    }
+
+    fn fetch_headers(&mut self) -> Result<HeaderMap, EspError> {
+        let mut headers = HeaderMap::new();
+        let mut authenticated = false;
 
         loop {
             // TODO: Implement a mechanism where the client can declare in which header it is interested
-            let headers_ptr = &mut headers as *mut BTreeMap<Uncased, String>;
+            let headers_ptr = &mut headers as *mut HeaderMap;
 
             let handler = move |event: &esp_http_client_event_t| {
                 if event.event_id == esp_http_client_event_id_t_HTTP_EVENT_ON_HEADER {
                     unsafe {
                         // TODO: Replace with a proper conversion from ISO-8859-1 to UTF8
 
-                        headers_ptr.as_mut().unwrap().insert(
-                            Uncased::from(from_cstr_ptr(event.header_key).into_owned()),
-                            from_cstr_ptr(event.header_value).into_owned(),
-                        );
+                        headers_ptr
+                            .as_mut()
+                            .unwrap()
+                            .entry(Uncased::from(from_cstr_ptr(event.header_key).into_owned()))
+                            .or_insert_with(Vec::new)
+                            .push(from_cstr_ptr(event.header_value).into_owned());
                     }
                 }
 
@@ -254,22 +579,49 @@ impl<'a> EspHttpRequestWrite<'a> {
 
             trace!("Fetched headers: {:?}", headers);
 
-            if self.follow_redirects {
-                let status = unsafe { esp_http_client_get_status_code(self.client.raw) as u16 };
+            if let Some(jar) = self.client.cookie_jar.as_mut() {
+                for set_cookie in headers
+                    .get(UncasedStr::new("Set-Cookie"))
+                    .into_iter()
+                    .flatten()
+                {
+                    jar.store(&self.url, set_cookie);
+                }
+            }
+
+            let status = unsafe { esp_http_client_get_status_code(self.client.raw) as u16 };
 
-                if status::REDIRECT.contains(&status) {
-                    info!("Got response {}, about to follow redirect", status);
+            let location = first_header(&headers, "Location").map(String::from);
+
+            if let Some(location) =
+                location.filter(|_| self.follow_redirects && status::REDIRECT.contains(&status))
+            {
+                if let Some(method) = self.redirect_method(status, &location) {
+                    info!(
+                        "Got response {}, about to follow redirect to {}",
+                        status, location
+                    );
 
                     let mut len = 0_i32;
                     esp!(unsafe { esp_http_client_flush_response(self.client.raw, &mut len) })?;
                     esp!(unsafe {
                         esp_http_client_set_method(
                             self.client.raw,
-                            esp_http_client_method_t_HTTP_METHOD_GET,
+                            Newtype::<(esp_http_client_method_t, ())>::from(method).0 .0,
                         )
                     })?;
                     esp!(unsafe { esp_http_client_set_redirection(self.client.raw) })?;
-                    esp!(unsafe { esp_http_client_open(self.client.raw, self.size as _) })?;
+
+                    self.url = resolve_url(&self.url, &location);
+                    self.client.apply_cookies(&self.url)?;
+
+                    // The body - if any - is not sent again
+                    esp!(unsafe { esp_http_client_open(self.client.raw, 0) })?;
+
+                    self.method = method;
+                    self.size = 0;
+                    self.chunked = false;
+                    self.redirects.push(location);
 
                     headers.clear();
 
@@ -277,12 +629,76 @@ impl<'a> EspHttpRequestWrite<'a> {
                 }
             }
 
+            if status == 401
+                && self.client.has_credentials
+                && !authenticated
+                && self.size == 0
+                && !self.chunked
+            {
+                info!("Got response 401, about to retry with credentials");
+
+                let mut len = 0_i32;
+                esp!(unsafe { esp_http_client_flush_response(self.client.raw, &mut len) })?;
+
+                // Picks Basic or Digest as requested by the server
+                esp!(unsafe { esp_http_client_add_auth(self.client.raw) })?;
+                self.client.apply_cookies(&self.url)?;
+                esp!(unsafe { esp_http_client_open(self.client.raw, 0) })?;
+
+                authenticated = true;
+
+                headers.clear();
+
+                continue;
+            }
+
             break;
         }
 
         Ok(headers)
     }
 
+    // Returns the method to follow the redirect with, or `None` if it should not be followed
+    fn redirect_method(&self, status: u16, location: &str) -> Option<Method> {
+        if self.redirects.len() >= self.client.max_redirects {
+            warn!("Not following redirect: too many redirects");
+            return None;
+        }
+
+        let https = unsafe { esp_http_client_get_transport_type(self.client.raw) }
+            == esp_http_client_transport_t_HTTP_TRANSPORT_OVER_SSL;
+
+        let to_https = if has_scheme(location, "https://") {
+            Some(true)
+        } else if has_scheme(location, "http://") {
+            Some(false)
+        } else {
+            None
+        };
+
+        if !self.client.allow_cross_scheme_redirects && to_https.map_or(false, |to| to != https) {
+            warn!(
+                "Not following redirect: cross-scheme redirect to {}",
+                location
+            );
+            return None;
+        }
+
+        let preserve = status == 307
+            || status == 308
+            || (self.client.preserve_method_on_redirect && status != 303)
+            || self.method == Method::Head;
+
+        if !preserve {
+            Some(Method::Get)
+        } else if self.size > 0 || self.chunked {
+            warn!("Not following redirect: the request body cannot be sent again");
+            None
+        } else {
+            Some(self.method)
+        }
+    }
+
     fn register_handler(
         &mut self,
         handler: impl Fn(&esp_http_client_event_t) -> esp_err_t + 'static,
@@ -299,11 +715,17 @@ impl<'a> RequestWrite<'a> for EspHttpRequestWrite<'a> {
     type Response = EspHttpResponse<'a>;
 
     fn into_response(mut self) -> Result<Self::Response, Self::Error> {
+        if self.chunked {
+            self.write_all_raw(b"0\r\n\r\n")?;
+        }
+
         let headers = self.fetch_headers()?;
 
         Ok(EspHttpResponse {
             client: self.client,
             headers,
+            redirects: self.redirects,
+            received: Cell::new(0),
         })
     }
 }
@@ -312,19 +734,54 @@ impl<'a> Write for EspHttpRequestWrite<'a> {
     type Error = EspError;
 
     fn do_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let result =
-            unsafe { esp_http_client_write(self.client.raw, buf.as_ptr() as _, buf.len() as _) };
-        if result < 0 {
-            esp!(result)?;
-        }
+        let len = if self.chunked {
+            // An empty chunk would end the body
+            if !buf.is_empty() {
+                self.write_all_raw(format!("{:x}\r\n", buf.len()).as_bytes())?;
+                self.write_all_raw(buf)?;
+                self.write_all_raw(b"\r\n")?;
+            }
 
-        Ok(result as _)
+            buf.len()
+        } else {
+            let result = unsafe {
+                esp_http_client_write(self.client.raw, buf.as_ptr() as _, buf.len() as _)
+            };
+            if result < 0 {
+                esp!(result)?;
+            }
+
+            result as usize
+        };
+
+        self.sent += len;
+        self.client.progress(Progress {
+            direction: TransferDirection::Sent,
+            transferred: self.sent,
+            total: if self.chunked { None } else { Some(self.size) },
+        });
+
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> std::io::Write for EspHttpRequestWrite<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Write::do_write(self, buf)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
     }
 }
 
 pub struct EspHttpResponse<'a> {
     client: &'a mut EspHttpClient,
-    headers: BTreeMap<Uncased<'static>, String>,
+    headers: HeaderMap,
+    redirects: Vec<String>,
+    received: Cell<usize>,
 }
 
 impl<'a> Response for EspHttpResponse<'a> {
@@ -345,9 +802,7 @@ impl<'a> Headers for EspHttpResponse<'a> {
         if name.as_ref().eq_ignore_ascii_case("Content-Length") {
             self.content_len().map(|l| Cow::Owned(l.to_string()))
         } else {
-            self.headers
-                .get(UncasedStr::new(name.as_ref()))
-                .map(|s| Cow::Borrowed(s.as_str()))
+            combined_header(&self.headers, name.as_ref())
         }
     }
 
@@ -372,6 +827,41 @@ impl<'a> Status for EspHttpResponse<'a> {
     }
 }
 
+impl<'a> EspHttpResponse<'a> {
+    // All the headers, with the names as sent by the server, and those sent more than once as
+    // many times
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        all_headers(&self.headers)
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.raw_header("Content-Type")
+    }
+
+    pub fn etag(&self) -> Option<&str> {
+        self.raw_header("ETag")
+    }
+
+    // As sent by the server, to be passed back in an `If-Modified-Since` header
+    pub fn last_modified(&self) -> Option<&str> {
+        self.raw_header("Last-Modified")
+    }
+
+    fn raw_header(&self, name: &str) -> Option<&str> {
+        first_header(&self.headers, name)
+    }
+
+    // The locations of the redirects followed to get this response, in order
+    pub fn redirects(&self) -> &[String] {
+        &self.redirects
+    }
+
+    // Whether the whole body has been read
+    pub fn is_complete(&self) -> bool {
+        unsafe { esp_http_client_is_complete_data_received(self.client.raw) }
+    }
+}
+
 impl<'a> Read for &EspHttpResponse<'a> {
     type Error = EspError;
 
@@ -383,6 +873,557 @@ impl<'a> Read for &EspHttpResponse<'a> {
             esp!(result)?;
         }
 
+        self.received.set(self.received.get() + result as usize);
+        self.client.progress(Progress {
+            direction: TransferDirection::Received,
+            transferred: self.received.get(),
+            total: self.content_len(),
+        });
+
         Ok(result as _)
     }
 }
+
+// The body is read from the connection as it is consumed, so it does not have to fit in memory
+#[cfg(feature = "std")]
+impl<'a> std::io::Read for &EspHttpResponse<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Read::do_read(self, buf).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+    }
+}
+
+// Reads the body of a part, see `Multipart::file`
+trait PartRead {
+    fn read_part(&mut self, buf: &mut [u8]) -> Result<usize, EspError>;
+}
+
+impl<R> PartRead for R
+where
+    R: Read,
+    R::Error: core::fmt::Debug,
+{
+    fn read_part(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.do_read(buf).map_err(|err| {
+            warn!("Failed to read a multipart file: {:?}", err);
+
+            EspError::from(ESP_FAIL).unwrap()
+        })
+    }
+}
+
+enum PartBody<'r> {
+    Data(Vec<u8>),
+    Read(Box<dyn PartRead + 'r>, Option<usize>),
+}
+
+struct Part<'r> {
+    header: String,
+    body: PartBody<'r>,
+}
+
+// A multipart/form-data request body. It is sent with a Content-Length if the length of all the
+// files is known, and with chunked transfer encoding otherwise
+pub struct Multipart<'r> {
+    boundary: String,
+    parts: Vec<Part<'r>>,
+}
+
+impl<'r> Multipart<'r> {
+    pub fn new() -> Self {
+        Self {
+            boundary: format!(
+                "esp-idf-svc-{:08x}{:08x}",
+                unsafe { esp_random() },
+                unsafe { esp_random() }
+            ),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn field(mut self, name: &str, value: &str) -> Self {
+        let header = self.part_header(name, None, None);
+
+        self.parts.push(Part {
+            header,
+            body: PartBody::Data(value.as_bytes().to_vec()),
+        });
+
+        self
+    }
+
+    // The file is read while the request is sent; `len` - if known - has to be exact
+    pub fn file<R>(
+        mut self,
+        name: &str,
+        filename: &str,
+        content_type: &str,
+        read: R,
+        len: Option<usize>,
+    ) -> Self
+    where
+        R: Read + 'r,
+        R::Error: core::fmt::Debug,
+    {
+        let header = self.part_header(name, Some(filename), Some(content_type));
+
+        self.parts.push(Part {
+            header,
+            body: PartBody::Read(Box::new(read), len),
+        });
+
+        self
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    pub fn content_len(&self) -> Option<usize> {
+        self.parts
+            .iter()
+            .try_fold(self.trailer().len(), |total, part| {
+                let body = match &part.body {
+                    PartBody::Data(data) => data.len(),
+                    PartBody::Read(_, len) => (*len)?,
+                };
+
+                Some(total + part.header.len() + body + 2)
+            })
+    }
+
+    pub fn send<'a>(
+        self,
+        mut request: EspHttpRequest<'a>,
+    ) -> Result<EspHttpResponse<'a>, EspError> {
+        request.set_header("Content-Type", self.content_type());
+
+        let mut write = match self.content_len() {
+            Some(len) => request.into_writer(len)?,
+            None => request.into_chunked_writer()?,
+        };
+
+        let trailer = self.trailer();
+
+        for part in self.parts {
+            write_all(&mut write, part.header.as_bytes())?;
+
+            match part.body {
+                PartBody::Data(data) => write_all(&mut write, &data)?,
+                PartBody::Read(mut read, len) => {
+                    let mut buf = [0_u8; 512];
+                    let mut total = 0;
+
+                    loop {
+                        let read_len = read.read_part(&mut buf)?;
+                        if read_len == 0 {
+                            break;
+                        }
+
+                        write_all(&mut write, &buf[..read_len])?;
+                        total += read_len;
+                    }
+
+                    if len.map_or(false, |len| len != total) {
+                        warn!(
+                            "Multipart file is {} bytes long rather than {:?}",
+                            total, len
+                        );
+                        esp!(ESP_ERR_INVALID_SIZE as i32)?;
+                    }
+                }
+            }
+
+            write_all(&mut write, b"\r\n")?;
+        }
+
+        write_all(&mut write, trailer.as_bytes())?;
+
+        write.into_response()
+    }
+
+    fn part_header(
+        &self,
+        name: &str,
+        filename: Option<&str>,
+        content_type: Option<&str>,
+    ) -> String {
+        let mut header = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"",
+            self.boundary,
+            escape_quotes(name)
+        );
+
+        if let Some(filename) = filename {
+            header.push_str(&format!("; filename=\"{}\"", escape_quotes(filename)));
+        }
+
+        header.push_str("\r\n");
+
+        if let Some(content_type) = content_type {
+            header.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+
+        header.push_str("\r\n");
+
+        header
+    }
+
+    fn trailer(&self) -> String {
+        format!("--{}--\r\n", self.boundary)
+    }
+}
+
+impl<'r> Default for Multipart<'r> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The values of a header sent more than once are combined as per RFC 7230, section 3.2.2, except
+// for `Set-Cookie`, whose values may contain commas themselves and are only returned by `headers`
+fn combined_header<'h>(headers: &'h HeaderMap, name: &str) -> Option<Cow<'h, str>> {
+    let values = headers.get(UncasedStr::new(name))?;
+
+    match values.as_slice() {
+        [value] => Some(Cow::Borrowed(value.as_str())),
+        [value, ..] if name.eq_ignore_ascii_case("Set-Cookie") => {
+            Some(Cow::Borrowed(value.as_str()))
+        }
+        values => Some(Cow::Owned(values.join(", "))),
+    }
+}
+
+fn first_header<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get(UncasedStr::new(name))
+        .and_then(|values| values.first())
+        .map(String::as_str)
+}
+
+fn all_headers(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers.iter().flat_map(|(name, values)| {
+        values
+            .iter()
+            .map(move |value| (name.as_str(), value.as_str()))
+    })
+}
+
+fn escape_quotes(value: &str) -> String {
+    value.replace('"', "%22")
+}
+
+fn write_all(write: &mut EspHttpRequestWrite<'_>, mut data: &[u8]) -> Result<(), EspError> {
+    // E.g. a file longer than announced, which would otherwise be truncated by ESP-IDF
+    if !write.chunked && write.sent + data.len() > write.size {
+        warn!(
+            "Multipart body exceeds its {} bytes Content-Length",
+            write.size
+        );
+        esp!(ESP_ERR_INVALID_SIZE as i32)?;
+    }
+
+    while !data.is_empty() {
+        let len = write.do_write(data)?;
+
+        // The connection was closed, retrying would loop forever
+        if len == 0 {
+            esp!(ESP_FAIL)?;
+        }
+
+        data = &data[len..];
+    }
+
+    Ok(())
+}
+
+// Returns the data and its length, which is 0 for PEM, as ESP-IDF expects
+fn x509(data: &'static [u8], name: &str) -> Result<(*const c_types::c_char, usize), EspError> {
+    if data.starts_with(b"-----BEGIN ") {
+        if data.last() != Some(&0) {
+            warn!("PEM {} is not NUL-terminated", name);
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        Ok((data.as_ptr() as *const _, 0))
+    } else if cfg!(esp_idf_version = "4.3") {
+        warn!("DER {} is not supported by ESP-IDF 4.3", name);
+        Err(EspError::from(ESP_ERR_NOT_SUPPORTED as i32).unwrap())
+    } else {
+        Ok((data.as_ptr() as *const _, data.len()))
+    }
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+struct Completion<T> {
+    result: Option<Result<T, EspError>>,
+    waker: Option<Waker>,
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+impl<T> Completion<T>
+where
+    T: Send,
+{
+    fn new() -> Arc<Waitable<Self>> {
+        Arc::new(Waitable::new(Self {
+            result: None,
+            waker: None,
+        }))
+    }
+
+    fn complete(completion: &Waitable<Self>, result: Result<T, EspError>) {
+        completion.modify(|completion| {
+            completion.result = Some(result);
+
+            if let Some(waker) = completion.waker.take() {
+                waker.wake();
+            }
+
+            (true, ())
+        });
+    }
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+struct CompletionFuture<T>(Arc<Waitable<Completion<T>>>);
+
+#[cfg(all(feature = "async", feature = "std"))]
+impl<T> Future for CompletionFuture<T>
+where
+    T: Send,
+{
+    type Output = Result<T, EspError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = self.0.modify(|completion| {
+            if completion.result.is_none() {
+                completion.waker = Some(cx.waker().clone());
+            }
+
+            (false, completion.result.take())
+        });
+
+        match result {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+struct ResponseHead {
+    status: u16,
+    headers: HeaderMap,
+    content_len: Option<usize>,
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+enum Job {
+    Request {
+        method: Method,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+        completion: Arc<Waitable<Completion<ResponseHead>>>,
+    },
+    Read {
+        max_len: usize,
+        completion: Arc<Waitable<Completion<Vec<u8>>>>,
+    },
+}
+
+// Runs the requests on a dedicated thread, as the ESP-IDF client only has a blocking API. The
+// requests are executed one at a time, and the body of a response can only be read until the next
+// request is made
+#[cfg(all(feature = "async", feature = "std"))]
+pub struct EspHttpAsyncClient {
+    jobs: mpsc::Sender<Job>,
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+impl EspHttpAsyncClient {
+    pub fn new(configuration: &EspHttpClientConfiguration) -> Result<Self, EspError> {
+        let (jobs, jobs_receiver) = mpsc::channel();
+        let (init, init_receiver) = mpsc::sync_channel(1);
+
+        let configuration = *configuration;
+
+        std::thread::Builder::new()
+            .name("http_client".into())
+            .stack_size(8192)
+            .spawn(move || match EspHttpClient::new(&configuration) {
+                Ok(client) => {
+                    let _ = init.send(Ok(()));
+
+                    Self::run(client, jobs_receiver);
+                }
+                Err(err) => {
+                    let _ = init.send(Err(err));
+                }
+            })
+            .map_err(|err| {
+                warn!("Failed to spawn the HTTP client thread: {}", err);
+
+                EspError::from(ESP_FAIL).unwrap()
+            })?;
+
+        init_receiver
+            .recv()
+            .map_err(|_| EspError::from(ESP_FAIL).unwrap())??;
+
+        Ok(Self { jobs })
+    }
+
+    pub async fn request(
+        &mut self,
+        method: Method,
+        url: impl AsRef<str>,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<EspHttpAsyncResponse<'_>, EspError> {
+        let completion = Completion::new();
+
+        self.send(Job::Request {
+            method,
+            url: url.as_ref().into(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| ((*name).into(), (*value).into()))
+                .collect(),
+            body: body.into(),
+            completion: completion.clone(),
+        })?;
+
+        let head = CompletionFuture(completion).await?;
+
+        Ok(EspHttpAsyncResponse { client: self, head })
+    }
+
+    fn send(&self, job: Job) -> Result<(), EspError> {
+        self.jobs
+            .send(job)
+            .map_err(|_| EspError::from(ESP_ERR_INVALID_STATE as i32).unwrap())
+    }
+
+    fn run(mut client: EspHttpClient, jobs: mpsc::Receiver<Job>) {
+        let mut next = jobs.recv().ok();
+
+        while let Some(job) = next.take() {
+            match job {
+                Job::Request {
+                    method,
+                    url,
+                    headers,
+                    body,
+                    completion,
+                } => match Self::execute(&mut client, method, &url, &headers, &body) {
+                    Ok(response) => {
+                        Completion::complete(
+                            &completion,
+                            Ok(ResponseHead {
+                                status: response.status(),
+                                headers: response.headers.clone(),
+                                content_len: response.content_len(),
+                            }),
+                        );
+
+                        // Serve the reads of the body until the next request
+                        loop {
+                            match jobs.recv() {
+                                Ok(Job::Read {
+                                    max_len,
+                                    completion,
+                                }) => {
+                                    let mut buf = vec![0; max_len];
+
+                                    let result = (&response).do_read(&mut buf).map(|len| {
+                                        buf.truncate(len);
+                                        buf
+                                    });
+
+                                    Completion::complete(&completion, result);
+                                }
+                                Ok(job) => {
+                                    next = Some(job);
+                                    break;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                    Err(err) => Completion::complete(&completion, Err(err)),
+                },
+                Job::Read { completion, .. } => Completion::complete(
+                    &completion,
+                    Err(EspError::from(ESP_ERR_INVALID_STATE as i32).unwrap()),
+                ),
+            }
+
+            if next.is_none() {
+                next = jobs.recv().ok();
+            }
+        }
+
+        info!("HTTP client thread finished");
+    }
+
+    fn execute<'a>(
+        client: &'a mut EspHttpClient,
+        method: Method,
+        url: &str,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> Result<EspHttpResponse<'a>, EspError> {
+        let mut request = client.request(method, url)?;
+
+        for (name, value) in headers {
+            request.set_header(name.clone(), value.clone());
+        }
+
+        let mut write = request.into_writer(body.len())?;
+
+        let mut written = 0;
+        while written < body.len() {
+            written += write.do_write(&body[written..])?;
+        }
+
+        write.into_response()
+    }
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+pub struct EspHttpAsyncResponse<'a> {
+    client: &'a mut EspHttpAsyncClient,
+    head: ResponseHead,
+}
+
+#[cfg(all(feature = "async", feature = "std"))]
+impl<'a> EspHttpAsyncResponse<'a> {
+    pub fn status(&self) -> u16 {
+        self.head.status
+    }
+
+    pub fn header(&self, name: impl AsRef<str>) -> Option<Cow<'_, str>> {
+        combined_header(&self.head.headers, name.as_ref())
+    }
+
+    pub fn content_len(&self) -> Option<usize> {
+        self.head.content_len
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        all_headers(&self.head.headers)
+    }
+
+    // Returns up to `max_len` bytes of the body, or an empty buffer at its end
+    pub async fn read(&mut self, max_len: usize) -> Result<Vec<u8>, EspError> {
+        let completion = Completion::new();
+
+        self.client.send(Job::Read {
+            max_len,
+            completion: completion.clone(),
+        })?;
+
+        CompletionFuture(completion).await
+    }
+}
@@ -0,0 +1,386 @@
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use embedded_svc::sys_time::SystemTime;
+
+use crate::systime::EspSystemTime;
+
+use super::date;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(Hash))]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+    // Sent only to the host which set it, as it had no `Domain` attribute
+    pub host_only: bool,
+    pub secure: bool,
+    // In seconds since the epoch; `None` for the cookies living as long as the jar
+    pub expires: Option<u64>,
+}
+
+impl Cookie {
+    pub fn matches(&self, https: bool, host: &str, path: &str) -> bool {
+        let domain_matches = if self.host_only {
+            host.eq_ignore_ascii_case(&self.domain)
+        } else {
+            domain_matches(host, &self.domain)
+        };
+
+        domain_matches && path_matches(path, &self.path) && (https || !self.secure)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
+}
+
+// Stores the cookies set by the servers and replays them on the matching requests, until they
+// expire with `Max-Age` or `Expires`. Both are checked against the system time, which has to be
+// synchronized (e.g. with SNTP) for `Expires`: before that, the system time is the one since boot,
+// so only the dates in the past - used by the servers to remove cookies - take effect
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn cookies(&self) -> &[Cookie] {
+        &self.cookies
+    }
+
+    pub fn clear(&mut self) {
+        self.cookies.clear();
+    }
+
+    // Stores the cookie of a `Set-Cookie` header received in the response to `url`
+    pub fn store(&mut self, url: &str, set_cookie: &str) {
+        self.store_at(url, set_cookie, now());
+    }
+
+    fn store_at(&mut self, url: &str, set_cookie: &str, now: u64) {
+        let (_, host, request_path) = match split_url(url) {
+            Some(parts) => parts,
+            None => return,
+        };
+
+        let mut attributes = set_cookie.split(';');
+
+        let (name, value) = match attributes.next().and_then(|pair| pair.split_once('=')) {
+            Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+            _ => return,
+        };
+
+        let mut cookie = Cookie {
+            name: name.into(),
+            value: value.into(),
+            domain: host.to_ascii_lowercase(),
+            path: default_path(request_path).into(),
+            host_only: true,
+            secure: false,
+            expires: None,
+        };
+
+        let mut max_age = None;
+        let mut expires = None;
+
+        for attribute in attributes {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let (key, value) = (key.trim(), value.trim());
+
+            if key.eq_ignore_ascii_case("Domain") {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+
+                if !domain.is_empty() {
+                    // Servers cannot set cookies for other domains
+                    if !domain_matches(host, &domain) {
+                        return;
+                    }
+
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+            } else if key.eq_ignore_ascii_case("Path") {
+                if value.starts_with('/') {
+                    cookie.path = value.into();
+                }
+            } else if key.eq_ignore_ascii_case("Secure") {
+                cookie.secure = true;
+            } else if key.eq_ignore_ascii_case("Max-Age") {
+                if let Ok(value) = value.parse::<i64>() {
+                    max_age = Some(value);
+                }
+            } else if key.eq_ignore_ascii_case("Expires") {
+                // Unparsable dates are ignored, as if the attribute was missing
+                if let Some(value) = date::parse(value) {
+                    expires = Some(value);
+                }
+            }
+        }
+
+        // Takes precedence over `Expires`
+        cookie.expires = match max_age {
+            Some(max_age) if max_age <= 0 => Some(0),
+            Some(max_age) => Some(now.saturating_add(max_age as u64)),
+            None => expires,
+        };
+
+        self.cookies.retain(|other| {
+            other.name != cookie.name || other.domain != cookie.domain || other.path != cookie.path
+        });
+
+        if !cookie.is_expired(now) {
+            self.cookies.push(cookie);
+        }
+    }
+
+    // The value of the `Cookie` header for a request to `url`, if any cookie matches it. The
+    // expired cookies are removed from the jar
+    pub fn header(&mut self, url: &str) -> Option<String> {
+        self.header_at(url, now())
+    }
+
+    fn header_at(&mut self, url: &str, now: u64) -> Option<String> {
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+
+        let (https, host, path) = split_url(url)?;
+
+        let mut cookies = self
+            .cookies
+            .iter()
+            .filter(|cookie| cookie.matches(https, host, path))
+            .collect::<Vec<_>>();
+
+        // Cookies with longer paths go first
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+
+        if cookies.is_empty() {
+            None
+        } else {
+            Some(
+                cookies
+                    .iter()
+                    .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            )
+        }
+    }
+}
+
+fn now() -> u64 {
+    EspSystemTime.now().as_secs()
+}
+
+// Returns whether the URL is HTTPS, the host and the path
+pub(crate) fn split_url(url: &str) -> Option<(bool, &str, &str)> {
+    let (https, rest) = if has_scheme(url, "https://") {
+        (true, &url[8..])
+    } else if has_scheme(url, "http://") {
+        (false, &url[7..])
+    } else {
+        return None;
+    };
+
+    let end = rest
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .unwrap_or(rest.len());
+
+    let authority = &rest[..end];
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, authority)| authority);
+
+    let host = if authority.starts_with('[') {
+        authority
+            .find(']')
+            .map_or(authority, |end| &authority[..=end])
+    } else {
+        authority.split(':').next().unwrap()
+    };
+
+    let path = &rest[end..];
+    let path = &path[..path.find(|c| c == '?' || c == '#').unwrap_or(path.len())];
+
+    Some((https, host, if path.is_empty() { "/" } else { path }))
+}
+
+// Resolves the `Location` of a redirect against the URL of the request
+pub(crate) fn resolve_url(base: &str, location: &str) -> String {
+    if split_url(location).is_some() {
+        return location.into();
+    }
+
+    let scheme_len = if has_scheme(base, "https://") { 8 } else { 7 };
+
+    if let Some(location) = location.strip_prefix("//") {
+        return format!("{}{}", &base[..scheme_len], location);
+    }
+
+    let authority_end = base[scheme_len..]
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .map_or(base.len(), |end| scheme_len + end);
+
+    if location.starts_with('/') {
+        format!("{}{}", &base[..authority_end], location)
+    } else {
+        let path = split_url(base).map_or("/", |(_, _, path)| path);
+        let dir = &path[..path.rfind('/').map_or(0, |end| end + 1)];
+
+        format!("{}{}{}", &base[..authority_end], dir, location)
+    }
+}
+
+pub(crate) fn has_scheme(url: &str, scheme: &str) -> bool {
+    url.get(..scheme.len())
+        .map_or(false, |prefix| prefix.eq_ignore_ascii_case(scheme))
+}
+
+fn domain_matches(host: &str, domain: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+
+    host == domain || (host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'))
+}
+
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || path[cookie_path.len()..].starts_with('/')))
+}
+
+fn default_path(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(0) | None => "/",
+        Some(end) => &path[..end],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn jar(url: &str, set_cookies: &[&str]) -> CookieJar {
+        let mut jar = CookieJar::new();
+
+        for set_cookie in set_cookies {
+            jar.store_at(url, set_cookie, NOW);
+        }
+
+        jar
+    }
+
+    #[test]
+    fn replays_the_matching_cookies() {
+        let mut jar = jar(
+            "http://www.example.com/app/login",
+            &[
+                "session=abc; Path=/app",
+                "lang=en; Domain=example.com; Path=/",
+                "token=xyz; Secure",
+            ],
+        );
+
+        assert_eq!(
+            jar.header_at("http://www.example.com/app/home", NOW),
+            Some("session=abc; lang=en".into())
+        );
+        assert_eq!(
+            jar.header_at("https://api.example.com/", NOW),
+            Some("lang=en".into())
+        );
+        assert_eq!(
+            jar.header_at("https://www.example.com/app/x", NOW),
+            Some("session=abc; token=xyz; lang=en".into())
+        );
+        assert_eq!(jar.header_at("http://example.org/", NOW), None);
+    }
+
+    #[test]
+    fn rejects_cookies_for_other_domains() {
+        let jar = jar("http://www.example.com/", &["a=1; Domain=example.org"]);
+
+        assert!(jar.cookies().is_empty());
+    }
+
+    #[test]
+    fn replaces_cookies_with_the_same_name_domain_and_path() {
+        let jar = jar("http://example.com/", &["a=1", "a=2"]);
+
+        assert_eq!(jar.cookies().len(), 1);
+        assert_eq!(jar.cookies()[0].value, "2");
+    }
+
+    #[test]
+    fn expires_with_max_age() {
+        let mut jar = jar("http://example.com/", &["a=1; Max-Age=60", "b=2"]);
+
+        assert_eq!(jar.cookies()[0].expires, Some(NOW + 60));
+        assert_eq!(jar.cookies()[1].expires, None);
+
+        assert_eq!(
+            jar.header_at("http://example.com/", NOW + 59),
+            Some("a=1; b=2".into())
+        );
+        assert_eq!(
+            jar.header_at("http://example.com/", NOW + 60),
+            Some("b=2".into())
+        );
+
+        // Evicted on lookup
+        assert_eq!(jar.cookies().len(), 1);
+    }
+
+    #[test]
+    fn expires_with_expires() {
+        let mut jar = jar(
+            "http://example.com/",
+            &["a=1; Expires=Wed, 15 Nov 2023 00:00:00 GMT"],
+        );
+
+        assert_eq!(jar.cookies()[0].expires, Some(1_700_006_400));
+
+        assert!(jar
+            .header_at("http://example.com/", 1_700_006_399)
+            .is_some());
+        assert!(jar
+            .header_at("http://example.com/", 1_700_006_400)
+            .is_none());
+        assert!(jar.cookies().is_empty());
+    }
+
+    #[test]
+    fn max_age_takes_precedence_over_expires() {
+        let jar = jar(
+            "http://example.com/",
+            &["a=1; Expires=Thu, 01 Jan 1970 00:00:00 GMT; Max-Age=60"],
+        );
+
+        assert_eq!(jar.cookies()[0].expires, Some(NOW + 60));
+    }
+
+    #[test]
+    fn removes_cookies_expired_by_the_server() {
+        let mut jar = jar("http://example.com/", &["a=1", "b=2", "c=3"]);
+
+        jar.store_at("http://example.com/", "a=; Max-Age=0", NOW);
+        jar.store_at(
+            "http://example.com/",
+            "b=; Expires=Thu, 01 Jan 1970 00:00:00 GMT",
+            NOW,
+        );
+        jar.store_at("http://example.com/", "c=4; Expires=garbage", NOW);
+
+        assert_eq!(jar.cookies().len(), 1);
+        assert_eq!(jar.cookies()[0].value, "4");
+        assert_eq!(jar.cookies()[0].expires, None);
+    }
+}
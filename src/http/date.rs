@@ -0,0 +1,174 @@
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+use alloc::string::String;
+
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Formats the seconds since the epoch like `Sun, 06 Nov 1994 08:49:37 GMT`
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+pub(crate) fn format(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// Returns the seconds since the epoch. Lenient like the parsing of the cookie dates in RFC 6265,
+// so that besides the format sent by `format`, the older ones like `Sunday, 06-Nov-94 08:49:37 GMT`
+// are accepted as well
+pub(crate) fn parse(date: &str) -> Option<u64> {
+    let mut time = None;
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+
+    let tokens = date
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != ':')
+        .filter(|token| !token.is_empty());
+
+    for token in tokens {
+        let numeric = token.bytes().all(|byte| byte.is_ascii_digit());
+
+        if time.is_none() && token.contains(':') {
+            time = parse_time(token);
+        } else if day.is_none() && numeric && token.len() <= 2 {
+            day = token.parse::<u32>().ok();
+        } else if month.is_none() && !numeric && token.len() >= 3 {
+            month = MONTHS
+                .iter()
+                .position(|name| token[..3].eq_ignore_ascii_case(name))
+                .map(|index| index as u32 + 1);
+        } else if year.is_none() && numeric && token.len() <= 4 {
+            year = token.parse::<i64>().ok();
+        }
+    }
+
+    let (hours, minutes, secs) = time?;
+    let (day, month) = (day?, month?);
+
+    let year = match year? {
+        year @ 0..=69 => year + 2000,
+        year @ 70..=99 => year + 1900,
+        year => year,
+    };
+
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || secs > 59 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+
+    if days < 0 {
+        return None;
+    }
+
+    Some(days as u64 * 86400 + hours * 3600 + minutes * 60 + secs)
+}
+
+fn parse_time(token: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = token.split(':').map(|part| {
+        if (1..=2).contains(&part.len()) {
+            part.parse::<u64>().ok()
+        } else {
+            None
+        }
+    });
+
+    let time = (parts.next()??, parts.next()??, parts.next()??);
+
+    if parts.next().is_some() {
+        None
+    } else {
+        Some(time)
+    }
+}
+
+// Both conversions are from http://howardhinnant.github.io/date_algorithms.html
+#[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_http_date_formats() {
+        let expected = Some(784111777);
+
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(parse("sun, 06-nov-1994 08:49:37 gmt"), expected);
+    }
+
+    #[test]
+    fn parses_the_epoch_and_two_digit_years() {
+        assert_eq!(parse("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+        assert_eq!(parse("Fri, 01 Jan 2038 00:00:00 GMT"), Some(2145916800));
+        assert_eq!(parse("Fri, 01-Jan-38 00:00:00 GMT"), Some(2145916800));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("Sun, 06 Nov 1994"), None);
+        assert_eq!(parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+        assert_eq!(parse("Sun, 06 Nov 1994 24:49:37 GMT"), None);
+        assert_eq!(parse("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+
+    #[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+    #[test]
+    fn formats_dates() {
+        assert_eq!(format(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[cfg(all(esp_idf_comp_esp_http_server_enabled, feature = "std"))]
+    #[test]
+    fn round_trips() {
+        for secs in &[
+            0, 59, 86399, 86400, 784111777, 951782400, 1700000000, 4102444799,
+        ] {
+            assert_eq!(parse(&format(*secs)), Some(*secs));
+        }
+    }
+}
@@ -0,0 +1,348 @@
+use core::str::FromStr;
+
+extern crate alloc;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// The path or query parameters of a request, with their values percent-decoded
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Parameters(BTreeMap<String, String>);
+
+impl Parameters {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Parses a query string like `a=1&b=two+words`. Of the parameters given more than once, the
+    // last value is kept
+    pub fn from_query(query: &str) -> Self {
+        let mut parameters = Self::new();
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+
+            parameters.insert(decode(name, true), decode(value, true));
+        }
+
+        parameters
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    // Returns `None` when the parameter is missing, and the parsing error when its value is not a
+    // valid `T`
+    pub fn parse<T>(&self, name: &str) -> Option<Result<T, T::Err>>
+    where
+        T: FromStr,
+    {
+        self.get(name).map(str::parse)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn insert(&mut self, name: String, value: String) {
+        self.0.insert(name, value);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Parameter(String),
+}
+
+// A route like `/api/sensor/{id}/history`, where each `{name}` matches exactly one path segment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Pattern {
+    segments: Vec<Segment>,
+}
+
+impl Pattern {
+    // Returns `None` for URIs without parameters, which are left to the matching of ESP-IDF, and
+    // fails for the segments mixing a parameter with other characters, like `{id}.json` or `v{n}`
+    #[allow(clippy::result_unit_err)]
+    pub(crate) fn new(uri: &str) -> Result<Option<Self>, ()> {
+        if !uri.contains(|c| c == '{' || c == '}') {
+            return Ok(None);
+        }
+
+        let segments = uri
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                match segment
+                    .strip_prefix('{')
+                    .and_then(|segment| segment.strip_suffix('}'))
+                {
+                    Some(name) if !name.is_empty() && !name.contains(|c| c == '{' || c == '}') => {
+                        Ok(Segment::Parameter(name.into()))
+                    }
+                    Some(_) => Err(()),
+                    None if segment.contains(|c| c == '{' || c == '}') => Err(()),
+                    None => Ok(Segment::Literal(segment.into())),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Some(Self { segments }))
+    }
+
+    // Matches exactly the given URI, for the plain handlers dispatched along with the patterns
+    pub(crate) fn literal(uri: &str) -> Self {
+        Self {
+            segments: uri
+                .trim_start_matches('/')
+                .split('/')
+                .map(|segment| Segment::Literal(segment.into()))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn is_literal(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    // The wildcard URI registered with ESP-IDF: the literal segments before the first parameter,
+    // followed by `*`
+    pub(crate) fn native_uri(&self) -> String {
+        let mut uri = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    uri.push('/');
+                    uri.push_str(literal);
+                }
+                Segment::Parameter(_) => break,
+            }
+        }
+
+        uri.push_str("/*");
+        uri
+    }
+
+    pub(crate) fn matches(&self, path: &str) -> Option<Parameters> {
+        let path = &path[..path.find(|c| c == '?' || c == '#').unwrap_or(path.len())];
+        let mut values = path.trim_start_matches('/').split('/');
+
+        let mut parameters = Parameters::new();
+
+        for segment in &self.segments {
+            let value = values.next()?;
+
+            match segment {
+                Segment::Literal(literal) => {
+                    if literal != value {
+                        return None;
+                    }
+                }
+                Segment::Parameter(name) => {
+                    if value.is_empty() {
+                        return None;
+                    }
+
+                    parameters.insert(name.clone(), decode(value, false));
+                }
+            }
+        }
+
+        if values.next().is_some() {
+            None
+        } else {
+            Some(parameters)
+        }
+    }
+}
+
+pub(crate) fn decode(s: &str, query: bool) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let byte = match bytes[index] {
+            b'%' => {
+                match s
+                    .get(index + 1..index + 3)
+                    .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        index += 2;
+                        byte
+                    }
+                    None => b'%',
+                }
+            }
+            b'+' if query => b' ',
+            byte => byte,
+        };
+
+        decoded.push(byte);
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(uri: &str) -> Pattern {
+        Pattern::new(uri).unwrap().unwrap()
+    }
+
+    #[test]
+    fn pattern_without_parameters() {
+        assert_eq!(Pattern::new("/api/status"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_mixed_segments() {
+        assert_eq!(Pattern::new("/api/sensor/{id}.json"), Err(()));
+        assert_eq!(Pattern::new("/api/v{n}/status"), Err(()));
+        assert_eq!(Pattern::new("/api/{}/status"), Err(()));
+        assert_eq!(Pattern::new("/api/{{id}}"), Err(()));
+        assert_eq!(Pattern::new("/api/id}"), Err(()));
+    }
+
+    #[test]
+    fn native_uri() {
+        let pattern = pattern("/api/sensor/{id}/history");
+
+        assert_eq!(pattern.native_uri(), "/api/sensor/*");
+    }
+
+    #[test]
+    fn matches_parameters() {
+        let pattern = pattern("/api/sensor/{id}/history/{day}");
+
+        let parameters = pattern.matches("/api/sensor/42/history/mon").unwrap();
+
+        assert_eq!(parameters.len(), 2);
+        assert_eq!(parameters.get("id"), Some("42"));
+        assert_eq!(parameters.get("day"), Some("mon"));
+        assert_eq!(parameters.parse::<u32>("id"), Some(Ok(42)));
+    }
+
+    #[test]
+    fn matches_ignores_query_and_fragment() {
+        let pattern = pattern("/api/sensor/{id}");
+
+        assert_eq!(
+            pattern
+                .matches("/api/sensor/7?verbose=1")
+                .unwrap()
+                .get("id"),
+            Some("7")
+        );
+        assert_eq!(
+            pattern.matches("/api/sensor/7#top").unwrap().get("id"),
+            Some("7")
+        );
+    }
+
+    #[test]
+    fn matches_decodes_parameters() {
+        let pattern = pattern("/files/{name}");
+
+        assert_eq!(
+            pattern.matches("/files/a%20b+c").unwrap().get("name"),
+            Some("a b+c")
+        );
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let pattern = pattern("/api/sensor/{id}");
+
+        assert_eq!(pattern.matches("/api/actuator/7"), None);
+        assert_eq!(pattern.matches("/api/sensor"), None);
+        assert_eq!(pattern.matches("/api/sensor/"), None);
+        assert_eq!(pattern.matches("/api/sensor/7/history"), None);
+    }
+
+    #[test]
+    fn matches_literals() {
+        let pattern = Pattern::literal("/api/sensor/list");
+
+        assert!(pattern.is_literal());
+        assert!(!self::pattern("/api/sensor/{id}").is_literal());
+
+        assert_eq!(
+            pattern.matches("/api/sensor/list?all=1"),
+            Some(Parameters::new())
+        );
+        assert_eq!(pattern.matches("/api/sensor/list/"), None);
+        assert_eq!(pattern.matches("/api/sensor/7"), None);
+    }
+
+    #[test]
+    fn decode_percent_escapes() {
+        assert_eq!(decode("a%2Fb%2fc", false), "a/b/c");
+        assert_eq!(decode("%C3%A9t%C3%A9", false), "été");
+    }
+
+    #[test]
+    fn decode_plus() {
+        assert_eq!(decode("two+words", true), "two words");
+        assert_eq!(decode("two+words", false), "two+words");
+    }
+
+    #[test]
+    fn decode_malformed_escapes() {
+        assert_eq!(decode("100%", false), "100%");
+        assert_eq!(decode("%zz", false), "%zz");
+        assert_eq!(decode("%4", false), "%4");
+        assert_eq!(decode("%ff", false), "\u{fffd}");
+    }
+
+    #[test]
+    fn from_query() {
+        let parameters = Parameters::from_query("a=1&b=two+words&&flag&c=%3D");
+
+        assert_eq!(parameters.len(), 4);
+        assert_eq!(parameters.get("a"), Some("1"));
+        assert_eq!(parameters.get("b"), Some("two words"));
+        assert_eq!(parameters.get("flag"), Some(""));
+        assert_eq!(parameters.get("c"), Some("="));
+        assert!(!parameters.contains("d"));
+    }
+
+    #[test]
+    fn from_query_keeps_last_value() {
+        let parameters = Parameters::from_query("a=1&a=2");
+
+        assert_eq!(parameters.get("a"), Some("2"));
+        assert_eq!(parameters.parse::<u8>("a"), Some(Ok(2)));
+        assert!(parameters.parse::<u8>("b").is_none());
+    }
+
+    #[test]
+    fn from_empty_query() {
+        assert!(Parameters::from_query("").is_empty());
+    }
+}
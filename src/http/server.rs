@@ -9,7 +9,7 @@ use alloc::vec::Vec;
 
 use log::{info, warn};
 
-use crate::private::cstr::CString;
+use crate::private::cstr::{from_cstr_ptr, CString};
 
 use embedded_svc::http::server::{
     attr, middleware, registry::*, session, Completion, Request, Response, ResponseWrite, Session,
@@ -23,6 +23,10 @@ use esp_idf_sys::*;
 
 use uncased::{Uncased, UncasedStr};
 
+use super::router::{Parameters, Pattern};
+
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+use crate::nvs_storage::EspNvsStorage;
 use crate::private::common::Newtype;
 
 #[derive(Copy, Clone, Debug)]
@@ -36,6 +40,15 @@ pub struct Configuration {
     pub max_uri_handlers: usize,
     pub max_resp_handlers: usize,
     pub session_cookie_name: &'static str,
+    // Lets handlers be registered for URIs ending with `*`, and is required for routes with path
+    // parameters like `/api/sensor/{id}`
+    pub uri_match_wildcard: bool,
+    // PEM (NUL-terminated) or DER. When set, the server is started with `esp_https_server` on
+    // `https_port` instead of on `http_port`
+    pub server_certificate: Option<&'static [u8]>,
+    pub private_key: Option<&'static [u8]>,
+    // When set, clients have to present a certificate signed by this CA
+    pub client_ca_certificate: Option<&'static [u8]>,
 }
 
 impl Default for Configuration {
@@ -50,6 +63,10 @@ impl Default for Configuration {
             max_uri_handlers: 32,
             max_resp_handlers: 8,
             session_cookie_name: "SESSIONID",
+            uri_match_wildcard: false,
+            server_certificate: None,
+            private_key: None,
+            client_ca_certificate: None,
         }
     }
 }
@@ -75,7 +92,11 @@ impl From<&Configuration> for Newtype<httpd_config_t> {
             global_transport_ctx_free_fn: None,
             open_fn: None,
             close_fn: None,
-            uri_match_fn: None,
+            uri_match_fn: if conf.uri_match_wildcard {
+                Some(esp_idf_sys::httpd_uri_match_wildcard)
+            } else {
+                None
+            },
         })
     }
 }
@@ -125,11 +146,20 @@ type EspSessionsMutex = mutex::Mutex<BTreeMap<String, session::SessionData<EspSe
 type EspSessions = session::Sessions<EspSessionsMutex, EspSessionMutex>;
 type EspRequestScopedSession = session::RequestScopedSession<EspSessionsMutex, EspSessionMutex>;
 
+type NativeHandler = Box<dyn Fn(*mut httpd_req_t) -> c_types::c_int>;
+type RoutedHandler = Arc<dyn Fn(*mut httpd_req_t, Parameters) -> c_types::c_int>;
+type EspRoutes = mutex::Mutex<Vec<(Pattern, RoutedHandler)>>;
+
 pub struct EspHttpServer {
     sd: esp_idf_sys::httpd_handle_t,
     registrations: Vec<(CString, esp_idf_sys::httpd_uri_t)>,
     sessions: Arc<EspSessions>,
     session_cookie_name: &'static str,
+    uri_match_wildcard: bool,
+    // The routes with path parameters, grouped by the wildcard URI and method they are
+    // dispatched from
+    routes: Vec<(String, c_types::c_uint, Arc<EspRoutes>)>,
+    secure: bool,
 }
 
 impl EspHttpServer {
@@ -139,9 +169,18 @@ impl EspHttpServer {
         let mut handle: esp_idf_sys::httpd_handle_t = ptr::null_mut();
         let handle_ref = &mut handle;
 
-        esp!(unsafe { esp_idf_sys::httpd_start(handle_ref, &config.0 as *const _) })?;
+        let secure = conf.server_certificate.is_some() || conf.private_key.is_some();
+
+        if secure {
+            Self::start_secure(handle_ref, conf, config.0)?;
+
+            // Do not log the configuration, as it contains the private key
+            info!("Started Httpd server with TLS on port {}", conf.https_port);
+        } else {
+            esp!(unsafe { esp_idf_sys::httpd_start(handle_ref, &config.0 as *const _) })?;
 
-        info!("Started Httpd server with config {:?}", conf);
+            info!("Started Httpd server with config {:?}", conf);
+        }
 
         Ok(EspHttpServer {
             sd: handle,
@@ -153,9 +192,87 @@ impl EspHttpServer {
                 conf.session_timeout,
             )),
             session_cookie_name: conf.session_cookie_name,
+            uri_match_wildcard: conf.uri_match_wildcard,
+            routes: vec![],
+            secure,
         })
     }
 
+    #[cfg(all(esp_idf_comp_esp_https_server_enabled, esp_idf_esp_https_server_enable))]
+    fn start_secure(
+        handle: &mut esp_idf_sys::httpd_handle_t,
+        conf: &Configuration,
+        config: esp_idf_sys::httpd_config_t,
+    ) -> Result<(), EspError> {
+        let (certificate, private_key) = match (conf.server_certificate, conf.private_key) {
+            (Some(certificate), Some(private_key)) => (
+                x509(certificate, "server certificate")?,
+                x509(private_key, "private key")?,
+            ),
+            _ => {
+                warn!("Both the server certificate and its private key are required for TLS");
+                return Err(EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap());
+            }
+        };
+
+        let client_ca_certificate = conf
+            .client_ca_certificate
+            .map(|certificate| x509(certificate, "client CA certificate"))
+            .transpose()?;
+
+        let mut ssl_config = esp_idf_sys::httpd_ssl_config_t {
+            // Same as HTTPD_SSL_CONFIG_DEFAULT(), so that a plain server can run alongside
+            httpd: esp_idf_sys::httpd_config_t {
+                server_port: conf.https_port,
+                ctrl_port: 32769,
+                ..config
+            },
+            transport_mode: esp_idf_sys::httpd_ssl_transport_mode_t_HTTPD_SSL_TRANSPORT_SECURE,
+            port_secure: conf.https_port,
+            port_insecure: conf.http_port,
+            prvtkey_pem: private_key.as_ptr(),
+            prvtkey_len: private_key.len() as _,
+            ..Default::default()
+        };
+
+        // The field holding the server certificate got renamed in ESP-IDF 5, and `cacert_pem` is
+        // now used for verifying the clients instead
+        #[cfg(not(esp_idf_version_major = "5"))]
+        {
+            ssl_config.cacert_pem = certificate.as_ptr();
+            ssl_config.cacert_len = certificate.len() as _;
+
+            if let Some(client_ca_certificate) = client_ca_certificate {
+                ssl_config.client_verify_cert_pem = client_ca_certificate.as_ptr();
+                ssl_config.client_verify_cert_len = client_ca_certificate.len() as _;
+            }
+        }
+
+        #[cfg(esp_idf_version_major = "5")]
+        {
+            ssl_config.servercert = certificate.as_ptr();
+            ssl_config.servercert_len = certificate.len() as _;
+
+            if let Some(client_ca_certificate) = client_ca_certificate {
+                ssl_config.cacert_pem = client_ca_certificate.as_ptr();
+                ssl_config.cacert_len = client_ca_certificate.len() as _;
+            }
+        }
+
+        esp!(unsafe { esp_idf_sys::httpd_ssl_start(handle, &mut ssl_config) })
+    }
+
+    #[cfg(not(all(esp_idf_comp_esp_https_server_enabled, esp_idf_esp_https_server_enable)))]
+    fn start_secure(
+        _handle: &mut esp_idf_sys::httpd_handle_t,
+        _conf: &Configuration,
+        _config: esp_idf_sys::httpd_config_t,
+    ) -> Result<(), EspError> {
+        warn!("TLS requires the esp_https_server component and CONFIG_ESP_HTTPS_SERVER_ENABLE");
+
+        Err(EspError::from(ESP_ERR_NOT_SUPPORTED as i32).unwrap())
+    }
+
     fn get_random() -> [u8; 16] {
         let mut result = [0; 16];
 
@@ -181,7 +298,7 @@ impl EspHttpServer {
                 conf.method
             ))?;
 
-            let _drop = Box::from_raw(conf.user_ctx as *mut _);
+            let _drop = Box::from_raw(conf.user_ctx as *mut NativeHandler);
         };
 
         info!(
@@ -201,7 +318,11 @@ impl EspHttpServer {
                 self.unregister(uri, registration)?;
             }
 
-            esp!(unsafe { esp_idf_sys::httpd_stop(self.sd) })?;
+            if self.secure {
+                Self::stop_secure(self.sd)?;
+            } else {
+                esp!(unsafe { esp_idf_sys::httpd_stop(self.sd) })?;
+            }
 
             self.sd = ptr::null_mut();
         }
@@ -211,6 +332,24 @@ impl EspHttpServer {
         Ok(())
     }
 
+    #[cfg(all(esp_idf_comp_esp_https_server_enabled, esp_idf_esp_https_server_enable))]
+    fn stop_secure(handle: esp_idf_sys::httpd_handle_t) -> Result<(), EspError> {
+        #[cfg(not(esp_idf_version_major = "5"))]
+        unsafe {
+            esp_idf_sys::httpd_ssl_stop(handle)
+        };
+
+        #[cfg(esp_idf_version_major = "5")]
+        esp!(unsafe { esp_idf_sys::httpd_ssl_stop(handle) })?;
+
+        Ok(())
+    }
+
+    #[cfg(not(all(esp_idf_comp_esp_https_server_enabled, esp_idf_esp_https_server_enable)))]
+    fn stop_secure(_handle: esp_idf_sys::httpd_handle_t) -> Result<(), EspError> {
+        unreachable!()
+    }
+
     fn handle_request<'a, H, E>(
         req: EspHttpRequest<'a>,
         resp: EspHttpResponse<'a>,
@@ -289,7 +428,17 @@ impl EspHttpServer {
         writer.complete()
     }
 
-    fn to_native_handler<H, E>(&self, handler: H) -> Box<dyn Fn(*mut httpd_req_t) -> c_types::c_int>
+    fn to_native_handler<H, E>(&self, handler: H) -> NativeHandler
+    where
+        H: for<'a> Fn(EspHttpRequest<'a>, EspHttpResponse<'a>) -> Result<Completion, E> + 'static,
+        E: fmt::Display + fmt::Debug,
+    {
+        let handler = self.to_routed_handler(handler);
+
+        Box::new(move |raw_req| handler(raw_req, Parameters::new()))
+    }
+
+    fn to_routed_handler<H, E>(&self, handler: H) -> RoutedHandler
     where
         H: for<'a> Fn(EspHttpRequest<'a>, EspHttpResponse<'a>) -> Result<Completion, E> + 'static,
         E: fmt::Display + fmt::Debug,
@@ -297,11 +446,11 @@ impl EspHttpServer {
         let sessions = self.sessions.clone();
         let session_cookie_name = self.session_cookie_name;
 
-        Box::new(move |raw_req| {
+        Arc::new(move |raw_req, path_params| {
             let mut response_state = ResponseState::New;
 
             let result = Self::handle_request(
-                EspHttpRequest::new(raw_req, session_cookie_name, sessions.clone()),
+                EspHttpRequest::new(raw_req, session_cookie_name, sessions.clone(), path_params),
                 EspHttpResponse::new(raw_req, session_cookie_name, &mut response_state),
                 &handler,
             );
@@ -313,9 +462,172 @@ impl EspHttpServer {
         })
     }
 
+    // Runs the first route matching the path of the request, in the order of their registration
+    fn to_dispatcher(routes: Arc<EspRoutes>) -> NativeHandler {
+        Box::new(move |raw_req| {
+            let path = from_cstr_ptr(unsafe { (*raw_req).uri.as_ptr() });
+
+            // Not holding the lock while the handler runs, which may take a while
+            let matched = routes.lock().iter().find_map(|(pattern, handler)| {
+                pattern
+                    .matches(&path)
+                    .map(|path_params| (handler.clone(), path_params))
+            });
+
+            if let Some((handler, path_params)) = matched {
+                return handler(raw_req, path_params);
+            }
+
+            unsafe {
+                esp_idf_sys::httpd_resp_send_err(
+                    raw_req,
+                    esp_idf_sys::httpd_err_code_t_HTTPD_404_NOT_FOUND,
+                    ptr::null(),
+                )
+            }
+        })
+    }
+
+    fn register(
+        &mut self,
+        uri: &str,
+        method: Method,
+        handler: NativeHandler,
+    ) -> Result<(), EspError> {
+        let c_str = CString::new(uri).unwrap();
+
+        let conf = esp_idf_sys::httpd_uri_t {
+            uri: c_str.as_ptr() as _,
+            method: Newtype::<c_types::c_uint>::from(method).0,
+            user_ctx: Box::into_raw(Box::new(handler)) as *mut _,
+            handler: Some(EspHttpServer::handle),
+        };
+
+        esp!(unsafe { esp_idf_sys::httpd_register_uri_handler(self.sd, &conf) })?;
+
+        info!(
+            "Registered Httpd server handler {:?} for URI \"{}\"",
+            method,
+            c_str.to_str().unwrap()
+        );
+
+        self.registrations.push((c_str, conf));
+
+        Ok(())
+    }
+
+    fn set_route<H, E>(
+        &mut self,
+        uri: &str,
+        pattern: Pattern,
+        method: Method,
+        handler: H,
+    ) -> Result<&mut Self, EspError>
+    where
+        H: for<'a> Fn(EspHttpRequest<'a>, EspHttpResponse<'a>) -> Result<Completion, E> + 'static,
+        E: fmt::Display + fmt::Debug,
+    {
+        if !self.uri_match_wildcard {
+            warn!(
+                "Route \"{}\" requires `uri_match_wildcard` to be enabled in the configuration",
+                uri
+            );
+
+            return Err(EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap());
+        }
+
+        let native_uri = pattern.native_uri();
+        let native_method = Newtype::<c_types::c_uint>::from(method).0;
+
+        let handler = self.to_routed_handler(handler);
+
+        let routes = self
+            .routes
+            .iter()
+            .find(|(other_uri, other_method, _)| {
+                *other_uri == native_uri && *other_method == native_method
+            })
+            .map(|(_, _, routes)| routes.clone());
+
+        if let Some(routes) = routes {
+            routes.lock().push((pattern, handler));
+        } else {
+            let routes = Arc::new(mutex::Mutex::new(vec![(pattern, handler)]));
+
+            self.register(&native_uri, method, Self::to_dispatcher(routes.clone()))?;
+            self.routes.push((native_uri, native_method, routes));
+        }
+
+        info!(
+            "Registered Httpd server route {:?} for URI \"{}\"",
+            method, uri
+        );
+
+        Ok(self)
+    }
+
+    fn set_literal_route<H, E>(
+        &mut self,
+        uri: &str,
+        method: Method,
+        routes: Arc<EspRoutes>,
+        handler: H,
+    ) -> Result<&mut Self, EspError>
+    where
+        H: for<'a> Fn(EspHttpRequest<'a>, EspHttpResponse<'a>) -> Result<Completion, E> + 'static,
+        E: fmt::Display + fmt::Debug,
+    {
+        // Only the exact URIs can be told apart from the patterns
+        if uri.contains('*') || uri.ends_with('?') {
+            warn!(
+                "Wildcard URI \"{}\" is shadowed by the route registered for its prefix",
+                uri
+            );
+
+            return Err(EspError::from(ESP_ERR_INVALID_STATE as i32).unwrap());
+        }
+
+        let handler = self.to_routed_handler(handler);
+
+        let mut routes = routes.lock();
+
+        // Like with most routers, an exact URI takes precedence over the patterns it matches
+        let index = routes
+            .iter()
+            .position(|(pattern, _)| !pattern.is_literal())
+            .unwrap_or(routes.len());
+
+        routes.insert(index, (Pattern::literal(uri), handler));
+
+        info!(
+            "Registered Httpd server route {:?} for URI \"{}\"",
+            method, uri
+        );
+
+        Ok(self)
+    }
+
+    fn find_routes(&self, uri: &str, method: Method) -> Option<Arc<EspRoutes>> {
+        let native_method = Newtype::<c_types::c_uint>::from(method).0;
+        let c_uri = CString::new(uri).ok()?;
+
+        self.routes
+            .iter()
+            .find(|(native_uri, other_method, _)| {
+                *other_method == native_method
+                    && CString::new(native_uri.as_str()).map_or(false, |native_uri| unsafe {
+                        esp_idf_sys::httpd_uri_match_wildcard(
+                            native_uri.as_ptr(),
+                            c_uri.as_ptr(),
+                            uri.len() as _,
+                        )
+                    })
+            })
+            .map(|(_, _, routes)| routes.clone())
+    }
+
     extern "C" fn handle(raw_req: *mut httpd_req_t) -> c_types::c_int {
-        let handler_ptr =
-            (unsafe { *raw_req }).user_ctx as *mut Box<dyn Fn(*mut httpd_req_t) -> c_types::c_int>;
+        let handler_ptr = (unsafe { *raw_req }).user_ctx as *mut NativeHandler;
 
         let handler = unsafe { handler_ptr.as_ref() }.unwrap();
 
@@ -359,34 +671,69 @@ impl Registry for EspHttpServer {
         H: for<'a> Fn(Self::Request<'a>, Self::Response<'a>) -> Result<Completion, E> + 'static,
         E: fmt::Display + fmt::Debug,
     {
-        let c_str = CString::new(uri).unwrap();
+        match Pattern::new(uri) {
+            Ok(Some(pattern)) => return self.set_route(uri, pattern, method, handler),
+            Ok(None) => (),
+            Err(()) => {
+                warn!(
+                    "Route \"{}\" mixes a parameter with other characters in a segment",
+                    uri
+                );
 
-        let conf = esp_idf_sys::httpd_uri_t {
-            uri: c_str.as_ptr() as _,
-            method: Newtype::<c_types::c_uint>::from(method).0,
-            user_ctx: Box::into_raw(Box::new(self.to_native_handler(handler))) as *mut _,
-            handler: Some(EspHttpServer::handle),
-        };
+                return Err(EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap());
+            }
+        }
 
-        esp!(unsafe { esp_idf_sys::httpd_register_uri_handler(self.sd, &conf) })?;
+        // ESP-IDF runs the first registered handler matching the request, so a plain URI under
+        // the wildcard of a route is dispatched along with that route
+        if let Some(routes) = self.find_routes(uri, method) {
+            return self.set_literal_route(uri, method, routes, handler);
+        }
 
-        info!(
-            "Registered Httpd server handler {:?} for URI \"{}\"",
-            method,
-            c_str.to_str().unwrap()
-        );
+        let handler = self.to_native_handler(handler);
 
-        self.registrations.push((c_str, conf));
+        self.register(uri, method, handler)?;
 
         Ok(self)
     }
 }
 
+// Loads a certificate or a private key stored as a blob in NVS, for use in `Configuration`.
+// The data is leaked, as the server keeps referring to it for as long as it runs, so only call
+// this once per boot
+#[cfg(esp_idf_comp_nvs_flash_enabled)]
+pub fn load_x509(
+    storage: &EspNvsStorage,
+    key: impl AsRef<str>,
+) -> Result<Option<&'static [u8]>, EspError> {
+    let mut data = match storage.get_blob(key)? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+
+    if data.starts_with(b"-----BEGIN ") && data.last() != Some(&0) {
+        data.push(0);
+    }
+
+    Ok(Some(Box::leak(data.into_boxed_slice())))
+}
+
+#[cfg(all(esp_idf_comp_esp_https_server_enabled, esp_idf_esp_https_server_enable))]
+fn x509(data: &'static [u8], name: &str) -> Result<&'static [u8], EspError> {
+    if data.starts_with(b"-----BEGIN ") && data.last() != Some(&0) {
+        warn!("PEM {} is not NUL-terminated", name);
+        esp!(ESP_ERR_INVALID_ARG as i32)?;
+    }
+
+    Ok(data)
+}
+
 pub struct EspHttpRequest<'a> {
     raw_req: *mut httpd_req_t,
     _ptr: PhantomData<&'a httpd_req_t>,
     attributes: RefCell<attr::RequestScopedAttributes>,
     session: RefCell<EspRequestScopedSession>,
+    path_params: Parameters,
 }
 
 impl<'a> EspHttpRequest<'a> {
@@ -394,6 +741,7 @@ impl<'a> EspHttpRequest<'a> {
         raw_req: *mut httpd_req_t,
         session_cookie_name: &'a str,
         sessions: Arc<EspSessions>,
+        path_params: Parameters,
     ) -> Self {
         let cookies = Self::header(raw_req, "cookies").map(cookies::Cookies::new);
 
@@ -409,9 +757,29 @@ impl<'a> EspHttpRequest<'a> {
             _ptr: PhantomData,
             attributes: RefCell::new(attr::RequestScopedAttributes::new()),
             session,
+            path_params,
         }
     }
 
+    // The URI of the request, without its query string
+    pub fn path(&self) -> Cow<'a, str> {
+        let uri = from_cstr_ptr(unsafe { (*self.raw_req).uri.as_ptr() });
+
+        match uri.find('?') {
+            Some(end) => uri[..end].to_owned().into(),
+            None => uri.into_owned().into(),
+        }
+    }
+
+    // The parameters extracted from the path by a route like `/api/sensor/{id}`
+    pub fn path_params(&self) -> &Parameters {
+        &self.path_params
+    }
+
+    pub fn query_params(&self) -> Parameters {
+        Parameters::from_query(&self.query_string())
+    }
+
     fn header<'b>(raw_req: *mut httpd_req_t, name: impl AsRef<str>) -> Option<Cow<'b, str>> {
         let c_name = CString::new(name.as_ref()).unwrap();
 
@@ -747,6 +1115,28 @@ impl<'a> EspHttpResponseWrite<'a> {
 
         Ok(())
     }
+
+    // Sends the whole body at once with a `Content-Length` rather than in chunks, which is also
+    // the only way to send a response without a body, like a 304
+    pub fn send(mut self, buf: &[u8]) -> Result<Completion, EspError> {
+        if *self.state == ResponseState::Opened {
+            return Err(EspError::from(ESP_ERR_INVALID_STATE as i32).unwrap());
+        }
+
+        self.send_headers()?;
+
+        esp!(unsafe {
+            esp_idf_sys::httpd_resp_send(
+                self.raw_req,
+                buf.as_ptr() as *const _,
+                buf.len() as esp_idf_sys::ssize_t,
+            )
+        })?;
+
+        *self.state = ResponseState::Closed;
+
+        Ok(unsafe { Completion::internal_new() })
+    }
 }
 
 impl<'a> ResponseWrite<'a> for EspHttpResponseWrite<'a> {
@@ -0,0 +1,303 @@
+use core::time::Duration;
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use log::{info, warn};
+
+use embedded_svc::http::server::{Completion, Response, ResponseWrite};
+use embedded_svc::http::*;
+use embedded_svc::io::Write;
+
+use esp_idf_sys::*;
+
+use super::date;
+use super::router::decode;
+use super::server::{EspHttpRequest, EspHttpResponse};
+
+const BUF_SIZE: usize = 1024;
+
+// Serves the files under a directory of a mounted filesystem (SPIFFS, FAT, LittleFS).
+// Register the handler for a wildcard URI, which requires `uri_match_wildcard` in the server
+// configuration:
+//
+// server.set_inline_handler("/ui/*", Method::Get, StaticFiles::new("/ui", "/spiffs/ui").handler())
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    index: Option<String>,
+    max_age: Option<Duration>,
+}
+
+impl StaticFiles {
+    pub fn new(prefix: impl AsRef<str>, root: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.as_ref().trim_end_matches('/').into(),
+            root: root.into(),
+            index: Some("index.html".into()),
+            max_age: None,
+        }
+    }
+
+    // The file served for the requests of a directory, `index.html` by default
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(Into::into);
+        self
+    }
+
+    // Sent as `Cache-Control: max-age`. Without it, the browsers revalidate the files with
+    // `If-None-Match` or `If-Modified-Since`
+    pub fn max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn handler(
+        self,
+    ) -> impl for<'a> Fn(EspHttpRequest<'a>, EspHttpResponse<'a>) -> Result<Completion, EspError>
+    {
+        move |req, resp| self.serve(req, resp)
+    }
+
+    pub fn serve<'a>(
+        &self,
+        req: EspHttpRequest<'a>,
+        resp: EspHttpResponse<'a>,
+    ) -> Result<Completion, EspError> {
+        let path = match self.resolve(&req.path()) {
+            Some(path) => path,
+            None => return not_found(req, resp),
+        };
+
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => return not_found(req, resp),
+            Err(err) if err.kind() == ErrorKind::NotFound => return not_found(req, resp),
+            Err(err) => return Err(io_error(&path, err)),
+        };
+
+        // SPIFFS only keeps the modification time with CONFIG_SPIFFS_USE_MTIME
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_secs())
+            .filter(|modified| *modified > 0);
+
+        let etag = modified.map(|modified| format!("\"{:x}-{:x}\"", modified, metadata.len()));
+        let last_modified = modified.map(date::format);
+
+        let not_modified = match (Headers::header(&req, "If-None-Match"), etag.as_ref()) {
+            (Some(if_none_match), Some(etag)) => if_none_match
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*"),
+            (Some(_), None) => false,
+            (None, _) => match (Headers::header(&req, "If-Modified-Since"), modified) {
+                (Some(if_modified_since), Some(modified)) => {
+                    not_modified_since(modified, &if_modified_since)
+                }
+                _ => false,
+            },
+        };
+
+        let mut resp = if not_modified {
+            resp.status(304)
+        } else {
+            resp.content_type(mime_type(&path))
+        };
+
+        if let Some(etag) = etag {
+            resp.set_header("ETag", etag);
+        }
+
+        if let Some(last_modified) = last_modified {
+            resp.set_header("Last-Modified", last_modified);
+        }
+
+        if let Some(max_age) = self.max_age {
+            resp.set_header("Cache-Control", format!("max-age={}", max_age.as_secs()));
+        }
+
+        if not_modified {
+            return resp.into_writer(req)?.send(&[]);
+        }
+
+        let mut file = File::open(&path).map_err(|err| io_error(&path, err))?;
+
+        let mut writer = resp.into_writer(req)?;
+        let mut buf = [0_u8; BUF_SIZE];
+
+        loop {
+            let len = io::Read::read(&mut file, &mut buf).map_err(|err| io_error(&path, err))?;
+
+            if len == 0 {
+                break;
+            }
+
+            writer.do_write_all(&buf[..len])?;
+        }
+
+        info!("Served {}", path.display());
+
+        writer.complete()
+    }
+
+    // Maps the path of the request to a file under the root, or returns `None` for the paths
+    // outside of the prefix and the ones trying to escape the root
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = path.strip_prefix(self.prefix.as_str())?;
+
+        if !relative.is_empty() && !relative.starts_with('/') {
+            return None;
+        }
+
+        let mut resolved = self.root.clone();
+
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = decode(segment, false);
+
+            if segment == "." || segment == ".." || segment.contains(|c| c == '/' || c == '\\') {
+                return None;
+            }
+
+            resolved.push(segment);
+        }
+
+        if relative.is_empty() || relative.ends_with('/') || resolved.is_dir() {
+            resolved.push(self.index.as_ref()?);
+        }
+
+        Some(resolved)
+    }
+}
+
+fn not_found<'a>(
+    req: EspHttpRequest<'a>,
+    resp: EspHttpResponse<'a>,
+) -> Result<Completion, EspError> {
+    resp.status(404)
+        .content_type("text/plain")
+        .into_writer(req)?
+        .send(b"Not Found")
+}
+
+fn io_error(path: &Path, err: io::Error) -> EspError {
+    warn!("Failed to read {}: {}", path.display(), err);
+
+    EspError::from(ESP_FAIL).unwrap()
+}
+
+// Unparseable dates count as modified, so that the file is sent in full
+fn not_modified_since(modified: u64, if_modified_since: &str) -> bool {
+    date::parse(if_modified_since).map_or(false, |since| modified <= since)
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn static_files() -> StaticFiles {
+        StaticFiles::new("/ui/", "/spiffs/ui")
+    }
+
+    #[test]
+    fn resolve_files() {
+        assert_eq!(
+            static_files().resolve("/ui/app.js"),
+            Some(PathBuf::from("/spiffs/ui/app.js"))
+        );
+        assert_eq!(
+            static_files().resolve("/ui/css/my%20style.css"),
+            Some(PathBuf::from("/spiffs/ui/css/my style.css"))
+        );
+    }
+
+    #[test]
+    fn resolve_index() {
+        assert_eq!(
+            static_files().resolve("/ui"),
+            Some(PathBuf::from("/spiffs/ui/index.html"))
+        );
+        assert_eq!(
+            static_files().resolve("/ui/docs/"),
+            Some(PathBuf::from("/spiffs/ui/docs/index.html"))
+        );
+        assert_eq!(static_files().index(None).resolve("/ui/"), None);
+    }
+
+    #[test]
+    fn resolve_outside_of_prefix() {
+        assert_eq!(static_files().resolve("/api/app.js"), None);
+        assert_eq!(static_files().resolve("/uix/app.js"), None);
+    }
+
+    #[test]
+    fn resolve_rejects_traversal() {
+        assert_eq!(static_files().resolve("/ui/../secret"), None);
+        assert_eq!(static_files().resolve("/ui/css/../../secret"), None);
+        assert_eq!(static_files().resolve("/ui/%2e%2e/secret"), None);
+        assert_eq!(static_files().resolve("/ui/%2E%2E/secret"), None);
+        assert_eq!(static_files().resolve("/ui/./app.js"), None);
+        assert_eq!(static_files().resolve("/ui/..%2fsecret"), None);
+        assert_eq!(static_files().resolve("/ui/css%2f..%2f..%2fsecret"), None);
+        assert_eq!(static_files().resolve("/ui/..%5csecret"), None);
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let since = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+        assert!(not_modified_since(784111777, since));
+        assert!(not_modified_since(784111000, since));
+        assert!(!not_modified_since(784111778, since));
+        assert!(!not_modified_since(784111777, "yesterday"));
+    }
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(mime_type(Path::new("/spiffs/index.HTML")), "text/html");
+        assert_eq!(
+            mime_type(Path::new("/spiffs/app.js")),
+            "application/javascript"
+        );
+        assert_eq!(
+            mime_type(Path::new("/spiffs/README")),
+            "application/octet-stream"
+        );
+    }
+}
@@ -6,6 +6,14 @@
 #[macro_use]
 extern crate alloc;
 
+#[cfg(feature = "std")]
+pub mod dns;
+#[cfg(all(
+    feature = "alloc",
+    esp_idf_comp_wpa_supplicant_enabled,
+    any(esp_idf_wpa_dpp_support, esp_idf_esp_wifi_dpp_support)
+))]
+pub mod dpp;
 #[cfg(feature = "alloc")]
 #[cfg(any(
     all(esp32, esp_idf_eth_use_esp32_emac),
@@ -36,6 +44,13 @@ pub mod netif;
 #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
 // TODO: Expose a subset which does not require "alloc"
 pub mod nvs;
+#[cfg(all(
+    feature = "alloc",
+    feature = "serde",
+    feature = "postcard",
+    esp_idf_comp_nvs_flash_enabled
+))]
+pub mod nvs_config;
 #[cfg(all(feature = "alloc", esp_idf_comp_nvs_flash_enabled))]
 // TODO: Expose a subset which does not require "alloc"
 pub mod nvs_storage;
@@ -47,6 +62,10 @@ pub mod nvs_storage;
 ))]
 pub mod ota;
 pub mod ping;
+#[cfg(all(feature = "std", esp_idf_ppp_support))]
+pub mod ppp;
+#[cfg(feature = "alloc")]
+pub mod smartconfig;
 #[cfg(feature = "alloc")]
 pub mod sntp;
 pub mod sysloop;
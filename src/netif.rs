@@ -1,9 +1,11 @@
-use core::ptr;
+use core::{cmp, ptr, time::Duration};
 
 extern crate alloc;
 use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
 
 use ::log::*;
 use cstr_core::CString;
@@ -14,6 +16,8 @@ use esp_idf_hal::mutex;
 
 use esp_idf_sys::*;
 
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventFetchData, EspEventSubscribeMetadata};
 use crate::private::common::*;
 use crate::private::cstr::*;
 
@@ -28,6 +32,8 @@ pub enum InterfaceStack {
     Ppp,
     #[cfg(esp_idf_slip_support)]
     Slip,
+    #[cfg(esp_idf_esp_netif_bridge_en)]
+    Bridge,
 }
 
 impl InterfaceStack {
@@ -40,6 +46,8 @@ impl InterfaceStack {
             Self::Ppp => InterfaceConfiguration::ppp_default_client(),
             #[cfg(esp_idf_slip_support)]
             Self::Slip => InterfaceConfiguration::slip_default_client(),
+            #[cfg(esp_idf_esp_netif_bridge_en)]
+            Self::Bridge => InterfaceConfiguration::bridge_default_client(),
         }
     }
 }
@@ -59,6 +67,8 @@ pub struct InterfaceConfiguration {
     pub route_priority: u32,
     pub ip_configuration: InterfaceIpConfiguration,
     pub interface_stack: InterfaceStack,
+    // Takes precedence over the hostname of the DHCP client settings
+    pub hostname: Option<String>,
 }
 
 impl Default for InterfaceConfiguration {
@@ -75,6 +85,7 @@ impl InterfaceConfiguration {
             route_priority: 60,
             ip_configuration: InterfaceIpConfiguration::Client(Default::default()),
             interface_stack: InterfaceStack::Eth,
+            hostname: None,
         }
     }
 
@@ -85,6 +96,7 @@ impl InterfaceConfiguration {
             route_priority: 50,
             ip_configuration: InterfaceIpConfiguration::Router(Default::default()),
             interface_stack: InterfaceStack::Eth,
+            hostname: None,
         }
     }
 
@@ -95,6 +107,7 @@ impl InterfaceConfiguration {
             route_priority: 100,
             ip_configuration: InterfaceIpConfiguration::Client(Default::default()),
             interface_stack: InterfaceStack::Sta,
+            hostname: None,
         }
     }
 
@@ -105,6 +118,7 @@ impl InterfaceConfiguration {
             route_priority: 10,
             ip_configuration: InterfaceIpConfiguration::Router(Default::default()),
             interface_stack: InterfaceStack::Ap,
+            hostname: None,
         }
     }
 
@@ -116,6 +130,7 @@ impl InterfaceConfiguration {
             route_priority: 30,
             ip_configuration: InterfaceIpConfiguration::Client(Default::default()),
             interface_stack: InterfaceStack::Ppp,
+            hostname: None,
         }
     }
 
@@ -127,6 +142,7 @@ impl InterfaceConfiguration {
             route_priority: 20,
             ip_configuration: InterfaceIpConfiguration::Router(Default::default()),
             interface_stack: InterfaceStack::Ppp,
+            hostname: None,
         }
     }
 
@@ -138,6 +154,7 @@ impl InterfaceConfiguration {
             route_priority: 35,
             ip_configuration: InterfaceIpConfiguration::Client(Default::default()),
             interface_stack: InterfaceStack::Slip,
+            hostname: None,
         }
     }
 
@@ -149,6 +166,247 @@ impl InterfaceConfiguration {
             route_priority: 25,
             ip_configuration: InterfaceIpConfiguration::Router(Default::default()),
             interface_stack: InterfaceStack::Slip,
+            hostname: None,
+        }
+    }
+
+    #[cfg(esp_idf_esp_netif_bridge_en)]
+    pub fn bridge_default_client() -> Self {
+        Self {
+            key: "BR_CL_DEF".into(),
+            description: "br".into(),
+            route_priority: 70,
+            ip_configuration: InterfaceIpConfiguration::Client(Default::default()),
+            interface_stack: InterfaceStack::Bridge,
+            hostname: None,
+        }
+    }
+
+    #[cfg(esp_idf_esp_netif_bridge_en)]
+    pub fn bridge_default_router() -> Self {
+        Self {
+            key: "BR_RT_DEF".into(),
+            description: "brrt".into(),
+            route_priority: 65,
+            ip_configuration: InterfaceIpConfiguration::Router(Default::default()),
+            interface_stack: InterfaceStack::Bridge,
+            hostname: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub enum DnsType {
+    Main,
+    Backup,
+    // Used when neither the main nor the backup server is set
+    Fallback,
+}
+
+impl From<DnsType> for esp_netif_dns_type_t {
+    fn from(dns_type: DnsType) -> Self {
+        match dns_type {
+            DnsType::Main => esp_netif_dns_type_t_ESP_NETIF_DNS_MAIN,
+            DnsType::Backup => esp_netif_dns_type_t_ESP_NETIF_DNS_BACKUP,
+            DnsType::Fallback => esp_netif_dns_type_t_ESP_NETIF_DNS_FALLBACK,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub struct DhcpServerConfiguration {
+    // Rounded down to whole minutes
+    pub lease_time: Duration,
+    // The first and the last address handed out; ESP-IDF picks a range after the netif IP if unset
+    pub pool: Option<(ipv4::Ipv4Addr, ipv4::Ipv4Addr)>,
+    // Whether the IP of the netif is offered to the clients as their gateway
+    pub offer_gateway: bool,
+    // The DNS server offered to the clients
+    pub dns: Option<ipv4::Ipv4Addr>,
+}
+
+impl Default for DhcpServerConfiguration {
+    fn default() -> Self {
+        Self {
+            lease_time: Duration::from_secs(120 * 60),
+            pool: None,
+            offer_gateway: true,
+            dns: None,
+        }
+    }
+}
+
+// The hostname (option 12) is set with the hostname of the interface, and the client is
+// identified by the MAC of the interface, as ESP-IDF does not support a custom client identifier
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "use_serde", derive(Serialize, Deserialize))]
+pub struct DhcpClientConfiguration {
+    // Sent as option 60, e.g. for servers handing out addresses per device class
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    pub vendor_class: Option<String>,
+    // The time to wait for an answer before a request is retried; rounded down to whole seconds
+    pub request_timeout: Option<Duration>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ip6AddrType {
+    Global,
+    LinkLocal,
+    SiteLocal,
+    UniqueLocal,
+    Ipv4Mapped,
+    Unknown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ip6Address {
+    // In network byte order
+    pub octets: [u8; 16],
+    pub zone: u8,
+    pub addr_type: Ip6AddrType,
+}
+
+#[cfg(esp_idf_lwip_ipv6)]
+impl From<Newtype<esp_ip6_addr_t>> for Ip6Address {
+    #[allow(non_upper_case_globals)]
+    fn from(addr: Newtype<esp_ip6_addr_t>) -> Self {
+        let mut addr = addr.0;
+
+        let mut octets = [0_u8; 16];
+        for (index, word) in addr.addr.iter().enumerate() {
+            // The words are already in network byte order
+            octets[index * 4..index * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+        }
+
+        let addr_type = match unsafe { esp_netif_ip6_get_addr_type(&mut addr) } {
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_GLOBAL => Ip6AddrType::Global,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_LINK_LOCAL => Ip6AddrType::LinkLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_SITE_LOCAL => Ip6AddrType::SiteLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_UNIQUE_LOCAL => Ip6AddrType::UniqueLocal,
+            esp_ip6_addr_type_t_ESP_IP6_ADDR_IS_IPV4_MAPPED_IPV6 => Ip6AddrType::Ipv4Mapped,
+            _ => Ip6AddrType::Unknown,
+        };
+
+        Self {
+            octets,
+            zone: addr.zone,
+            addr_type,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IpEvent {
+    StaGotIp {
+        ip: ipv4::Ipv4Addr,
+        netmask: ipv4::Ipv4Addr,
+        gateway: ipv4::Ipv4Addr,
+        changed: bool,
+    },
+    StaLostIp,
+    ApStaIpAssigned {
+        ip: ipv4::Ipv4Addr,
+        // The MAC of the station is only reported since ESP-IDF 5
+        mac: Option<[u8; 6]>,
+    },
+    EthGotIp {
+        ip: ipv4::Ipv4Addr,
+        netmask: ipv4::Ipv4Addr,
+        gateway: ipv4::Ipv4Addr,
+        changed: bool,
+    },
+    #[cfg(esp_idf_version_major = "5")]
+    EthLostIp,
+    PppGotIp {
+        ip: ipv4::Ipv4Addr,
+        netmask: ipv4::Ipv4Addr,
+        gateway: ipv4::Ipv4Addr,
+        changed: bool,
+    },
+    PppLostIp,
+    GotIp6 {
+        netif_index: u32,
+        ip: Ip6Address,
+        // The slot of the address among the addresses of the netif
+        index: i32,
+    },
+    Other(u32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for IpEvent {
+    fn source() -> *const c_types::c_char {
+        unsafe { IP_EVENT }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for IpEvent {
+    #[allow(non_upper_case_globals)]
+    fn from(data: EspEventFetchData) -> Self {
+        let event_id = data.event_id as u32;
+
+        unsafe {
+            match event_id {
+                ip_event_t_IP_EVENT_STA_GOT_IP => {
+                    let payload: ip_event_got_ip_t = data.as_payload();
+
+                    IpEvent::StaGotIp {
+                        ip: Newtype(payload.ip_info.ip).into(),
+                        netmask: Newtype(payload.ip_info.netmask).into(),
+                        gateway: Newtype(payload.ip_info.gw).into(),
+                        changed: payload.ip_changed,
+                    }
+                }
+                ip_event_t_IP_EVENT_STA_LOST_IP => IpEvent::StaLostIp,
+                ip_event_t_IP_EVENT_AP_STAIPASSIGNED => {
+                    let payload: ip_event_ap_staipassigned_t = data.as_payload();
+
+                    IpEvent::ApStaIpAssigned {
+                        ip: Newtype(payload.ip).into(),
+                        #[cfg(esp_idf_version_major = "5")]
+                        mac: Some(payload.mac),
+                        #[cfg(not(esp_idf_version_major = "5"))]
+                        mac: None,
+                    }
+                }
+                ip_event_t_IP_EVENT_ETH_GOT_IP => {
+                    let payload: ip_event_got_ip_t = data.as_payload();
+
+                    IpEvent::EthGotIp {
+                        ip: Newtype(payload.ip_info.ip).into(),
+                        netmask: Newtype(payload.ip_info.netmask).into(),
+                        gateway: Newtype(payload.ip_info.gw).into(),
+                        changed: payload.ip_changed,
+                    }
+                }
+                #[cfg(esp_idf_version_major = "5")]
+                ip_event_t_IP_EVENT_ETH_LOST_IP => IpEvent::EthLostIp,
+                ip_event_t_IP_EVENT_PPP_GOT_IP => {
+                    let payload: ip_event_got_ip_t = data.as_payload();
+
+                    IpEvent::PppGotIp {
+                        ip: Newtype(payload.ip_info.ip).into(),
+                        netmask: Newtype(payload.ip_info.netmask).into(),
+                        gateway: Newtype(payload.ip_info.gw).into(),
+                        changed: payload.ip_changed,
+                    }
+                }
+                ip_event_t_IP_EVENT_PPP_LOST_IP => IpEvent::PppLostIp,
+                #[cfg(esp_idf_lwip_ipv6)]
+                ip_event_t_IP_EVENT_GOT_IP6 => {
+                    let payload: ip_event_got_ip6_t = data.as_payload();
+
+                    IpEvent::GotIp6 {
+                        netif_index: esp_netif_get_netif_impl_index(payload.esp_netif) as _,
+                        ip: Newtype(payload.ip6_info.ip).into(),
+                        index: payload.ip_index,
+                    }
+                }
+                other => IpEvent::Other(other),
+            }
         }
     }
 }
@@ -210,7 +468,13 @@ impl EspNetif {
                                 | esp_netif_flags_ESP_NETIF_FLAG_EVENT_IP_MODIFIED
                         }
                         ipv4::ClientConfiguration::Fixed(_) => {
-                            esp_netif_flags_ESP_NETIF_FLAG_AUTOUP
+                            if conf.interface_stack == InterfaceStack::Sta {
+                                // Announce the fixed address once associated, as with DHCP
+                                esp_netif_flags_ESP_NETIF_FLAG_AUTOUP
+                                    | esp_netif_flags_ESP_NETIF_FLAG_GARP
+                            } else {
+                                esp_netif_flags_ESP_NETIF_FLAG_AUTOUP
+                            }
                         }
                     },
                     mac: [0; 6],
@@ -292,6 +556,28 @@ impl EspNetif {
             esp_inherent_config.ip_info = ip_info;
         }
 
+        // The IP configuration of PPP links is negotiated by the PPP stack
+        #[cfg(esp_idf_ppp_support)]
+        if conf.interface_stack == InterfaceStack::Ppp {
+            esp_inherent_config.flags = esp_netif_flags_ESP_NETIF_FLAG_IS_PPP;
+            esp_inherent_config.ip_info = ptr::null();
+            esp_inherent_config.get_ip_event = ip_event_t_IP_EVENT_PPP_GOT_IP;
+            esp_inherent_config.lost_ip_event = ip_event_t_IP_EVENT_PPP_LOST_IP;
+        }
+
+        #[cfg(esp_idf_esp_netif_bridge_en)]
+        let bridge_info = bridgeif_config_t {
+            max_fdb_dyn_entries: BRIDGE_MAX_FDB_DYN_ENTRIES,
+            max_fdb_sta_entries: BRIDGE_MAX_FDB_STA_ENTRIES,
+            max_ports: BRIDGE_MAX_PORTS,
+        };
+
+        #[cfg(esp_idf_esp_netif_bridge_en)]
+        if conf.interface_stack == InterfaceStack::Bridge {
+            esp_inherent_config.flags |= esp_netif_flags_ESP_NETIF_FLAG_IS_BRIDGE;
+            esp_inherent_config.bridge_info = &bridge_info as *const _ as *mut _;
+        }
+
         let cfg = esp_netif_config_t {
             base: &esp_inherent_config,
             driver: ptr::null(),
@@ -304,12 +590,20 @@ impl EspNetif {
                     InterfaceStack::Ppp => _g_esp_netif_netstack_default_ppp,
                     #[cfg(esp_idf_slip_support)]
                     InterfaceStack::Slip => _g_esp_netif_netstack_default_slip,
+                    #[cfg(esp_idf_esp_netif_bridge_en)]
+                    InterfaceStack::Bridge => _g_esp_netif_netstack_default_br,
                 }
             },
         };
 
         let mut netif = Self(netif_stack, unsafe { esp_netif_new(&cfg) });
 
+        if let InterfaceIpConfiguration::Client(ipv4::ClientConfiguration::Fixed(ref fixed_conf)) =
+            conf.ip_configuration
+        {
+            netif.set_fixed_ip(fixed_conf)?;
+        }
+
         if let Some(dns) = dns {
             netif.set_dns(dns);
 
@@ -332,7 +626,7 @@ impl EspNetif {
             netif.set_secondary_dns(secondary_dns);
         }
 
-        if let Some(hostname) = hostname {
+        if let Some(hostname) = conf.hostname.as_ref().or(hostname) {
             netif.set_hostname(hostname)?;
         }
 
@@ -347,6 +641,40 @@ impl EspNetif {
         unsafe { esp_netif_get_netif_impl_index(self.1) as _ }
     }
 
+    pub fn get_route_priority(&self) -> u32 {
+        unsafe { esp_netif_get_route_prio(self.1) as _ }
+    }
+
+    pub fn is_default(&self) -> bool {
+        unsafe { esp_netif_get_default_netif() == self.1 }
+    }
+
+    // Routes the traffic through this interface. Note that ESP-IDF picks the interface with the
+    // highest route priority again whenever an interface goes up or down, so a lasting
+    // preference is better expressed with the route priority of the interface configuration
+    pub fn set_default(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_netif_set_default_netif(self.1) })?;
+
+        info!("Default netif set to {}", self.get_key());
+
+        Ok(())
+    }
+
+    // Drops the dynamic ARP entries learned on this interface
+    pub fn flush_arp_table(&mut self) -> Result<(), EspError> {
+        let lwip_netif = unsafe { lwip_netif(self.1) };
+
+        if lwip_netif.is_null() {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        tcpip_exec(|| {
+            unsafe { etharp_cleanup_netif(lwip_netif) };
+
+            err_enum_t_ERR_OK as _
+        })
+    }
+
     pub fn get_name(&self) -> Cow<'_, str> {
         let mut netif_name = [0u8; 7];
 
@@ -356,6 +684,43 @@ impl EspNetif {
         Cow::Owned(from_cstr(&netif_name).into_owned())
     }
 
+    pub fn get_mac(&self) -> Result<[u8; 6], EspError> {
+        let mut mac = [0_u8; 6];
+
+        esp!(unsafe { esp_netif_get_mac(self.1, mac.as_mut_ptr()) })?;
+
+        Ok(mac)
+    }
+
+    pub fn set_mac(&mut self, mac: &[u8; 6]) -> Result<(), EspError> {
+        check_mac(mac)?;
+
+        esp!(unsafe { esp_netif_set_mac(self.1, mac.as_ptr() as *mut _) })
+    }
+
+    pub fn get_dns_server(&self, dns_type: DnsType) -> Result<ipv4::Ipv4Addr, EspError> {
+        let mut dns_info = Default::default();
+
+        unsafe {
+            esp!(esp_netif_get_dns_info(
+                self.1,
+                dns_type.into(),
+                &mut dns_info
+            ))?;
+
+            Ok(Newtype(dns_info.ip.u_addr.ip4).into())
+        }
+    }
+
+    // Note that servers received via DHCP replace the main and backup servers on every lease
+    pub fn set_dns_server(
+        &mut self,
+        dns_type: DnsType,
+        dns: ipv4::Ipv4Addr,
+    ) -> Result<(), EspError> {
+        unsafe { set_dns_server(self.1, dns_type, dns) }
+    }
+
     pub fn get_dns(&self) -> ipv4::Ipv4Addr {
         let mut dns_info = Default::default();
 
@@ -416,14 +781,191 @@ impl EspNetif {
         }
     }
 
+    // Routes the traffic of the interface's clients through the other interfaces (e.g. the
+    // SoftAP clients to the upstream STA network). Only effective once the interface is up
     #[cfg(esp_idf_config_lwip_ipv4_napt)]
     pub fn enable_napt(&mut self, enable: bool) {
-        unsafe {
-            esp_idf_sys::ip_napt_enable_no(
-                (esp_netif_get_netif_impl_index(self.1) - 1) as u8,
-                if enable { 1 } else { 0 },
-            )
+        unsafe { enable_napt(self.1, enable) };
+    }
+
+    // Stops the DHCP client (if running) and assigns the given settings to the interface
+    pub fn set_fixed_ip(&mut self, settings: &ipv4::ClientSettings) -> Result<(), EspError> {
+        let err = unsafe { esp_netif_dhcpc_stop(self.1) };
+        if err != ESP_ERR_ESP_NETIF_DHCP_ALREADY_STOPPED as i32 {
+            esp!(err)?;
+        }
+
+        let ip_info = esp_netif_ip_info_t {
+            ip: Newtype::<esp_ip4_addr_t>::from(settings.ip).0,
+            netmask: Newtype::<esp_ip4_addr_t>::from(settings.subnet.mask).0,
+            gw: Newtype::<esp_ip4_addr_t>::from(settings.subnet.gateway).0,
+        };
+
+        esp!(unsafe { esp_netif_set_ip_info(self.1, &ip_info) })?;
+
+        if let Some(dns) = settings.dns {
+            self.set_dns(dns);
+        }
+
+        if let Some(secondary_dns) = settings.secondary_dns {
+            self.set_secondary_dns(secondary_dns);
+        }
+
+        info!("Fixed IP configuration applied: {:?}", settings);
+
+        Ok(())
+    }
+
+    pub fn set_dhcp_server_conf(&mut self, conf: &DhcpServerConfiguration) -> Result<(), EspError> {
+        info!("Setting DHCP server configuration: {:?}", conf);
+
+        let mut status: esp_netif_dhcp_status_t = 0;
+        esp!(unsafe { esp_netif_dhcps_get_status(self.1, &mut status) })?;
+
+        // Options can only be changed while the server is stopped
+        let started = status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED;
+        if started {
+            esp!(unsafe { esp_netif_dhcps_stop(self.1) })?;
+        }
+
+        let mut lease_time: u32 = cmp::max(conf.lease_time.as_secs() / 60, 1) as _;
+        self.set_dhcps_option(
+            esp_netif_dhcp_option_id_t_ESP_NETIF_IP_ADDRESS_LEASE_TIME,
+            &mut lease_time,
+        )?;
+
+        if let Some((start, end)) = conf.pool {
+            let mut lease = dhcps_lease_t {
+                enable: true,
+                start_ip: ip4_addr_t {
+                    addr: Newtype::<esp_ip4_addr_t>::from(start).0.addr,
+                },
+                end_ip: ip4_addr_t {
+                    addr: Newtype::<esp_ip4_addr_t>::from(end).0.addr,
+                },
+            };
+
+            self.set_dhcps_option(
+                esp_netif_dhcp_option_id_t_ESP_NETIF_REQUESTED_IP_ADDRESS,
+                &mut lease,
+            )?;
+        }
+
+        let mut offer_router: dhcps_offer_t = if conf.offer_gateway {
+            dhcps_offer_option_OFFER_ROUTER as _
+        } else {
+            0
         };
+        self.set_dhcps_option(
+            esp_netif_dhcp_option_id_t_ESP_NETIF_ROUTER_SOLICITATION_ADDRESS,
+            &mut offer_router,
+        )?;
+
+        if let Some(dns) = conf.dns {
+            self.set_dns(dns);
+
+            let mut offer_dns: dhcps_offer_t = dhcps_offer_option_OFFER_DNS as _;
+            self.set_dhcps_option(
+                esp_netif_dhcp_option_id_t_ESP_NETIF_DOMAIN_NAME_SERVER,
+                &mut offer_dns,
+            )?;
+        }
+
+        if started {
+            esp!(unsafe { esp_netif_dhcps_start(self.1) })?;
+        }
+
+        Ok(())
+    }
+
+    pub fn set_dhcp_client_conf(&mut self, conf: &DhcpClientConfiguration) -> Result<(), EspError> {
+        info!("Setting DHCP client configuration: {:?}", conf);
+
+        let mut status: esp_netif_dhcp_status_t = 0;
+        esp!(unsafe { esp_netif_dhcpc_get_status(self.1, &mut status) })?;
+
+        // As with the server, options can only be changed while the client is stopped
+        let started = status == esp_netif_dhcp_status_t_ESP_NETIF_DHCP_STARTED;
+        if started {
+            esp!(unsafe { esp_netif_dhcpc_stop(self.1) })?;
+        }
+
+        #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+        if let Some(vendor_class) = conf.vendor_class.as_ref() {
+            esp!(unsafe {
+                esp_netif_dhcpc_option(
+                    self.1,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_VENDOR_CLASS_IDENTIFIER,
+                    vendor_class.as_ptr() as *mut _,
+                    vendor_class.len() as u32,
+                )
+            })?;
+        }
+
+        if let Some(request_timeout) = conf.request_timeout {
+            let mut request_timeout: u16 =
+                cmp::max(cmp::min(request_timeout.as_secs(), u16::MAX as u64), 1) as _;
+
+            esp!(unsafe {
+                esp_netif_dhcpc_option(
+                    self.1,
+                    esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                    esp_netif_dhcp_option_id_t_ESP_NETIF_IP_REQUEST_RETRY_TIME,
+                    &mut request_timeout as *mut _ as *mut _,
+                    core::mem::size_of::<u16>() as u32,
+                )
+            })?;
+        }
+
+        if started {
+            esp!(unsafe { esp_netif_dhcpc_start(self.1) })?;
+        }
+
+        Ok(())
+    }
+
+    fn set_dhcps_option<T>(
+        &mut self,
+        option: esp_netif_dhcp_option_id_t,
+        value: &mut T,
+    ) -> Result<(), EspError> {
+        esp!(unsafe {
+            esp_netif_dhcps_option(
+                self.1,
+                esp_netif_dhcp_option_mode_t_ESP_NETIF_OP_SET,
+                option,
+                value as *mut _ as *mut _,
+                core::mem::size_of::<T>() as u32,
+            )
+        })
+    }
+
+    // The link-local address gets reported with `IpEvent::GotIp6` once it is valid
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn create_ip6_linklocal(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_netif_create_ip6_linklocal(self.1) })
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn get_ip6_linklocal(&self) -> Result<Ip6Address, EspError> {
+        let mut addr: esp_ip6_addr_t = Default::default();
+
+        esp!(unsafe { esp_netif_get_ip6_linklocal(self.1, &mut addr) })?;
+
+        Ok(Newtype(addr).into())
+    }
+
+    #[cfg(esp_idf_lwip_ipv6)]
+    pub fn get_ip6_addresses(&self) -> vec::Vec<Ip6Address> {
+        let mut addrs: [esp_ip6_addr_t; LWIP_IPV6_NUM_ADDRESSES as usize] = Default::default();
+
+        let count = unsafe { esp_netif_get_all_ip6(self.1, addrs.as_mut_ptr()) };
+
+        addrs[..cmp::max(count, 0) as usize]
+            .iter()
+            .map(|addr| Newtype(*addr).into())
+            .collect()
     }
 
     pub fn get_hostname(&self) -> Result<Cow<'_, str>, EspError> {
@@ -444,8 +986,1005 @@ impl EspNetif {
     }
 }
 
+// Multicast addresses can't be assigned to an interface
+pub fn is_unicast_mac(mac: &[u8; 6]) -> bool {
+    mac[0] & 0x01 == 0
+}
+
+// Locally administered addresses don't clash with the vendor-assigned (universal) ones, which
+// makes them the right choice for randomized or fleet-assigned addresses
+pub fn is_locally_administered_mac(mac: &[u8; 6]) -> bool {
+    mac[0] & 0x02 != 0
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ArpEntry {
+    pub ip: ipv4::Ipv4Addr,
+    pub mac: [u8; 6],
+    pub netif_index: u32,
+}
+
+// The resolved entries of the lwIP ARP table, across all interfaces
+pub fn get_arp_table() -> Result<vec::Vec<ArpEntry>, EspError> {
+    let mut entries = vec::Vec::new();
+
+    tcpip_exec(|| {
+        for index in 0..ARP_TABLE_SIZE {
+            let mut ip: *mut ip4_addr_t = ptr::null_mut();
+            let mut lwip_netif: *mut netif = ptr::null_mut();
+            let mut eth: *mut eth_addr = ptr::null_mut();
+
+            if unsafe { etharp_get_entry(index as _, &mut ip, &mut lwip_netif, &mut eth) } != 0 {
+                let (ip, lwip_netif, eth) = unsafe { (&*ip, &*lwip_netif, &*eth) };
+
+                entries.push(ArpEntry {
+                    ip: Newtype(esp_ip4_addr_t { addr: ip.addr }).into(),
+                    mac: eth.addr,
+                    // The same numbering as `EspNetif::get_index`
+                    netif_index: lwip_netif.num as u32 + 1,
+                });
+            }
+        }
+
+        err_enum_t_ERR_OK as _
+    })?;
+
+    Ok(entries)
+}
+
+// Static entries are never aged out, and take precedence over the ARP replies
+pub fn add_static_arp_entry(ip: ipv4::Ipv4Addr, mac: &[u8; 6]) -> Result<(), EspError> {
+    check_mac(mac)?;
+
+    let ip = ip4_addr_t {
+        addr: Newtype::<esp_ip4_addr_t>::from(ip).0.addr,
+    };
+    let eth = eth_addr { addr: *mac };
+
+    tcpip_exec(|| unsafe { etharp_add_static_entry(&ip, &eth as *const _ as *mut _) })
+}
+
+pub fn remove_static_arp_entry(ip: ipv4::Ipv4Addr) -> Result<(), EspError> {
+    let ip = ip4_addr_t {
+        addr: Newtype::<esp_ip4_addr_t>::from(ip).0.addr,
+    };
+
+    tcpip_exec(|| unsafe { etharp_remove_static_entry(&ip) })
+}
+
+// lwIP's raw API is not thread-safe, so the calls are run in the context of the TCP/IP thread
+pub(crate) fn tcpip_exec<F>(f: F) -> Result<(), EspError>
+where
+    F: FnOnce() -> err_t,
+{
+    #[repr(C)]
+    struct Call<F> {
+        // Has to be the first field, as lwIP only passes a pointer to it
+        data: tcpip_api_call_data,
+        f: Option<F>,
+    }
+
+    unsafe extern "C" fn call<F>(data: *mut tcpip_api_call_data) -> err_t
+    where
+        F: FnOnce() -> err_t,
+    {
+        let call = (data as *mut Call<F>).as_mut().unwrap();
+
+        (call.f.take().unwrap())()
+    }
+
+    let mut call = Call {
+        data: Default::default(),
+        f: Some(f),
+    };
+
+    let err = unsafe { tcpip_api_call(Some(call::<F>), &mut call.data) };
+
+    if err != err_enum_t_ERR_OK as err_t {
+        esp!(ESP_FAIL)?;
+    }
+
+    Ok(())
+}
+
+// A random locally administered unicast address, e.g. for MAC randomization
+pub fn random_local_mac() -> [u8; 6] {
+    let mut mac = [0_u8; 6];
+
+    unsafe { esp_fill_random(mac.as_mut_ptr() as *mut _, mac.len() as _) };
+
+    mac[0] = (mac[0] & !0x01) | 0x02;
+
+    mac
+}
+
+pub(crate) fn check_mac(mac: &[u8; 6]) -> Result<(), EspError> {
+    if !is_unicast_mac(mac) || mac == &[0; 6] || mac == &[0xff; 6] {
+        esp!(ESP_ERR_INVALID_MAC as i32)?;
+    }
+
+    Ok(())
+}
+
+/// # Safety
+///
+/// The netif pointer should point to a live netif
+pub(crate) unsafe fn set_dns_server(
+    netif: *mut esp_netif_t,
+    dns_type: DnsType,
+    dns: ipv4::Ipv4Addr,
+) -> Result<(), EspError> {
+    let mut dns_info: esp_netif_dns_info_t = Default::default();
+
+    dns_info.ip.u_addr.ip4 = Newtype::<esp_ip4_addr_t>::from(dns).0;
+
+    esp!(esp_netif_set_dns_info(
+        netif,
+        dns_type.into(),
+        &mut dns_info
+    ))
+}
+
+/// # Safety
+///
+/// The netif pointer should point to a live netif
+#[cfg(esp_idf_config_lwip_ipv4_napt)]
+pub(crate) unsafe fn enable_napt(netif: *mut esp_netif_t, enable: bool) {
+    // lwIP numbers its interfaces from 0, while esp-netif reports them from 1
+    esp_idf_sys::ip_napt_enable_no(
+        (esp_netif_get_netif_impl_index(netif) - 1) as u8,
+        if enable { 1 } else { 0 },
+    );
+}
+
 impl Drop for EspNetif {
     fn drop(&mut self) {
+        // Dropped once TAPS is unlocked, see `set_capture`
+        let _tap = {
+            let mut taps = TAPS.lock();
+
+            taps.iter()
+                .position(|tap| tap.esp_netif == self.1)
+                .map(|index| taps.remove(index))
+        };
+
         unsafe { esp_netif_destroy(self.1) };
     }
 }
+
+#[cfg(esp_idf_esp_netif_bridge_en)]
+const BRIDGE_MAX_PORTS: u8 = 3;
+#[cfg(esp_idf_esp_netif_bridge_en)]
+const BRIDGE_MAX_FDB_DYN_ENTRIES: u16 = 10;
+#[cfg(esp_idf_esp_netif_bridge_en)]
+const BRIDGE_MAX_FDB_STA_ENTRIES: u16 = 2;
+
+#[cfg(esp_idf_esp_netif_bridge_en)]
+pub enum BridgePort<'a> {
+    Eth(&'a EspNetif),
+    WifiAp(&'a EspNetif),
+}
+
+#[cfg(esp_idf_esp_netif_bridge_en)]
+struct BridgeGlue(esp_netif_br_glue_handle_t);
+
+#[cfg(esp_idf_esp_netif_bridge_en)]
+impl Drop for BridgeGlue {
+    fn drop(&mut self) {
+        unsafe { esp_netif_br_glue_del(self.0) };
+    }
+}
+
+// Bridges the traffic of its ports at L2, e.g. to build a transparent Ethernet to Wi-Fi adapter.
+// The ports should be configured without IP (routers with DHCP disabled), as the IP
+// configuration belongs to the bridge itself. The bridge borrows its ports, so that they
+// outlive it
+#[cfg(esp_idf_esp_netif_bridge_en)]
+pub struct EspNetifBridge<'a> {
+    // Destroyed before the glue it is attached to
+    netif: EspNetif,
+    _glue: BridgeGlue,
+    _ports: core::marker::PhantomData<&'a EspNetif>,
+}
+
+#[cfg(esp_idf_esp_netif_bridge_en)]
+impl<'a> EspNetifBridge<'a> {
+    pub fn new(
+        netif_stack: Arc<EspNetifStack>,
+        conf: &InterfaceConfiguration,
+        ports: &[BridgePort<'a>],
+    ) -> Result<Self, EspError> {
+        if conf.interface_stack != InterfaceStack::Bridge || ports.len() > BRIDGE_MAX_PORTS as _ {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        let netif = EspNetif::new(netif_stack, conf)?;
+
+        let glue = unsafe { esp_netif_br_glue_new() };
+        if glue.is_null() {
+            esp!(ESP_ERR_NO_MEM as i32)?;
+        }
+
+        let glue = BridgeGlue(glue);
+
+        for port in ports {
+            match port {
+                BridgePort::Eth(port) => {
+                    esp!(unsafe { esp_netif_br_glue_add_port(glue.0, port.1) })?
+                }
+                BridgePort::WifiAp(port) => {
+                    esp!(unsafe { esp_netif_br_glue_add_wifi_port(glue.0, port.1) })?
+                }
+            }
+        }
+
+        esp!(unsafe { esp_netif_attach(netif.1, glue.0 as *mut _) })?;
+
+        info!("Bridge {} created with {} ports", conf.key, ports.len());
+
+        Ok(Self {
+            netif,
+            _glue: glue,
+            _ports: core::marker::PhantomData,
+        })
+    }
+
+    pub fn netif(&self) -> &EspNetif {
+        &self.netif
+    }
+
+    pub fn netif_mut(&mut self) -> &mut EspNetif {
+        &mut self.netif
+    }
+}
+
+// ESP-IDF does not post statistics events, so they are posted with this base
+static NETIF_STATS_EVENT: &str = "NETIF_STATS_EVENT\0";
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct NetifStats {
+    pub mtu: u16,
+    pub up: bool,
+    pub link_up: bool,
+    pub tx_packets: u32,
+    pub tx_bytes: u64,
+    pub tx_errors: u32,
+    pub rx_packets: u32,
+    pub rx_bytes: u64,
+    pub rx_errors: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct NetifStatsEvent {
+    pub netif_index: u32,
+    pub stats: NetifStats,
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for NetifStatsEvent {
+    fn source() -> *const c_types::c_char {
+        NETIF_STATS_EVENT.as_ptr() as *const _
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for NetifStatsEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        unsafe { data.as_payload() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaptureDirection {
+    Rx,
+    Tx,
+}
+
+// Called from the esp_timer task with the frames captured since its previous call, so it may
+// block, e.g. on a socket of the captured netif itself
+pub type CaptureCallback = alloc::boxed::Box<dyn FnMut(CaptureDirection, &[u8]) + Send + 'static>;
+
+// The frames captured beyond it, while the callback is busy, are dropped
+const CAPTURE_QUEUE_LEN: usize = 32;
+
+// The frames are copied into a queue by the hooks, and handed to the callback by a timer
+struct Capture {
+    callback: Arc<mutex::Mutex<CaptureCallback>>,
+    frames: VecDeque<(CaptureDirection, vec::Vec<u8>)>,
+    dropped: u32,
+    timer: esp_timer_handle_t,
+}
+
+impl Capture {
+    fn new(esp_netif: *mut esp_netif_t, callback: CaptureCallback) -> Result<Self, EspError> {
+        let mut timer: esp_timer_handle_t = ptr::null_mut();
+
+        esp!(unsafe {
+            esp_timer_create(
+                &esp_timer_create_args_t {
+                    callback: Some(deliver_frames),
+                    name: b"netif_capture\0" as *const _ as *const _,
+                    arg: esp_netif as *mut _,
+                    dispatch_method: esp_timer_dispatch_t_ESP_TIMER_TASK,
+                    skip_unhandled_events: true,
+                },
+                &mut timer as *mut _,
+            )
+        })?;
+
+        Ok(Self {
+            callback: Arc::new(mutex::Mutex::new(callback)),
+            frames: VecDeque::new(),
+            dropped: 0,
+            timer,
+        })
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        unsafe {
+            esp_timer_stop(self.timer);
+            esp_timer_delete(self.timer);
+        }
+    }
+}
+
+// The traffic of the netifs with statistics or capture enabled is tapped by replacing the input
+// and link output functions of their lwIP interfaces
+struct Tap {
+    esp_netif: *mut esp_netif_t,
+    lwip_netif: *mut netif,
+    input: netif_input_fn,
+    linkoutput: netif_linkoutput_fn,
+    stats_enabled: bool,
+    stats: NetifStats,
+    capture: Option<Capture>,
+}
+
+unsafe impl Send for Tap {}
+
+// Never locked while waiting for the TCP/IP thread, which locks it in the hooks
+static TAPS: mutex::Mutex<vec::Vec<Tap>> = mutex::Mutex::new(vec::Vec::new());
+
+impl EspNetif {
+    // Starts counting the traffic of the interface. The interface has to be up; when it is
+    // restarted, the counting resumes with the next `enable_stats` call or statistics report
+    pub fn enable_stats(&mut self) -> Result<(), EspError> {
+        tap_mut(&mut TAPS.lock(), self.1).stats_enabled = true;
+
+        unsafe { install_tap(self.1) }
+    }
+
+    pub fn disable_stats(&mut self) {
+        if let Some(tap) = TAPS.lock().iter_mut().find(|tap| tap.esp_netif == self.1) {
+            tap.stats_enabled = false;
+            tap.stats = Default::default();
+        }
+
+        release_tap(self.1);
+    }
+
+    // The traffic counters stay at zero unless enabled with `enable_stats`
+    pub fn get_stats(&self) -> Result<NetifStats, EspError> {
+        let lwip_netif = unsafe { lwip_netif(self.1) };
+
+        if lwip_netif.is_null() {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let stats = TAPS
+            .lock()
+            .iter()
+            .find(|tap| tap.esp_netif == self.1 && tap.stats_enabled)
+            .map(|tap| tap.stats)
+            .unwrap_or_default();
+
+        Ok(unsafe { link_stats(lwip_netif, stats) })
+    }
+
+    // Hands each frame received or sent by the interface to the callback; `None` stops the
+    // capture. As with the statistics, the interface has to be up
+    pub fn set_capture(&mut self, callback: Option<CaptureCallback>) -> Result<(), EspError> {
+        // The replaced capture is dropped once TAPS is unlocked, as its callback might own a
+        // socket, whose closing waits for the TCP/IP thread
+        let _replaced = if let Some(callback) = callback {
+            let capture = Capture::new(self.1, callback)?;
+            let replaced = tap_mut(&mut TAPS.lock(), self.1).capture.replace(capture);
+
+            unsafe { install_tap(self.1) }?;
+
+            info!("Capture enabled");
+
+            replaced
+        } else {
+            let replaced = TAPS
+                .lock()
+                .iter_mut()
+                .find(|tap| tap.esp_netif == self.1)
+                .and_then(|tap| tap.capture.take());
+
+            release_tap(self.1);
+
+            info!("Capture disabled");
+
+            replaced
+        };
+
+        Ok(())
+    }
+
+    // Writes the traffic of the interface as a pcap stream, e.g. to a file on an SD card or
+    // to a TCP socket, to be opened with Wireshark
+    #[cfg(feature = "std")]
+    pub fn set_pcap_capture<W>(&mut self, writer: W) -> Result<(), EspError>
+    where
+        W: std::io::Write + Send + 'static,
+    {
+        let lwip_netif = unsafe { lwip_netif(self.1).as_ref() };
+
+        // Interfaces without ARP (PPP, SLIP) carry bare IP packets
+        let link_type = match lwip_netif {
+            Some(lwip_netif) if lwip_netif.flags as u32 & NETIF_FLAG_ETHARP != 0 => {
+                PcapLinkType::Ethernet
+            }
+            Some(_) => PcapLinkType::Raw,
+            None => return esp!(ESP_ERR_INVALID_STATE as i32),
+        };
+
+        let mut pcap = PcapWriter::new(writer, link_type).map_err(|err| {
+            warn!("Failed to write the pcap header: {}", err);
+
+            EspError::from(ESP_FAIL).unwrap()
+        })?;
+
+        self.set_capture(Some(alloc::boxed::Box::new(move |_, frame| {
+            if let Err(err) = pcap.write_frame(frame) {
+                warn!("Failed to write a pcap record: {}", err);
+            }
+        })))
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PcapLinkType {
+    Ethernet = 1,
+    Raw = 101,
+}
+
+#[cfg(feature = "std")]
+pub struct PcapWriter<W> {
+    writer: W,
+}
+
+#[cfg(feature = "std")]
+impl<W> PcapWriter<W>
+where
+    W: std::io::Write,
+{
+    const SNAPLEN: u32 = 65535;
+
+    pub fn new(mut writer: W, link_type: PcapLinkType) -> std::io::Result<Self> {
+        writer.write_all(&0xa1b2c3d4_u32.to_le_bytes())?;
+        writer.write_all(&2_u16.to_le_bytes())?;
+        writer.write_all(&4_u16.to_le_bytes())?;
+        writer.write_all(&0_i32.to_le_bytes())?; // GMT offset
+        writer.write_all(&0_u32.to_le_bytes())?; // Timestamp accuracy
+        writer.write_all(&Self::SNAPLEN.to_le_bytes())?;
+        writer.write_all(&(link_type as u32).to_le_bytes())?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        // Since the epoch once the time is synchronized (e.g. with SNTP), since boot otherwise
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let captured = cmp::min(frame.len(), Self::SNAPLEN as usize);
+
+        self.writer
+            .write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&timestamp.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(captured as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&frame[..captured])?;
+
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Periodically posts a `NetifStatsEvent` for each netif with statistics enabled
+pub struct EspNetifStatsReporter(esp_timer_handle_t);
+
+unsafe impl Send for EspNetifStatsReporter {}
+
+impl EspNetifStatsReporter {
+    pub fn new(interval: Duration) -> Result<Self, EspError> {
+        let mut timer: esp_timer_handle_t = ptr::null_mut();
+
+        esp!(unsafe {
+            esp_timer_create(
+                &esp_timer_create_args_t {
+                    callback: Some(Self::report),
+                    name: b"netif_stats\0" as *const _ as *const _,
+                    arg: ptr::null_mut(),
+                    dispatch_method: esp_timer_dispatch_t_ESP_TIMER_TASK,
+                    skip_unhandled_events: true,
+                },
+                &mut timer as *mut _,
+            )
+        })?;
+
+        let reporter = Self(timer);
+
+        esp!(unsafe { esp_timer_start_periodic(timer, interval.as_micros() as _) })?;
+
+        info!("Reporting netif statistics every {:?}", interval);
+
+        Ok(reporter)
+    }
+
+    unsafe extern "C" fn report(_arg: *mut c_types::c_void) {
+        let esp_netifs = TAPS
+            .lock()
+            .iter()
+            .filter(|tap| tap.stats_enabled)
+            .map(|tap| tap.esp_netif)
+            .collect::<vec::Vec<_>>();
+
+        // The taps of the restarted interfaces are reinstalled, without TAPS being locked
+        let events = esp_netifs
+            .into_iter()
+            .filter_map(|esp_netif| {
+                install_tap(esp_netif).ok()?;
+
+                let lwip_netif = lwip_netif(esp_netif);
+                if lwip_netif.is_null() {
+                    return None;
+                }
+
+                let stats = TAPS
+                    .lock()
+                    .iter()
+                    .find(|tap| tap.esp_netif == esp_netif && tap.stats_enabled)?
+                    .stats;
+
+                Some(NetifStatsEvent {
+                    netif_index: esp_netif_get_netif_impl_index(esp_netif) as _,
+                    stats: link_stats(lwip_netif, stats),
+                })
+            })
+            .collect::<vec::Vec<_>>();
+
+        for event in &events {
+            if let Err(err) = esp!(esp_event_post(
+                NETIF_STATS_EVENT.as_ptr() as *const _,
+                0,
+                event as *const _ as *mut c_types::c_void,
+                core::mem::size_of::<NetifStatsEvent>() as _,
+                0
+            )) {
+                warn!("Failed to post netif statistics event: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for EspNetifStatsReporter {
+    fn drop(&mut self) {
+        unsafe {
+            esp_timer_stop(self.0);
+            esp_timer_delete(self.0);
+        }
+    }
+}
+
+fn tap_mut(taps: &mut vec::Vec<Tap>, esp_netif: *mut esp_netif_t) -> &mut Tap {
+    if let Some(index) = taps.iter().position(|tap| tap.esp_netif == esp_netif) {
+        &mut taps[index]
+    } else {
+        taps.push(Tap {
+            esp_netif,
+            lwip_netif: ptr::null_mut(),
+            input: None,
+            linkoutput: None,
+            stats_enabled: false,
+            stats: Default::default(),
+            capture: None,
+        });
+
+        taps.last_mut().unwrap()
+    }
+}
+
+// Removes the tap once neither the statistics nor the capture use it
+fn release_tap(esp_netif: *mut esp_netif_t) {
+    if let Err(err) = unsafe { call_tap(esp_netif, false) } {
+        warn!("Failed to release the tap: {}", err);
+    }
+}
+
+unsafe fn lwip_netif(esp_netif: *mut esp_netif_t) -> *mut netif {
+    // lwIP only knows the interfaces which are up
+    netif_get_by_index(esp_netif_get_netif_impl_index(esp_netif) as _)
+}
+
+// (Re)installs the tap if the lwIP interface was (re)created since the last call
+unsafe fn install_tap(esp_netif: *mut esp_netif_t) -> Result<(), EspError> {
+    call_tap(esp_netif, true)
+}
+
+#[repr(C)]
+struct TapCall {
+    // The first field, as lwIP hands it back to `tap_call`
+    call: tcpip_api_call_data,
+    esp_netif: *mut esp_netif_t,
+    install: bool,
+}
+
+// The hooks are swapped from the TCP/IP thread, so that no frame goes through a half
+// installed tap
+unsafe fn call_tap(esp_netif: *mut esp_netif_t, install: bool) -> Result<(), EspError> {
+    let mut call = TapCall {
+        call: core::mem::zeroed(),
+        esp_netif,
+        install,
+    };
+
+    if tcpip_api_call(Some(tap_call), &mut call.call) != err_enum_t_ERR_OK as err_t {
+        esp!(ESP_ERR_INVALID_STATE as i32)?;
+    }
+
+    Ok(())
+}
+
+unsafe extern "C" fn tap_call(call: *mut tcpip_api_call_data) -> err_t {
+    let call = &*(call as *const TapCall);
+
+    let mut taps = TAPS.lock();
+
+    let index = match taps.iter().position(|tap| tap.esp_netif == call.esp_netif) {
+        Some(index) => index,
+        None => return err_enum_t_ERR_ARG as _,
+    };
+
+    if call.install {
+        let tap = &mut taps[index];
+        let lwip_netif = lwip_netif(tap.esp_netif);
+
+        let lwip_netif_ref = match lwip_netif.as_mut() {
+            Some(lwip_netif_ref) => lwip_netif_ref,
+            None => return err_enum_t_ERR_IF as _,
+        };
+
+        if tap.lwip_netif != lwip_netif || lwip_netif_ref.input != Some(tap_input) {
+            tap.lwip_netif = lwip_netif;
+            tap.input = lwip_netif_ref.input;
+            tap.linkoutput = lwip_netif_ref.linkoutput;
+
+            lwip_netif_ref.input = Some(tap_input);
+
+            if tap.linkoutput.is_some() {
+                lwip_netif_ref.linkoutput = Some(tap_linkoutput);
+            }
+        }
+    } else if !taps[index].stats_enabled && taps[index].capture.is_none() {
+        let tap = taps.remove(index);
+
+        if let Some(lwip_netif_ref) = lwip_netif(tap.esp_netif).as_mut() {
+            if lwip_netif_ref.input == Some(tap_input) {
+                lwip_netif_ref.input = tap.input;
+                lwip_netif_ref.linkoutput = tap.linkoutput;
+            }
+        }
+    }
+
+    err_enum_t_ERR_OK as _
+}
+
+unsafe fn link_stats(lwip_netif: *mut netif, stats: NetifStats) -> NetifStats {
+    let lwip_netif_ref = lwip_netif.as_ref().unwrap();
+
+    NetifStats {
+        mtu: lwip_netif_ref.mtu,
+        up: lwip_netif_ref.flags as u32 & NETIF_FLAG_UP != 0,
+        link_up: lwip_netif_ref.flags as u32 & NETIF_FLAG_LINK_UP != 0,
+        ..stats
+    }
+}
+
+unsafe extern "C" fn tap_input(p: *mut pbuf, inp: *mut netif) -> err_t {
+    // The frame is copied before being passed on, as the stack frees it once processed
+    let input = with_tap(inp, |tap| {
+        tap.stats.rx_packets += 1;
+        tap.stats.rx_bytes += (*p).tot_len as u64;
+
+        capture(tap, CaptureDirection::Rx, p);
+
+        tap.input
+    })
+    .flatten();
+
+    let err = if let Some(input) = input {
+        input(p, inp)
+    } else {
+        err_enum_t_ERR_IF as _
+    };
+
+    if err != err_enum_t_ERR_OK as err_t {
+        with_tap(inp, |tap| tap.stats.rx_errors += 1);
+    }
+
+    err
+}
+
+unsafe extern "C" fn tap_linkoutput(outp: *mut netif, p: *mut pbuf) -> err_t {
+    let linkoutput = with_tap(outp, |tap| {
+        capture(tap, CaptureDirection::Tx, p);
+
+        tap.linkoutput
+    })
+    .flatten();
+
+    let err = if let Some(linkoutput) = linkoutput {
+        linkoutput(outp, p)
+    } else {
+        err_enum_t_ERR_IF as _
+    };
+
+    with_tap(outp, |tap| {
+        if err == err_enum_t_ERR_OK as err_t {
+            tap.stats.tx_packets += 1;
+            tap.stats.tx_bytes += (*p).tot_len as u64;
+        } else {
+            tap.stats.tx_errors += 1;
+        }
+    });
+
+    err
+}
+
+unsafe fn capture(tap: &mut Tap, direction: CaptureDirection, p: *mut pbuf) {
+    if let Some(capture) = tap.capture.as_mut() {
+        if capture.frames.len() < CAPTURE_QUEUE_LEN {
+            // The frame might be split across a chain of buffers
+            let mut frame = vec![0_u8; (*p).tot_len as usize];
+
+            pbuf_copy_partial(p, frame.as_mut_ptr() as *mut _, frame.len() as _, 0);
+
+            capture.frames.push_back((direction, frame));
+        } else {
+            capture.dropped += 1;
+        }
+
+        // Fails when the delivery is already scheduled, which is fine
+        esp_timer_start_once(capture.timer, 0);
+    }
+}
+
+unsafe extern "C" fn deliver_frames(arg: *mut c_types::c_void) {
+    let esp_netif = arg as *mut esp_netif_t;
+
+    let queued = TAPS
+        .lock()
+        .iter_mut()
+        .find(|tap| tap.esp_netif == esp_netif)
+        .and_then(|tap| tap.capture.as_mut())
+        .map(|capture| {
+            (
+                capture.callback.clone(),
+                core::mem::take(&mut capture.frames),
+                core::mem::take(&mut capture.dropped),
+            )
+        });
+
+    if let Some((callback, frames, dropped)) = queued {
+        if dropped > 0 {
+            warn!("Dropped {} captured frames", dropped);
+        }
+
+        let mut callback = callback.lock();
+
+        for (direction, frame) in frames {
+            (*callback)(direction, &frame);
+        }
+    }
+}
+
+fn with_tap<T>(lwip_netif: *mut netif, f: impl FnOnce(&mut Tap) -> T) -> Option<T> {
+    TAPS.lock()
+        .iter_mut()
+        .find(|tap| tap.lwip_netif == lwip_netif)
+        .map(f)
+}
+
+// A link layer driving a custom netif: outgoing frames are passed to `transmit`, while
+// incoming ones are handed to the stack with `EspNetifGlue::receive`
+pub trait NetifDriver: Send {
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), EspError>;
+
+    // Called once the driver is attached to the netif, before any frame is transmitted
+    fn attached(&mut self) -> Result<(), EspError> {
+        Ok(())
+    }
+}
+
+// The layout expected by `esp_netif_attach`: the driver base has to be the first field
+#[repr(C)]
+struct Glue<D> {
+    base: esp_netif_driver_base_t,
+    driver: mutex::Mutex<D>,
+}
+
+pub struct EspNetifGlue<D> {
+    // Dropped first, as the netif keeps a pointer to the glue
+    netif: EspNetif,
+    glue: alloc::boxed::Box<Glue<D>>,
+    // Whether the stack takes ownership of the received buffers instead of copying them
+    owned_rx: bool,
+}
+
+impl<D> EspNetifGlue<D>
+where
+    D: NetifDriver,
+{
+    pub fn new(
+        netif_stack: Arc<EspNetifStack>,
+        conf: &InterfaceConfiguration,
+        driver: D,
+    ) -> Result<Self, EspError> {
+        let netif = EspNetif::new(netif_stack, conf)?;
+
+        let mut glue = alloc::boxed::Box::new(Glue {
+            base: esp_netif_driver_base_t {
+                post_attach: Some(Self::post_attach),
+                netif: ptr::null_mut(),
+            },
+            driver: mutex::Mutex::new(driver),
+        });
+
+        let glue_ref: *mut _ = &mut *glue;
+
+        esp!(unsafe { esp_netif_attach(netif.1, glue_ref as *mut c_types::c_void) })?;
+
+        info!("Custom driver attached to netif {}", conf.key);
+
+        let owned_rx = match conf.interface_stack {
+            #[cfg(esp_idf_ppp_support)]
+            InterfaceStack::Ppp => false,
+            #[cfg(esp_idf_slip_support)]
+            InterfaceStack::Slip => false,
+            _ => true,
+        };
+
+        Ok(Self {
+            netif,
+            glue,
+            owned_rx,
+        })
+    }
+
+    pub fn netif(&self) -> &EspNetif {
+        &self.netif
+    }
+
+    pub fn netif_mut(&mut self) -> &mut EspNetif {
+        &mut self.netif
+    }
+
+    pub fn with_driver<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut D) -> T,
+    {
+        f(&mut self.glue.driver.lock())
+    }
+
+    // Passes a frame received by the driver to the stack
+    pub fn receive(&self, frame: &[u8]) -> Result<(), EspError> {
+        if !self.owned_rx {
+            // The PPP and SLIP stacks copy the data right away
+            return esp!(unsafe {
+                esp_netif_receive(
+                    self.netif.1,
+                    frame.as_ptr() as *mut _,
+                    frame.len() as _,
+                    ptr::null_mut(),
+                )
+            });
+        }
+
+        // The other stacks keep the buffer until the frame is processed, and release it
+        // with `free_rx_buffer`
+        let buffer = unsafe { malloc(frame.len() as _) };
+        if buffer.is_null() {
+            esp!(ESP_ERR_NO_MEM as i32)?;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(frame.as_ptr(), buffer as *mut u8, frame.len());
+
+            esp!(esp_netif_receive(
+                self.netif.1,
+                buffer,
+                frame.len() as _,
+                ptr::null_mut()
+            ))
+        }
+    }
+
+    pub fn start(&mut self) {
+        unsafe { esp_netif_action_start(self.netif.1 as _, ptr::null(), 0, ptr::null_mut()) };
+    }
+
+    pub fn connected(&mut self) {
+        unsafe { esp_netif_action_connected(self.netif.1 as _, ptr::null(), 0, ptr::null_mut()) };
+    }
+
+    pub fn disconnected(&mut self) {
+        unsafe {
+            esp_netif_action_disconnected(self.netif.1 as _, ptr::null(), 0, ptr::null_mut())
+        };
+    }
+
+    pub fn stop(&mut self) {
+        unsafe { esp_netif_action_stop(self.netif.1 as _, ptr::null(), 0, ptr::null_mut()) };
+    }
+
+    unsafe extern "C" fn post_attach(
+        netif: *mut esp_netif_t,
+        args: *mut c_types::c_void,
+    ) -> esp_err_t {
+        let glue = (args as *mut Glue<D>).as_mut().unwrap();
+
+        glue.base.netif = netif;
+
+        let ifconfig = esp_netif_driver_ifconfig_t {
+            handle: args,
+            transmit: Some(Self::transmit),
+            driver_free_rx_buffer: Some(Self::free_rx_buffer),
+            ..Default::default()
+        };
+
+        let err = esp_netif_set_driver_config(netif, &ifconfig);
+        if err != ESP_OK as i32 {
+            return err;
+        }
+
+        match glue.driver.lock().attached() {
+            Ok(()) => ESP_OK as _,
+            Err(err) => err.code(),
+        }
+    }
+
+    unsafe extern "C" fn transmit(
+        handle: *mut c_types::c_void,
+        buffer: *mut c_types::c_void,
+        len: usize,
+    ) -> esp_err_t {
+        let glue = (handle as *const Glue<D>).as_ref().unwrap();
+
+        let frame = core::slice::from_raw_parts(buffer as *const u8, len);
+
+        match glue.driver.lock().transmit(frame) {
+            Ok(()) => ESP_OK as _,
+            Err(err) => err.code(),
+        }
+    }
+
+    unsafe extern "C" fn free_rx_buffer(
+        _handle: *mut c_types::c_void,
+        buffer: *mut c_types::c_void,
+    ) {
+        free(buffer);
+    }
+}
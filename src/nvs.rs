@@ -1,4 +1,7 @@
 extern crate alloc;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 
 use ::log::*;
 
@@ -8,15 +11,88 @@ use esp_idf_sys::*;
 
 use crate::private::cstr::*;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NvsDataType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    Str,
+    Blob,
+    Other(nvs_type_t),
+}
+
+impl From<nvs_type_t> for NvsDataType {
+    #[allow(non_upper_case_globals)]
+    fn from(data_type: nvs_type_t) -> Self {
+        match data_type {
+            nvs_type_t_NVS_TYPE_U8 => NvsDataType::U8,
+            nvs_type_t_NVS_TYPE_I8 => NvsDataType::I8,
+            nvs_type_t_NVS_TYPE_U16 => NvsDataType::U16,
+            nvs_type_t_NVS_TYPE_I16 => NvsDataType::I16,
+            nvs_type_t_NVS_TYPE_U32 => NvsDataType::U32,
+            nvs_type_t_NVS_TYPE_I32 => NvsDataType::I32,
+            nvs_type_t_NVS_TYPE_U64 => NvsDataType::U64,
+            nvs_type_t_NVS_TYPE_I64 => NvsDataType::I64,
+            nvs_type_t_NVS_TYPE_STR => NvsDataType::Str,
+            nvs_type_t_NVS_TYPE_BLOB => NvsDataType::Blob,
+            other => NvsDataType::Other(other),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub data_type: NvsDataType,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NvsStats {
+    pub used_entries: usize,
+    pub free_entries: usize,
+    pub total_entries: usize,
+    pub namespace_count: usize,
+}
+
+impl NvsStats {
+    // Percentage of the entries in use
+    pub fn usage(&self) -> u8 {
+        if self.total_entries == 0 {
+            0
+        } else {
+            (self.used_entries * 100 / self.total_entries) as u8
+        }
+    }
+}
+
+impl From<nvs_stats_t> for NvsStats {
+    fn from(stats: nvs_stats_t) -> Self {
+        Self {
+            used_entries: stats.used_entries as _,
+            free_entries: stats.free_entries as _,
+            total_entries: stats.total_entries as _,
+            namespace_count: stats.namespace_count as _,
+        }
+    }
+}
+
 static DEFAULT_TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
+static DEFAULT_SHARED: mutex::Mutex<Option<Weak<EspDefaultNvs>>> = mutex::Mutex::new(None);
 static NONDEFAULT_LOCKED: mutex::Mutex<alloc::collections::BTreeSet<CString>> =
     mutex::Mutex::new(alloc::collections::BTreeSet::new());
 
 #[derive(Debug)]
 struct PrivateData;
 
+// The second field holds the encryption keys if the partition is encrypted
 #[derive(Debug)]
-pub struct EspDefaultNvs(PrivateData);
+pub struct EspDefaultNvs(PrivateData, Option<nvs_sec_cfg_t>);
 
 impl EspDefaultNvs {
     pub fn new() -> Result<Self, EspError> {
@@ -32,6 +108,47 @@ impl EspDefaultNvs {
         Ok(default_nvs)
     }
 
+    // Returns the instance shared by all the callers, initializing it if none is alive, so that
+    // independent components can each open their own namespaces with `EspNvsStorage`.
+    // Fails if the default partition was taken exclusively with `new`
+    pub fn get_or_init() -> Result<Arc<Self>, EspError> {
+        let mut shared = DEFAULT_SHARED.lock();
+
+        if let Some(default_nvs) = shared.as_ref().and_then(Weak::upgrade) {
+            return Ok(default_nvs);
+        }
+
+        let default_nvs = Arc::new(Self::new()?);
+
+        *shared = Some(Arc::downgrade(&default_nvs));
+
+        Ok(default_nvs)
+    }
+
+    // Uses the keys from the first NVS keys partition, or the one with the given label;
+    // the keys are generated on first use
+    #[cfg(esp_idf_nvs_encryption)]
+    pub fn new_encrypted(keys_partition: Option<&str>) -> Result<Self, EspError> {
+        let mut taken = DEFAULT_TAKEN.lock();
+
+        if *taken {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let mut sec_cfg = read_security_cfg(keys_partition)?;
+
+        init_partition(
+            CStr::from_bytes_with_nul(NVS_DEFAULT_PART_NAME).unwrap(),
+            Some(&mut sec_cfg),
+            true,
+        )?;
+
+        info!("Encrypted default NVS initialized");
+
+        *taken = true;
+        Ok(Self(PrivateData, Some(sec_cfg)))
+    }
+
     fn init() -> Result<Self, EspError> {
         if let Some(err) = EspError::from(unsafe { nvs_flash_init() }) {
             match err.code() {
@@ -43,7 +160,38 @@ impl EspDefaultNvs {
             }
         }
 
-        Ok(Self(PrivateData))
+        Ok(Self(PrivateData, None))
+    }
+}
+
+impl EspDefaultNvs {
+    pub fn is_encrypted(&self) -> bool {
+        self.1.is_some()
+    }
+
+    // Erases all the namespaces of the partition, e.g. for a factory reset. Requiring `&mut`
+    // ensures that no storage (which keeps an `Arc` to the partition) is open
+    pub fn erase_and_reinit(&mut self) -> Result<(), EspError> {
+        erase_and_reinit(
+            CStr::from_bytes_with_nul(NVS_DEFAULT_PART_NAME).unwrap(),
+            self.1.as_mut(),
+        )
+    }
+
+    // All the entries of the namespace, or of the whole partition without a namespace
+    pub fn entries(&self, namespace: Option<&str>) -> Result<Vec<NvsEntry>, EspError> {
+        entries(
+            CStr::from_bytes_with_nul(NVS_DEFAULT_PART_NAME).unwrap(),
+            namespace,
+        )
+    }
+
+    pub fn namespaces(&self) -> Result<Vec<String>, EspError> {
+        Ok(namespaces(self.entries(None)?))
+    }
+
+    pub fn stats(&self) -> Result<NvsStats, EspError> {
+        stats(CStr::from_bytes_with_nul(NVS_DEFAULT_PART_NAME).unwrap())
     }
 }
 
@@ -56,8 +204,9 @@ impl Drop for EspDefaultNvs {
     }
 }
 
+// The second field holds the encryption keys if the partition is encrypted
 #[derive(Debug)]
-pub struct EspNvs(pub(crate) CString);
+pub struct EspNvs(pub(crate) CString, Option<nvs_sec_cfg_t>);
 
 impl EspNvs {
     pub fn new(partition: impl AsRef<str>) -> Result<Self, EspError> {
@@ -66,6 +215,54 @@ impl EspNvs {
         Self::init(partition, &mut registrations)
     }
 
+    // Uses the keys from the first NVS keys partition, or the one with the given label;
+    // the keys are generated on first use
+    #[cfg(esp_idf_nvs_encryption)]
+    pub fn new_encrypted(
+        partition: impl AsRef<str>,
+        keys_partition: Option<&str>,
+    ) -> Result<Self, EspError> {
+        let mut registrations = NONDEFAULT_LOCKED.lock();
+
+        let c_partition = CString::new(partition.as_ref()).unwrap();
+
+        if registrations.contains(c_partition.as_ref()) {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let mut sec_cfg = read_security_cfg(keys_partition)?;
+
+        init_partition(&c_partition, Some(&mut sec_cfg), true)?;
+
+        info!("Encrypted NVS partition {} initialized", partition.as_ref());
+
+        registrations.insert(c_partition.clone());
+
+        Ok(Self(c_partition, Some(sec_cfg)))
+    }
+
+    // Unlike `new`, does not erase the partition if it is full or was written by a newer
+    // ESP-IDF version, which makes it suitable for factory data partitions
+    pub fn new_no_erase(partition: impl AsRef<str>) -> Result<Self, EspError> {
+        let mut registrations = NONDEFAULT_LOCKED.lock();
+
+        let c_partition = CString::new(partition.as_ref()).unwrap();
+
+        if registrations.contains(c_partition.as_ref()) {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        init_partition(&c_partition, None, false)?;
+
+        registrations.insert(c_partition.clone());
+
+        Ok(Self(c_partition, None))
+    }
+
+    pub fn partition(&self) -> &CStr {
+        &self.0
+    }
+
     fn init(
         partition: impl AsRef<str>,
         registrations: &mut alloc::collections::BTreeSet<CString>,
@@ -76,21 +273,36 @@ impl EspNvs {
             return Err(EspError::from(ESP_ERR_INVALID_STATE as i32).unwrap());
         }
 
-        unsafe {
-            if let Some(err) = EspError::from(nvs_flash_init_partition(c_partition.as_ptr())) {
-                match err.code() {
-                    ESP_ERR_NVS_NO_FREE_PAGES | ESP_ERR_NVS_NEW_VERSION_FOUND => {
-                        esp!(nvs_flash_erase_partition(c_partition.as_ptr()))?;
-                        esp!(nvs_flash_init_partition(c_partition.as_ptr()))?;
-                    }
-                    _ => (),
-                }
-            }
-        }
+        init_partition(&c_partition, None, true)?;
 
         registrations.insert(c_partition.clone());
 
-        Ok(Self(c_partition))
+        Ok(Self(c_partition, None))
+    }
+}
+
+impl EspNvs {
+    pub fn is_encrypted(&self) -> bool {
+        self.1.is_some()
+    }
+
+    // Erases all the namespaces of the partition, e.g. for a factory reset. Requiring `&mut`
+    // ensures that no storage (which keeps an `Arc` to the partition) is open
+    pub fn erase_and_reinit(&mut self) -> Result<(), EspError> {
+        erase_and_reinit(&self.0, self.1.as_mut())
+    }
+
+    // All the entries of the namespace, or of the whole partition without a namespace
+    pub fn entries(&self, namespace: Option<&str>) -> Result<Vec<NvsEntry>, EspError> {
+        entries(&self.0, namespace)
+    }
+
+    pub fn namespaces(&self) -> Result<Vec<String>, EspError> {
+        Ok(namespaces(self.entries(None)?))
+    }
+
+    pub fn stats(&self) -> Result<NvsStats, EspError> {
+        stats(&self.0)
     }
 }
 
@@ -106,3 +318,160 @@ impl Drop for EspNvs {
         info!("Dropped");
     }
 }
+
+#[cfg(esp_idf_nvs_encryption)]
+fn read_security_cfg(keys_partition: Option<&str>) -> Result<nvs_sec_cfg_t, EspError> {
+    let c_label = keys_partition.map(|label| CString::new(label).unwrap());
+
+    let partition = unsafe {
+        esp_partition_find_first(
+            esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+            esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+            c_label
+                .as_ref()
+                .map_or(core::ptr::null(), |label| label.as_ptr()),
+        )
+    };
+
+    if partition.is_null() {
+        info!("No NVS keys partition found");
+
+        esp!(ESP_ERR_NOT_FOUND as i32)?;
+    }
+
+    let mut sec_cfg: nvs_sec_cfg_t = Default::default();
+
+    match unsafe { nvs_flash_read_security_cfg(partition, &mut sec_cfg) } {
+        ESP_ERR_NVS_KEYS_NOT_INITIALIZED => {
+            info!("Generating NVS encryption keys");
+
+            esp!(unsafe { nvs_flash_generate_keys(partition, &mut sec_cfg) })?;
+        }
+        err => esp!(err)?,
+    }
+
+    Ok(sec_cfg)
+}
+
+fn init_partition(
+    partition: &CStr,
+    sec_cfg: Option<&mut nvs_sec_cfg_t>,
+    erase: bool,
+) -> Result<(), EspError> {
+    let sec_cfg = sec_cfg.map_or(core::ptr::null_mut(), |sec_cfg| sec_cfg as *mut _);
+
+    let init = || unsafe {
+        if sec_cfg.is_null() {
+            nvs_flash_init_partition(partition.as_ptr())
+        } else {
+            nvs_flash_secure_init_partition(partition.as_ptr(), sec_cfg)
+        }
+    };
+
+    if let Some(err) = EspError::from(init()) {
+        match err.code() {
+            ESP_ERR_NVS_NO_FREE_PAGES | ESP_ERR_NVS_NEW_VERSION_FOUND if erase => {
+                info!("Erasing NVS partition {:?}: {}", partition, err);
+
+                esp!(unsafe { nvs_flash_erase_partition(partition.as_ptr()) })?;
+                esp!(init())?;
+            }
+            _ => return Err(err),
+        }
+    }
+
+    Ok(())
+}
+
+fn erase_and_reinit(partition: &CStr, sec_cfg: Option<&mut nvs_sec_cfg_t>) -> Result<(), EspError> {
+    info!("Erasing NVS partition {:?}", partition);
+
+    esp!(unsafe { nvs_flash_deinit_partition(partition.as_ptr()) })?;
+    esp!(unsafe { nvs_flash_erase_partition(partition.as_ptr()) })?;
+
+    init_partition(partition, sec_cfg, false)
+}
+
+fn stats(partition: &CStr) -> Result<NvsStats, EspError> {
+    let mut stats: nvs_stats_t = Default::default();
+
+    esp!(unsafe { nvs_get_stats(partition.as_ptr(), &mut stats) })?;
+
+    Ok(stats.into())
+}
+
+fn entries(partition: &CStr, namespace: Option<&str>) -> Result<Vec<NvsEntry>, EspError> {
+    let c_namespace = namespace.map(|namespace| CString::new(namespace).unwrap());
+    let namespace_ptr = c_namespace
+        .as_ref()
+        .map_or(core::ptr::null(), |namespace| namespace.as_ptr());
+
+    let mut entries = Vec::new();
+
+    #[cfg(not(esp_idf_version_major = "5"))]
+    let mut iterator =
+        unsafe { nvs_entry_find(partition.as_ptr(), namespace_ptr, nvs_type_t_NVS_TYPE_ANY) };
+
+    #[cfg(esp_idf_version_major = "5")]
+    let mut iterator = {
+        let mut iterator: nvs_iterator_t = core::ptr::null_mut();
+
+        match unsafe {
+            nvs_entry_find(
+                partition.as_ptr(),
+                namespace_ptr,
+                nvs_type_t_NVS_TYPE_ANY,
+                &mut iterator,
+            )
+        } {
+            ESP_ERR_NVS_NOT_FOUND => (),
+            err => esp!(err)?,
+        }
+
+        iterator
+    };
+
+    // The iterator is released by ESP-IDF once exhausted
+    while !iterator.is_null() {
+        let mut info: nvs_entry_info_t = Default::default();
+
+        unsafe { nvs_entry_info(iterator, &mut info) };
+
+        entries.push(NvsEntry {
+            namespace: from_cstr_ptr(info.namespace_name.as_ptr()).into(),
+            key: from_cstr_ptr(info.key.as_ptr()).into(),
+            data_type: info.type_.into(),
+        });
+
+        #[cfg(not(esp_idf_version_major = "5"))]
+        {
+            iterator = unsafe { nvs_entry_next(iterator) };
+        }
+
+        #[cfg(esp_idf_version_major = "5")]
+        match unsafe { nvs_entry_next(&mut iterator) } {
+            ESP_ERR_NVS_NOT_FOUND => iterator = core::ptr::null_mut(),
+            err => {
+                if let Err(err) = esp!(err) {
+                    unsafe { nvs_release_iterator(iterator) };
+
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+fn namespaces(entries: Vec<NvsEntry>) -> Vec<String> {
+    let mut namespaces = entries
+        .into_iter()
+        .map(|entry| entry.namespace)
+        .collect::<Vec<_>>();
+
+    namespaces.sort();
+    namespaces.dedup();
+
+    namespaces
+}
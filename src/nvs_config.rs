@@ -0,0 +1,124 @@
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use ::log::*;
+
+use esp_idf_sys::*;
+
+use crate::nvs_storage::EspNvsStorage;
+
+type Migration<T> = Box<dyn Fn(&[u8]) -> Result<T, EspError>>;
+
+// Stores a value of type `T` together with its schema version. Values stored by an older firmware
+// are converted with the migration registered for their version, and then stored again with the
+// current version. Bump the version whenever `T` changes in a way that breaks decoding it
+pub struct ConfigStore<T> {
+    storage: EspNvsStorage,
+    key: String,
+    version: u16,
+    migrations: BTreeMap<u16, Migration<T>>,
+}
+
+impl<T> ConfigStore<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn new(storage: EspNvsStorage, key: impl AsRef<str>, version: u16) -> Self {
+        Self {
+            storage,
+            key: key.as_ref().into(),
+            version,
+            migrations: BTreeMap::new(),
+        }
+    }
+
+    // The migration gets the payload stored with `from_version`, which can be decoded with
+    // `decode` into the type used back then, and converts it to the current type
+    pub fn migration(
+        mut self,
+        from_version: u16,
+        migration: impl Fn(&[u8]) -> Result<T, EspError> + 'static,
+    ) -> Self {
+        self.migrations.insert(from_version, Box::new(migration));
+        self
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn load(&mut self) -> Result<Option<T>, EspError> {
+        let (version, payload) = match self.storage.get_struct::<(u16, Vec<u8>)>(&self.key)? {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+
+        if version == self.version {
+            return decode(&payload).map(Some);
+        }
+
+        let migration = if version < self.version {
+            self.migrations.get(&version)
+        } else {
+            None
+        };
+
+        let value = match migration {
+            Some(migration) => migration(&payload)?,
+            None => {
+                warn!(
+                    "Cannot migrate {} from version {} to version {}",
+                    self.key, version, self.version
+                );
+
+                return Err(EspError::from(ESP_ERR_NOT_SUPPORTED as i32).unwrap());
+            }
+        };
+
+        info!(
+            "Migrated {} from version {} to version {}",
+            self.key, version, self.version
+        );
+
+        self.save(&value)?;
+
+        Ok(Some(value))
+    }
+
+    pub fn load_or_default(&mut self) -> Result<T, EspError>
+    where
+        T: Default,
+    {
+        Ok(self.load()?.unwrap_or_default())
+    }
+
+    pub fn save(&mut self, value: &T) -> Result<(), EspError> {
+        let payload = postcard::to_allocvec(value).map_err(|err| {
+            warn!("Failed to encode the value of {}: {:?}", self.key, err);
+
+            EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap()
+        })?;
+
+        self.storage.set_struct(&self.key, &(self.version, payload))
+    }
+
+    pub fn into_storage(self) -> EspNvsStorage {
+        self.storage
+    }
+}
+
+pub fn decode<T>(payload: &[u8]) -> Result<T, EspError>
+where
+    T: DeserializeOwned,
+{
+    postcard::from_bytes(payload).map_err(|err| {
+        warn!("Failed to decode the configuration: {:?}", err);
+
+        EspError::from(ESP_ERR_NVS_TYPE_MISMATCH as i32).unwrap()
+    })
+}
@@ -1,18 +1,80 @@
-use core::{any::Any, ptr};
+use core::{any::Any, convert::TryInto, ptr};
 
 extern crate alloc;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 
+use embedded_svc::io::{Read, Write};
 use embedded_svc::storage::Storage;
 
+use esp_idf_hal::mutex;
+
 use esp_idf_sys::*;
 
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventLoop, EspEventLoopType};
 use crate::nvs::*;
 
 use crate::private::cstr::*;
 
-pub struct EspNvsStorage(Arc<dyn Any>, nvs_handle_t);
+const MAX_KEY_LEN: usize = NVS_KEY_NAME_MAX_SIZE as usize - 1;
+
+// Stays within a single NVS page, leaving room for the entry headers
+const MAX_CHUNK_LEN: usize = 4000;
+
+#[cfg(all(feature = "serde", feature = "postcard"))]
+const BLOB_INLINE: u8 = 0;
+const BLOB_CHUNKED: u8 = 1;
+
+// A lock for each key being updated with the `update_*` calls of any of the handles, so that e.g.
+// counters can be updated from several tasks without losing increments, while the updates of the
+// other keys do not wait for them
+#[allow(clippy::type_complexity)]
+static UPDATE_LOCKS: mutex::Mutex<vec::Vec<(String, String, Arc<mutex::Mutex<()>>)>> =
+    mutex::Mutex::new(vec::Vec::new());
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum NvsChange {
+    Set,
+    Removed,
+    // All the keys of the namespace were erased
+    Erased,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct NvsChangeEvent {
+    namespace: [u8; NVS_KEY_NAME_MAX_SIZE as usize],
+    key: [u8; NVS_KEY_NAME_MAX_SIZE as usize],
+    pub change: NvsChange,
+}
+
+impl NvsChangeEvent {
+    pub fn namespace(&self) -> &str {
+        from_name(&self.namespace)
+    }
+
+    // Empty for `NvsChange::Erased`
+    pub fn key(&self) -> &str {
+        from_name(&self.key)
+    }
+}
+
+#[cfg(feature = "experimental")]
+crate::esp_typed_event!(NvsChangeEvent);
+
+pub struct EspNvsStorage {
+    _nvs: Arc<dyn Any>,
+    handle: nvs_handle_t,
+    // Set while a transaction is being applied, so that it is committed only once
+    deferred: bool,
+    namespace: String,
+    on_change: Option<Box<dyn Fn(&NvsChangeEvent)>>,
+}
 
 impl EspNvsStorage {
     pub fn new_default(
@@ -35,7 +97,19 @@ impl EspNvsStorage {
             )
         })?;
 
-        Ok(Self(default_nvs, handle))
+        Ok(Self {
+            _nvs: default_nvs,
+            handle,
+            deferred: false,
+            namespace: namespace.as_ref().into(),
+            on_change: None,
+        })
+    }
+
+    // Opens a namespace of the shared default partition, see `EspDefaultNvs::get_or_init`.
+    // The handle is closed when dropped
+    pub fn open_default(namespace: impl AsRef<str>, read_write: bool) -> Result<Self, EspError> {
+        Self::new_default(EspDefaultNvs::get_or_init()?, namespace, read_write)
     }
 
     pub fn new(
@@ -59,14 +133,522 @@ impl EspNvsStorage {
             )
         })?;
 
-        Ok(Self(nvs, handle))
+        Ok(Self {
+            _nvs: nvs,
+            handle,
+            deferred: false,
+            namespace: namespace.as_ref().into(),
+            on_change: None,
+        })
+    }
+}
+
+macro_rules! nvs_primitive {
+    ($get:ident, $set:ident, $update:ident, $ty:ty, $nvs_get:ident, $nvs_set:ident) => {
+        pub fn $get(&self, key: impl AsRef<str>) -> Result<Option<$ty>, EspError> {
+            let c_key = CString::new(key.as_ref()).unwrap();
+
+            let mut value: $ty = 0;
+
+            match unsafe { $nvs_get(self.handle, c_key.as_ptr(), &mut value as *mut _) } {
+                ESP_ERR_NVS_NOT_FOUND => Ok(None),
+                err => {
+                    esp!(err)?;
+
+                    Ok(Some(value))
+                }
+            }
+        }
+
+        pub fn $set(&mut self, key: impl AsRef<str>, value: $ty) -> Result<(), EspError> {
+            let c_key = CString::new(key.as_ref()).unwrap();
+
+            esp!(unsafe { $nvs_set(self.handle, c_key.as_ptr(), value) })?;
+
+            self.commit()?;
+            self.changed(key.as_ref(), NvsChange::Set);
+
+            Ok(())
+        }
+
+        // Returns the new value
+        pub fn $update(
+            &mut self,
+            key: impl AsRef<str>,
+            f: impl FnOnce(Option<$ty>) -> $ty,
+        ) -> Result<$ty, EspError> {
+            let namespace = self.namespace.clone();
+
+            with_update_lock(&namespace, key.as_ref(), || {
+                let value = f(self.$get(key.as_ref())?);
+                self.$set(key.as_ref(), value)?;
+
+                Ok(value)
+            })
+        }
+    };
+}
+
+// Typed access to the native NVS entries, which - unlike the values of the `Storage` impl - can
+// also be read and written by C code using the same keys. Reading an entry with a different type
+// than the one it was written with returns `ESP_ERR_NVS_TYPE_MISMATCH`
+impl EspNvsStorage {
+    nvs_primitive!(get_u8, set_u8, update_u8, u8, nvs_get_u8, nvs_set_u8);
+    nvs_primitive!(get_i8, set_i8, update_i8, i8, nvs_get_i8, nvs_set_i8);
+    nvs_primitive!(get_u16, set_u16, update_u16, u16, nvs_get_u16, nvs_set_u16);
+    nvs_primitive!(get_i16, set_i16, update_i16, i16, nvs_get_i16, nvs_set_i16);
+    nvs_primitive!(get_u32, set_u32, update_u32, u32, nvs_get_u32, nvs_set_u32);
+    nvs_primitive!(get_i32, set_i32, update_i32, i32, nvs_get_i32, nvs_set_i32);
+    nvs_primitive!(get_u64, set_u64, update_u64, u64, nvs_get_u64, nvs_set_u64);
+    nvs_primitive!(get_i64, set_i64, update_i64, i64, nvs_get_i64, nvs_set_i64);
+
+    pub fn get_str(&self, key: impl AsRef<str>) -> Result<Option<String>, EspError> {
+        let c_key = CString::new(key.as_ref()).unwrap();
+
+        // The length includes the terminating NUL
+        let mut len: size_t = 0;
+        match unsafe {
+            nvs_get_str(
+                self.handle,
+                c_key.as_ptr(),
+                ptr::null_mut(),
+                &mut len as *mut _,
+            )
+        } {
+            ESP_ERR_NVS_NOT_FOUND => return Ok(None),
+            err => esp!(err)?,
+        }
+
+        let mut buf: vec::Vec<u8> = vec![0; len as usize];
+        esp!(unsafe {
+            nvs_get_str(
+                self.handle,
+                c_key.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                &mut len as *mut _,
+            )
+        })?;
+
+        Ok(Some(from_cstr(&buf).into_owned()))
+    }
+
+    pub fn set_str(&mut self, key: impl AsRef<str>, value: &str) -> Result<(), EspError> {
+        let c_key = CString::new(key.as_ref()).unwrap();
+        let c_value = CString::new(value).unwrap();
+
+        esp!(unsafe { nvs_set_str(self.handle, c_key.as_ptr(), c_value.as_ptr()) })?;
+
+        self.commit()?;
+        self.changed(key.as_ref(), NvsChange::Set);
+
+        Ok(())
+    }
+
+    pub fn update_str(
+        &mut self,
+        key: impl AsRef<str>,
+        f: impl FnOnce(Option<String>) -> String,
+    ) -> Result<String, EspError> {
+        let namespace = self.namespace.clone();
+
+        with_update_lock(&namespace, key.as_ref(), || {
+            let value = f(self.get_str(key.as_ref())?);
+            self.set_str(key.as_ref(), &value)?;
+
+            Ok(value)
+        })
+    }
+
+    pub fn get_blob(&self, key: impl AsRef<str>) -> Result<Option<vec::Vec<u8>>, EspError> {
+        let c_key = CString::new(key.as_ref()).unwrap();
+
+        let mut len: size_t = 0;
+        match unsafe {
+            nvs_get_blob(
+                self.handle,
+                c_key.as_ptr(),
+                ptr::null_mut(),
+                &mut len as *mut _,
+            )
+        } {
+            ESP_ERR_NVS_NOT_FOUND => return Ok(None),
+            err => esp!(err)?,
+        }
+
+        let mut buf: vec::Vec<u8> = vec![0; len as usize];
+        esp!(unsafe {
+            nvs_get_blob(
+                self.handle,
+                c_key.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                &mut len as *mut _,
+            )
+        })?;
+
+        buf.truncate(len as usize);
+
+        Ok(Some(buf))
+    }
+
+    pub fn update_blob(
+        &mut self,
+        key: impl AsRef<str>,
+        f: impl FnOnce(Option<vec::Vec<u8>>) -> vec::Vec<u8>,
+    ) -> Result<vec::Vec<u8>, EspError> {
+        let namespace = self.namespace.clone();
+
+        with_update_lock(&namespace, key.as_ref(), || {
+            let value = f(self.get_blob(key.as_ref())?);
+            self.set_blob(key.as_ref(), &value)?;
+
+            Ok(value)
+        })
+    }
+
+    // Returns the part of the buffer holding the blob, or `ESP_ERR_NVS_INVALID_LENGTH` if the
+    // buffer is too small
+    pub fn get_blob_into<'a>(
+        &self,
+        key: impl AsRef<str>,
+        buf: &'a mut [u8],
+    ) -> Result<Option<&'a [u8]>, EspError> {
+        let c_key = CString::new(key.as_ref()).unwrap();
+
+        let mut len: size_t = buf.len() as _;
+        match unsafe {
+            nvs_get_blob(
+                self.handle,
+                c_key.as_ptr(),
+                buf.as_mut_ptr() as *mut _,
+                &mut len as *mut _,
+            )
+        } {
+            ESP_ERR_NVS_NOT_FOUND => Ok(None),
+            err => {
+                esp!(err)?;
+
+                Ok(Some(&buf[..len as usize]))
+            }
+        }
+    }
+
+    pub fn set_blob(&mut self, key: impl AsRef<str>, value: &[u8]) -> Result<(), EspError> {
+        let key = key.as_ref();
+
+        // Replacing a chunked value, whose header has to go first so that the new value is not
+        // mistaken for its marker
+        let old = self.get_chunks(key)?;
+
+        if old.is_some() {
+            self.erase_raw(&header_key(key));
+        }
+
+        self.put_blob(key, value)?;
+
+        if let Some((_, generation)) = old {
+            self.erase_chunks(key, generation, 0);
+        }
+
+        self.commit()?;
+        self.changed(key, NvsChange::Set);
+
+        Ok(())
+    }
+
+    // Encoded with postcard; values larger than a single NVS entry are split across several
+    // entries, keyed `<key>#<n>` or `<key>$<n>` plus a header keyed `<key>#h`, so such keys
+    // should be at most 11 characters long. The previous value stays readable until the new one
+    // is completely written
+    #[cfg(all(feature = "serde", feature = "postcard"))]
+    pub fn set_struct<T>(&mut self, key: impl AsRef<str>, value: &T) -> Result<(), EspError>
+    where
+        T: serde::Serialize,
+    {
+        let key = key.as_ref();
+
+        let encoded = postcard::to_allocvec(value).map_err(|err| {
+            ::log::warn!("Failed to encode the value of {}: {:?}", key, err);
+
+            EspError::from(ESP_ERR_INVALID_ARG as i32).unwrap()
+        })?;
+
+        if encoded.len() < MAX_CHUNK_LEN {
+            let mut blob = vec::Vec::with_capacity(encoded.len() + 1);
+            blob.push(BLOB_INLINE);
+            blob.extend_from_slice(&encoded);
+
+            return self.set_blob(key, &blob);
+        }
+
+        let chunks = encoded.chunks(MAX_CHUNK_LEN).collect::<vec::Vec<_>>();
+
+        if chunks.len() > u8::MAX as usize || key.len() + 4 > MAX_KEY_LEN {
+            esp!(ESP_ERR_NVS_VALUE_TOO_LONG as i32)?;
+        }
+
+        let old = self.get_chunks(key)?;
+
+        // The chunks go to the keys of the other generation, so that the old header keeps
+        // pointing at complete data until the new one replaces it
+        let generation = next_generation(old);
+
+        for (index, chunk) in chunks.iter().enumerate() {
+            self.put_blob(&chunk_key(key, generation, index), chunk)?;
+        }
+
+        // Leftovers of a write interrupted before its header
+        self.erase_chunks(key, generation, chunks.len());
+
+        self.put_chunked(key, chunks.len(), generation, old)
+    }
+
+    #[cfg(all(feature = "serde", feature = "postcard"))]
+    pub fn get_struct<T>(&self, key: impl AsRef<str>) -> Result<Option<T>, EspError>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let key = key.as_ref();
+
+        let encoded = if let Some((chunks, generation)) = self.get_chunks(key)? {
+            let mut encoded = vec::Vec::new();
+
+            for index in 0..chunks {
+                let chunk = self
+                    .get_blob(chunk_key(key, generation, index))?
+                    .ok_or_else(|| EspError::from(ESP_ERR_NVS_NOT_FOUND as i32).unwrap())?;
+
+                encoded.extend_from_slice(&chunk);
+            }
+
+            encoded
+        } else {
+            let blob = match self.get_blob(key)? {
+                Some(blob) => blob,
+                None => return Ok(None),
+            };
+
+            match blob.split_first() {
+                Some((&BLOB_INLINE, encoded)) => encoded.to_vec(),
+                _ => return Err(EspError::from(ESP_ERR_NVS_TYPE_MISMATCH as i32).unwrap()),
+            }
+        };
+
+        postcard::from_bytes(&encoded).map(Some).map_err(|err| {
+            ::log::warn!("Failed to decode the value of {}: {:?}", key, err);
+
+            EspError::from(ESP_ERR_NVS_TYPE_MISMATCH as i32).unwrap()
+        })
+    }
+
+    // Stores the blob in chunks as it is being written, keyed like the chunks of `set_struct`,
+    // so such keys should be at most 11 characters long. The previous value stays readable until
+    // `EspNvsBlobWriter::finish` is called
+    pub fn blob_writer(&mut self, key: impl AsRef<str>) -> Result<EspNvsBlobWriter<'_>, EspError> {
+        let key = key.as_ref();
+
+        if key.len() + 4 > MAX_KEY_LEN {
+            esp!(ESP_ERR_NVS_KEY_TOO_LONG as i32)?;
+        }
+
+        let old = self.get_chunks(key)?;
+
+        Ok(EspNvsBlobWriter {
+            storage: self,
+            key: key.into(),
+            buf: vec::Vec::with_capacity(MAX_CHUNK_LEN),
+            chunks: 0,
+            generation: next_generation(old),
+            old,
+        })
+    }
+
+    // Reads blobs written with `blob_writer` one chunk at a time; plain blobs are read at once
+    pub fn blob_reader(
+        &self,
+        key: impl AsRef<str>,
+    ) -> Result<Option<EspNvsBlobReader<'_>>, EspError> {
+        let key = key.as_ref();
+
+        let (buf, (chunks, generation)) = match self.get_chunks(key)? {
+            Some(chunks) => (vec::Vec::new(), chunks),
+            None => match self.get_blob(key)? {
+                Some(blob) => (blob, (0, 0)),
+                None => return Ok(None),
+            },
+        };
+
+        Ok(Some(EspNvsBlobReader {
+            storage: self,
+            key: key.into(),
+            buf,
+            offset: 0,
+            chunk: 0,
+            chunks,
+            generation,
+        }))
+    }
+
+    // The number of chunks and their generation, if the key holds a chunked value
+    fn get_chunks(&self, key: &str) -> Result<Option<(usize, u8)>, EspError> {
+        let mut marker = [0_u8; 1];
+
+        let marker = match self.get_blob_into(key, &mut marker) {
+            Ok(Some(marker)) => marker,
+            Ok(None) => return Ok(None),
+            // A plain value
+            Err(err) if err.code() == ESP_ERR_NVS_INVALID_LENGTH as i32 => return Ok(None),
+            Err(err) if err.code() == ESP_ERR_NVS_TYPE_MISMATCH as i32 => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut header = [0_u8; 2];
+
+        let header = match self.get_blob_into(header_key(key), &mut header) {
+            Ok(header) => header,
+            Err(err) if err.code() == ESP_ERR_NVS_INVALID_LENGTH as i32 => None,
+            Err(err) if err.code() == ESP_ERR_NVS_TYPE_MISMATCH as i32 => None,
+            Err(err) => return Err(err),
+        };
+
+        Ok(chunked(marker, header))
+    }
+
+    // Switches the key over to the chunks of the generation, and erases the ones of the old value
+    fn put_chunked(
+        &mut self,
+        key: &str,
+        chunks: usize,
+        generation: u8,
+        old: Option<(usize, u8)>,
+    ) -> Result<(), EspError> {
+        self.put_blob(&header_key(key), &[chunks as u8, generation])?;
+        self.put_blob(key, &[BLOB_CHUNKED])?;
+
+        self.commit()?;
+        self.changed(key, NvsChange::Set);
+
+        if let Some((_, generation)) = old {
+            self.erase_chunks(key, generation, 0);
+            self.commit()?;
+        }
+
+        Ok(())
+    }
+
+    // Erases the header and the chunks of the value, if it is a chunked one
+    fn erase_chunked(&mut self, key: &str) -> Result<(), EspError> {
+        if let Some((_, generation)) = self.get_chunks(key)? {
+            self.erase_raw(&header_key(key));
+            self.erase_chunks(key, generation, 0);
+        }
+
+        Ok(())
+    }
+
+    fn erase_raw(&mut self, key: &str) -> bool {
+        let c_key = CString::new(key).unwrap();
+
+        unsafe { nvs_erase_key(self.handle, c_key.as_ptr()) == ESP_OK }
+    }
+
+    // Erases the chunks of the generation from `from` on, up to the first missing one
+    fn erase_chunks(&mut self, key: &str, generation: u8, from: usize) {
+        for index in from..=u8::MAX as usize {
+            if !self.erase_raw(&chunk_key(key, generation, index)) {
+                break;
+            }
+        }
+    }
+
+    // The number of entries used by the namespace; see also `EspNvs::stats` for the whole partition
+    pub fn used_entries(&self) -> Result<usize, EspError> {
+        let mut count: size_t = 0;
+
+        esp!(unsafe { nvs_get_used_entry_count(self.handle, &mut count as *mut _) })?;
+
+        Ok(count as _)
+    }
+
+    // Returns `false` if the key did not exist
+    pub fn erase_key(&mut self, key: impl AsRef<str>) -> Result<bool, EspError> {
+        self.remove(key)
+    }
+
+    // Erases all the keys of the namespace
+    pub fn erase_all(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { nvs_erase_all(self.handle) })?;
+
+        self.commit()?;
+        self.changed("", NvsChange::Erased);
+
+        Ok(())
+    }
+
+    // Buffers the writes until `EspNvsTransaction::commit` is called, so that several keys can be
+    // updated with a single commit, or the changes be discarded altogether
+    pub fn transaction(&mut self) -> EspNvsTransaction<'_> {
+        EspNvsTransaction {
+            storage: self,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    // Posts a `NvsChangeEvent` on the event loop after every change done via this handle. Changes
+    // done via other handles to the same namespace are not reported
+    #[cfg(feature = "experimental")]
+    pub fn enable_change_events<T>(&mut self, event_loop: &EspEventLoop<T>)
+    where
+        T: EspEventLoopType + 'static,
+    {
+        let event_loop = event_loop.clone();
+
+        self.on_change = Some(Box::new(move |event| {
+            if let Err(err) = event_loop.post_raw(&event.into(), None) {
+                ::log::warn!("Failed to post NVS change event: {}", err);
+            }
+        }));
+    }
+
+    pub fn disable_change_events(&mut self) {
+        self.on_change = None;
+    }
+
+    fn put_blob(&mut self, key: &str, value: &[u8]) -> Result<(), EspError> {
+        let c_key = CString::new(key).unwrap();
+
+        esp!(unsafe {
+            nvs_set_blob(
+                self.handle,
+                c_key.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len() as _,
+            )
+        })
+    }
+
+    fn commit(&mut self) -> Result<(), EspError> {
+        if self.deferred {
+            Ok(())
+        } else {
+            esp!(unsafe { nvs_commit(self.handle) })
+        }
+    }
+
+    fn changed(&self, key: &str, change: NvsChange) {
+        if let Some(on_change) = self.on_change.as_ref() {
+            on_change(&NvsChangeEvent {
+                namespace: to_name(&self.namespace),
+                key: to_name(key),
+                change,
+            });
+        }
     }
 }
 
 impl Drop for EspNvsStorage {
     fn drop(&mut self) {
         unsafe {
-            nvs_close(self.1);
+            nvs_close(self.handle);
         }
     }
 }
@@ -80,12 +662,17 @@ impl Storage for EspNvsStorage {
         let mut dummy: u_int64_t = 0;
 
         // check if key is present for u64 datatype
-        match unsafe { nvs_get_u64(self.1, c_key.as_ptr(), &mut dummy as *mut _) } {
+        match unsafe { nvs_get_u64(self.handle, c_key.as_ptr(), &mut dummy as *mut _) } {
             ESP_ERR_NVS_NOT_FOUND => {
                 // now check if key is present for blob datatype
                 let mut len: size_t = 0;
                 match unsafe {
-                    nvs_get_blob(self.1, c_key.as_ptr(), ptr::null_mut(), &mut len as *mut _)
+                    nvs_get_blob(
+                        self.handle,
+                        c_key.as_ptr(),
+                        ptr::null_mut(),
+                        &mut len as *mut _,
+                    )
                 } {
                     // not found as u64, nor as blob, this key has not been found
                     ESP_ERR_NVS_NOT_FOUND => Ok(false),
@@ -105,16 +692,19 @@ impl Storage for EspNvsStorage {
     }
 
     fn remove(&mut self, key: impl AsRef<str>) -> Result<bool, Self::Error> {
+        self.erase_chunked(key.as_ref())?;
+
         let c_key = CString::new(key.as_ref()).unwrap();
 
         // nvs_erase_key is not scoped by datatype
-        let result = unsafe { nvs_erase_key(self.1, c_key.as_ptr()) };
+        let result = unsafe { nvs_erase_key(self.handle, c_key.as_ptr()) };
 
         if result == ESP_ERR_NVS_NOT_FOUND as i32 {
             Ok(false)
         } else {
             esp!(result)?;
-            esp!(unsafe { nvs_commit(self.1) })?;
+            self.commit()?;
+            self.changed(key.as_ref(), NvsChange::Removed);
 
             Ok(true)
         }
@@ -126,12 +716,17 @@ impl Storage for EspNvsStorage {
         let mut value: u_int64_t = 0;
 
         // check for u64 value
-        match unsafe { nvs_get_u64(self.1, c_key.as_ptr(), &mut value as *mut _) } {
+        match unsafe { nvs_get_u64(self.handle, c_key.as_ptr(), &mut value as *mut _) } {
             ESP_ERR_NVS_NOT_FOUND => {
                 // check for blob value, by getting blob length
                 let mut len: size_t = 0;
                 match unsafe {
-                    nvs_get_blob(self.1, c_key.as_ptr(), ptr::null_mut(), &mut len as *mut _)
+                    nvs_get_blob(
+                        self.handle,
+                        c_key.as_ptr(),
+                        ptr::null_mut(),
+                        &mut len as *mut _,
+                    )
                 } {
                     ESP_ERR_NVS_NOT_FOUND => Ok(None),
                     err => {
@@ -142,7 +737,7 @@ impl Storage for EspNvsStorage {
                         let mut vec: vec::Vec<u8> = vec::Vec::with_capacity(len as usize);
                         esp!(unsafe {
                             nvs_get_blob(
-                                self.1,
+                                self.handle,
                                 c_key.as_ptr(),
                                 vec.as_mut_ptr() as *mut _,
                                 &mut len as *mut _,
@@ -186,8 +781,10 @@ impl Storage for EspNvsStorage {
         let mut value = value.into();
         let mut uvalue: u_int64_t = 0;
 
+        self.erase_chunked(key.as_ref())?;
+
         // start by just clearing this key
-        unsafe { nvs_erase_key(self.1, c_key.as_ptr()) };
+        unsafe { nvs_erase_key(self.handle, c_key.as_ptr()) };
 
         if value.len() < 8 {
             for v in value.iter().rev() {
@@ -198,11 +795,11 @@ impl Storage for EspNvsStorage {
             uvalue <<= 8;
             uvalue |= value.len() as u_int64_t;
 
-            esp!(unsafe { nvs_set_u64(self.1, c_key.as_ptr(), uvalue) })?;
+            esp!(unsafe { nvs_set_u64(self.handle, c_key.as_ptr(), uvalue) })?;
         } else {
             esp!(unsafe {
                 nvs_set_blob(
-                    self.1,
+                    self.handle,
                     c_key.as_ptr(),
                     value.as_mut_ptr() as *mut _,
                     value.len() as u32,
@@ -210,8 +807,463 @@ impl Storage for EspNvsStorage {
             })?;
         }
 
-        esp!(unsafe { nvs_commit(self.1) })?;
+        self.commit()?;
+        self.changed(key.as_ref(), NvsChange::Set);
+
+        Ok(true)
+    }
+}
+
+// A `Storage` impl which stores values of 1, 2, 4 and 8 bytes as native little-endian integers,
+// UTF-8 values as strings and only the rest as blobs, so that C code can read the same keys with
+// `nvs_get_u32`, `nvs_get_str` etc. Not compatible with the values stored by `EspNvsStorage`
+pub struct EspNvsTypedStorage(EspNvsStorage);
+
+impl EspNvsTypedStorage {
+    pub fn new_default(
+        default_nvs: Arc<EspDefaultNvs>,
+        namespace: impl AsRef<str>,
+        read_write: bool,
+    ) -> Result<Self, EspError> {
+        Ok(Self(EspNvsStorage::new_default(
+            default_nvs,
+            namespace,
+            read_write,
+        )?))
+    }
+
+    pub fn new(
+        nvs: Arc<EspNvs>,
+        namespace: impl AsRef<str>,
+        read_write: bool,
+    ) -> Result<Self, EspError> {
+        Ok(Self(EspNvsStorage::new(nvs, namespace, read_write)?))
+    }
+
+    pub fn storage(&self) -> &EspNvsStorage {
+        &self.0
+    }
+
+    pub fn storage_mut(&mut self) -> &mut EspNvsStorage {
+        &mut self.0
+    }
+}
+
+impl From<EspNvsStorage> for EspNvsTypedStorage {
+    fn from(storage: EspNvsStorage) -> Self {
+        Self(storage)
+    }
+}
+
+impl Storage for EspNvsTypedStorage {
+    type Error = EspError;
+
+    fn contains(&self, key: impl AsRef<str>) -> Result<bool, Self::Error> {
+        Ok(self.get_raw(key)?.is_some())
+    }
+
+    fn remove(&mut self, key: impl AsRef<str>) -> Result<bool, Self::Error> {
+        self.0.remove(key)
+    }
+
+    fn get_raw(&self, key: impl AsRef<str>) -> Result<Option<vec::Vec<u8>>, Self::Error> {
+        let key = key.as_ref();
+
+        // NVS lookups are typed, so each type has to be tried in turn
+        if let Some(value) = self.0.get_u8(key)? {
+            Ok(Some(value.to_le_bytes().to_vec()))
+        } else if let Some(value) = self.0.get_u16(key)? {
+            Ok(Some(value.to_le_bytes().to_vec()))
+        } else if let Some(value) = self.0.get_u32(key)? {
+            Ok(Some(value.to_le_bytes().to_vec()))
+        } else if let Some(value) = self.0.get_u64(key)? {
+            Ok(Some(value.to_le_bytes().to_vec()))
+        } else if let Some(value) = self.0.get_str(key)? {
+            Ok(Some(value.into_bytes()))
+        } else {
+            self.0.get_blob(key)
+        }
+    }
+
+    fn put_raw(
+        &mut self,
+        key: impl AsRef<str>,
+        value: impl Into<vec::Vec<u8>>,
+    ) -> Result<bool, Self::Error> {
+        let key = key.as_ref();
+        let value = value.into();
+
+        // Entries of different types may share a key, so the old one has to go first
+        let c_key = CString::new(key).unwrap();
+        unsafe { nvs_erase_key(self.0.handle, c_key.as_ptr()) };
+
+        match value.len() {
+            1 => self.0.set_u8(key, value[0])?,
+            2 => self
+                .0
+                .set_u16(key, u16::from_le_bytes(value[..].try_into().unwrap()))?,
+            4 => self
+                .0
+                .set_u32(key, u32::from_le_bytes(value[..].try_into().unwrap()))?,
+            8 => self
+                .0
+                .set_u64(key, u64::from_le_bytes(value[..].try_into().unwrap()))?,
+            len if len < MAX_CHUNK_LEN && !value.contains(&0) => {
+                match core::str::from_utf8(&value) {
+                    Ok(value) => self.0.set_str(key, value)?,
+                    Err(_) => self.0.set_blob(key, &value)?,
+                }
+            }
+            _ => self.0.set_blob(key, &value)?,
+        }
 
         Ok(true)
     }
 }
+
+#[derive(Clone, Debug)]
+enum Pending {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    Str(String),
+    Blob(vec::Vec<u8>),
+    // A value of the `Storage` impl
+    Raw(vec::Vec<u8>),
+    Removed,
+}
+
+macro_rules! nvs_pending {
+    ($set:ident, $ty:ty, $variant:ident) => {
+        pub fn $set(&mut self, key: impl AsRef<str>, value: $ty) {
+            self.pending
+                .insert(key.as_ref().into(), Pending::$variant(value));
+        }
+    };
+}
+
+// Nothing is written until `commit` is called; dropping the transaction discards the changes.
+// NVS has no atomic multi-key writes though: `commit` applies the changes one key after the other,
+// so readers of the namespace might see some of them before it returns, and a failure or a reset
+// midway leaves only a part of them applied
+pub struct EspNvsTransaction<'a> {
+    storage: &'a mut EspNvsStorage,
+    pending: BTreeMap<String, Pending>,
+}
+
+impl<'a> EspNvsTransaction<'a> {
+    nvs_pending!(set_u8, u8, U8);
+    nvs_pending!(set_i8, i8, I8);
+    nvs_pending!(set_u16, u16, U16);
+    nvs_pending!(set_i16, i16, I16);
+    nvs_pending!(set_u32, u32, U32);
+    nvs_pending!(set_i32, i32, I32);
+    nvs_pending!(set_u64, u64, U64);
+    nvs_pending!(set_i64, i64, I64);
+
+    pub fn set_str(&mut self, key: impl AsRef<str>, value: &str) {
+        self.pending
+            .insert(key.as_ref().into(), Pending::Str(value.into()));
+    }
+
+    pub fn set_blob(&mut self, key: impl AsRef<str>, value: &[u8]) {
+        self.pending
+            .insert(key.as_ref().into(), Pending::Blob(value.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn commit(self) -> Result<(), EspError> {
+        let storage = self.storage;
+
+        storage.deferred = true;
+
+        let result = self
+            .pending
+            .into_iter()
+            .try_for_each(|(key, value)| match value {
+                Pending::U8(value) => storage.set_u8(key, value),
+                Pending::I8(value) => storage.set_i8(key, value),
+                Pending::U16(value) => storage.set_u16(key, value),
+                Pending::I16(value) => storage.set_i16(key, value),
+                Pending::U32(value) => storage.set_u32(key, value),
+                Pending::I32(value) => storage.set_i32(key, value),
+                Pending::U64(value) => storage.set_u64(key, value),
+                Pending::I64(value) => storage.set_i64(key, value),
+                Pending::Str(value) => storage.set_str(key, &value),
+                Pending::Blob(value) => storage.set_blob(key, &value),
+                Pending::Raw(value) => storage.put_raw(key, value).map(|_| ()),
+                Pending::Removed => storage.remove(key).map(|_| ()),
+            });
+
+        storage.deferred = false;
+
+        // Whatever got written is committed, even if applying the rest failed
+        let commit = storage.commit();
+
+        result.and(commit)
+    }
+
+    pub fn discard(self) {}
+}
+
+impl<'a> Storage for EspNvsTransaction<'a> {
+    type Error = EspError;
+
+    fn contains(&self, key: impl AsRef<str>) -> Result<bool, Self::Error> {
+        match self.pending.get(key.as_ref()) {
+            Some(Pending::Removed) => Ok(false),
+            Some(_) => Ok(true),
+            None => self.storage.contains(key),
+        }
+    }
+
+    fn remove(&mut self, key: impl AsRef<str>) -> Result<bool, Self::Error> {
+        let existed = self.contains(key.as_ref())?;
+
+        self.pending.insert(key.as_ref().into(), Pending::Removed);
+
+        Ok(existed)
+    }
+
+    fn get_raw(&self, key: impl AsRef<str>) -> Result<Option<vec::Vec<u8>>, Self::Error> {
+        match self.pending.get(key.as_ref()) {
+            Some(Pending::Raw(value)) => Ok(Some(value.clone())),
+            Some(Pending::Removed) => Ok(None),
+            Some(_) => Err(EspError::from(ESP_ERR_NVS_TYPE_MISMATCH as i32).unwrap()),
+            None => self.storage.get_raw(key),
+        }
+    }
+
+    fn put_raw(
+        &mut self,
+        key: impl AsRef<str>,
+        value: impl Into<vec::Vec<u8>>,
+    ) -> Result<bool, Self::Error> {
+        self.pending
+            .insert(key.as_ref().into(), Pending::Raw(value.into()));
+
+        Ok(true)
+    }
+}
+
+pub struct EspNvsBlobWriter<'a> {
+    storage: &'a mut EspNvsStorage,
+    key: String,
+    buf: vec::Vec<u8>,
+    chunks: usize,
+    generation: u8,
+    old: Option<(usize, u8)>,
+}
+
+impl<'a> EspNvsBlobWriter<'a> {
+    pub fn finish(mut self) -> Result<(), EspError> {
+        if !self.buf.is_empty() {
+            self.write_chunk()?;
+        }
+
+        self.storage
+            .erase_chunks(&self.key, self.generation, self.chunks);
+
+        self.storage
+            .put_chunked(&self.key, self.chunks, self.generation, self.old)
+    }
+
+    fn write_chunk(&mut self) -> Result<(), EspError> {
+        if self.chunks == u8::MAX as usize {
+            esp!(ESP_ERR_NVS_VALUE_TOO_LONG as i32)?;
+        }
+
+        self.storage.put_blob(
+            &chunk_key(&self.key, self.generation, self.chunks),
+            &self.buf,
+        )?;
+
+        self.buf.clear();
+        self.chunks += 1;
+
+        Ok(())
+    }
+}
+
+impl<'a> Write for EspNvsBlobWriter<'a> {
+    type Error = EspError;
+
+    fn do_write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = usize::min(buf.len(), MAX_CHUNK_LEN - self.buf.len());
+
+        self.buf.extend_from_slice(&buf[..len]);
+
+        if self.buf.len() == MAX_CHUNK_LEN {
+            self.write_chunk()?;
+        }
+
+        Ok(len)
+    }
+}
+
+pub struct EspNvsBlobReader<'a> {
+    storage: &'a EspNvsStorage,
+    key: String,
+    buf: vec::Vec<u8>,
+    offset: usize,
+    chunk: usize,
+    chunks: usize,
+    generation: u8,
+}
+
+impl<'a> Read for EspNvsBlobReader<'a> {
+    type Error = EspError;
+
+    fn do_read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if self.offset == self.buf.len() && self.chunk < self.chunks {
+            self.buf.resize(MAX_CHUNK_LEN, 0);
+
+            let len = self
+                .storage
+                .get_blob_into(
+                    chunk_key(&self.key, self.generation, self.chunk),
+                    &mut self.buf,
+                )?
+                .ok_or_else(|| EspError::from(ESP_ERR_NVS_NOT_FOUND as i32).unwrap())?
+                .len();
+
+            self.buf.truncate(len);
+            self.offset = 0;
+            self.chunk += 1;
+        }
+
+        let len = usize::min(buf.len(), self.buf.len() - self.offset);
+
+        buf[..len].copy_from_slice(&self.buf[self.offset..self.offset + len]);
+        self.offset += len;
+
+        Ok(len)
+    }
+}
+
+fn with_update_lock<T>(namespace: &str, key: &str, f: impl FnOnce() -> T) -> T {
+    let lock = {
+        let mut locks = UPDATE_LOCKS.lock();
+
+        match locks.iter().find(|(other_namespace, other_key, _)| {
+            other_namespace == namespace && other_key == key
+        }) {
+            Some((_, _, lock)) => lock.clone(),
+            None => {
+                let lock = Arc::new(mutex::Mutex::new(()));
+                locks.push((namespace.into(), key.into(), lock.clone()));
+
+                lock
+            }
+        }
+    };
+
+    let result = {
+        let _guard = lock.lock();
+
+        f()
+    };
+
+    let mut locks = UPDATE_LOCKS.lock();
+
+    // Nobody else is waiting for the key
+    if Arc::strong_count(&lock) == 2 {
+        locks.retain(|(_, _, other)| !Arc::ptr_eq(other, &lock));
+    }
+
+    result
+}
+
+fn to_name(name: &str) -> [u8; NVS_KEY_NAME_MAX_SIZE as usize] {
+    let mut array = [0; NVS_KEY_NAME_MAX_SIZE as usize];
+
+    let len = usize::min(name.len(), MAX_KEY_LEN);
+    array[..len].copy_from_slice(&name.as_bytes()[..len]);
+
+    array
+}
+
+fn from_name(name: &[u8]) -> &str {
+    let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+
+    core::str::from_utf8(&name[..len]).unwrap_or_default()
+}
+
+// A chunked value is marked with a single `BLOB_CHUNKED` byte under its key, and its header, the
+// number of its chunks and the generation of their keys, which alternates with each write, is
+// kept under a key of its own. Without the header, the marker is just a plain 1-byte blob
+fn chunked(marker: &[u8], header: Option<&[u8]>) -> Option<(usize, u8)> {
+    match (marker, header?) {
+        ([BLOB_CHUNKED], &[chunks, generation]) if generation < 2 => {
+            Some((chunks as usize, generation))
+        }
+        _ => None,
+    }
+}
+
+fn header_key(key: &str) -> String {
+    alloc::format!("{}#h", key)
+}
+
+fn next_generation(old: Option<(usize, u8)>) -> u8 {
+    old.map_or(0, |(_, generation)| generation ^ 1)
+}
+
+fn chunk_key(key: &str, generation: u8, index: usize) -> String {
+    if generation == 0 {
+        alloc::format!("{}#{}", key, index)
+    } else {
+        alloc::format!("{}${}", key, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_headers() {
+        assert_eq!(chunked(&[BLOB_CHUNKED], Some(&[3, 0])), Some((3, 0)));
+        assert_eq!(chunked(&[BLOB_CHUNKED], Some(&[3, 1])), Some((3, 1)));
+        assert_eq!(chunked(&[BLOB_CHUNKED], Some(&[3, 2])), None);
+        assert_eq!(chunked(&[BLOB_CHUNKED], Some(&[3])), None);
+        assert_eq!(chunked(&[BLOB_CHUNKED], Some(&[3, 1, 0])), None);
+    }
+
+    #[test]
+    fn short_plain_blobs() {
+        // Shaped like the headers kept with the values before
+        assert_eq!(chunked(&[BLOB_CHUNKED, 5], None), None);
+        assert_eq!(chunked(&[BLOB_CHUNKED, 5, 1], None), None);
+        assert_eq!(chunked(&[BLOB_CHUNKED, 5], Some(&[5, 1])), None);
+
+        assert_eq!(chunked(&[BLOB_CHUNKED], None), None);
+        assert_eq!(chunked(&[0], Some(&[3, 1])), None);
+        assert_eq!(chunked(&[], Some(&[3, 1])), None);
+    }
+
+    #[test]
+    fn generations_alternate() {
+        assert_eq!(next_generation(None), 0);
+        assert_eq!(next_generation(Some((2, 0))), 1);
+        assert_eq!(next_generation(Some((2, 1))), 0);
+    }
+
+    #[test]
+    fn chunk_keys() {
+        assert_eq!(chunk_key("config", 0, 0), "config#0");
+        assert_eq!(chunk_key("config", 1, 12), "config$12");
+        assert!(chunk_key("abcdefghijk", 1, 255).len() <= MAX_KEY_LEN);
+
+        assert_eq!(header_key("config"), "config#h");
+        assert!(header_key("abcdefghijk").len() <= MAX_KEY_LEN);
+    }
+}
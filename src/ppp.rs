@@ -0,0 +1,692 @@
+use core::convert::TryInto;
+use core::ptr;
+use core::time::Duration;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+
+use ::log::*;
+
+use embedded_svc::ipv4;
+use embedded_svc::wifi::{ClientConnectionStatus, ClientIpStatus};
+
+use esp_idf_hal::gpio;
+use esp_idf_hal::mutex;
+use esp_idf_hal::serial;
+
+use esp_idf_sys::*;
+
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventFetchData, EspEventSubscribeMetadata};
+use crate::netif::*;
+use crate::sysloop::*;
+
+use crate::private::common::*;
+use crate::private::cstr::*;
+use crate::private::waitable::*;
+
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 1024;
+
+// How often the receiving thread checks whether it should exit
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
+
+pub struct PppPeripherals<UART, TX, RX> {
+    pub uart: UART,
+    pub tx: TX,
+    pub rx: RX,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PppAuth {
+    None,
+    Pap { username: String, password: String },
+    Chap { username: String, password: String },
+}
+
+impl Default for PppAuth {
+    fn default() -> Self {
+        PppAuth::None
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PppConfiguration {
+    pub baudrate: u32,
+    pub auth: PppAuth,
+}
+
+impl Default for PppConfiguration {
+    fn default() -> Self {
+        Self {
+            baudrate: 115200,
+            auth: Default::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PppEvent {
+    // The link is up; the IP is reported with `IpEvent::PppGotIp`
+    Connected,
+    AuthFailed,
+    // The link was closed, either by the peer or with `EspPpp::disconnect`
+    Disconnected,
+    Error(u32),
+    // One of the `NETIF_PPP_PHASE_*` codes, relative to `NETIF_PP_PHASE_OFFSET`
+    Phase(u32),
+}
+
+impl From<u32> for PppEvent {
+    #[allow(non_upper_case_globals)]
+    fn from(event_id: u32) -> Self {
+        match event_id {
+            esp_netif_ppp_status_event_t_NETIF_PPP_ERRORNONE => PppEvent::Connected,
+            esp_netif_ppp_status_event_t_NETIF_PPP_ERRORAUTHFAIL => PppEvent::AuthFailed,
+            esp_netif_ppp_status_event_t_NETIF_PPP_ERRORUSER
+            | esp_netif_ppp_status_event_t_NETIF_PPP_ERRORCONNECT
+            | esp_netif_ppp_status_event_t_NETIF_PPP_ERRORPEERDEAD => PppEvent::Disconnected,
+            other if other >= NETIF_PP_PHASE_OFFSET => {
+                PppEvent::Phase(other - NETIF_PP_PHASE_OFFSET)
+            }
+            other => PppEvent::Error(other),
+        }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for PppEvent {
+    fn source() -> *const c_types::c_char {
+        unsafe { NETIF_PPP_STATUS }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for PppEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        (data.event_id as u32).into()
+    }
+}
+
+struct PppDriver {
+    port: uart_port_t,
+}
+
+impl NetifDriver for PppDriver {
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        if unsafe { uart_write_bytes(self.port, frame.as_ptr() as *const _, frame.len() as _) } < 0
+        {
+            esp!(ESP_FAIL)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Shared {
+    status: ClientConnectionStatus,
+    last_event: Option<PppEvent>,
+    netif: *mut esp_netif_t,
+}
+
+unsafe impl Send for Shared {}
+
+// The receiving thread only touches the netif and the UART, both of which outlive it
+struct RxContext {
+    netif: *mut esp_netif_t,
+    port: uart_port_t,
+    running: Arc<AtomicBool>,
+}
+
+unsafe impl Send for RxContext {}
+
+pub struct EspPpp<UART, TX, RX>
+where
+    UART: serial::Uart,
+    TX: gpio::OutputPin,
+    RX: gpio::InputPin,
+{
+    _netif_stack: Arc<EspNetifStack>,
+    _sys_loop_stack: Arc<EspSysLoopStack>,
+
+    _peripherals: PppPeripherals<UART, TX, RX>,
+
+    port: uart_port_t,
+    glue: EspNetifGlue<PppDriver>,
+
+    rx_running: Arc<AtomicBool>,
+    rx_thread: Option<thread::JoinHandle<()>>,
+
+    shared: Box<Waitable<Shared>>,
+}
+
+impl<UART, TX, RX> EspPpp<UART, TX, RX>
+where
+    UART: serial::Uart,
+    TX: gpio::OutputPin,
+    RX: gpio::InputPin,
+{
+    pub fn new(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        peripherals: PppPeripherals<UART, TX, RX>,
+        conf: &PppConfiguration,
+    ) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let ppp = Self::init(netif_stack, sys_loop_stack, peripherals, conf)?;
+
+        *taken = true;
+        Ok(ppp)
+    }
+
+    fn init(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        peripherals: PppPeripherals<UART, TX, RX>,
+        conf: &PppConfiguration,
+    ) -> Result<Self, EspError> {
+        let port = UART::port();
+
+        let uart_config = uart_config_t {
+            baud_rate: conf.baudrate as _,
+            data_bits: uart_word_length_t_UART_DATA_8_BITS,
+            parity: uart_parity_t_UART_PARITY_DISABLE,
+            stop_bits: uart_stop_bits_t_UART_STOP_BITS_1,
+            flow_ctrl: uart_hw_flowcontrol_t_UART_HW_FLOWCTRL_DISABLE,
+            ..Default::default()
+        };
+
+        unsafe {
+            esp!(uart_param_config(port, &uart_config))?;
+            esp!(uart_set_pin(
+                port,
+                peripherals.tx.pin(),
+                peripherals.rx.pin(),
+                UART_PIN_NO_CHANGE,
+                UART_PIN_NO_CHANGE
+            ))?;
+            esp!(uart_driver_install(
+                port,
+                RX_BUFFER_SIZE as _,
+                TX_BUFFER_SIZE as _,
+                0,
+                ptr::null_mut(),
+                0
+            ))?;
+        }
+
+        info!("UART {} initialized", port);
+
+        Self::init_netif(netif_stack, sys_loop_stack, peripherals, conf, port).map_err(|err| {
+            // Nothing else uses the UART driver yet
+            if let Err(err) = esp!(unsafe { uart_driver_delete(port) }) {
+                warn!("Failed to delete the UART driver: {}", err);
+            }
+
+            err
+        })
+    }
+
+    fn init_netif(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        peripherals: PppPeripherals<UART, TX, RX>,
+        conf: &PppConfiguration,
+        port: uart_port_t,
+    ) -> Result<Self, EspError> {
+        let glue = EspNetifGlue::new(
+            netif_stack.clone(),
+            &InterfaceConfiguration::ppp_default_client(),
+            PppDriver { port },
+        )?;
+
+        set_auth(glue.netif().1, &conf.auth)?;
+
+        let ppp_config = esp_netif_ppp_config_t {
+            ppp_phase_event_enabled: true,
+            ppp_error_event_enabled: true,
+            ..Default::default()
+        };
+
+        esp!(unsafe { esp_netif_ppp_set_params(glue.netif().1, &ppp_config) })?;
+
+        let mut shared = Box::new(Waitable::new(Shared {
+            status: ClientConnectionStatus::Disconnected,
+            last_event: None,
+            netif: glue.netif().1,
+        }));
+
+        let shared_ref: *mut _ = &mut *shared;
+
+        unsafe {
+            esp!(esp_event_handler_register(
+                NETIF_PPP_STATUS,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                shared_ref as *mut c_types::c_void
+            ))?;
+
+            if let Err(err) = esp!(esp_event_handler_register(
+                IP_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                shared_ref as *mut c_types::c_void
+            )) {
+                esp_event_handler_unregister(
+                    NETIF_PPP_STATUS,
+                    ESP_EVENT_ANY_ID,
+                    Option::Some(Self::event_handler),
+                );
+
+                return Err(err);
+            }
+        }
+
+        info!("Event handlers registered");
+
+        // Constructed last, as from here on, the cleanup is left to `Drop`
+        let ppp = Self {
+            _netif_stack: netif_stack,
+            _sys_loop_stack: sys_loop_stack,
+            _peripherals: peripherals,
+            port,
+            glue,
+            rx_running: Arc::new(AtomicBool::new(false)),
+            rx_thread: None,
+            shared,
+        };
+
+        info!("Initialization complete");
+
+        Ok(ppp)
+    }
+
+    pub fn set_auth(&mut self, auth: &PppAuth) -> Result<(), EspError> {
+        set_auth(self.glue.netif().1, auth)
+    }
+
+    // Sends an AT command - e.g. `ATD*99#` to switch the modem to data mode - and collects the
+    // response until `OK`, `CONNECT` or an error is received. Only usable while disconnected
+    pub fn at_command(&mut self, command: &str, timeout: Duration) -> Result<String, EspError> {
+        if self.rx_thread.is_some() {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let port = self.port;
+
+        info!("Sending AT command: {}", command);
+
+        unsafe {
+            esp!(uart_flush_input(port))?;
+
+            for data in &[command.as_bytes(), b"\r"] {
+                if uart_write_bytes(port, data.as_ptr() as *const _, data.len() as _) < 0 {
+                    esp!(ESP_FAIL)?;
+                }
+            }
+        }
+
+        let mut response = vec::Vec::new();
+        let mut buf = [0_u8; 64];
+
+        let mut remaining = timeout;
+
+        loop {
+            let wait = core::cmp::min(remaining, RX_POLL_INTERVAL);
+
+            let len = unsafe {
+                uart_read_bytes(
+                    port,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as _,
+                    ms_to_ticks(wait),
+                )
+            };
+
+            if len < 0 {
+                esp!(ESP_FAIL)?;
+            }
+
+            response.extend_from_slice(&buf[..len as usize]);
+
+            let text = String::from_utf8_lossy(&response);
+            if text.contains("OK\r") || text.contains("CONNECT") {
+                break;
+            } else if text.contains("ERROR") || text.contains("NO CARRIER") {
+                info!("AT command failed: {}", text.trim());
+
+                esp!(ESP_FAIL)?;
+            }
+
+            remaining = remaining.checked_sub(wait).unwrap_or_default();
+
+            if remaining == Duration::from_millis(0) {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
+        }
+
+        let response = String::from_utf8_lossy(&response).trim().into();
+
+        info!("AT command response: {}", response);
+
+        Ok(response)
+    }
+
+    // Starts the PPP negotiation over a modem already switched to data mode
+    pub fn connect(&mut self, timeout: Duration) -> Result<ClientConnectionStatus, EspError> {
+        info!("Connecting");
+
+        self.start_rx()?;
+
+        self.shared.modify(|shared| {
+            shared.status = ClientConnectionStatus::Connecting;
+            shared.last_event = None;
+
+            (false, ())
+        });
+
+        self.glue.start();
+        self.glue.connected();
+
+        let (timed_out, status) = self.shared.wait_timeout_while_and_get(
+            timeout,
+            |shared| {
+                matches!(
+                    shared.status,
+                    ClientConnectionStatus::Connecting
+                        | ClientConnectionStatus::Connected(ClientIpStatus::Waiting)
+                )
+            },
+            |shared| shared.status.clone(),
+        );
+
+        if timed_out {
+            info!("Timeout while connecting");
+
+            esp!(ESP_ERR_TIMEOUT as i32)?;
+        }
+
+        info!("Connection attempt finished: {:?}", status);
+
+        Ok(status)
+    }
+
+    pub fn disconnect(&mut self) -> Result<(), EspError> {
+        info!("Disconnecting");
+
+        self.glue.disconnected();
+        self.glue.stop();
+
+        self.stop_rx();
+
+        self.shared.modify(|shared| {
+            shared.status = ClientConnectionStatus::Disconnected;
+
+            (true, ())
+        });
+
+        info!("Disconnected");
+
+        Ok(())
+    }
+
+    pub fn get_status(&self) -> ClientConnectionStatus {
+        self.shared.get(|shared| shared.status.clone())
+    }
+
+    pub fn get_last_event(&self) -> Option<PppEvent> {
+        self.shared.get(|shared| shared.last_event)
+    }
+
+    pub fn with_netif<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&EspNetif) -> T,
+    {
+        f(self.glue.netif())
+    }
+
+    fn start_rx(&mut self) -> Result<(), EspError> {
+        if self.rx_thread.is_some() {
+            return Ok(());
+        }
+
+        self.rx_running.store(true, Ordering::SeqCst);
+
+        let context = RxContext {
+            netif: self.glue.netif().1,
+            port: self.port,
+            running: self.rx_running.clone(),
+        };
+
+        let handle = thread::Builder::new()
+            .name("ppp_rx".into())
+            .stack_size(4096)
+            .spawn(move || Self::receive(context))
+            .map_err(|_| EspError::from(ESP_ERR_NO_MEM as i32).unwrap())?;
+
+        self.rx_thread = Some(handle);
+
+        Ok(())
+    }
+
+    fn stop_rx(&mut self) {
+        self.rx_running.store(false, Ordering::SeqCst);
+
+        if let Some(handle) = self.rx_thread.take() {
+            // Also called from `Drop`, so a panicked thread must not panic again
+            if handle.join().is_err() {
+                error!("The RX thread panicked");
+            }
+        }
+    }
+
+    fn receive(context: RxContext) {
+        let mut buf = [0_u8; RX_BUFFER_SIZE];
+
+        while context.running.load(Ordering::SeqCst) {
+            let len = unsafe {
+                uart_read_bytes(
+                    context.port,
+                    buf.as_mut_ptr() as *mut _,
+                    buf.len() as _,
+                    ms_to_ticks(RX_POLL_INTERVAL),
+                )
+            };
+
+            if len > 0 {
+                let err = unsafe {
+                    esp_netif_receive(
+                        context.netif,
+                        buf.as_mut_ptr() as *mut _,
+                        len as _,
+                        ptr::null_mut(),
+                    )
+                };
+
+                if err != ESP_OK as i32 {
+                    warn!(
+                        "Failed to pass {} received bytes to the netif: {}",
+                        len, err
+                    );
+                }
+            }
+        }
+    }
+
+    // Every step is attempted even if an earlier one failed, returning the first error
+    fn clear_all(&mut self) -> Result<(), EspError> {
+        let disconnected = self.disconnect();
+
+        let unregistered = unsafe {
+            esp!(esp_event_handler_unregister(
+                NETIF_PPP_STATUS,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler)
+            ))
+            .and(esp!(esp_event_handler_unregister(
+                IP_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler)
+            )))
+        };
+
+        info!("Event handlers deregistered");
+
+        let deleted = esp!(unsafe { uart_driver_delete(self.port) });
+
+        info!("UART driver deleted");
+
+        info!("Deinitialization complete");
+
+        disconnected.and(unregistered).and(deleted)
+    }
+
+    unsafe extern "C" fn event_handler(
+        arg: *mut c_types::c_void,
+        event_base: esp_event_base_t,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) {
+        let shared_ref = (arg as *mut Waitable<Shared>).as_mut().unwrap();
+
+        shared_ref.modify(|shared| {
+            let notify = if event_base == NETIF_PPP_STATUS {
+                Self::on_ppp_event(shared, event_id as u32)
+            } else if event_base == IP_EVENT {
+                Self::on_ip_event(shared, event_id as u32, event_data)
+            } else {
+                false
+            };
+
+            (notify, ())
+        });
+    }
+
+    fn on_ppp_event(shared: &mut Shared, event_id: u32) -> bool {
+        let event: PppEvent = event_id.into();
+
+        info!("Got PPP event: {:?}", event);
+
+        shared.last_event = Some(event);
+
+        match event {
+            PppEvent::Connected => {
+                if shared.status == ClientConnectionStatus::Connecting {
+                    shared.status = ClientConnectionStatus::Connected(ClientIpStatus::Waiting);
+                }
+
+                true
+            }
+            // lwIP closes the link on any error, also when it is already up (idle timeout,
+            // connect time limit, protocol errors, loopback)
+            PppEvent::AuthFailed | PppEvent::Disconnected | PppEvent::Error(_) => {
+                shared.status = ClientConnectionStatus::Disconnected;
+
+                true
+            }
+            PppEvent::Phase(_) => false,
+        }
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn on_ip_event(shared: &mut Shared, event_id: u32, event_data: *mut c_types::c_void) -> bool {
+        match event_id {
+            ip_event_t_IP_EVENT_PPP_GOT_IP => {
+                let event = unsafe { (event_data as *const ip_event_got_ip_t).as_ref() }.unwrap();
+
+                if event.esp_netif != shared.netif {
+                    return false;
+                }
+
+                let mask = Newtype(event.ip_info.netmask).try_into();
+
+                shared.status =
+                    ClientConnectionStatus::Connected(ClientIpStatus::Done(ipv4::ClientSettings {
+                        ip: Newtype(event.ip_info.ip).into(),
+                        subnet: ipv4::Subnet {
+                            gateway: Newtype(event.ip_info.gw).into(),
+                            mask: mask.unwrap_or_else(|_| ipv4::Mask(32)),
+                        },
+                        dns: None,
+                        secondary_dns: None,
+                    }));
+
+                info!("PPP got IP, set status: {:?}", shared.status);
+
+                true
+            }
+            ip_event_t_IP_EVENT_PPP_LOST_IP => {
+                if matches!(shared.status, ClientConnectionStatus::Connected(_)) {
+                    shared.status = ClientConnectionStatus::Connected(ClientIpStatus::Waiting);
+                }
+
+                info!("PPP lost IP, set status: {:?}", shared.status);
+
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<UART, TX, RX> Drop for EspPpp<UART, TX, RX>
+where
+    UART: serial::Uart,
+    TX: gpio::OutputPin,
+    RX: gpio::InputPin,
+{
+    fn drop(&mut self) {
+        {
+            let mut taken = TAKEN.lock();
+
+            if let Err(err) = self.clear_all() {
+                error!("Failed to deinitialize: {}", err);
+            }
+
+            *taken = false;
+        }
+
+        info!("Dropped");
+    }
+}
+
+fn set_auth(netif: *mut esp_netif_t, auth: &PppAuth) -> Result<(), EspError> {
+    let mut rcs = RawCstrs::new();
+
+    let (auth_type, username, password) = match auth {
+        PppAuth::None => (
+            esp_netif_auth_type_t_NETIF_PPP_AUTHTYPE_NONE,
+            ptr::null(),
+            ptr::null(),
+        ),
+        PppAuth::Pap { username, password } => (
+            esp_netif_auth_type_t_NETIF_PPP_AUTHTYPE_PAP,
+            rcs.as_ptr(username.as_str()),
+            rcs.as_ptr(password.as_str()),
+        ),
+        PppAuth::Chap { username, password } => (
+            esp_netif_auth_type_t_NETIF_PPP_AUTHTYPE_CHAP,
+            rcs.as_ptr(username.as_str()),
+            rcs.as_ptr(password.as_str()),
+        ),
+    };
+
+    esp!(unsafe { esp_netif_ppp_set_auth(netif, auth_type, username, password) })
+}
+
+fn ms_to_ticks(duration: Duration) -> TickType_t {
+    (duration.as_millis() as u64 * configTICK_RATE_HZ as u64 / 1000) as TickType_t
+}
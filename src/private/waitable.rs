@@ -33,7 +33,7 @@ impl<T> Waitable<T> {
         getter(&Mutex::lock(&self.state))
     }
 
-    pub fn modify<Q>(&mut self, modifier: impl FnOnce(&mut T) -> (bool, Q)) -> Q
+    pub fn modify<Q>(&self, modifier: impl FnOnce(&mut T) -> (bool, Q)) -> Q
     where
         T: Send,
     {
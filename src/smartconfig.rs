@@ -0,0 +1,319 @@
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+extern crate alloc;
+use alloc::boxed::Box;
+use alloc::string::String;
+
+use ::log::*;
+
+use embedded_svc::wifi::{AuthMethod, ClientConfiguration};
+
+use esp_idf_sys::*;
+
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventFetchData, EspEventSubscribeMetadata};
+use crate::wifi::EspWifi;
+
+use crate::private::cstr::*;
+use crate::private::waitable::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SmartConfigType {
+    EspTouch,
+    AirKiss,
+    EspTouchAirKiss,
+    EspTouchV2,
+    // Reported by newer ESP-IDF versions
+    Unknown(u32),
+}
+
+impl From<SmartConfigType> for smartconfig_type_t {
+    fn from(sc_type: SmartConfigType) -> Self {
+        match sc_type {
+            SmartConfigType::EspTouch => smartconfig_type_t_SC_TYPE_ESPTOUCH,
+            SmartConfigType::AirKiss => smartconfig_type_t_SC_TYPE_AIRKISS,
+            SmartConfigType::EspTouchAirKiss => smartconfig_type_t_SC_TYPE_ESPTOUCH_AIRKISS,
+            SmartConfigType::EspTouchV2 => smartconfig_type_t_SC_TYPE_ESPTOUCH_V2,
+            SmartConfigType::Unknown(sc_type) => sc_type as _,
+        }
+    }
+}
+
+impl From<smartconfig_type_t> for SmartConfigType {
+    #[allow(non_upper_case_globals)]
+    fn from(sc_type: smartconfig_type_t) -> Self {
+        match sc_type {
+            smartconfig_type_t_SC_TYPE_ESPTOUCH => SmartConfigType::EspTouch,
+            smartconfig_type_t_SC_TYPE_AIRKISS => SmartConfigType::AirKiss,
+            smartconfig_type_t_SC_TYPE_ESPTOUCH_AIRKISS => SmartConfigType::EspTouchAirKiss,
+            smartconfig_type_t_SC_TYPE_ESPTOUCH_V2 => SmartConfigType::EspTouchV2,
+            sc_type => SmartConfigType::Unknown(sc_type as _),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmartConfigCredentials {
+    pub ssid: String,
+    pub password: String,
+    pub bssid: Option<[u8; 6]>,
+    pub sc_type: SmartConfigType,
+}
+
+impl From<&smartconfig_event_got_ssid_pswd_t> for SmartConfigCredentials {
+    fn from(event: &smartconfig_event_got_ssid_pswd_t) -> Self {
+        Self {
+            ssid: from_cstr(&event.ssid).into(),
+            password: from_cstr(&event.password).into(),
+            bssid: if event.bssid_set {
+                Some(event.bssid)
+            } else {
+                None
+            },
+            sc_type: event.type_.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SmartConfigEvent {
+    ScanDone,
+    FoundChannel,
+    GotCredentials(SmartConfigCredentials),
+    AckSent,
+    Other(u32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for SmartConfigEvent {
+    fn source() -> *const c_types::c_char {
+        unsafe { SC_EVENT }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for SmartConfigEvent {
+    #[allow(non_upper_case_globals)]
+    fn from(data: EspEventFetchData) -> Self {
+        match data.event_id as u32 {
+            smartconfig_event_t_SC_EVENT_SCAN_DONE => SmartConfigEvent::ScanDone,
+            smartconfig_event_t_SC_EVENT_FOUND_CHANNEL => SmartConfigEvent::FoundChannel,
+            smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD => {
+                let payload: smartconfig_event_got_ssid_pswd_t = unsafe { data.as_payload() };
+
+                SmartConfigEvent::GotCredentials((&payload).into())
+            }
+            smartconfig_event_t_SC_EVENT_SEND_ACK_DONE => SmartConfigEvent::AckSent,
+            other => SmartConfigEvent::Other(other),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    credentials: Option<SmartConfigCredentials>,
+    ack_sent: bool,
+    waker: Option<Waker>,
+}
+
+pub struct EspSmartConfig<'a> {
+    wifi: &'a mut EspWifi,
+    state: Box<Waitable<State>>,
+}
+
+impl<'a> EspSmartConfig<'a> {
+    pub fn new(wifi: &'a mut EspWifi, sc_type: SmartConfigType) -> Result<Self, EspError> {
+        info!("Starting SmartConfig: {:?}", sc_type);
+
+        wifi.start_sta_idle()?;
+
+        let mut smart_config = Self {
+            wifi,
+            state: Box::new(Waitable::new(Default::default())),
+        };
+
+        let state_ref: *mut _ = &mut *smart_config.state;
+
+        unsafe {
+            esp!(esp_event_handler_register(
+                SC_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                state_ref as *mut c_types::c_void
+            ))?;
+
+            esp!(esp_smartconfig_set_type(sc_type.into()))?;
+
+            let config = smartconfig_start_config_t {
+                enable_log: false,
+                ..Default::default()
+            };
+
+            esp!(esp_smartconfig_start(&config))?;
+        }
+
+        info!("SmartConfig started");
+
+        Ok(smart_config)
+    }
+
+    pub fn wait_for_credentials(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<SmartConfigCredentials, EspError> {
+        let credentials = if let Some(timeout) = timeout {
+            let (timed_out, credentials) = self.state.wait_timeout_while_and_get(
+                timeout,
+                |state| state.credentials.is_none(),
+                |state| state.credentials.clone(),
+            );
+
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
+
+            credentials
+        } else {
+            self.state.wait_while_and_get(
+                |state| state.credentials.is_none(),
+                |state| state.credentials.clone(),
+            )
+        };
+
+        Ok(credentials.unwrap())
+    }
+
+    pub fn credentials(&self) -> EspSmartConfigCredentials<'_, 'a> {
+        EspSmartConfigCredentials(self)
+    }
+
+    // Connects to the AP with the received credentials and waits for the acknowledgement, which
+    // SmartConfig only sends to the phone once connected, so stop it (by dropping this instance)
+    // only afterwards
+    pub fn connect(
+        &mut self,
+        credentials: &SmartConfigCredentials,
+        timeout: Option<Duration>,
+    ) -> Result<(), EspError> {
+        info!("Connecting to SSID {}", credentials.ssid);
+
+        self.wifi
+            .set_client_configuration(Some(&ClientConfiguration {
+                ssid: credentials.ssid.clone(),
+                bssid: credentials.bssid,
+                auth_method: if credentials.password.is_empty() {
+                    AuthMethod::None
+                } else {
+                    AuthMethod::WPA2Personal
+                },
+                password: credentials.password.clone(),
+                ip_conf: Some(Default::default()),
+                ..Default::default()
+            }))?;
+
+        if let Some(timeout) = timeout {
+            let (timed_out, _) =
+                self.state
+                    .wait_timeout_while_and_get(timeout, |state| !state.ack_sent, |_| ());
+
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
+        } else {
+            self.state.wait_while(|state| !state.ack_sent);
+        }
+
+        info!("SmartConfig acknowledgement sent");
+
+        Ok(())
+    }
+
+    // The acknowledgement is sent to the phone once the device has connected to the AP
+    pub fn is_ack_sent(&self) -> bool {
+        self.state.get(|state| state.ack_sent)
+    }
+
+    #[allow(non_upper_case_globals)]
+    unsafe extern "C" fn event_handler(
+        arg: *mut c_types::c_void,
+        _event_base: esp_event_base_t,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) {
+        let state_ref = (arg as *mut Waitable<State>).as_mut().unwrap();
+
+        state_ref.modify(|state| match event_id as u32 {
+            smartconfig_event_t_SC_EVENT_GOT_SSID_PSWD => {
+                let event = (event_data as *const smartconfig_event_got_ssid_pswd_t)
+                    .as_ref()
+                    .unwrap();
+
+                let credentials: SmartConfigCredentials = event.into();
+
+                info!("SmartConfig got credentials for SSID {}", credentials.ssid);
+
+                state.credentials = Some(credentials);
+
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+
+                (true, ())
+            }
+            smartconfig_event_t_SC_EVENT_SEND_ACK_DONE => {
+                state.ack_sent = true;
+
+                (true, ())
+            }
+            _ => (false, ()),
+        });
+    }
+}
+
+impl<'a> Drop for EspSmartConfig<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = esp!(unsafe { esp_smartconfig_stop() }) {
+            error!("Failed to stop SmartConfig: {}", err);
+        }
+
+        if let Err(err) = esp!(unsafe {
+            esp_event_handler_unregister(
+                SC_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+            )
+        }) {
+            error!(
+                "Failed to unregister the SmartConfig event handler: {}",
+                err
+            );
+        }
+
+        info!("SmartConfig dropped");
+    }
+}
+
+pub struct EspSmartConfigCredentials<'s, 'a>(&'s EspSmartConfig<'a>);
+
+impl<'s, 'a> Future for EspSmartConfigCredentials<'s, 'a> {
+    type Output = SmartConfigCredentials;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let credentials = self.0.state.modify(|state| {
+            if state.credentials.is_none() {
+                state.waker = Some(cx.waker().clone());
+            }
+
+            (false, state.credentials.clone())
+        });
+
+        if let Some(credentials) = credentials {
+            Poll::Ready(credentials)
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -1,8 +1,17 @@
-use core::{cmp, convert::TryInto, ptr, time::Duration};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::{
+    cmp,
+    convert::{TryFrom, TryInto},
+    ptr,
+    time::Duration,
+};
 
 extern crate alloc;
 use alloc::borrow::ToOwned;
 use alloc::boxed::Box;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec;
 
@@ -16,6 +25,8 @@ use esp_idf_hal::mutex;
 
 use esp_idf_sys::*;
 
+#[cfg(feature = "experimental")]
+use crate::eventloop::{EspEventFetchData, EspEventSubscribeMetadata};
 use crate::netif::*;
 use crate::nvs::EspDefaultNvs;
 use crate::sysloop::*;
@@ -26,6 +37,31 @@ use crate::private::waitable::*;
 
 const MAX_AP: usize = 20;
 
+// Maximum number of stations the SoftAP accepts, across all supported chips
+const MAX_AP_CONNECTIONS: u16 = 10;
+
+// Beacon interval range accepted for the SoftAP, in TUs (1024 us)
+const MIN_BEACON_INTERVAL: u16 = 100;
+const MAX_BEACON_INTERVAL: u16 = 60000;
+
+// Range accepted by `esp_wifi_set_max_tx_power`, in units of 0.25 dBm. The maximum is the one of
+// the RF of each chip
+const MIN_TX_POWER: i8 = 8;
+#[cfg(any(esp32, esp32s2, esp32s3, esp32c3))]
+const MAX_TX_POWER: i8 = 84;
+#[cfg(esp32c2)]
+const MAX_TX_POWER: i8 = 80;
+// The chips without a known maximum are left to ESP-IDF, which clamps the power
+#[cfg(not(any(esp32, esp32s2, esp32s3, esp32c3, esp32c2)))]
+const MAX_TX_POWER: i8 = i8::MAX;
+
+// Frames accepted by `esp_wifi_80211_tx`: at least the 802.11 MAC header, FCS excluded
+const MIN_RAW_FRAME_LEN: usize = 24;
+const MAX_RAW_FRAME_LEN: usize = 1500;
+
+// The IE length is a single byte which also covers the OUI and the OUI type
+const MAX_VENDOR_IE_PAYLOAD_LEN: usize = 251;
+
 impl From<AuthMethod> for Newtype<wifi_auth_mode_t> {
     fn from(method: AuthMethod) -> Self {
         Newtype(match method {
@@ -42,10 +78,14 @@ impl From<AuthMethod> for Newtype<wifi_auth_mode_t> {
     }
 }
 
-impl From<Newtype<wifi_auth_mode_t>> for AuthMethod {
+// Fails with `ESP_ERR_NOT_SUPPORTED` for the modes `AuthMethod` has no variant for, like OWE or
+// WPA3-Enterprise
+impl TryFrom<Newtype<wifi_auth_mode_t>> for AuthMethod {
+    type Error = EspError;
+
     #[allow(non_upper_case_globals)]
-    fn from(mode: Newtype<wifi_auth_mode_t>) -> Self {
-        match mode.0 {
+    fn try_from(mode: Newtype<wifi_auth_mode_t>) -> Result<Self, Self::Error> {
+        Ok(match mode.0 {
             wifi_auth_mode_t_WIFI_AUTH_OPEN => AuthMethod::None,
             wifi_auth_mode_t_WIFI_AUTH_WEP => AuthMethod::WEP,
             wifi_auth_mode_t_WIFI_AUTH_WPA_PSK => AuthMethod::WPA,
@@ -55,8 +95,8 @@ impl From<Newtype<wifi_auth_mode_t>> for AuthMethod {
             wifi_auth_mode_t_WIFI_AUTH_WPA3_PSK => AuthMethod::WPA3Personal,
             wifi_auth_mode_t_WIFI_AUTH_WPA2_WPA3_PSK => AuthMethod::WPA2WPA3Personal,
             wifi_auth_mode_t_WIFI_AUTH_WAPI_PSK => AuthMethod::WAPIPersonal,
-            _ => panic!(),
-        }
+            _ => return Err(EspError::from(ESP_ERR_NOT_SUPPORTED as i32).unwrap()),
+        })
     }
 }
 
@@ -94,16 +134,18 @@ impl From<&ClientConfiguration> for Newtype<wifi_sta_config_t> {
     }
 }
 
-impl From<Newtype<wifi_sta_config_t>> for ClientConfiguration {
-    fn from(conf: Newtype<wifi_sta_config_t>) -> Self {
-        ClientConfiguration {
+impl TryFrom<Newtype<wifi_sta_config_t>> for ClientConfiguration {
+    type Error = EspError;
+
+    fn try_from(conf: Newtype<wifi_sta_config_t>) -> Result<Self, Self::Error> {
+        Ok(ClientConfiguration {
             ssid: from_cstr(&conf.0.ssid).into(),
             bssid: if conf.0.bssid_set {
                 Some(conf.0.bssid)
             } else {
                 None
             },
-            auth_method: Newtype(conf.0.threshold.authmode).into(),
+            auth_method: Newtype(conf.0.threshold.authmode).try_into()?,
             password: from_cstr(&conf.0.password).into(),
             channel: if conf.0.channel != 0 {
                 Some(conf.0.channel)
@@ -111,7 +153,7 @@ impl From<Newtype<wifi_sta_config_t>> for ClientConfiguration {
                 None
             },
             ip_conf: None, // This must be set at a later stage
-        }
+        })
     }
 }
 
@@ -124,7 +166,7 @@ impl From<&AccessPointConfiguration> for Newtype<wifi_ap_config_t> {
             channel: conf.channel,
             authmode: Newtype::<wifi_auth_mode_t>::from(conf.auth_method).0,
             ssid_hidden: if conf.ssid_hidden { 1 } else { 0 },
-            max_connection: cmp::max(conf.max_connections, 16) as u8,
+            max_connection: cmp::min(conf.max_connections, MAX_AP_CONNECTIONS) as u8,
             beacon_interval: 100,
             ..Default::default()
         };
@@ -136,9 +178,11 @@ impl From<&AccessPointConfiguration> for Newtype<wifi_ap_config_t> {
     }
 }
 
-impl From<Newtype<wifi_ap_config_t>> for AccessPointConfiguration {
-    fn from(conf: Newtype<wifi_ap_config_t>) -> Self {
-        AccessPointConfiguration {
+impl TryFrom<Newtype<wifi_ap_config_t>> for AccessPointConfiguration {
+    type Error = EspError;
+
+    fn try_from(conf: Newtype<wifi_ap_config_t>) -> Result<Self, Self::Error> {
+        Ok(AccessPointConfiguration {
             ssid: if conf.0.ssid_len == 0 {
                 from_cstr(&conf.0.ssid).into()
             } else {
@@ -150,702 +194,3954 @@ impl From<Newtype<wifi_ap_config_t>> for AccessPointConfiguration {
             ssid_hidden: conf.0.ssid_hidden != 0,
             channel: conf.0.channel,
             secondary_channel: None,
-            auth_method: AuthMethod::from(Newtype(conf.0.authmode)),
+            auth_method: Newtype(conf.0.authmode).try_into()?,
             protocols: EnumSet::<Protocol>::empty(), // TODO
             password: from_cstr(&conf.0.password).into(),
             max_connections: conf.0.max_connection as u16,
             ip_conf: None, // This must be set at a later stage
+        })
+    }
+}
+
+impl From<Newtype<wifi_second_chan_t>> for SecondaryChannel {
+    #[allow(non_upper_case_globals)]
+    fn from(second: Newtype<wifi_second_chan_t>) -> Self {
+        match second.0 {
+            wifi_second_chan_t_WIFI_SECOND_CHAN_NONE => SecondaryChannel::None,
+            wifi_second_chan_t_WIFI_SECOND_CHAN_ABOVE => SecondaryChannel::Above,
+            wifi_second_chan_t_WIFI_SECOND_CHAN_BELOW => SecondaryChannel::Below,
+            _ => panic!(),
         }
     }
 }
 
-impl From<Newtype<&wifi_ap_record_t>> for AccessPointInfo {
+// Fails for the APs whose auth mode `AuthMethod` has no variant for
+impl TryFrom<Newtype<&wifi_ap_record_t>> for AccessPointInfo {
+    type Error = EspError;
+
     #[allow(non_upper_case_globals)]
-    fn from(ap_info: Newtype<&wifi_ap_record_t>) -> Self {
+    fn try_from(ap_info: Newtype<&wifi_ap_record_t>) -> Result<Self, Self::Error> {
         let a = ap_info.0;
 
-        Self {
+        Ok(Self {
             ssid: from_cstr(&a.ssid).into(),
             bssid: a.bssid,
             channel: a.primary,
-            secondary_channel: match a.second {
-                wifi_second_chan_t_WIFI_SECOND_CHAN_NONE => SecondaryChannel::None,
-                wifi_second_chan_t_WIFI_SECOND_CHAN_ABOVE => SecondaryChannel::Above,
-                wifi_second_chan_t_WIFI_SECOND_CHAN_BELOW => SecondaryChannel::Below,
-                _ => panic!(),
-            },
+            secondary_channel: Newtype(a.second).into(),
             signal_strength: a.rssi as u8,
             protocols: EnumSet::<Protocol>::empty(), // TODO
-            auth_method: AuthMethod::from(Newtype::<wifi_auth_mode_t>(a.authmode)),
-        }
+            auth_method: Newtype::<wifi_auth_mode_t>(a.authmode).try_into()?,
+        })
     }
 }
 
-static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    None,
+    WEP40,
+    WEP104,
+    TKIP,
+    CCMP,
+    TKIPCCMP,
+    AESCMAC128,
+    SMS4,
+    Unknown,
+}
 
-struct Shared {
-    client_ip_conf: Option<ipv4::ClientConfiguration>,
-    router_ip_conf: Option<ipv4::RouterConfiguration>,
+impl From<Newtype<wifi_cipher_type_t>> for Cipher {
+    #[allow(non_upper_case_globals)]
+    fn from(cipher: Newtype<wifi_cipher_type_t>) -> Self {
+        match cipher.0 {
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_NONE => Cipher::None,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP40 => Cipher::WEP40,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_WEP104 => Cipher::WEP104,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP => Cipher::TKIP,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_CCMP => Cipher::CCMP,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_TKIP_CCMP => Cipher::TKIPCCMP,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_AES_CMAC128 => Cipher::AESCMAC128,
+            wifi_cipher_type_t_WIFI_CIPHER_TYPE_SMS4 => Cipher::SMS4,
+            _ => Cipher::Unknown,
+        }
+    }
+}
 
-    status: Status,
-    operating: bool,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CountryPolicy {
+    // Follow the country information advertised by the AP
+    Auto,
+    Manual,
+}
 
-    sta_netif: Option<*mut esp_netif_t>,
-    ap_netif: Option<*mut esp_netif_t>,
+// The third character of the country string, as advertised by 802.11d
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CountryEnvironment {
+    Any,
+    Indoor,
+    Outdoor,
+    // The channels and powers are not the ones of a country
+    NonCountry,
 }
 
-impl Default for Shared {
+impl Default for CountryEnvironment {
     fn default() -> Self {
-        Self {
-            client_ip_conf: None,
-            router_ip_conf: None,
-            status: Status(ClientStatus::Stopped, ApStatus::Stopped),
-            operating: false,
-            sta_netif: None,
-            ap_netif: None,
-        }
+        CountryEnvironment::Any
     }
 }
 
-unsafe impl Send for Shared {}
-
-pub struct EspWifi {
-    netif_stack: Arc<EspNetifStack>,
-    _sys_loop_stack: Arc<EspSysLoopStack>,
-    _nvs: Arc<EspDefaultNvs>,
+impl From<u8> for CountryEnvironment {
+    fn from(ch: u8) -> Self {
+        match ch {
+            b'I' => CountryEnvironment::Indoor,
+            b'O' => CountryEnvironment::Outdoor,
+            b'X' => CountryEnvironment::NonCountry,
+            _ => CountryEnvironment::Any,
+        }
+    }
+}
 
-    sta_netif: Option<EspNetif>,
-    ap_netif: Option<EspNetif>,
+impl From<CountryEnvironment> for u8 {
+    fn from(environment: CountryEnvironment) -> Self {
+        match environment {
+            CountryEnvironment::Any => b' ',
+            CountryEnvironment::Indoor => b'I',
+            CountryEnvironment::Outdoor => b'O',
+            CountryEnvironment::NonCountry => b'X',
+        }
+    }
+}
 
-    shared: Box<Waitable<Shared>>,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CountryInfo {
+    // The two letter ISO 3166 code
+    pub code: String,
+    pub environment: CountryEnvironment,
+    pub start_channel: u8,
+    pub channels: u8,
+    pub max_tx_power: i8,
+    pub policy: CountryPolicy,
 }
 
-impl EspWifi {
-    pub fn new(
-        netif_stack: Arc<EspNetifStack>,
-        sys_loop_stack: Arc<EspSysLoopStack>,
-        nvs: Arc<EspDefaultNvs>,
-    ) -> Result<Self, EspError> {
-        let mut taken = TAKEN.lock();
+impl From<&CountryInfo> for Newtype<wifi_country_t> {
+    fn from(country: &CountryInfo) -> Self {
+        let mut cc = [b' ' as c_types::c_char, b' ' as _, b' ' as _];
 
-        if *taken {
-            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        for (dst, src) in cc.iter_mut().zip(country.code.bytes().take(2)) {
+            *dst = src as _;
         }
 
-        let wifi = Self::init(netif_stack, sys_loop_stack, nvs)?;
+        cc[2] = u8::from(country.environment) as _;
 
-        *taken = true;
-        Ok(wifi)
+        Newtype(wifi_country_t {
+            cc,
+            schan: country.start_channel,
+            nchan: country.channels,
+            max_tx_power: country.max_tx_power,
+            policy: match country.policy {
+                CountryPolicy::Auto => wifi_country_policy_t_WIFI_COUNTRY_POLICY_AUTO,
+                CountryPolicy::Manual => wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
+            },
+        })
     }
+}
 
-    fn init(
-        netif_stack: Arc<EspNetifStack>,
-        sys_loop_stack: Arc<EspSysLoopStack>,
-        nvs: Arc<EspDefaultNvs>,
-    ) -> Result<Self, EspError> {
-        let mut wifi = Self {
-            netif_stack,
-            _sys_loop_stack: sys_loop_stack,
-            _nvs: nvs,
-            sta_netif: None,
-            ap_netif: None,
-            shared: Box::new(Waitable::new(Default::default())),
-        };
+impl From<Newtype<&wifi_country_t>> for CountryInfo {
+    fn from(country: Newtype<&wifi_country_t>) -> Self {
+        let c = country.0;
 
-        unsafe {
-            let cfg = wifi_init_config_t {
-                event_handler: Some(esp_event_send_internal),
-                osi_funcs: &mut g_wifi_osi_funcs,
-                wpa_crypto_funcs: g_wifi_default_wpa_crypto_funcs,
-                static_rx_buf_num: 10,
-                dynamic_rx_buf_num: 32,
-                tx_buf_type: 1,
-                static_tx_buf_num: 0,
-                dynamic_tx_buf_num: 32,
-                csi_enable: 0,
-                ampdu_rx_enable: 1,
-                ampdu_tx_enable: 1,
-                nvs_enable: 0,
-                nano_enable: 0,
-                //tx_ba_win: 6,
-                rx_ba_win: 6,
-                wifi_task_core_id: 0,
-                beacon_max_len: 752,
-                mgmt_sbuf_num: 32,
-                feature_caps: 1, // CONFIG_FEATURE_WPA3_SAE_BIT
-                magic: 0x1F2F3F4F,
-                ..Default::default()
-            };
-            esp!(esp_wifi_init(&cfg))?;
+        Self {
+            code: c.cc[..2]
+                .iter()
+                .take_while(|ch| **ch != 0)
+                .map(|ch| *ch as u8 as char)
+                .collect(),
+            environment: (c.cc[2] as u8).into(),
+            start_channel: c.schan,
+            channels: c.nchan,
+            max_tx_power: c.max_tx_power,
+            policy: if c.policy == wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL {
+                CountryPolicy::Manual
+            } else {
+                CountryPolicy::Auto
+            },
+        }
+    }
+}
 
-            info!("Driver initialized");
+#[derive(Clone, Debug, PartialEq)]
+pub struct EspAccessPointInfo {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub secondary_channel: SecondaryChannel,
+    pub rssi: i8,
+    // `None` for the auth modes `AuthMethod` has no variant for, like OWE or WPA3-Enterprise
+    pub auth_method: Option<AuthMethod>,
+    pub pairwise_cipher: Cipher,
+    pub group_cipher: Cipher,
+    pub country: CountryInfo,
+    pub phy_11b: bool,
+    pub phy_11g: bool,
+    pub phy_11n: bool,
+    pub phy_lr: bool,
+    pub wps: bool,
+}
 
-            let shared_ref: *mut _ = &mut *wifi.shared;
+impl TryFrom<Newtype<&wifi_ap_record_t>> for EspAccessPointInfo {
+    type Error = EspError;
 
-            esp!(esp_event_handler_register(
-                WIFI_EVENT,
-                ESP_EVENT_ANY_ID,
-                Option::Some(Self::event_handler),
-                shared_ref as *mut c_types::c_void
-            ))?;
-            esp!(esp_event_handler_register(
-                IP_EVENT,
-                ESP_EVENT_ANY_ID,
-                Option::Some(Self::event_handler),
-                shared_ref as *mut c_types::c_void
-            ))?;
+    fn try_from(ap_info: Newtype<&wifi_ap_record_t>) -> Result<Self, Self::Error> {
+        let a = ap_info.0;
 
-            info!("Event handlers registered");
-        }
+        Ok(Self {
+            ssid: from_cstr(&a.ssid).into(),
+            bssid: a.bssid,
+            channel: a.primary,
+            secondary_channel: Newtype(a.second).into(),
+            rssi: a.rssi,
+            auth_method: Newtype::<wifi_auth_mode_t>(a.authmode).try_into().ok(),
+            pairwise_cipher: Newtype(a.pairwise_cipher).into(),
+            group_cipher: Newtype(a.group_cipher).into(),
+            country: Newtype(&a.country).into(),
+            phy_11b: a.phy_11b() != 0,
+            phy_11g: a.phy_11g() != 0,
+            phy_11n: a.phy_11n() != 0,
+            phy_lr: a.phy_lr() != 0,
+            wps: a.wps() != 0,
+        })
+    }
+}
 
-        info!("Initialization complete");
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StaInfo {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub secondary_channel: SecondaryChannel,
+    pub rssi: i8,
+    // `None` for the auth modes `AuthMethod` has no variant for, like OWE or WPA3-Enterprise
+    pub auth_method: Option<AuthMethod>,
+    // The protocols supported by both the AP and the STA interface
+    pub protocols: EnumSet<WifiProtocol>,
+}
 
-        Ok(wifi)
-    }
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WifiInterface {
+    Client,
+    AccessPoint,
+}
 
-    pub fn with_client_netif<F, T>(&self, f: F) -> T
-    where
-        F: FnOnce(Option<&EspNetif>) -> T,
-    {
-        f(self.sta_netif.as_ref())
+impl From<WifiInterface> for wifi_interface_t {
+    fn from(interface: WifiInterface) -> Self {
+        match interface {
+            WifiInterface::Client => wifi_interface_t_WIFI_IF_STA,
+            WifiInterface::AccessPoint => wifi_interface_t_WIFI_IF_AP,
+        }
     }
+}
 
-    pub fn with_client_netif_mut<F, T>(&mut self, f: F) -> T
-    where
-        F: FnOnce(Option<&mut EspNetif>) -> T,
-    {
-        f(self.sta_netif.as_mut())
-    }
+#[derive(EnumSetType, Debug)]
+pub enum WifiProtocol {
+    P802D11B,
+    P802D11G,
+    P802D11N,
+    // Espressif's proprietary Long Range mode, only understood by other ESP devices
+    LR,
+}
 
-    pub fn with_router_netif<F, T>(&self, f: F) -> T
-    where
-        F: FnOnce(Option<&EspNetif>) -> T,
-    {
-        f(self.ap_netif.as_ref())
+impl From<Newtype<EnumSet<WifiProtocol>>> for u8 {
+    fn from(protocols: Newtype<EnumSet<WifiProtocol>>) -> Self {
+        protocols
+            .0
+            .iter()
+            .map(|protocol| match protocol {
+                WifiProtocol::P802D11B => WIFI_PROTOCOL_11B,
+                WifiProtocol::P802D11G => WIFI_PROTOCOL_11G,
+                WifiProtocol::P802D11N => WIFI_PROTOCOL_11N,
+                WifiProtocol::LR => WIFI_PROTOCOL_LR,
+            })
+            .fold(0, |bitmap, protocol| bitmap | protocol as u8)
     }
+}
 
-    pub fn with_router_netif_mut<F, T>(&mut self, f: F) -> T
-    where
-        F: FnOnce(Option<&mut EspNetif>) -> T,
-    {
-        f(self.ap_netif.as_mut())
+impl From<u8> for Newtype<EnumSet<WifiProtocol>> {
+    fn from(bitmap: u8) -> Self {
+        let mut protocols = EnumSet::empty();
+
+        for (protocol, bit) in [
+            (WifiProtocol::P802D11B, WIFI_PROTOCOL_11B),
+            (WifiProtocol::P802D11G, WIFI_PROTOCOL_11G),
+            (WifiProtocol::P802D11N, WIFI_PROTOCOL_11N),
+            (WifiProtocol::LR, WIFI_PROTOCOL_LR),
+        ] {
+            if bitmap & bit as u8 != 0 {
+                protocols |= protocol;
+            }
+        }
+
+        Newtype(protocols)
     }
+}
 
-    fn get_client_conf(&self) -> Result<ClientConfiguration, EspError> {
-        let mut wifi_config: wifi_config_t = Default::default();
-        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bandwidth {
+    HT20,
+    HT40,
+}
 
-        let mut result: ClientConfiguration = unsafe { Newtype(wifi_config.sta).into() };
-        result.ip_conf = self.shared.get(|shared| shared.client_ip_conf.clone());
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PowerSaveMode {
+    None,
+    MinModem,
+    MaxModem,
+}
 
-        info!("Providing STA configuration: {:?}", &result);
+impl From<PowerSaveMode> for wifi_ps_type_t {
+    fn from(mode: PowerSaveMode) -> Self {
+        match mode {
+            PowerSaveMode::None => wifi_ps_type_t_WIFI_PS_NONE,
+            PowerSaveMode::MinModem => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+            PowerSaveMode::MaxModem => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+        }
+    }
+}
 
-        Ok(result)
+impl From<wifi_ps_type_t> for PowerSaveMode {
+    #[allow(non_upper_case_globals)]
+    fn from(mode: wifi_ps_type_t) -> Self {
+        match mode {
+            wifi_ps_type_t_WIFI_PS_NONE => PowerSaveMode::None,
+            wifi_ps_type_t_WIFI_PS_MIN_MODEM => PowerSaveMode::MinModem,
+            wifi_ps_type_t_WIFI_PS_MAX_MODEM => PowerSaveMode::MaxModem,
+            _ => panic!(),
+        }
     }
+}
 
-    fn set_client_conf(&mut self, conf: &ClientConfiguration) -> Result<(), EspError> {
-        info!("Setting STA configuration: {:?}", conf);
+// PEM certificates and keys must include their NUL terminator
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnterpriseCredentials {
+    pub identity: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca_cert: Option<vec::Vec<u8>>,
+    pub client_cert: Option<vec::Vec<u8>>,
+    pub client_key: Option<vec::Vec<u8>>,
+    pub client_key_password: Option<String>,
+}
 
-        let mut wifi_config = wifi_config_t {
-            sta: Newtype::<wifi_sta_config_t>::from(conf).0,
-        };
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScanType {
+    Active { min: Duration, max: Duration },
+    Passive(Duration),
+}
 
-        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+impl Default for ScanType {
+    fn default() -> Self {
+        // Zero dwell times make ESP-IDF use its own defaults
+        ScanType::Active {
+            min: Duration::from_millis(0),
+            max: Duration::from_millis(0),
+        }
+    }
+}
 
-        self.set_client_ip_conf(&conf.ip_conf)?;
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanConfig {
+    pub ssid: Option<String>,
+    pub bssid: Option<[u8; 6]>,
+    // All channels are scanned when empty
+    pub channels: vec::Vec<u8>,
+    pub show_hidden: bool,
+    pub scan_type: ScanType,
+}
 
-        info!("STA configuration done");
+// How the STA looks for the configured AP when connecting
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScanMethod {
+    // Connect to the first matching AP found
+    Fast,
+    // Scan all channels and connect to the best matching AP; more reliable with hidden SSIDs
+    AllChannels,
+}
 
-        Ok(())
+impl Default for ScanMethod {
+    fn default() -> Self {
+        ScanMethod::Fast
     }
+}
 
-    fn get_ap_conf(&self) -> Result<AccessPointConfiguration, EspError> {
-        let mut wifi_config: wifi_config_t = Default::default();
-        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+impl From<ScanMethod> for wifi_scan_method_t {
+    fn from(method: ScanMethod) -> Self {
+        match method {
+            ScanMethod::Fast => wifi_scan_method_t_WIFI_FAST_SCAN,
+            ScanMethod::AllChannels => wifi_scan_method_t_WIFI_ALL_CHANNEL_SCAN,
+        }
+    }
+}
 
-        let mut result: AccessPointConfiguration = unsafe { Newtype(wifi_config.ap).into() };
-        result.ip_conf = self.shared.get(|shared| shared.router_ip_conf.clone());
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RoamingConfig {
+    // Roaming is only attempted when the RSSI of the current AP is below this threshold
+    pub rssi_threshold: i8,
+    // How much stronger (in dB) another AP of the same SSID must be to roam to it
+    pub min_rssi_gain: u8,
+}
 
-        info!("Providing AP configuration: {:?}", &result);
+impl Default for RoamingConfig {
+    fn default() -> Self {
+        Self {
+            rssi_threshold: -75,
+            min_rssi_gain: 8,
+        }
+    }
+}
 
-        Ok(result)
+// 802.11k/v/r support of the STA; the corresponding WPA supplicant options need to be enabled
+// in the ESP-IDF configuration as well
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FastRoamingConfig {
+    // 802.11k Radio Resource Measurement (neighbor reports)
+    pub radio_measurement: bool,
+    // 802.11v BSS Transition Management
+    pub bss_transition: bool,
+    // 802.11r Fast BSS Transition (FT-PSK)
+    pub fast_transition: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum WifiEvent {
+    Ready,
+    ScanDone {
+        success: bool,
+        ap_count: u8,
+    },
+    StaStarted,
+    StaStopped,
+    StaConnected {
+        ssid: String,
+        bssid: [u8; 6],
+        channel: u8,
+        // `None` for the auth modes `AuthMethod` has no variant for, like OWE or WPA3-Enterprise
+        auth_method: Option<AuthMethod>,
+    },
+    StaDisconnected {
+        ssid: String,
+        bssid: [u8; 6],
+        reason: u8,
+    },
+    StaAuthModeChanged {
+        old: Option<AuthMethod>,
+        new: Option<AuthMethod>,
+    },
+    StaBssRssiLow {
+        rssi: i32,
+    },
+    #[cfg(esp_idf_version_major = "5")]
+    StaNeighborReport {
+        report: vec::Vec<u8>,
+    },
+    ApStarted,
+    ApStopped,
+    ApStaConnected {
+        mac: [u8; 6],
+        aid: u8,
+    },
+    ApStaDisconnected {
+        mac: [u8; 6],
+        aid: u8,
+    },
+    ApProbeRequestReceived {
+        mac: [u8; 6],
+        rssi: i32,
+    },
+    Other(u32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for WifiEvent {
+    fn source() -> *const c_types::c_char {
+        unsafe { WIFI_EVENT }
     }
+}
 
-    fn set_ap_conf(&mut self, conf: &AccessPointConfiguration) -> Result<(), EspError> {
-        info!("Setting AP configuration: {:?}", conf);
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for WifiEvent {
+    #[allow(non_upper_case_globals)]
+    fn from(data: EspEventFetchData) -> Self {
+        let event_id = data.event_id as u32;
 
-        let mut wifi_config = wifi_config_t {
-            ap: Newtype::<wifi_ap_config_t>::from(conf).0,
-        };
+        unsafe {
+            match event_id {
+                wifi_event_t_WIFI_EVENT_WIFI_READY => WifiEvent::Ready,
+                wifi_event_t_WIFI_EVENT_SCAN_DONE => {
+                    let payload: wifi_event_sta_scan_done_t = data.as_payload();
+
+                    WifiEvent::ScanDone {
+                        success: payload.status == 0,
+                        ap_count: payload.number,
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_STA_START => WifiEvent::StaStarted,
+                wifi_event_t_WIFI_EVENT_STA_STOP => WifiEvent::StaStopped,
+                wifi_event_t_WIFI_EVENT_STA_CONNECTED => {
+                    let payload: wifi_event_sta_connected_t = data.as_payload();
+
+                    WifiEvent::StaConnected {
+                        ssid: String::from_utf8_lossy(&payload.ssid[..payload.ssid_len as usize])
+                            .into_owned(),
+                        bssid: payload.bssid,
+                        channel: payload.channel,
+                        auth_method: Newtype(payload.authmode).try_into().ok(),
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                    let payload: wifi_event_sta_disconnected_t = data.as_payload();
+
+                    WifiEvent::StaDisconnected {
+                        ssid: String::from_utf8_lossy(&payload.ssid[..payload.ssid_len as usize])
+                            .into_owned(),
+                        bssid: payload.bssid,
+                        reason: payload.reason,
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_STA_AUTHMODE_CHANGE => {
+                    let payload: wifi_event_sta_authmode_change_t = data.as_payload();
 
-        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
-        self.set_router_ip_conf(&conf.ip_conf)?;
+                    WifiEvent::StaAuthModeChanged {
+                        old: Newtype(payload.old_mode).try_into().ok(),
+                        new: Newtype(payload.new_mode).try_into().ok(),
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_STA_BSS_RSSI_LOW => {
+                    let payload: wifi_event_bss_rssi_low_t = data.as_payload();
 
-        info!("AP configuration done");
+                    WifiEvent::StaBssRssiLow {
+                        rssi: payload.rssi as _,
+                    }
+                }
+                #[cfg(esp_idf_version_major = "5")]
+                wifi_event_t_WIFI_EVENT_STA_NEIGHBOR_REP => {
+                    let payload: wifi_event_neighbor_report_t = data.as_payload();
 
-        Ok(())
-    }
+                    WifiEvent::StaNeighborReport {
+                        report: payload.report[..payload.report_len as usize].to_vec(),
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_AP_START => WifiEvent::ApStarted,
+                wifi_event_t_WIFI_EVENT_AP_STOP => WifiEvent::ApStopped,
+                wifi_event_t_WIFI_EVENT_AP_STACONNECTED => {
+                    let payload: wifi_event_ap_staconnected_t = data.as_payload();
+
+                    WifiEvent::ApStaConnected {
+                        mac: payload.mac,
+                        aid: payload.aid,
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED => {
+                    let payload: wifi_event_ap_stadisconnected_t = data.as_payload();
 
-    fn set_client_ip_conf(
+                    WifiEvent::ApStaDisconnected {
+                        mac: payload.mac,
+                        aid: payload.aid,
+                    }
+                }
+                wifi_event_t_WIFI_EVENT_AP_PROBEREQRECVED => {
+                    let payload: wifi_event_ap_probe_req_rx_t = data.as_payload();
+
+                    WifiEvent::ApProbeRequestReceived {
+                        mac: payload.mac,
+                        rssi: payload.rssi as _,
+                    }
+                }
+                other => WifiEvent::Other(other),
+            }
+        }
+    }
+}
+
+// The reconnect supervisor reports its state changes on the system event loop with this base
+static WIFI_RECONNECT_EVENT: &str = "WIFI_RECONNECT_EVENT\0";
+
+const WIFI_RECONNECT_EVENT_RETRYING: i32 = 0;
+const WIFI_RECONNECT_EVENT_RECONNECTED: i32 = 1;
+const WIFI_RECONNECT_EVENT_GAVE_UP: i32 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: u32,
+    // Each delay is randomly spread by up to this percentage in both directions
+    pub jitter_percent: u8,
+    // None retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2,
+            jitter_percent: 20,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = (self.multiplier as u64).saturating_pow(attempt.saturating_sub(1));
+
+        let delay_ms = (self.initial_delay.as_millis() as u64)
+            .saturating_mul(factor)
+            .min(self.max_delay.as_millis() as u64);
+
+        let jitter_ms = delay_ms * cmp::min(self.jitter_percent, 100) as u64 / 100;
+
+        let delay_ms = if jitter_ms > 0 {
+            delay_ms - jitter_ms + unsafe { esp_random() } as u64 % (2 * jitter_ms + 1)
+        } else {
+            delay_ms
+        };
+
+        Duration::from_millis(delay_ms)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WifiReconnectEvent {
+    Retrying { attempt: u32, delay: Duration },
+    Reconnected { attempts: u32 },
+    GaveUp { attempts: u32 },
+    Other(i32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for WifiReconnectEvent {
+    fn source() -> *const c_types::c_char {
+        WIFI_RECONNECT_EVENT.as_ptr() as *const _
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for WifiReconnectEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        // All events carry the attempt count and the delay in milliseconds
+        let payload = || -> [u32; 2] { unsafe { data.as_payload() } };
+
+        match data.event_id {
+            WIFI_RECONNECT_EVENT_RETRYING => {
+                let [attempt, delay_ms] = payload();
+
+                WifiReconnectEvent::Retrying {
+                    attempt,
+                    delay: Duration::from_millis(delay_ms as u64),
+                }
+            }
+            WIFI_RECONNECT_EVENT_RECONNECTED => WifiReconnectEvent::Reconnected {
+                attempts: payload()[0],
+            },
+            WIFI_RECONNECT_EVENT_GAVE_UP => WifiReconnectEvent::GaveUp {
+                attempts: payload()[0],
+            },
+            other => WifiReconnectEvent::Other(other),
+        }
+    }
+}
+
+// Transitions of `WifiState` are posted on the system event loop with this base
+static WIFI_STATE_EVENT: &str = "WIFI_STATE_EVENT\0";
+
+const WIFI_STATE_EVENT_STOPPED: i32 = 0;
+const WIFI_STATE_EVENT_SCANNING: i32 = 1;
+const WIFI_STATE_EVENT_CONNECTING: i32 = 2;
+const WIFI_STATE_EVENT_CONNECTED: i32 = 3;
+const WIFI_STATE_EVENT_DISCONNECTED: i32 = 4;
+
+// The state of the STA interface
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WifiState {
+    Stopped,
+    Scanning,
+    Connecting,
+    Connected {
+        ssid: String,
+        bssid: [u8; 6],
+        channel: u8,
+    },
+    // The reason is one of the `wifi_err_reason_t` codes, if the STA lost a connection
+    Disconnected {
+        reason: Option<u8>,
+    },
+    // A state event ID unknown to this version, e.g. posted by a newer one
+    Other(i32),
+}
+
+impl WifiState {
+    fn post(&self) {
+        let (event_id, connected, disconnected) = match self {
+            WifiState::Stopped => (WIFI_STATE_EVENT_STOPPED, None, None),
+            WifiState::Scanning => (WIFI_STATE_EVENT_SCANNING, None, None),
+            WifiState::Connecting => (WIFI_STATE_EVENT_CONNECTING, None, None),
+            WifiState::Connected {
+                ssid,
+                bssid,
+                channel,
+            } => {
+                let mut payload: wifi_event_sta_connected_t = Default::default();
+
+                let len = cmp::min(ssid.len(), payload.ssid.len());
+                payload.ssid[..len].copy_from_slice(&ssid.as_bytes()[..len]);
+                payload.ssid_len = len as _;
+                payload.bssid = *bssid;
+                payload.channel = *channel;
+
+                (WIFI_STATE_EVENT_CONNECTED, Some(payload), None)
+            }
+            WifiState::Disconnected { reason } => {
+                let payload = wifi_event_sta_disconnected_t {
+                    reason: reason.unwrap_or(0),
+                    ..Default::default()
+                };
+
+                (WIFI_STATE_EVENT_DISCONNECTED, None, Some(payload))
+            }
+            WifiState::Other(event_id) => (*event_id, None, None),
+        };
+
+        let (payload, payload_len) = if let Some(connected) = connected.as_ref() {
+            (
+                connected as *const _ as *mut c_types::c_void,
+                core::mem::size_of_val(connected),
+            )
+        } else if let Some(disconnected) = disconnected.as_ref() {
+            (
+                disconnected as *const _ as *mut c_types::c_void,
+                core::mem::size_of_val(disconnected),
+            )
+        } else {
+            (ptr::null_mut(), 0)
+        };
+
+        if let Err(err) = esp!(unsafe {
+            esp_event_post(
+                WIFI_STATE_EVENT.as_ptr() as *const _,
+                event_id,
+                payload,
+                payload_len as _,
+                0,
+            )
+        }) {
+            warn!("Failed to post state event {}: {}", event_id, err);
+        }
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for WifiState {
+    fn source() -> *const c_types::c_char {
+        WIFI_STATE_EVENT.as_ptr() as *const _
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for WifiState {
+    fn from(data: EspEventFetchData) -> Self {
+        match data.event_id {
+            WIFI_STATE_EVENT_STOPPED => WifiState::Stopped,
+            WIFI_STATE_EVENT_SCANNING => WifiState::Scanning,
+            WIFI_STATE_EVENT_CONNECTING => WifiState::Connecting,
+            WIFI_STATE_EVENT_CONNECTED => {
+                let payload: wifi_event_sta_connected_t = unsafe { data.as_payload() };
+
+                WifiState::Connected {
+                    ssid: String::from_utf8_lossy(&payload.ssid[..payload.ssid_len as usize])
+                        .into_owned(),
+                    bssid: payload.bssid,
+                    channel: payload.channel,
+                }
+            }
+            WIFI_STATE_EVENT_DISCONNECTED => {
+                let payload: wifi_event_sta_disconnected_t = unsafe { data.as_payload() };
+
+                WifiState::Disconnected {
+                    reason: if payload.reason != 0 {
+                        Some(payload.reason)
+                    } else {
+                        None
+                    },
+                }
+            }
+            other => WifiState::Other(other),
+        }
+    }
+}
+
+// Reassociations to another AP of the same network are posted on the system event loop with this base
+static WIFI_ROAM_EVENT: &str = "WIFI_ROAM_EVENT\0";
+
+const WIFI_ROAM_EVENT_ROAMED: i32 = 0;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WifiRoamEvent {
+    Roamed { from: [u8; 6], to: [u8; 6] },
+    Other(i32),
+}
+
+#[cfg(feature = "experimental")]
+impl EspEventSubscribeMetadata for WifiRoamEvent {
+    fn source() -> *const c_types::c_char {
+        WIFI_ROAM_EVENT.as_ptr() as *const _
+    }
+}
+
+#[cfg(feature = "experimental")]
+impl From<EspEventFetchData> for WifiRoamEvent {
+    fn from(data: EspEventFetchData) -> Self {
+        match data.event_id {
+            WIFI_ROAM_EVENT_ROAMED => {
+                let [from, to]: [[u8; 6]; 2] = unsafe { data.as_payload() };
+
+                WifiRoamEvent::Roamed { from, to }
+            }
+            other => WifiRoamEvent::Other(other),
+        }
+    }
+}
+
+struct Reconnect {
+    policy: ReconnectPolicy,
+    attempt: u32,
+    timer: esp_timer_handle_t,
+}
+
+static TAKEN: mutex::Mutex<bool> = mutex::Mutex::new(false);
+
+struct Shared {
+    client_ip_conf: Option<ipv4::ClientConfiguration>,
+    router_ip_conf: Option<ipv4::RouterConfiguration>,
+
+    status: Status,
+    operating: bool,
+
+    sta_netif: Option<*mut esp_netif_t>,
+    ap_netif: Option<*mut esp_netif_t>,
+
+    scan_done: bool,
+    scan_waker: Option<Waker>,
+    status_waker: Option<Waker>,
+
+    reconnect: Option<Reconnect>,
+
+    state: WifiState,
+
+    // The AP of the last connection, cleared when the STA is stopped
+    bssid: Option<[u8; 6]>,
+
+    // Re-applied whenever the STA gets an IP, as DHCP overrides the DNS servers
+    client_dns: vec::Vec<(DnsType, ipv4::Ipv4Addr)>,
+
+    // Re-applied whenever the AP is started, as the AP netif is only up from then on
+    #[cfg(esp_idf_config_lwip_ipv4_napt)]
+    ap_napt: bool,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Self {
+            client_ip_conf: None,
+            router_ip_conf: None,
+            status: Status(ClientStatus::Stopped, ApStatus::Stopped),
+            operating: false,
+            sta_netif: None,
+            ap_netif: None,
+            scan_done: false,
+            scan_waker: None,
+            status_waker: None,
+            reconnect: None,
+            state: WifiState::Stopped,
+            bssid: None,
+            client_dns: vec::Vec::new(),
+            #[cfg(esp_idf_config_lwip_ipv4_napt)]
+            ap_napt: false,
+        }
+    }
+}
+
+unsafe impl Send for Shared {}
+
+pub struct EspWifi {
+    netif_stack: Arc<EspNetifStack>,
+    _sys_loop_stack: Arc<EspSysLoopStack>,
+    _nvs: Arc<EspDefaultNvs>,
+
+    sta_netif: Option<EspNetif>,
+    ap_netif: Option<EspNetif>,
+
+    client_hostname: Option<String>,
+    client_dhcp: Option<DhcpClientConfiguration>,
+    client_route_priority: Option<u32>,
+
+    // ESP-IDF keeps pointers to the certificates rather than copying them
+    enterprise_credentials: Option<EnterpriseCredentials>,
+
+    listen_interval: u16,
+
+    scan_method: ScanMethod,
+
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    fast_roaming: FastRoamingConfig,
+
+    ap_beacon_interval: u16,
+    ap_dhcp_server: Option<DhcpServerConfiguration>,
+    #[cfg(all(esp_idf_version_major = "5", not(esp_idf_version = "5.0")))]
+    ap_dtim_period: u8,
+
+    #[cfg(esp_idf_esp_wifi_ftm_enable)]
+    ftm_responder: bool,
+
+    vendor_ie_callback: Option<Box<Box<dyn FnMut(&VendorIeFrame) + Send + 'static>>>,
+    // The replaced callbacks, which the driver task might still be running until the driver
+    // is deinitialized
+    retired_vendor_ie_callbacks: vec::Vec<Box<Box<dyn FnMut(&VendorIeFrame) + Send + 'static>>>,
+
+    initialized: bool,
+
+    shared: Box<Waitable<Shared>>,
+}
+
+impl EspWifi {
+    pub fn new(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        nvs: Arc<EspDefaultNvs>,
+    ) -> Result<Self, EspError> {
+        let mut taken = TAKEN.lock();
+
+        if *taken {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        let wifi = Self::init(netif_stack, sys_loop_stack, nvs)?;
+
+        *taken = true;
+        Ok(wifi)
+    }
+
+    fn init(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        nvs: Arc<EspDefaultNvs>,
+    ) -> Result<Self, EspError> {
+        let mut wifi = Self {
+            netif_stack,
+            _sys_loop_stack: sys_loop_stack,
+            _nvs: nvs,
+            sta_netif: None,
+            ap_netif: None,
+            client_hostname: None,
+            client_dhcp: None,
+            client_route_priority: None,
+            enterprise_credentials: None,
+            listen_interval: 0,
+            scan_method: Default::default(),
+            #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+            fast_roaming: Default::default(),
+            ap_beacon_interval: MIN_BEACON_INTERVAL,
+            ap_dhcp_server: None,
+            #[cfg(all(esp_idf_version_major = "5", not(esp_idf_version = "5.0")))]
+            ap_dtim_period: 1,
+            #[cfg(esp_idf_esp_wifi_ftm_enable)]
+            ftm_responder: false,
+            vendor_ie_callback: None,
+            retired_vendor_ie_callbacks: vec::Vec::new(),
+            initialized: false,
+            shared: Box::new(Waitable::new(Default::default())),
+        };
+
+        wifi.driver_init()?;
+
+        info!("Initialization complete");
+
+        Ok(wifi)
+    }
+
+    fn driver_init(&mut self) -> Result<(), EspError> {
+        unsafe {
+            let cfg = wifi_init_config_t {
+                event_handler: Some(esp_event_send_internal),
+                osi_funcs: &mut g_wifi_osi_funcs,
+                wpa_crypto_funcs: g_wifi_default_wpa_crypto_funcs,
+                static_rx_buf_num: 10,
+                dynamic_rx_buf_num: 32,
+                tx_buf_type: 1,
+                static_tx_buf_num: 0,
+                dynamic_tx_buf_num: 32,
+                csi_enable: 0,
+                ampdu_rx_enable: 1,
+                ampdu_tx_enable: 1,
+                nvs_enable: 0,
+                nano_enable: 0,
+                //tx_ba_win: 6,
+                rx_ba_win: 6,
+                wifi_task_core_id: 0,
+                beacon_max_len: 752,
+                mgmt_sbuf_num: 32,
+                feature_caps: 1, // CONFIG_FEATURE_WPA3_SAE_BIT
+                magic: 0x1F2F3F4F,
+                ..Default::default()
+            };
+            esp!(esp_wifi_init(&cfg))?;
+
+            info!("Driver initialized");
+
+            let shared_ref: *mut _ = &mut *self.shared;
+
+            esp!(esp_event_handler_register(
+                WIFI_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                shared_ref as *mut c_types::c_void
+            ))?;
+            esp!(esp_event_handler_register(
+                IP_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                shared_ref as *mut c_types::c_void
+            ))?;
+
+            info!("Event handlers registered");
+        }
+
+        self.initialized = true;
+
+        Ok(())
+    }
+
+    // Tears down the driver along with the netifs and the event handlers. The reconnect policy
+    // and the vendor IE callback are cleared as well.
+    // Use `reinit` to bring the driver back with a new configuration
+    pub fn deinit(&mut self) -> Result<(), EspError> {
+        if self.initialized {
+            self.clear_all()?;
+            self.initialized = false;
+        }
+
+        Ok(())
+    }
+
+    // Re-initializes the driver - e.g. to switch from a provisioning SoftAP to a plain STA
+    // configuration - without having to drop this instance
+    pub fn reinit(&mut self, conf: &Configuration) -> Result<(), EspError> {
+        info!("Re-initializing with configuration: {:?}", conf);
+
+        self.deinit()?;
+        self.driver_init()?;
+
+        self.set_configuration(conf)?;
+
+        info!("Re-initialization complete");
+
+        Ok(())
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
+    // Used when the client configuration is set with `AuthMethod::WPA2Enterprise`
+    pub fn set_enterprise_credentials(
         &mut self,
-        conf: &Option<ipv4::ClientConfiguration>,
+        credentials: Option<EnterpriseCredentials>,
     ) -> Result<(), EspError> {
-        Self::netif_unbind(self.sta_netif.as_mut())?;
+        // Make sure that ESP-IDF does not keep pointers to the credentials being replaced
+        Self::set_enterprise_conf(None)?;
 
-        if let Some(conf) = conf {
-            let mut iconf = InterfaceConfiguration::wifi_default_client();
-            iconf.ip_configuration = InterfaceIpConfiguration::Client(conf.clone());
+        self.enterprise_credentials = credentials;
 
-            info!("Setting STA interface configuration: {:?}", iconf);
+        Ok(())
+    }
 
-            let netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+    pub fn get_power_save(&self) -> Result<PowerSaveMode, EspError> {
+        let mut mode: wifi_ps_type_t = 0;
 
-            esp!(unsafe { esp_netif_attach_wifi_station(netif.1) })?;
-            esp!(unsafe { esp_wifi_set_default_wifi_sta_handlers() })?;
+        esp!(unsafe { esp_wifi_get_ps(&mut mode) })?;
 
-            self.sta_netif = Some(netif);
+        Ok(mode.into())
+    }
 
-            info!("STA IP configuration done");
-        } else {
-            self.sta_netif = None;
+    pub fn set_power_save(&mut self, mode: PowerSaveMode) -> Result<(), EspError> {
+        info!("Setting power save mode: {:?}", mode);
+
+        esp!(unsafe { esp_wifi_set_ps(mode.into()) })
+    }
+
+    pub fn get_max_tx_power(&self) -> Result<f32, EspError> {
+        let mut power: i8 = 0;
+
+        esp!(unsafe { esp_wifi_get_max_tx_power(&mut power) })?;
+
+        Ok(power as f32 / 4.0)
+    }
+
+    // Only possible while the driver is started
+    pub fn set_max_tx_power(&mut self, dbm: f32) -> Result<(), EspError> {
+        let power = (dbm * 4.0) as i32;
+
+        if power < MIN_TX_POWER as i32 || power > MAX_TX_POWER as i32 {
+            warn!(
+                "Max TX power {} dBm out of range [{}, {}] dBm",
+                dbm,
+                MIN_TX_POWER as f32 / 4.0,
+                MAX_TX_POWER as f32 / 4.0
+            );
+
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        info!("Setting max TX power: {} dBm", dbm);
+
+        esp!(unsafe { esp_wifi_set_max_tx_power(power as i8) })
+    }
+
+    pub fn get_country(&self) -> Result<CountryInfo, EspError> {
+        let mut country: wifi_country_t = Default::default();
+
+        esp!(unsafe { esp_wifi_get_country(&mut country) })?;
+
+        Ok(Newtype(&country).into())
+    }
+
+    pub fn set_country(&mut self, country: &CountryInfo) -> Result<(), EspError> {
+        info!("Setting country: {:?}", country);
+
+        esp!(unsafe { esp_wifi_set_country(&Newtype::<wifi_country_t>::from(country).0) })
+    }
+
+    // Uses the ESP-IDF channel and power tables of the country, rather than explicit ones
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    pub fn set_country_code(
+        &mut self,
+        code: &str,
+        ieee80211d_enabled: bool,
+    ) -> Result<(), EspError> {
+        info!("Setting country code: {}", code);
+
+        let mut rcs = RawCstrs::new();
+
+        esp!(unsafe { esp_wifi_set_country_code(rcs.as_ptr(code), ieee80211d_enabled) })
+    }
+
+    // Fails with `ESP_ERR_WIFI_NOT_CONNECT` unless the STA is connected
+    pub fn sta_info(&self) -> Result<StaInfo, EspError> {
+        let mut ap_info: wifi_ap_record_t = Default::default();
+
+        esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) })?;
+
+        let mut ap_protocols = EnumSet::empty();
+
+        if ap_info.phy_11b() != 0 {
+            ap_protocols |= WifiProtocol::P802D11B;
+        }
+
+        if ap_info.phy_11g() != 0 {
+            ap_protocols |= WifiProtocol::P802D11G;
+        }
+
+        if ap_info.phy_11n() != 0 {
+            ap_protocols |= WifiProtocol::P802D11N;
+        }
+
+        if ap_info.phy_lr() != 0 {
+            ap_protocols |= WifiProtocol::LR;
+        }
+
+        Ok(StaInfo {
+            ssid: from_cstr(&ap_info.ssid).into(),
+            bssid: ap_info.bssid,
+            channel: ap_info.primary,
+            secondary_channel: Newtype(ap_info.second).into(),
+            rssi: ap_info.rssi,
+            auth_method: Newtype(ap_info.authmode).try_into().ok(),
+            protocols: ap_protocols & self.get_protocols(WifiInterface::Client)?,
+        })
+    }
+
+    // Arms `WIFI_EVENT_STA_BSS_RSSI_LOW`, which fires once when the RSSI of the current AP
+    // drops below the threshold and has to be re-armed afterwards
+    pub fn set_rssi_threshold(&mut self, rssi: i8) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_set_rssi_threshold(rssi as _) })
+    }
+
+    // Rescans while staying connected and reassociates to the strongest AP with the same SSID,
+    // if the current AP is weaker than the threshold. The new AP is pinned by BSSID and channel.
+    // Returns the BSSID of the new AP, if the STA roamed
+    pub fn roam(&mut self, conf: &RoamingConfig) -> Result<Option<[u8; 6]>, EspError> {
+        let current = self.sta_info()?;
+
+        let result = if current.rssi < conf.rssi_threshold {
+            info!(
+                "RSSI {} of AP {:?} below {}, looking for a stronger AP",
+                current.rssi, current.bssid, conf.rssi_threshold
+            );
+
+            let scan_conf = ScanConfig {
+                ssid: Some(current.ssid.clone()),
+                ..Default::default()
+            };
+
+            Self::do_scan_channel(Some(&scan_conf), 0, true)?;
+
+            let candidate = self
+                .do_get_scan_results::<EspAccessPointInfo>()?
+                .into_iter()
+                .filter(|ap| ap.ssid == current.ssid && ap.bssid != current.bssid)
+                .filter(|ap| ap.rssi as i16 >= current.rssi as i16 + conf.min_rssi_gain as i16)
+                .max_by_key(|ap| ap.rssi);
+
+            if let Some(ap) = candidate {
+                info!(
+                    "Roaming to AP {:?} on channel {} with RSSI {}",
+                    ap.bssid, ap.channel, ap.rssi
+                );
+
+                let mut wifi_config: wifi_config_t = Default::default();
+                esp!(unsafe {
+                    esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config)
+                })?;
+
+                unsafe {
+                    wifi_config.sta.bssid_set = true;
+                    wifi_config.sta.bssid = ap.bssid;
+                    wifi_config.sta.channel = ap.channel;
+                }
+
+                esp!(unsafe {
+                    esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config)
+                })?;
+
+                // The STA reconnects - now to the pinned AP - once the disconnect is reported
+                esp!(unsafe { esp_wifi_disconnect() })?;
+
+                Some(ap.bssid)
+            } else {
+                info!("No stronger AP found");
+
+                None
+            }
+        } else {
+            None
+        };
+
+        self.set_rssi_threshold(conf.rssi_threshold)?;
+
+        Ok(result)
+    }
+
+    pub fn get_mac(&self, interface: WifiInterface) -> Result<[u8; 6], EspError> {
+        let mut mac = [0_u8; 6];
+
+        esp!(unsafe { esp_wifi_get_mac(interface.into(), mac.as_mut_ptr()) })?;
+
+        Ok(mac)
+    }
+
+    // The driver has to be initialized, and the STA and the AP can't share the same address.
+    // Kept until the driver is deinitialized or the MAC is set again
+    pub fn set_mac(&mut self, interface: WifiInterface, mac: &[u8; 6]) -> Result<(), EspError> {
+        check_mac(mac)?;
+
+        if !is_locally_administered_mac(mac) {
+            warn!(
+                "MAC {:?} is not locally administered and might clash with another device",
+                mac
+            );
+        }
+
+        esp!(unsafe { esp_wifi_set_mac(interface.into(), mac.as_ptr() as *mut _) })?;
+
+        info!("{:?} MAC set to {:?}", interface, mac);
+
+        Ok(())
+    }
+
+    pub fn get_protocols(
+        &self,
+        interface: WifiInterface,
+    ) -> Result<EnumSet<WifiProtocol>, EspError> {
+        let mut bitmap: u8 = 0;
+
+        esp!(unsafe { esp_wifi_get_protocol(interface.into(), &mut bitmap) })?;
+
+        Ok(Newtype::<EnumSet<WifiProtocol>>::from(bitmap).0)
+    }
+
+    pub fn set_protocols(
+        &mut self,
+        interface: WifiInterface,
+        protocols: EnumSet<WifiProtocol>,
+    ) -> Result<(), EspError> {
+        info!("Setting {:?} protocols: {:?}", interface, protocols);
+
+        esp!(unsafe { esp_wifi_set_protocol(interface.into(), Newtype(protocols).into()) })
+    }
+
+    #[allow(non_upper_case_globals)]
+    pub fn get_bandwidth(&self, interface: WifiInterface) -> Result<Bandwidth, EspError> {
+        let mut bandwidth: wifi_bandwidth_t = 0;
+
+        esp!(unsafe { esp_wifi_get_bandwidth(interface.into(), &mut bandwidth) })?;
+
+        Ok(match bandwidth {
+            wifi_bandwidth_t_WIFI_BW_HT40 => Bandwidth::HT40,
+            _ => Bandwidth::HT20,
+        })
+    }
+
+    // HT40 is only possible when 802.11n is among the protocols of the interface
+    pub fn set_bandwidth(
+        &mut self,
+        interface: WifiInterface,
+        bandwidth: Bandwidth,
+    ) -> Result<(), EspError> {
+        info!("Setting {:?} bandwidth: {:?}", interface, bandwidth);
+
+        esp!(unsafe {
+            esp_wifi_set_bandwidth(
+                interface.into(),
+                match bandwidth {
+                    Bandwidth::HT20 => wifi_bandwidth_t_WIFI_BW_HT20,
+                    Bandwidth::HT40 => wifi_bandwidth_t_WIFI_BW_HT40,
+                },
+            )
+        })
+    }
+
+    // The frame is sent as is, without the FCS which is appended by the hardware.
+    // With `use_sys_seq`, the sequence number in the frame is overwritten by the driver
+    pub fn transmit_raw(
+        &mut self,
+        interface: WifiInterface,
+        frame: &[u8],
+        use_sys_seq: bool,
+    ) -> Result<(), EspError> {
+        if frame.len() < MIN_RAW_FRAME_LEN || frame.len() > MAX_RAW_FRAME_LEN {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        esp!(unsafe {
+            esp_wifi_80211_tx(
+                interface.into(),
+                frame.as_ptr() as *const _,
+                frame.len() as _,
+                use_sys_seq,
+            )
+        })
+    }
+
+    // Adds the element to the outgoing management frames of the given type, replacing any
+    // element previously set under the same id
+    pub fn set_vendor_ie(
+        &mut self,
+        ie_type: VendorIeType,
+        id: VendorIeId,
+        ie: &VendorIe,
+    ) -> Result<(), EspError> {
+        if ie.payload.len() > MAX_VENDOR_IE_PAYLOAD_LEN {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        info!("Setting vendor IE {:?}/{:?}: {:?}", ie_type, id, ie);
+
+        let data = ie.to_bytes();
+
+        esp!(unsafe {
+            esp_wifi_set_vendor_ie(true, ie_type.into(), id.into(), data.as_ptr() as *const _)
+        })
+    }
+
+    pub fn clear_vendor_ie(
+        &mut self,
+        ie_type: VendorIeType,
+        id: VendorIeId,
+    ) -> Result<(), EspError> {
+        info!("Clearing vendor IE {:?}/{:?}", ie_type, id);
+
+        esp!(unsafe { esp_wifi_set_vendor_ie(false, ie_type.into(), id.into(), ptr::null()) })
+    }
+
+    // The callback is invoked from the Wi-Fi driver task for every vendor IE received in
+    // beacons, probe requests/responses and association requests/responses
+    pub fn set_vendor_ie_callback(
+        &mut self,
+        callback: impl FnMut(&VendorIeFrame) + Send + 'static,
+    ) -> Result<(), EspError> {
+        let callback: Box<dyn FnMut(&VendorIeFrame) + Send + 'static> = Box::new(callback);
+        let mut callback = Box::new(callback);
+
+        let callback_ptr: *mut _ = &mut *callback;
+
+        esp!(unsafe {
+            esp_wifi_set_vendor_ie_cb(
+                Some(Self::handle_vendor_ie),
+                callback_ptr as *mut c_types::c_void,
+            )
+        })?;
+
+        if let Some(old) = self.vendor_ie_callback.replace(callback) {
+            self.retired_vendor_ie_callbacks.push(old);
+        }
+
+        info!("Vendor IE callback set");
+
+        Ok(())
+    }
+
+    pub fn clear_vendor_ie_callback(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_set_vendor_ie_cb(None, ptr::null_mut()) })?;
+
+        if let Some(old) = self.vendor_ie_callback.take() {
+            self.retired_vendor_ie_callbacks.push(old);
+        }
+
+        info!("Vendor IE callback cleared");
+
+        Ok(())
+    }
+
+    unsafe extern "C" fn handle_vendor_ie(
+        ctx: *mut c_types::c_void,
+        ie_type: wifi_vendor_ie_type_t,
+        sa: *const u8,
+        vnd_ie: *const vendor_ie_data_t,
+        rssi: c_types::c_int,
+    ) {
+        let callback = (ctx as *mut Box<dyn FnMut(&VendorIeFrame) + Send + 'static>)
+            .as_mut()
+            .unwrap();
+        let vnd_ie = vnd_ie.as_ref().unwrap();
+
+        // The length covers the OUI and the OUI type as well
+        let payload_len = (vnd_ie.length as usize).saturating_sub(4);
+
+        let mut source = [0_u8; 6];
+        source.copy_from_slice(core::slice::from_raw_parts(sa, 6));
+
+        callback(&VendorIeFrame {
+            ie_type: ie_type.into(),
+            source,
+            rssi: rssi as _,
+            oui: vnd_ie.vendor_oui,
+            oui_type: vnd_ie.vendor_oui_type,
+            payload: vnd_ie.payload.as_slice(payload_len),
+        });
+    }
+
+    pub fn get_ap_dhcp_server_conf(&self) -> Option<&DhcpServerConfiguration> {
+        self.ap_dhcp_server.as_ref()
+    }
+
+    // Applied to the AP netif if its DHCP server is enabled; the ESP-IDF defaults are used
+    // without a configuration. Takes effect the next time the AP configuration is set
+    pub fn set_ap_dhcp_server_conf(&mut self, conf: Option<DhcpServerConfiguration>) {
+        self.ap_dhcp_server = conf;
+    }
+
+    #[cfg(esp_idf_config_lwip_ipv4_napt)]
+    pub fn get_ap_napt(&self) -> bool {
+        self.shared.get(|shared| shared.ap_napt)
+    }
+
+    // Turns the device into a router for the SoftAP clients, forwarding their traffic to the
+    // upstream network of the STA interface. Requires the Mixed configuration and lwIP built
+    // with `CONFIG_LWIP_IP_FORWARD` and `CONFIG_LWIP_IPV4_NAPT`
+    #[cfg(esp_idf_config_lwip_ipv4_napt)]
+    pub fn set_ap_napt(&mut self, enable: bool) {
+        info!("Setting AP NAPT: {}", enable);
+
+        let started = self.shared.modify(|shared| {
+            shared.ap_napt = enable;
+
+            (false, matches!(shared.status.1, ApStatus::Started(_)))
+        });
+
+        if let Some(netif) = self.ap_netif.as_mut().filter(|_| started) {
+            netif.enable_napt(enable);
+        }
+    }
+
+    pub fn get_ap_beacon_interval(&self) -> u16 {
+        self.ap_beacon_interval
+    }
+
+    // In TUs (1024 us); takes effect the next time the AP configuration is set
+    pub fn set_ap_beacon_interval(&mut self, beacon_interval: u16) -> Result<(), EspError> {
+        if !(MIN_BEACON_INTERVAL..=MAX_BEACON_INTERVAL).contains(&beacon_interval) {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        self.ap_beacon_interval = beacon_interval;
+
+        Ok(())
+    }
+
+    #[cfg(all(esp_idf_version_major = "5", not(esp_idf_version = "5.0")))]
+    pub fn get_ap_dtim_period(&self) -> u8 {
+        self.ap_dtim_period
+    }
+
+    // In beacon intervals (1 - 10); takes effect the next time the AP configuration is set
+    #[cfg(all(esp_idf_version_major = "5", not(esp_idf_version = "5.0")))]
+    pub fn set_ap_dtim_period(&mut self, dtim_period: u8) -> Result<(), EspError> {
+        if !(1..=10).contains(&dtim_period) {
+            esp!(ESP_ERR_INVALID_ARG as i32)?;
+        }
+
+        self.ap_dtim_period = dtim_period;
+
+        Ok(())
+    }
+
+    // Takes effect the next time the AP configuration is set
+    #[cfg(esp_idf_esp_wifi_ftm_enable)]
+    pub fn set_ftm_responder(&mut self, enabled: bool) {
+        self.ftm_responder = enabled;
+    }
+
+    // Compensates for the antenna and RF path delays of this device, in centimeters
+    #[cfg(esp_idf_esp_wifi_ftm_enable)]
+    pub fn set_ftm_responder_offset(&mut self, offset_cm: i16) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_ftm_resp_set_offset(offset_cm) })
+    }
+
+    // Subscribe to `WifiState` on the system event loop to get notified of the transitions
+    pub fn get_state(&self) -> WifiState {
+        self.shared.get(|shared| shared.state.clone())
+    }
+
+    pub fn get_reconnect_policy(&self) -> Option<ReconnectPolicy> {
+        self.shared
+            .get(|shared| shared.reconnect.as_ref().map(|reconnect| reconnect.policy))
+    }
+
+    // Without a policy, the STA reconnects immediately and indefinitely after a disconnect
+    pub fn set_reconnect_policy(
+        &mut self,
+        policy: Option<ReconnectPolicy>,
+    ) -> Result<(), EspError> {
+        info!("Setting reconnect policy: {:?}", policy);
+
+        let timer = self
+            .shared
+            .get(|shared| shared.reconnect.as_ref().map(|reconnect| reconnect.timer));
+
+        let timer = match (timer, policy.is_some()) {
+            (Some(timer), true) => timer,
+            (Some(timer), false) => {
+                Self::delete_reconnect_timer(timer)?;
+
+                ptr::null_mut()
+            }
+            (None, true) => {
+                let mut timer: esp_timer_handle_t = ptr::null_mut();
+
+                let shared_ref: *mut _ = &mut *self.shared;
+
+                esp!(unsafe {
+                    esp_timer_create(
+                        &esp_timer_create_args_t {
+                            callback: Some(Self::on_reconnect_timer),
+                            name: b"wifi_reconnect\0" as *const _ as *const _,
+                            arg: shared_ref as *mut c_types::c_void,
+                            dispatch_method: esp_timer_dispatch_t_ESP_TIMER_TASK,
+                            skip_unhandled_events: false,
+                        },
+                        &mut timer as *mut _,
+                    )
+                })?;
+
+                timer
+            }
+            (None, false) => ptr::null_mut(),
+        };
+
+        self.shared.modify(|shared| {
+            shared.reconnect = policy.map(|policy| Reconnect {
+                policy,
+                attempt: shared
+                    .reconnect
+                    .as_ref()
+                    .map(|reconnect| reconnect.attempt)
+                    .unwrap_or(0),
+                timer,
+            });
+
+            (false, ())
+        });
+
+        Ok(())
+    }
+
+    fn delete_reconnect_timer(timer: esp_timer_handle_t) -> Result<(), EspError> {
+        let err = unsafe { esp_timer_stop(timer) };
+        if err != ESP_ERR_INVALID_STATE as i32 {
+            esp!(err)?;
+        }
+
+        esp!(unsafe { esp_timer_delete(timer) })
+    }
+
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    pub fn get_fast_roaming(&self) -> FastRoamingConfig {
+        self.fast_roaming
+    }
+
+    // Takes effect on the next connection
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    pub fn set_fast_roaming(&mut self, conf: FastRoamingConfig) -> Result<(), EspError> {
+        info!("Setting fast roaming configuration: {:?}", conf);
+
+        #[cfg(not(esp_idf_version_major = "5"))]
+        if conf.fast_transition {
+            // 802.11r is only available since ESP-IDF 5
+            esp!(ESP_ERR_NOT_SUPPORTED as i32)?;
+        }
+
+        self.fast_roaming = conf;
+
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        Self::apply_fast_roaming(unsafe { &mut wifi_config.sta }, &conf);
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })
+    }
+
+    #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+    fn apply_fast_roaming(sta: &mut wifi_sta_config_t, conf: &FastRoamingConfig) {
+        sta.set_rm_enabled(conf.radio_measurement as _);
+        sta.set_btm_enabled(conf.bss_transition as _);
+
+        #[cfg(esp_idf_version_major = "5")]
+        sta.set_ft_enabled(conf.fast_transition as _);
+    }
+
+    pub fn get_scan_method(&self) -> ScanMethod {
+        self.scan_method
+    }
+
+    // Takes effect the next time the client configuration is set
+    pub fn set_scan_method(&mut self, scan_method: ScanMethod) {
+        self.scan_method = scan_method;
+    }
+
+    // Hidden APs only answer probe requests carrying their SSID, so with `targeted_scan` such
+    // a scan is done first, and the strongest AP found is pinned by BSSID and channel unless
+    // these are already set in the configuration. All channels are scanned when connecting with
+    // this configuration
+    pub fn set_hidden_client_configuration(
+        &mut self,
+        conf: &ClientConfiguration,
+        targeted_scan: bool,
+    ) -> Result<(), EspError> {
+        info!("Setting hidden network configuration: {:?}", conf);
+
+        let mut conf = conf.clone();
+
+        if targeted_scan && conf.bssid.is_none() {
+            let scan_conf = ScanConfig {
+                ssid: Some(conf.ssid.clone()),
+                channels: conf.channel.into_iter().collect(),
+                show_hidden: true,
+                ..Default::default()
+            };
+
+            let ap = self
+                .scan_with_config(&scan_conf)?
+                .into_iter()
+                .filter(|ap| ap.ssid == conf.ssid)
+                .max_by_key(|ap| ap.rssi);
+
+            if let Some(ap) = ap {
+                info!(
+                    "Found hidden network AP {:?} on channel {}",
+                    ap.bssid, ap.channel
+                );
+
+                conf.bssid = Some(ap.bssid);
+                conf.channel = Some(ap.channel);
+            } else {
+                info!("Hidden network not found by the targeted scan");
+            }
+        }
+
+        // Only for this configuration, the one set with `set_scan_method` is kept for the others
+        let scan_method = core::mem::replace(&mut self.scan_method, ScanMethod::AllChannels);
+
+        let result = self.set_configuration(&Configuration::Client(conf));
+
+        self.scan_method = scan_method;
+
+        result
+    }
+
+    pub fn get_listen_interval(&self) -> u16 {
+        self.listen_interval
+    }
+
+    // In units of AP beacon intervals; 0 selects the ESP-IDF default (3).
+    // Only relevant with `PowerSaveMode::MaxModem`, and takes effect on the next connection
+    pub fn set_listen_interval(&mut self, listen_interval: u16) -> Result<(), EspError> {
+        info!("Setting STA listen interval: {}", listen_interval);
+
+        self.listen_interval = listen_interval;
+
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        unsafe {
+            wifi_config.sta.listen_interval = listen_interval;
+        }
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })
+    }
+
+    // Configures the STA interface - or disables it with `None` - leaving the AP interface and
+    // its connected stations untouched. The STA is (re)connected if Wi-Fi is running
+    pub fn set_client_configuration(
+        &mut self,
+        conf: Option<&ClientConfiguration>,
+    ) -> Result<(), EspError> {
+        info!("Setting STA interface configuration: {:?}", conf);
+
+        let (sta, ap) = Self::get_mode()?;
+
+        if conf.is_none() && !ap {
+            return self.set_configuration(&Configuration::None);
+        }
+
+        let running = self.shared.modify(|shared| {
+            shared.operating = false;
+
+            (
+                false,
+                !matches!(
+                    shared.status,
+                    Status(ClientStatus::Stopped, ApStatus::Stopped)
+                ),
+            )
+        });
+
+        if sta && running {
+            esp!(unsafe { esp_wifi_disconnect() })?;
+
+            self.wait_transition(|s| {
+                matches!(
+                    s.0,
+                    ClientStatus::Stopped
+                        | ClientStatus::Started(ClientConnectionStatus::Disconnected)
+                )
+            })?;
+        }
+
+        if let Some(conf) = conf {
+            self.shared.modify(|shared| {
+                shared.operating = true;
+
+                if !(sta && running) {
+                    shared.status.0 = ClientStatus::Starting;
+                }
+
+                (false, ())
+            });
+
+            Self::set_mode(true, ap)?;
+            self.set_client_conf(conf)?;
+
+            if !running {
+                esp!(unsafe { esp_wifi_start() })?;
+            } else if sta {
+                self.shared.modify(|shared| {
+                    shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connecting);
+
+                    (false, ())
+                });
+
+                esp!(unsafe { esp_wifi_connect() })?;
+            }
+
+            self.wait_transition(|s| !s.is_transitional())?;
+        } else {
+            Self::set_mode(false, ap)?;
+            self.set_client_ip_conf(&None)?;
+
+            if running {
+                self.wait_transition(|s| s.0 == ClientStatus::Stopped)?;
+            }
+        }
+
+        info!("STA interface configuration done");
+
+        Ok(())
+    }
+
+    // Configures the AP interface - or disables it with `None` - leaving the STA interface and
+    // its connection untouched. The AP is started if Wi-Fi is running
+    pub fn set_ap_configuration(
+        &mut self,
+        conf: Option<&AccessPointConfiguration>,
+    ) -> Result<(), EspError> {
+        info!("Setting AP interface configuration: {:?}", conf);
+
+        let (sta, ap) = Self::get_mode()?;
+
+        if conf.is_none() && !sta {
+            return self.set_configuration(&Configuration::None);
+        }
+
+        let running = self.shared.get(|shared| {
+            !matches!(
+                shared.status,
+                Status(ClientStatus::Stopped, ApStatus::Stopped)
+            )
+        });
+
+        if let Some(conf) = conf {
+            if !(ap && running) {
+                self.shared.modify(|shared| {
+                    shared.status.1 = ApStatus::Starting;
+
+                    (false, ())
+                });
+            }
+
+            Self::set_mode(sta, true)?;
+            self.set_ap_conf(conf)?;
+
+            if !running {
+                self.shared.modify(|shared| {
+                    shared.operating = sta;
+
+                    (false, ())
+                });
+
+                esp!(unsafe { esp_wifi_start() })?;
+            }
+
+            self.wait_transition(|s| !s.is_transitional())?;
+        } else {
+            Self::set_mode(sta, false)?;
+            self.set_router_ip_conf(&None)?;
+
+            if running {
+                self.wait_transition(|s| s.1 == ApStatus::Stopped)?;
+            }
+        }
+
+        info!("AP interface configuration done");
+
+        Ok(())
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn get_mode() -> Result<(bool, bool), EspError> {
+        let mut mode: wifi_mode_t = 0;
+
+        esp!(unsafe { esp_wifi_get_mode(&mut mode) })?;
+
+        Ok(match mode {
+            wifi_mode_t_WIFI_MODE_STA => (true, false),
+            wifi_mode_t_WIFI_MODE_AP => (false, true),
+            wifi_mode_t_WIFI_MODE_APSTA => (true, true),
+            _ => (false, false),
+        })
+    }
+
+    fn set_mode(sta: bool, ap: bool) -> Result<(), EspError> {
+        let mode = match (sta, ap) {
+            (true, false) => wifi_mode_t_WIFI_MODE_STA,
+            (false, true) => wifi_mode_t_WIFI_MODE_AP,
+            (true, true) => wifi_mode_t_WIFI_MODE_APSTA,
+            (false, false) => wifi_mode_t_WIFI_MODE_NULL,
+        };
+
+        esp!(unsafe { esp_wifi_set_mode(mode) })?;
+
+        info!("Wifi mode {} set", mode);
+
+        Ok(())
+    }
+
+    fn wait_transition(&self, matcher: impl Fn(&Status) -> bool) -> Result<(), EspError> {
+        if self
+            .wait_status_with_timeout(Duration::from_secs(10), matcher)
+            .is_err()
+        {
+            info!("Timeout while waiting for the requested state");
+
+            esp!(ESP_ERR_TIMEOUT as i32)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_client_hostname(&self) -> Option<&str> {
+        self.client_hostname.as_deref()
+    }
+
+    // The hostname of the STA interface, reported to the DHCP server; applied right away if the
+    // interface exists, and kept across configuration changes
+    pub fn set_client_hostname(&mut self, hostname: Option<&str>) -> Result<(), EspError> {
+        if let (Some(netif), Some(hostname)) = (self.sta_netif.as_ref(), hostname) {
+            netif.set_hostname(hostname)?;
+        }
+
+        self.client_hostname = hostname.map(|hostname| hostname.to_owned());
+
+        Ok(())
+    }
+
+    pub fn get_client_route_priority(&self) -> u32 {
+        self.client_route_priority
+            .unwrap_or_else(|| InterfaceConfiguration::wifi_default_client().route_priority)
+    }
+
+    // Interfaces with a higher priority are preferred as the default route; e.g. with a priority
+    // below 60 (the Ethernet default) Ethernet is preferred while up, with a fail-over to Wi-Fi.
+    // Takes effect the next time the client configuration is set
+    pub fn set_client_route_priority(&mut self, route_priority: Option<u32>) {
+        self.client_route_priority = route_priority;
+    }
+
+    pub fn get_client_dhcp_conf(&self) -> Option<&DhcpClientConfiguration> {
+        self.client_dhcp.as_ref()
+    }
+
+    // Applied to the STA netif if it uses DHCP. Takes effect the next time the client
+    // configuration is set
+    pub fn set_client_dhcp_conf(&mut self, conf: Option<DhcpClientConfiguration>) {
+        self.client_dhcp = conf;
+    }
+
+    pub fn get_client_dns(&self, dns_type: DnsType) -> Option<ipv4::Ipv4Addr> {
+        self.shared.get(|shared| {
+            shared
+                .client_dns
+                .iter()
+                .find(|(t, _)| *t == dns_type)
+                .map(|(_, dns)| *dns)
+        })
+    }
+
+    // Overrides the DNS server of the STA interface, also across reconnects and DHCP lease
+    // renewals; `None` removes the override
+    pub fn set_client_dns(
+        &mut self,
+        dns_type: DnsType,
+        dns: Option<ipv4::Ipv4Addr>,
+    ) -> Result<(), EspError> {
+        info!("Setting STA {:?} DNS override: {:?}", dns_type, dns);
+
+        self.shared.modify(|shared| {
+            shared.client_dns.retain(|(t, _)| *t != dns_type);
+
+            if let Some(dns) = dns {
+                shared.client_dns.push((dns_type, dns));
+            }
+
+            (false, ())
+        });
+
+        if let (Some(netif), Some(dns)) = (self.sta_netif.as_mut(), dns) {
+            netif.set_dns_server(dns_type, dns)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn with_client_netif<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(Option<&EspNetif>) -> T,
+    {
+        f(self.sta_netif.as_ref())
+    }
+
+    pub fn with_client_netif_mut<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(Option<&mut EspNetif>) -> T,
+    {
+        f(self.sta_netif.as_mut())
+    }
+
+    pub fn with_router_netif<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(Option<&EspNetif>) -> T,
+    {
+        f(self.ap_netif.as_ref())
+    }
+
+    pub fn with_router_netif_mut<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(Option<&mut EspNetif>) -> T,
+    {
+        f(self.ap_netif.as_mut())
+    }
+
+    fn get_client_conf(&self) -> Result<ClientConfiguration, EspError> {
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        let mut result: ClientConfiguration = unsafe { Newtype(wifi_config.sta).try_into()? };
+        result.ip_conf = self.shared.get(|shared| shared.client_ip_conf.clone());
+
+        info!("Providing STA configuration: {:?}", &result);
+
+        Ok(result)
+    }
+
+    fn set_client_conf(&mut self, conf: &ClientConfiguration) -> Result<(), EspError> {
+        info!("Setting STA configuration: {:?}", conf);
+
+        #[allow(unused_mut)]
+        let mut wifi_config = wifi_config_t {
+            sta: wifi_sta_config_t {
+                listen_interval: self.listen_interval,
+                scan_method: self.scan_method.into(),
+                ..Newtype::<wifi_sta_config_t>::from(conf).0
+            },
+        };
+
+        #[cfg(any(esp_idf_version = "4.4", esp_idf_version_major = "5"))]
+        Self::apply_fast_roaming(unsafe { &mut wifi_config.sta }, &self.fast_roaming);
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut wifi_config) })?;
+
+        Self::set_enterprise_conf(if conf.auth_method == AuthMethod::WPA2Enterprise {
+            self.enterprise_credentials.as_ref()
+        } else {
+            None
+        })?;
+
+        self.set_client_ip_conf(&conf.ip_conf)?;
+
+        info!("STA configuration done");
+
+        Ok(())
+    }
+
+    fn set_enterprise_conf(credentials: Option<&EnterpriseCredentials>) -> Result<(), EspError> {
+        unsafe {
+            esp!(esp_wifi_sta_wpa2_ent_disable())?;
+
+            esp_wifi_sta_wpa2_ent_clear_identity();
+            esp_wifi_sta_wpa2_ent_clear_username();
+            esp_wifi_sta_wpa2_ent_clear_password();
+            esp_wifi_sta_wpa2_ent_clear_ca_cert();
+            esp_wifi_sta_wpa2_ent_clear_cert_key();
+
+            if let Some(credentials) = credentials {
+                info!(
+                    "Setting WPA2 Enterprise credentials for identity {:?}",
+                    credentials.identity
+                );
+
+                if let Some(identity) = credentials.identity.as_ref() {
+                    esp!(esp_wifi_sta_wpa2_ent_set_identity(
+                        identity.as_ptr(),
+                        identity.len() as _
+                    ))?;
+                }
+
+                if let Some(username) = credentials.username.as_ref() {
+                    esp!(esp_wifi_sta_wpa2_ent_set_username(
+                        username.as_ptr(),
+                        username.len() as _
+                    ))?;
+                }
+
+                if let Some(password) = credentials.password.as_ref() {
+                    esp!(esp_wifi_sta_wpa2_ent_set_password(
+                        password.as_ptr(),
+                        password.len() as _
+                    ))?;
+                }
+
+                if let Some(ca_cert) = credentials.ca_cert.as_ref() {
+                    esp!(esp_wifi_sta_wpa2_ent_set_ca_cert(
+                        ca_cert.as_ptr(),
+                        ca_cert.len() as _
+                    ))?;
+                }
+
+                if let (Some(client_cert), Some(client_key)) = (
+                    credentials.client_cert.as_ref(),
+                    credentials.client_key.as_ref(),
+                ) {
+                    let (key_password, key_password_len) = credentials
+                        .client_key_password
+                        .as_ref()
+                        .map(|password| (password.as_ptr(), password.len()))
+                        .unwrap_or((ptr::null(), 0));
+
+                    esp!(esp_wifi_sta_wpa2_ent_set_cert_key(
+                        client_cert.as_ptr(),
+                        client_cert.len() as _,
+                        client_key.as_ptr(),
+                        client_key.len() as _,
+                        key_password,
+                        key_password_len as _
+                    ))?;
+                }
+
+                esp!(esp_wifi_sta_wpa2_ent_enable())?;
+
+                info!("WPA2 Enterprise enabled");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_ap_conf(&self) -> Result<AccessPointConfiguration, EspError> {
+        let mut wifi_config: wifi_config_t = Default::default();
+        esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+
+        let mut result: AccessPointConfiguration = unsafe { Newtype(wifi_config.ap).try_into()? };
+        result.ip_conf = self.shared.get(|shared| shared.router_ip_conf.clone());
+
+        info!("Providing AP configuration: {:?}", &result);
+
+        Ok(result)
+    }
+
+    fn set_ap_conf(&mut self, conf: &AccessPointConfiguration) -> Result<(), EspError> {
+        info!("Setting AP configuration: {:?}", conf);
+
+        let mut wifi_config = wifi_config_t {
+            ap: Newtype::<wifi_ap_config_t>::from(conf).0,
+        };
+
+        unsafe {
+            wifi_config.ap.beacon_interval = self.ap_beacon_interval;
+        }
+
+        #[cfg(all(esp_idf_version_major = "5", not(esp_idf_version = "5.0")))]
+        unsafe {
+            wifi_config.ap.dtim_period = self.ap_dtim_period;
+        }
+
+        #[cfg(esp_idf_esp_wifi_ftm_enable)]
+        unsafe {
+            wifi_config.ap.ftm_responder = self.ftm_responder;
+        }
+
+        esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_AP, &mut wifi_config) })?;
+        self.set_router_ip_conf(&conf.ip_conf)?;
+
+        info!("AP configuration done");
+
+        Ok(())
+    }
+
+    fn set_client_ip_conf(
+        &mut self,
+        conf: &Option<ipv4::ClientConfiguration>,
+    ) -> Result<(), EspError> {
+        Self::netif_unbind(self.sta_netif.as_mut())?;
+
+        if let Some(conf) = conf {
+            let mut iconf = InterfaceConfiguration::wifi_default_client();
+            iconf.ip_configuration = InterfaceIpConfiguration::Client(conf.clone());
+            iconf.hostname = self.client_hostname.clone();
+            iconf.route_priority = self.get_client_route_priority();
+
+            info!("Setting STA interface configuration: {:?}", iconf);
+
+            let mut netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+
+            if let (ipv4::ClientConfiguration::DHCP(_), Some(dhcp_client)) =
+                (conf, self.client_dhcp.as_ref())
+            {
+                netif.set_dhcp_client_conf(dhcp_client)?;
+            }
+
+            esp!(unsafe { esp_netif_attach_wifi_station(netif.1) })?;
+            esp!(unsafe { esp_wifi_set_default_wifi_sta_handlers() })?;
+
+            self.sta_netif = Some(netif);
+
+            info!("STA IP configuration done");
+        } else {
+            self.sta_netif = None;
+
+            info!("Skipping STA IP configuration (not configured)");
+        }
+
+        let netif_ptr = self.sta_netif.as_ref().map(|sta_netif| sta_netif.1);
+
+        self.shared.modify(|shared| {
+            shared.client_ip_conf = conf.clone();
+            shared.sta_netif = netif_ptr;
+
+            (false, ())
+        });
+
+        Ok(())
+    }
+
+    fn set_router_ip_conf(
+        &mut self,
+        conf: &Option<ipv4::RouterConfiguration>,
+    ) -> Result<(), EspError> {
+        Self::netif_unbind(self.ap_netif.as_mut())?;
+
+        if let Some(conf) = conf {
+            let mut iconf = InterfaceConfiguration::wifi_default_router();
+            iconf.ip_configuration = InterfaceIpConfiguration::Router(conf.clone());
+
+            info!("Setting AP interface configuration: {:?}", iconf);
+
+            let mut netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+
+            if let Some(dhcp_server) = self.ap_dhcp_server.as_ref().filter(|_| conf.dhcp_enabled) {
+                netif.set_dhcp_server_conf(dhcp_server)?;
+            }
+
+            esp!(unsafe { esp_netif_attach_wifi_ap(netif.1) })?;
+            esp!(unsafe { esp_wifi_set_default_wifi_ap_handlers() })?;
+
+            self.ap_netif = Some(netif);
+
+            info!("AP IP configuration done");
+        } else {
+            self.ap_netif = None;
+
+            info!("Skipping AP IP configuration (not configured)");
+        }
+
+        let netif_ptr = self.ap_netif.as_ref().map(|ap_netif| ap_netif.1);
+
+        self.shared.modify(|shared| {
+            shared.router_ip_conf = conf.clone();
+            shared.ap_netif = netif_ptr;
+
+            (false, ())
+        });
+
+        Ok(())
+    }
+
+    fn wait_status(&self, matcher: impl Fn(&Status) -> bool) {
+        info!("About to wait for status");
+
+        self.shared.wait_while(|shared| !matcher(&shared.status));
+
+        info!("Waiting for status done - success");
+    }
+
+    fn wait_status_with_timeout(
+        &self,
+        dur: Duration,
+        matcher: impl Fn(&Status) -> bool,
+    ) -> Result<(), Status> {
+        info!("About to wait {:?} for status", dur);
+
+        let (timeout, status) = self.shared.wait_timeout_while_and_get(
+            dur,
+            |shared| !matcher(&shared.status),
+            |shared| shared.status.clone(),
+        );
+
+        if !timeout {
+            info!("Waiting for status done - success");
+            Ok(())
+        } else {
+            info!("Timeout while waiting for status");
+            Err(status)
+        }
+    }
+
+    fn start(&mut self, status: Status) -> Result<(), EspError> {
+        if self.request_start(status)? {
+            let result =
+                self.wait_status_with_timeout(Duration::from_secs(10), |s| !s.is_transitional());
+
+            if result.is_err() {
+                info!("Timeout while waiting for the requested state");
+
+                return Err(EspError::from(ESP_ERR_TIMEOUT as i32).unwrap());
+            }
+
+            info!("Started");
+
+            Self::netif_info("STA", self.sta_netif.as_ref())?;
+            Self::netif_info("AP", self.ap_netif.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    // Returns true if the driver was started, i.e. a new status is to be waited for
+    fn request_start(&mut self, status: Status) -> Result<bool, EspError> {
+        info!("Starting with status: {:?}", status);
+
+        self.shared.modify(|shared| {
+            shared.status = status.clone();
+            shared.operating = status.is_operating();
+
+            (false, ())
+        });
+
+        if status.is_operating() {
+            info!("Status is of operating type, starting");
+
+            esp!(unsafe { esp_wifi_start() })?;
+
+            info!("Start requested");
+
+            Ok(true)
+        } else {
+            info!("Status is NOT of operating type, not starting");
+
+            Ok(false)
+        }
+    }
+
+    // Stops the driver, keeping the configuration; set a configuration to start it again
+    pub fn stop(&mut self) -> Result<(), EspError> {
+        self.request_stop()?;
+
+        self.wait_status(|s| matches!(s, Status(ClientStatus::Stopped, ApStatus::Stopped)));
+
+        info!("Stopped");
+
+        Ok(())
+    }
+
+    fn request_stop(&mut self) -> Result<(), EspError> {
+        info!("Stopping");
+
+        let timer = self.shared.modify(|shared| {
+            shared.operating = false;
+
+            if let Some(reconnect) = shared.reconnect.as_mut() {
+                reconnect.attempt = 0;
+            }
+
+            (
+                false,
+                shared.reconnect.as_ref().map(|reconnect| reconnect.timer),
+            )
+        });
+
+        if let Some(timer) = timer {
+            let err = unsafe { esp_timer_stop(timer) };
+            if err != ESP_ERR_INVALID_STATE as i32 {
+                esp!(err)?;
+            }
+        }
+
+        esp!(unsafe { esp_wifi_disconnect() }).or_else(|err| {
+            if err.code() == esp_idf_sys::ESP_ERR_WIFI_NOT_STARTED as esp_err_t {
+                Ok(())
+            } else {
+                Err(err)
+            }
+        })?;
+        info!("Disconnect requested");
+
+        esp!(unsafe { esp_wifi_stop() })?;
+        info!("Stop requested");
+
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.get(|shared| {
+            matches!(
+                shared.status.0,
+                ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(_)))
+            )
+        })
+    }
+
+    #[cfg(feature = "async")]
+    pub fn wait_status_async<F>(&self, matcher: F) -> EspWifiStatus<'_, F>
+    where
+        F: Fn(&Status) -> bool + Unpin,
+    {
+        EspWifiStatus(self, matcher)
+    }
+
+    #[cfg(feature = "async")]
+    pub async fn stop_async(&mut self) -> Result<(), EspError> {
+        self.request_stop()?;
+
+        self.wait_status_async(|s| matches!(s, Status(ClientStatus::Stopped, ApStatus::Stopped)))
+            .await;
+
+        info!("Stopped");
+
+        Ok(())
+    }
+
+    // The async counterpart of `Wifi::set_configuration`
+    #[cfg(feature = "async")]
+    pub async fn start_async(&mut self, conf: &Configuration) -> Result<Status, EspError> {
+        info!("Setting configuration: {:?}", conf);
+
+        self.stop_async().await?;
+
+        let status = self.apply_configuration(conf)?;
+
+        let status = if self.request_start(status)? {
+            self.wait_status_async(|s| !s.is_transitional()).await
+        } else {
+            self.get_status()
+        };
+
+        info!("Configuration set, status: {:?}", status);
+
+        Ok(status)
+    }
+
+    // Reconnects the STA interface with its current configuration
+    #[cfg(feature = "async")]
+    pub async fn connect_async(&mut self) -> Result<Status, EspError> {
+        self.shared.modify(|shared| {
+            shared.operating = true;
+            shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connecting);
+
+            (false, ())
+        });
+
+        esp!(unsafe { esp_wifi_connect() })?;
+
+        info!("Connect requested");
+
+        Ok(self.wait_status_async(|s| !s.is_transitional()).await)
+    }
+
+    fn clear_all(&mut self) -> Result<(), EspError> {
+        self.stop()?;
+
+        if self.vendor_ie_callback.is_some() {
+            self.clear_vendor_ie_callback()?;
+        }
+
+        self.set_reconnect_policy(None)?;
+
+        unsafe {
+            Self::netif_unbind(self.ap_netif.as_mut())?;
+            Self::netif_unbind(self.sta_netif.as_mut())?;
+            self.ap_netif = None;
+            self.sta_netif = None;
+            self.shared.modify(|shared| {
+                shared.sta_netif = None;
+                shared.ap_netif = None;
+
+                (false, ())
+            });
+
+            esp!(esp_event_handler_unregister(
+                WIFI_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler)
+            ))?;
+            esp!(esp_event_handler_unregister(
+                IP_EVENT,
+                ESP_EVENT_ANY_ID as i32,
+                Option::Some(Self::event_handler)
+            ))?;
+
+            info!("Event handlers deregistered");
+
+            esp!(esp_wifi_deinit())?;
+
+            info!("Driver deinitialized");
+        }
+
+        self.retired_vendor_ie_callbacks.clear();
+
+        info!("Deinitialization complete");
+
+        Ok(())
+    }
+
+    pub fn scan_full(&mut self) -> Result<vec::Vec<EspAccessPointInfo>, EspError> {
+        self.do_scan_start(None, 0, true)?;
+
+        self.do_get_scan_results()
+    }
+
+    pub fn scan_with_config(
+        &mut self,
+        conf: &ScanConfig,
+    ) -> Result<vec::Vec<EspAccessPointInfo>, EspError> {
+        let mut channels = conf.channels.iter().copied();
+
+        // ESP-IDF can only scan one or all channels, so scan the requested channels one by one
+        self.do_scan_start(Some(conf), channels.next().unwrap_or(0), true)?;
+
+        let mut result = self.do_get_scan_results()?;
+
+        for channel in channels {
+            Self::do_scan_channel(Some(conf), channel, true)?;
+
+            result.extend(self.do_get_scan_results()?);
+        }
+
+        Ok(result)
+    }
+
+    pub fn scan_async(&mut self) -> Result<EspWifiScan<'_>, EspError> {
+        self.do_scan_start(None, 0, false)?;
+
+        Ok(EspWifiScan(self))
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn do_scan(&mut self) -> Result<usize, EspError> {
+        self.do_scan_start(None, 0, true)?;
+
+        self.do_get_scan_count()
+    }
+
+    fn do_scan_start(
+        &mut self,
+        conf: Option<&ScanConfig>,
+        channel: u8,
+        block: bool,
+    ) -> Result<(), EspError> {
+        info!("About to scan for access points");
+
+        self.shared.modify(|shared| {
+            shared.scan_done = false;
+
+            (false, ())
+        });
+
+        self.start_sta_idle()?;
+
+        self.shared.modify(|shared| {
+            Self::set_state(shared, WifiState::Scanning);
+
+            (false, ())
+        });
+
+        Self::do_scan_channel(conf, channel, block)
+    }
+
+    // Starts the STA interface without connecting, as needed for scanning and provisioning
+    pub(crate) fn start_sta_idle(&mut self) -> Result<(), EspError> {
+        self.stop()?;
+
+        unsafe {
+            esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA))?;
+            esp!(esp_wifi_start())?;
+        }
+
+        Ok(())
+    }
+
+    fn do_scan_channel(
+        conf: Option<&ScanConfig>,
+        channel: u8,
+        block: bool,
+    ) -> Result<(), EspError> {
+        if let Some(conf) = conf {
+            let mut rcs = RawCstrs::new();
+            let bssid = conf.bssid;
+
+            let (scan_type, scan_time) = match conf.scan_type {
+                ScanType::Active { min, max } => (
+                    wifi_scan_type_t_WIFI_SCAN_TYPE_ACTIVE,
+                    wifi_scan_time_t {
+                        active: wifi_active_scan_time_t {
+                            min: min.as_millis() as _,
+                            max: max.as_millis() as _,
+                        },
+                        ..Default::default()
+                    },
+                ),
+                ScanType::Passive(time) => (
+                    wifi_scan_type_t_WIFI_SCAN_TYPE_PASSIVE,
+                    wifi_scan_time_t {
+                        passive: time.as_millis() as _,
+                        ..Default::default()
+                    },
+                ),
+            };
+
+            let scan_config = wifi_scan_config_t {
+                ssid: rcs.as_nptr(conf.ssid.as_ref()) as *mut _,
+                bssid: bssid
+                    .as_ref()
+                    .map(|bssid| bssid.as_ptr() as *mut _)
+                    .unwrap_or(ptr::null_mut()),
+                channel,
+                show_hidden: conf.show_hidden,
+                scan_type,
+                scan_time,
+                ..Default::default()
+            };
+
+            info!("Scanning channel {} with configuration {:?}", channel, conf);
+
+            esp!(unsafe { esp_wifi_scan_start(&scan_config, block) })
+        } else {
+            esp!(unsafe { esp_wifi_scan_start(ptr::null_mut(), block) })
+        }
+    }
+
+    fn do_get_scan_count(&mut self) -> Result<usize, EspError> {
+        let mut found_ap: u16 = 0;
+        esp!(unsafe { esp_wifi_scan_get_ap_num(&mut found_ap as *mut _) })?;
+
+        info!("Found {} access points", found_ap);
+
+        Ok(found_ap as usize)
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn do_get_scan_infos(
+        &mut self,
+        ap_infos_raw: &mut [wifi_ap_record_t],
+    ) -> Result<usize, EspError> {
+        info!("About to get info for found access points");
+
+        let mut ap_count: u16 = ap_infos_raw.len() as u16;
+
+        esp!(unsafe { esp_wifi_scan_get_ap_records(&mut ap_count, ap_infos_raw.as_mut_ptr(),) })?;
+
+        info!("Got info for {} access points", ap_count);
+
+        Ok(ap_count as usize)
+    }
+
+    fn do_get_scan_results<I>(&mut self) -> Result<vec::Vec<I>, EspError>
+    where
+        I: for<'a> TryFrom<Newtype<&'a wifi_ap_record_t>, Error = EspError> + core::fmt::Debug,
+    {
+        let total_count = self.do_get_scan_count()?;
+
+        let mut ap_infos_raw: vec::Vec<wifi_ap_record_t> =
+            vec::Vec::with_capacity(total_count as usize);
+        #[allow(clippy::uninit_vec)]
+        // ... because we are filling it in on the next line and only reading the initialized members
+        unsafe {
+            ap_infos_raw.set_len(total_count as usize)
+        };
+
+        let real_count = self.do_get_scan_infos(&mut ap_infos_raw)?;
+
+        let mut result = vec::Vec::with_capacity(real_count);
+        for ap_info_raw in ap_infos_raw.iter().take(real_count) {
+            match I::try_from(Newtype(ap_info_raw)) {
+                Ok(ap_info) => {
+                    info!("Found access point {:?}", ap_info);
+
+                    result.push(ap_info);
+                }
+                Err(err) => info!(
+                    "Skipping access point {:?} with auth mode {}: {}",
+                    from_cstr(&ap_info_raw.ssid),
+                    ap_info_raw.authmode,
+                    err
+                ),
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn netif_unbind(netif: Option<&mut EspNetif>) -> Result<(), EspError> {
+        if let Some(netif) = netif {
+            esp!(unsafe {
+                esp_wifi_clear_default_wifi_driver_and_handlers(netif.1 as *mut c_types::c_void)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn netif_info(name: &'static str, netif: Option<&EspNetif>) -> Result<(), EspError> {
+        if let Some(netif) = netif {
+            info!(
+                "{} netif status: {:?}, index: {}, name: {}, ifkey: {}",
+                name,
+                netif,
+                netif.get_index(),
+                netif.get_name(),
+                netif.get_key()
+            );
+        } else {
+            info!("{} netif is not allocated", name);
+        }
+
+        Ok(())
+    }
+
+    unsafe extern "C" fn event_handler(
+        arg: *mut c_types::c_void,
+        event_base: esp_event_base_t,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) {
+        let shared_ref = (arg as *mut Waitable<Shared>).as_mut().unwrap();
+
+        shared_ref.modify(|shared| {
+            if event_base == WIFI_EVENT {
+                Self::on_wifi_event(shared, event_id, event_data)
+            } else if event_base == IP_EVENT {
+                Self::on_ip_event(shared, event_id, event_data)
+            } else {
+                warn!("Got unknown event base");
+
+                Ok(false)
+            }
+            .map(|notify| {
+                if notify {
+                    if let Some(waker) = shared.status_waker.take() {
+                        waker.wake();
+                    }
+                }
+
+                (notify, ())
+            })
+            .unwrap()
+        });
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn on_wifi_event(
+        shared: &mut Shared,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) -> Result<bool, EspError> {
+        info!("Got wifi event: {} ", event_id);
+
+        let handled = match event_id as u32 {
+            wifi_event_t_WIFI_EVENT_STA_START => {
+                shared.status.0 = Self::reconnect_if_operating(shared.operating)?;
+
+                // A scan might have been requested before this event got dispatched
+                if shared.operating {
+                    Self::set_state(shared, WifiState::Connecting);
+                } else if shared.state != WifiState::Scanning {
+                    Self::set_state(shared, WifiState::Disconnected { reason: None });
+                }
+
+                true
+            }
+            wifi_event_t_WIFI_EVENT_STA_STOP => {
+                shared.status.0 = ClientStatus::Stopped;
+                shared.bssid = None;
+
+                Self::set_state(shared, WifiState::Stopped);
+
+                true
+            }
+            wifi_event_t_WIFI_EVENT_STA_CONNECTED => {
+                if let Some(reconnect) = shared.reconnect.as_mut() {
+                    if reconnect.attempt > 0 {
+                        info!("Reconnected after {} attempt(s)", reconnect.attempt);
+
+                        Self::post_reconnect_event(
+                            WIFI_RECONNECT_EVENT_RECONNECTED,
+                            reconnect.attempt,
+                            0,
+                        );
+
+                        reconnect.attempt = 0;
+                    }
+                }
+
+                let event =
+                    unsafe { (event_data as *const wifi_event_sta_connected_t).as_ref() }.unwrap();
+
+                if let Some(bssid) = shared.bssid {
+                    if bssid != event.bssid {
+                        info!("Roamed from AP {:?} to AP {:?}", bssid, event.bssid);
+
+                        Self::post_roam_event(bssid, event.bssid);
+                    }
+                }
+
+                shared.bssid = Some(event.bssid);
+
+                Self::set_state(
+                    shared,
+                    WifiState::Connected {
+                        ssid: String::from_utf8_lossy(&event.ssid[..event.ssid_len as usize])
+                            .into_owned(),
+                        bssid: event.bssid,
+                        channel: event.channel,
+                    },
+                );
+
+                shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connected(
+                    match shared.client_ip_conf.as_ref() {
+                        None => ClientIpStatus::Disabled,
+                        Some(ipv4::ClientConfiguration::DHCP(_)) => ClientIpStatus::Waiting,
+                        Some(ipv4::ClientConfiguration::Fixed(ref status)) => {
+                            ClientIpStatus::Done(status.clone())
+                        }
+                    },
+                ));
+
+                true
+            }
+            wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let event =
+                    unsafe { (event_data as *const wifi_event_sta_disconnected_t).as_ref() }
+                        .unwrap();
+
+                Self::set_state(
+                    shared,
+                    WifiState::Disconnected {
+                        reason: Some(event.reason),
+                    },
+                );
+
+                shared.status.0 = if let Some(reconnect) = shared.reconnect.as_mut() {
+                    Self::reconnect_with_policy(shared.operating, reconnect)?
+                } else {
+                    Self::reconnect_if_operating(shared.operating)?
+                };
+
+                if matches!(
+                    shared.status.0,
+                    ClientStatus::Started(ClientConnectionStatus::Connecting)
+                ) {
+                    Self::set_state(shared, WifiState::Connecting);
+                }
+
+                true
+            }
+            wifi_event_t_WIFI_EVENT_SCAN_DONE => {
+                shared.scan_done = true;
+
+                if shared.state == WifiState::Scanning {
+                    Self::set_state(shared, WifiState::Disconnected { reason: None });
+                }
+
+                if let Some(waker) = shared.scan_waker.take() {
+                    waker.wake();
+                }
+
+                true
+            }
+            wifi_event_t_WIFI_EVENT_AP_START => {
+                #[cfg(esp_idf_config_lwip_ipv4_napt)]
+                if let Some(ap_netif) = shared.ap_netif.filter(|_| shared.ap_napt) {
+                    unsafe { enable_napt(ap_netif, true) };
+
+                    info!("NAPT enabled on the AP interface");
+                }
+
+                shared.status.1 = ApStatus::Started(ApIpStatus::Done);
+                true
+            }
+            wifi_event_t_WIFI_EVENT_AP_STOP => {
+                shared.status.1 = ApStatus::Stopped;
+                true
+            }
+            wifi_event_t_WIFI_EVENT_AP_STACONNECTED => {
+                let event = unsafe { (event_data as *const wifi_event_ap_staconnected_t).as_ref() }
+                    .unwrap();
+                info!("Station {:?} AID={} connected", event.mac, event.aid);
+
+                false
+            }
+            wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED => {
+                let event =
+                    unsafe { (event_data as *const wifi_event_ap_stadisconnected_t).as_ref() }
+                        .unwrap();
+                info!("Station {:?} AID={} disconnected", event.mac, event.aid);
+
+                false
+            }
+            _ => false,
+        };
+
+        if handled {
+            info!(
+                "Wifi event {} handled, set status: {:?}",
+                event_id, shared.status
+            );
+        }
+
+        Ok(handled)
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn on_ip_event(
+        shared: &mut Shared,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) -> Result<bool, EspError> {
+        let event_id = event_id as u32;
+
+        let handled = if shared.sta_netif.is_some()
+            && (event_id == ip_event_t_IP_EVENT_STA_GOT_IP
+                || event_id == ip_event_t_IP_EVENT_STA_LOST_IP)
+        {
+            if event_id == ip_event_t_IP_EVENT_STA_GOT_IP {
+                let event = unsafe { (event_data as *const ip_event_got_ip_t).as_ref() }.unwrap();
+
+                // Check the exact netif because it seems that the Eth stack mistakenly sends IP_EVENT_STA_GOT_IP instead of IP_EVENT_ETH_GOT_IP
+                if shared.sta_netif.unwrap() != (*event).esp_netif {
+                    return Ok(false);
+                }
+
+                for (dns_type, dns) in &shared.client_dns {
+                    if let Err(err) = unsafe { set_dns_server((*event).esp_netif, *dns_type, *dns) }
+                    {
+                        warn!("Failed to set the DNS server {}: {}", dns, err);
+                    }
+                }
+
+                info!("Got IP event: {}", event_id);
+
+                shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connected(
+                    ClientIpStatus::Done(ipv4::ClientSettings {
+                        ip: ipv4::Ipv4Addr::from(Newtype((*event).ip_info.ip)),
+                        subnet: ipv4::Subnet {
+                            gateway: ipv4::Ipv4Addr::from(Newtype((*event).ip_info.gw)),
+                            mask: Newtype((*event).ip_info.netmask).try_into()?,
+                        },
+                        dns: None,           // TODO
+                        secondary_dns: None, // TODO
+                    }),
+                ));
+
+                info!(
+                    "IP event {} handled, set status: {:?}",
+                    event_id, shared.status
+                );
+
+                true
+            } else {
+                info!("Got IP event: {}", event_id);
+
+                shared.status.0 = Self::reconnect_if_operating(shared.operating)?;
+
+                info!(
+                    "IP event {} handled, set status: {:?}",
+                    event_id, shared.status
+                );
+
+                true
+            }
+        } else if shared.ap_netif.is_some() && event_id == ip_event_t_IP_EVENT_AP_STAIPASSIGNED {
+            info!("Got IP event: {}", event_id);
+
+            let event =
+                unsafe { (event_data as *const ip_event_ap_staipassigned_t).as_ref() }.unwrap();
+            info!(
+                "AP assigned IP to a station: {}",
+                ipv4::Ipv4Addr::from(Newtype((*event).ip))
+            );
+
+            info!("IP event {} handled", event_id);
+
+            false
+        } else {
+            false
+        };
+
+        Ok(handled)
+    }
+
+    fn set_state(shared: &mut Shared, state: WifiState) {
+        if shared.state != state {
+            info!("State changed: {:?}", state);
+
+            state.post();
+
+            shared.state = state;
+        }
+    }
+
+    fn reconnect_with_policy(
+        operating: bool,
+        reconnect: &mut Reconnect,
+    ) -> Result<ClientStatus, EspError> {
+        if !operating {
+            return Ok(ClientStatus::Started(ClientConnectionStatus::Disconnected));
+        }
+
+        reconnect.attempt += 1;
+
+        if let Some(max_attempts) = reconnect.policy.max_attempts {
+            if reconnect.attempt > max_attempts {
+                info!("Giving up reconnecting after {} attempt(s)", max_attempts);
+
+                Self::post_reconnect_event(WIFI_RECONNECT_EVENT_GAVE_UP, max_attempts, 0);
+
+                reconnect.attempt = 0;
+
+                return Ok(ClientStatus::Started(ClientConnectionStatus::Disconnected));
+            }
+        }
+
+        let delay = reconnect.policy.delay(reconnect.attempt);
+
+        info!(
+            "Reconnecting in {:?} (attempt {})",
+            delay, reconnect.attempt
+        );
+
+        // Re-armed when the connection drops again before the previous delay expired
+        let err = unsafe { esp_timer_stop(reconnect.timer) };
+        if err != ESP_ERR_INVALID_STATE as i32 {
+            if let Err(err) = esp!(err) {
+                warn!("Failed to stop the reconnect timer: {}", err);
+            }
+        }
+
+        if let Err(err) =
+            esp!(unsafe { esp_timer_start_once(reconnect.timer, delay.as_micros() as u64) })
+        {
+            warn!("Failed to start the reconnect timer: {}", err);
+
+            return Ok(ClientStatus::Started(ClientConnectionStatus::Disconnected));
+        }
+
+        Self::post_reconnect_event(
+            WIFI_RECONNECT_EVENT_RETRYING,
+            reconnect.attempt,
+            delay.as_millis() as u32,
+        );
+
+        Ok(ClientStatus::Started(ClientConnectionStatus::Connecting))
+    }
+
+    unsafe extern "C" fn on_reconnect_timer(arg: *mut c_types::c_void) {
+        let shared_ref = (arg as *mut Waitable<Shared>).as_ref().unwrap();
+
+        if shared_ref.get(|shared| shared.operating) {
+            info!("Reconnecting");
+
+            if let Err(err) = esp!(esp_wifi_connect()) {
+                warn!("Failed to reconnect: {}", err);
+            }
+        }
+    }
+
+    fn post_roam_event(from: [u8; 6], to: [u8; 6]) {
+        let payload = [from, to];
+
+        if let Err(err) = esp!(unsafe {
+            esp_event_post(
+                WIFI_ROAM_EVENT.as_ptr() as *const _,
+                WIFI_ROAM_EVENT_ROAMED,
+                &payload as *const _ as *mut c_types::c_void,
+                core::mem::size_of_val(&payload) as _,
+                0,
+            )
+        }) {
+            warn!("Failed to post roam event: {}", err);
+        }
+    }
+
+    fn post_reconnect_event(event_id: i32, attempt: u32, delay_ms: u32) {
+        let payload = [attempt, delay_ms];
+
+        if let Err(err) = esp!(unsafe {
+            esp_event_post(
+                WIFI_RECONNECT_EVENT.as_ptr() as *const _,
+                event_id,
+                &payload as *const _ as *mut c_types::c_void,
+                core::mem::size_of_val(&payload) as _,
+                0,
+            )
+        }) {
+            warn!("Failed to post reconnect event {}: {}", event_id, err);
+        }
+    }
+
+    fn reconnect_if_operating(operating: bool) -> Result<ClientStatus, EspError> {
+        Ok(if operating {
+            info!("Reconnecting");
+
+            esp_nofail!(unsafe { esp_wifi_connect() });
+
+            ClientStatus::Started(ClientConnectionStatus::Connecting)
+        } else {
+            ClientStatus::Started(ClientConnectionStatus::Disconnected)
+        })
+    }
+}
+
+impl Drop for EspWifi {
+    fn drop(&mut self) {
+        {
+            let mut taken = TAKEN.lock();
+
+            if let Err(err) = self.deinit() {
+                error!(
+                    "Failed to deinitialize the driver, leaking its state: {}",
+                    err
+                );
+
+                // The event handlers, the reconnect timer and the driver itself may still use
+                // all of these, and the driver cannot be taken again
+                core::mem::forget(core::mem::replace(
+                    &mut self.shared,
+                    Box::new(Waitable::new(Default::default())),
+                ));
+                core::mem::forget(self.vendor_ie_callback.take());
+                core::mem::forget(core::mem::take(&mut self.retired_vendor_ie_callbacks));
+                core::mem::forget(self.enterprise_credentials.take());
+                core::mem::forget(self.sta_netif.take());
+                core::mem::forget(self.ap_netif.take());
+            } else {
+                *taken = false;
+            }
+        }
+
+        info!("Dropped");
+    }
+}
+
+#[cfg(feature = "async")]
+pub struct EspWifiStatus<'a, F>(&'a EspWifi, F);
+
+#[cfg(feature = "async")]
+impl<'a, F> Future for EspWifiStatus<'a, F>
+where
+    F: Fn(&Status) -> bool + Unpin,
+{
+    type Output = Status;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let matcher = &self.1;
+
+        let status = self.0.shared.modify(|shared| {
+            if matcher(&shared.status) {
+                (false, Some(shared.status.clone()))
+            } else {
+                shared.status_waker = Some(cx.waker().clone());
+
+                (false, None)
+            }
+        });
+
+        if let Some(status) = status {
+            Poll::Ready(status)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+pub struct EspWifiScan<'a>(&'a mut EspWifi);
+
+impl<'a> Future for EspWifiScan<'a> {
+    type Output = Result<vec::Vec<AccessPointInfo>, EspError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let scan_done = self.0.shared.modify(|shared| {
+            if !shared.scan_done {
+                shared.scan_waker = Some(cx.waker().clone());
+            }
+
+            (false, shared.scan_done)
+        });
+
+        if scan_done {
+            Poll::Ready(self.0.do_get_scan_results())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a> Drop for EspWifiScan<'a> {
+    fn drop(&mut self) {
+        // Abort the scan if the future is dropped before completion
+        let scan_done = self.0.shared.modify(|shared| {
+            shared.scan_waker = None;
+
+            (false, shared.scan_done)
+        });
+
+        if !scan_done {
+            if let Err(err) = esp!(unsafe { esp_wifi_scan_stop() }) {
+                warn!("Failed to stop scan: {}", err);
+            }
+        }
+    }
+}
+
+#[derive(EnumSetType, Debug)]
+pub enum PacketType {
+    Management,
+    Control,
+    Data,
+    Misc,
+}
+
+impl From<Newtype<EnumSet<PacketType>>> for wifi_promiscuous_filter_t {
+    fn from(packet_types: Newtype<EnumSet<PacketType>>) -> Self {
+        let filter_mask = packet_types
+            .0
+            .iter()
+            .map(|packet_type| match packet_type {
+                PacketType::Management => WIFI_PROMIS_FILTER_MASK_MGMT,
+                PacketType::Control => WIFI_PROMIS_FILTER_MASK_CTRL,
+                PacketType::Data => WIFI_PROMIS_FILTER_MASK_DATA,
+                PacketType::Misc => WIFI_PROMIS_FILTER_MASK_MISC,
+            })
+            .fold(0, |mask, filter| mask | filter);
+
+        wifi_promiscuous_filter_t { filter_mask }
+    }
+}
+
+pub struct PromiscuousPacket<'a> {
+    pub packet_type: PacketType,
+    pub rssi: i8,
+    pub channel: u8,
+    // Local time when the packet was received, in microseconds
+    pub timestamp: u32,
+    pub payload: &'a [u8],
+}
+
+type SnifferCallback = Box<dyn FnMut(&PromiscuousPacket) + Send + 'static>;
+
+// Only locked to clone the callback out of it, so that the callback does not run under the lock
+static SNIFFER: mutex::Mutex<Option<Arc<mutex::Mutex<SnifferCallback>>>> = mutex::Mutex::new(None);
+
+pub struct EspWifiSniffer<'a> {
+    _wifi: &'a mut EspWifi,
+}
+
+impl<'a> EspWifiSniffer<'a> {
+    // The callback is invoked from the Wi-Fi driver task, so it should return quickly
+    pub fn new(
+        wifi: &'a mut EspWifi,
+        packet_types: EnumSet<PacketType>,
+        callback: impl FnMut(&PromiscuousPacket) + Send + 'static,
+    ) -> Result<Self, EspError> {
+        let mut sniffer = SNIFFER.lock();
+
+        if sniffer.is_some() {
+            esp!(ESP_ERR_INVALID_STATE as i32)?;
+        }
+
+        wifi.start_sta_idle()?;
+
+        let callback: SnifferCallback = Box::new(callback);
+
+        *sniffer = Some(Arc::new(mutex::Mutex::new(callback)));
+
+        drop(sniffer);
 
-            info!("Skipping STA IP configuration (not configured)");
-        }
+        let this = Self { _wifi: wifi };
 
-        let netif_ptr = self.sta_netif.as_ref().map(|sta_netif| sta_netif.1);
+        unsafe {
+            esp!(esp_wifi_set_promiscuous_filter(
+                &Newtype(packet_types).into()
+            ))?;
+            esp!(esp_wifi_set_promiscuous_rx_cb(Some(Self::handle)))?;
+            esp!(esp_wifi_set_promiscuous(true))?;
+        }
 
-        self.shared.modify(|shared| {
-            shared.client_ip_conf = conf.clone();
-            shared.sta_netif = netif_ptr;
+        info!("Sniffer enabled for {:?}", packet_types);
 
-            (false, ())
-        });
+        Ok(this)
+    }
 
-        Ok(())
+    pub fn set_channel(&mut self, channel: u8) -> Result<(), EspError> {
+        esp!(unsafe { esp_wifi_set_channel(channel, wifi_second_chan_t_WIFI_SECOND_CHAN_NONE) })
     }
 
-    fn set_router_ip_conf(
-        &mut self,
-        conf: &Option<ipv4::RouterConfiguration>,
-    ) -> Result<(), EspError> {
-        Self::netif_unbind(self.ap_netif.as_mut())?;
+    #[allow(non_upper_case_globals)]
+    unsafe extern "C" fn handle(
+        buf: *mut c_types::c_void,
+        packet_type: wifi_promiscuous_pkt_type_t,
+    ) {
+        let packet = (buf as *const wifi_promiscuous_pkt_t).as_ref().unwrap();
+        let rx_ctrl = &packet.rx_ctrl;
+
+        let packet = PromiscuousPacket {
+            packet_type: match packet_type {
+                wifi_promiscuous_pkt_type_t_WIFI_PKT_MGMT => PacketType::Management,
+                wifi_promiscuous_pkt_type_t_WIFI_PKT_CTRL => PacketType::Control,
+                wifi_promiscuous_pkt_type_t_WIFI_PKT_DATA => PacketType::Data,
+                _ => PacketType::Misc,
+            },
+            rssi: rx_ctrl.rssi() as _,
+            channel: rx_ctrl.channel() as _,
+            timestamp: rx_ctrl.timestamp() as _,
+            payload: packet.payload.as_slice(rx_ctrl.sig_len() as usize),
+        };
 
-        if let Some(conf) = conf {
-            let mut iconf = InterfaceConfiguration::wifi_default_router();
-            iconf.ip_configuration = InterfaceIpConfiguration::Router(conf.clone());
+        let callback = SNIFFER.lock().clone();
 
-            info!("Setting AP interface configuration: {:?}", iconf);
+        if let Some(callback) = callback {
+            (*callback.lock())(&packet);
+        }
+    }
+}
 
-            let netif = EspNetif::new(self.netif_stack.clone(), &iconf)?;
+impl<'a> Drop for EspWifiSniffer<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = esp!(unsafe { esp_wifi_set_promiscuous(false) }) {
+            error!("Failed to disable the promiscuous mode: {}", err);
+        }
 
-            esp!(unsafe { esp_netif_attach_wifi_ap(netif.1) })?;
-            esp!(unsafe { esp_wifi_set_default_wifi_ap_handlers() })?;
+        if let Err(err) = esp!(unsafe { esp_wifi_set_promiscuous_rx_cb(None) }) {
+            error!("Failed to unregister the sniffer callback: {}", err);
+        }
 
-            self.ap_netif = Some(netif);
+        *SNIFFER.lock() = None;
 
-            info!("AP IP configuration done");
-        } else {
-            self.ap_netif = None;
+        info!("Sniffer disabled");
+    }
+}
 
-            info!("Skipping AP IP configuration (not configured)");
-        }
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VendorIeType {
+    Beacon,
+    ProbeRequest,
+    ProbeResponse,
+    AssocRequest,
+    AssocResponse,
+    // Reported by newer ESP-IDF versions
+    Unknown(u32),
+}
 
-        let netif_ptr = self.ap_netif.as_ref().map(|ap_netif| ap_netif.1);
+impl From<VendorIeType> for wifi_vendor_ie_type_t {
+    fn from(ie_type: VendorIeType) -> Self {
+        match ie_type {
+            VendorIeType::Beacon => wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_BEACON,
+            VendorIeType::ProbeRequest => wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_REQ,
+            VendorIeType::ProbeResponse => wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_RESP,
+            VendorIeType::AssocRequest => wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_ASSOC_REQ,
+            VendorIeType::AssocResponse => wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_ASSOC_RESP,
+            VendorIeType::Unknown(ie_type) => ie_type as _,
+        }
+    }
+}
 
-        self.shared.modify(|shared| {
-            shared.router_ip_conf = conf.clone();
-            shared.ap_netif = netif_ptr;
+impl From<wifi_vendor_ie_type_t> for VendorIeType {
+    #[allow(non_upper_case_globals)]
+    fn from(ie_type: wifi_vendor_ie_type_t) -> Self {
+        match ie_type {
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_BEACON => VendorIeType::Beacon,
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_REQ => VendorIeType::ProbeRequest,
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_PROBE_RESP => VendorIeType::ProbeResponse,
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_ASSOC_REQ => VendorIeType::AssocRequest,
+            wifi_vendor_ie_type_t_WIFI_VND_IE_TYPE_ASSOC_RESP => VendorIeType::AssocResponse,
+            ie_type => VendorIeType::Unknown(ie_type as _),
+        }
+    }
+}
 
-            (false, ())
-        });
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VendorIeId {
+    Id0,
+    Id1,
+}
 
-        Ok(())
+impl From<VendorIeId> for wifi_vendor_ie_id_t {
+    fn from(id: VendorIeId) -> Self {
+        match id {
+            VendorIeId::Id0 => wifi_vendor_ie_id_t_WIFI_VND_IE_ID_0,
+            VendorIeId::Id1 => wifi_vendor_ie_id_t_WIFI_VND_IE_ID_1,
+        }
     }
+}
 
-    fn wait_status(&self, matcher: impl Fn(&Status) -> bool) {
-        info!("About to wait for status");
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VendorIe {
+    pub oui: [u8; 3],
+    pub oui_type: u8,
+    pub payload: vec::Vec<u8>,
+}
 
-        self.shared.wait_while(|shared| !matcher(&shared.status));
+impl VendorIe {
+    fn to_bytes(&self) -> vec::Vec<u8> {
+        let mut data = vec::Vec::with_capacity(6 + self.payload.len());
 
-        info!("Waiting for status done - success");
+        data.push(WIFI_VENDOR_IE_ELEMENT_ID as u8);
+        data.push((4 + self.payload.len()) as u8);
+        data.extend_from_slice(&self.oui);
+        data.push(self.oui_type);
+        data.extend_from_slice(&self.payload);
+
+        data
     }
+}
 
-    fn wait_status_with_timeout(
-        &self,
-        dur: Duration,
-        matcher: impl Fn(&Status) -> bool,
-    ) -> Result<(), Status> {
-        info!("About to wait {:?} for status", dur);
+pub struct VendorIeFrame<'a> {
+    pub ie_type: VendorIeType,
+    pub source: [u8; 6],
+    pub rssi: i8,
+    pub oui: [u8; 3],
+    pub oui_type: u8,
+    pub payload: &'a [u8],
+}
 
-        let (timeout, status) = self.shared.wait_timeout_while_and_get(
-            dur,
-            |shared| !matcher(&shared.status),
-            |shared| shared.status.clone(),
-        );
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CsiConfig {
+    pub lltf: bool,
+    pub htltf: bool,
+    pub stbc_htltf2: bool,
+    pub ltf_merge: bool,
+    pub channel_filter: bool,
+    // When set, `shift` is applied to scale the CSI data instead of the automatic scaling
+    pub manual_scale: bool,
+    pub shift: u8,
+}
 
-        if !timeout {
-            info!("Waiting for status done - success");
-            Ok(())
-        } else {
-            info!("Timeout while waiting for status");
-            Err(status)
+impl Default for CsiConfig {
+    fn default() -> Self {
+        Self {
+            lltf: true,
+            htltf: true,
+            stbc_htltf2: true,
+            ltf_merge: true,
+            channel_filter: true,
+            manual_scale: false,
+            shift: 0,
         }
     }
+}
 
-    fn start(&mut self, status: Status) -> Result<(), EspError> {
-        info!("Starting with status: {:?}", status);
+impl From<&CsiConfig> for wifi_csi_config_t {
+    fn from(conf: &CsiConfig) -> Self {
+        Self {
+            lltf_en: conf.lltf,
+            htltf_en: conf.htltf,
+            stbc_htltf2_en: conf.stbc_htltf2,
+            ltf_merge_en: conf.ltf_merge,
+            channel_filter_en: conf.channel_filter,
+            manu_scale: conf.manual_scale,
+            shift: conf.shift,
+        }
+    }
+}
 
-        self.shared.modify(|shared| {
-            shared.status = status.clone();
-            shared.operating = status.is_operating();
+pub struct CsiRecord<'a> {
+    pub mac: [u8; 6],
+    pub rssi: i8,
+    pub channel: u8,
+    // Local time when the packet was received, in microseconds
+    pub timestamp: u32,
+    pub first_word_invalid: bool,
+    pub data: &'a [i8],
+}
 
-            (false, ())
-        });
+#[cfg(esp_idf_esp32_wifi_csi_enabled)]
+pub struct EspWifiCsi<'a> {
+    _wifi: &'a mut EspWifi,
+    _callback: Box<Box<dyn FnMut(&CsiRecord) + Send + 'static>>,
+}
 
-        if status.is_operating() {
-            info!("Status is of operating type, starting");
+#[cfg(esp_idf_esp32_wifi_csi_enabled)]
+impl<'a> EspWifiCsi<'a> {
+    // The callback is invoked from the Wi-Fi driver task, so it should return quickly
+    pub fn new(
+        wifi: &'a mut EspWifi,
+        conf: &CsiConfig,
+        callback: impl FnMut(&CsiRecord) + Send + 'static,
+    ) -> Result<Self, EspError> {
+        let callback: Box<dyn FnMut(&CsiRecord) + Send + 'static> = Box::new(callback);
+        let mut callback = Box::new(callback);
 
-            esp!(unsafe { esp_wifi_start() })?;
+        let callback_ptr: *mut _ = &mut *callback;
 
-            info!("Start requested");
+        let this = Self {
+            _wifi: wifi,
+            _callback: callback,
+        };
 
-            let result =
-                self.wait_status_with_timeout(Duration::from_secs(10), |s| !s.is_transitional());
+        unsafe {
+            esp!(esp_wifi_set_csi_config(&conf.into()))?;
+            esp!(esp_wifi_set_csi_rx_cb(
+                Some(Self::handle),
+                callback_ptr as *mut c_types::c_void
+            ))?;
+            esp!(esp_wifi_set_csi(true))?;
+        }
 
-            if result.is_err() {
-                info!("Timeout while waiting for the requested state");
+        info!("CSI enabled with configuration {:?}", conf);
 
-                return Err(EspError::from(ESP_ERR_TIMEOUT as i32).unwrap());
-            }
+        Ok(this)
+    }
 
-            info!("Started");
+    unsafe extern "C" fn handle(ctx: *mut c_types::c_void, info: *mut wifi_csi_info_t) {
+        let callback = (ctx as *mut Box<dyn FnMut(&CsiRecord) + Send + 'static>)
+            .as_mut()
+            .unwrap();
+        let info = info.as_ref().unwrap();
 
-            Self::netif_info("STA", self.sta_netif.as_ref())?;
-            Self::netif_info("AP", self.ap_netif.as_ref())?;
+        let data = if info.buf.is_null() {
+            &[]
         } else {
-            info!("Status is NOT of operating type, not starting");
-        }
+            core::slice::from_raw_parts(info.buf as *const i8, info.len as usize)
+        };
 
-        Ok(())
+        callback(&CsiRecord {
+            mac: info.mac,
+            rssi: info.rx_ctrl.rssi() as _,
+            channel: info.rx_ctrl.channel() as _,
+            timestamp: info.rx_ctrl.timestamp() as _,
+            first_word_invalid: info.first_word_invalid,
+            data,
+        });
     }
+}
 
-    fn stop(&mut self) -> Result<(), EspError> {
-        info!("Stopping");
+#[cfg(esp_idf_esp32_wifi_csi_enabled)]
+impl<'a> Drop for EspWifiCsi<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = esp!(unsafe { esp_wifi_set_csi(false) }) {
+            warn!("Failed to disable CSI: {}", err);
+        }
 
-        self.shared.modify(|shared| {
-            shared.operating = false;
+        if let Err(err) = esp!(unsafe { esp_wifi_set_csi_rx_cb(None, ptr::null_mut()) }) {
+            error!("Failed to clear the CSI callback, leaking it: {}", err);
 
-            (false, ())
-        });
+            // The driver might still invoke it
+            core::mem::forget(core::mem::replace(
+                &mut self._callback,
+                Box::new(Box::new(|_| ())),
+            ));
+        }
 
-        esp!(unsafe { esp_wifi_disconnect() }).or_else(|err| {
-            if err.code() == esp_idf_sys::ESP_ERR_WIFI_NOT_STARTED as esp_err_t {
-                Ok(())
-            } else {
-                Err(err)
-            }
-        })?;
-        info!("Disconnect requested");
+        info!("CSI disabled");
+    }
+}
 
-        esp!(unsafe { esp_wifi_stop() })?;
-        info!("Stop requested");
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FtmConfig {
+    pub peer: [u8; 6],
+    pub channel: u8,
+    // 0 means no preference; otherwise one of 16, 24, 32 or 64
+    pub frame_count: u8,
+    // In units of 100 ms; 0 means no preference
+    pub burst_period: u16,
+}
 
-        self.wait_status(|s| matches!(s, Status(ClientStatus::Stopped, ApStatus::Stopped)));
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FtmStatus {
+    Success,
+    Unsupported,
+    ConfigRejected,
+    NoResponse,
+    Failed,
+}
 
-        info!("Stopped");
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FtmReport {
+    pub peer: [u8; 6],
+    pub status: FtmStatus,
+    pub rtt_raw_ns: u32,
+    pub rtt_estimate_ns: u32,
+    pub distance_estimate_cm: u32,
+}
 
-        Ok(())
+#[cfg(esp_idf_esp_wifi_ftm_enable)]
+impl From<&wifi_event_ftm_report_t> for FtmReport {
+    #[allow(non_upper_case_globals)]
+    fn from(report: &wifi_event_ftm_report_t) -> Self {
+        Self {
+            peer: report.peer_mac,
+            status: match report.status {
+                wifi_ftm_status_t_FTM_STATUS_SUCCESS => FtmStatus::Success,
+                wifi_ftm_status_t_FTM_STATUS_UNSUPPORTED => FtmStatus::Unsupported,
+                wifi_ftm_status_t_FTM_STATUS_CONF_REJECTED => FtmStatus::ConfigRejected,
+                wifi_ftm_status_t_FTM_STATUS_NO_RESPONSE => FtmStatus::NoResponse,
+                _ => FtmStatus::Failed,
+            },
+            rtt_raw_ns: report.rtt_raw,
+            rtt_estimate_ns: report.rtt_est,
+            distance_estimate_cm: report.dist_est,
+        }
     }
+}
 
-    fn clear_all(&mut self) -> Result<(), EspError> {
-        self.stop()?;
+#[cfg(esp_idf_esp_wifi_ftm_enable)]
+pub struct EspFtmSession<'a> {
+    _wifi: &'a mut EspWifi,
+    report: Box<Waitable<Option<FtmReport>>>,
+}
 
-        unsafe {
-            Self::netif_unbind(self.ap_netif.as_mut())?;
-            Self::netif_unbind(self.sta_netif.as_mut())?;
-            self.shared.modify(|shared| {
-                shared.sta_netif = None;
-                shared.ap_netif = None;
+#[cfg(esp_idf_esp_wifi_ftm_enable)]
+impl<'a> EspFtmSession<'a> {
+    pub fn new(wifi: &'a mut EspWifi, conf: &FtmConfig) -> Result<Self, EspError> {
+        info!("Starting FTM session: {:?}", conf);
 
-                (false, ())
-            });
+        let mut session = Self {
+            _wifi: wifi,
+            report: Box::new(Waitable::new(None)),
+        };
 
-            esp!(esp_event_handler_unregister(
+        let report_ref: *mut _ = &mut *session.report;
+
+        unsafe {
+            esp!(esp_event_handler_register(
                 WIFI_EVENT,
-                ESP_EVENT_ANY_ID,
-                Option::Some(Self::event_handler)
-            ))?;
-            esp!(esp_event_handler_unregister(
-                IP_EVENT,
-                ESP_EVENT_ANY_ID as i32,
-                Option::Some(Self::event_handler)
+                wifi_event_t_WIFI_EVENT_FTM_REPORT as _,
+                Option::Some(Self::event_handler),
+                report_ref as *mut c_types::c_void
             ))?;
 
-            info!("Event handlers deregistered");
-
-            esp!(esp_wifi_deinit())?;
+            let mut cfg = wifi_ftm_initiator_cfg_t {
+                resp_mac: conf.peer,
+                channel: conf.channel,
+                frm_count: conf.frame_count,
+                burst_period: conf.burst_period,
+            };
 
-            info!("Driver deinitialized");
+            esp!(esp_wifi_ftm_initiate_session(&mut cfg))?;
         }
 
-        info!("Deinitialization complete");
-
-        Ok(())
+        Ok(session)
     }
 
-    #[allow(non_upper_case_globals)]
-    fn do_scan(&mut self) -> Result<usize, EspError> {
-        info!("About to scan for access points");
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<FtmReport, EspError> {
+        let report = if let Some(timeout) = timeout {
+            let (timed_out, report) = self.report.wait_timeout_while_and_get(
+                timeout,
+                |report| report.is_none(),
+                |report| *report,
+            );
 
-        self.stop()?;
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
 
-        unsafe {
-            esp!(esp_wifi_set_mode(wifi_mode_t_WIFI_MODE_STA))?;
-            esp!(esp_wifi_start())?;
+            report
+        } else {
+            self.report
+                .wait_while_and_get(|report| report.is_none(), |report| *report)
+        };
 
-            esp!(esp_wifi_scan_start(ptr::null_mut(), true))?;
-        }
+        Ok(report.unwrap())
+    }
 
-        let mut found_ap: u16 = 0;
-        esp!(unsafe { esp_wifi_scan_get_ap_num(&mut found_ap as *mut _) })?;
+    unsafe extern "C" fn event_handler(
+        arg: *mut c_types::c_void,
+        _event_base: esp_event_base_t,
+        _event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) {
+        let report_ref = (arg as *mut Waitable<Option<FtmReport>>).as_mut().unwrap();
+        let event = (event_data as *const wifi_event_ftm_report_t)
+            .as_ref()
+            .unwrap();
 
-        info!("Found {} access points", found_ap);
+        let report: FtmReport = event.into();
 
-        Ok(found_ap as usize)
+        info!("FTM report: {:?}", report);
+
+        report_ref.modify(|state| {
+            *state = Some(report);
+
+            (true, ())
+        });
     }
+}
 
-    #[allow(non_upper_case_globals)]
-    fn do_get_scan_infos(
-        &mut self,
-        ap_infos_raw: &mut [wifi_ap_record_t],
-    ) -> Result<usize, EspError> {
-        info!("About to get info for found access points");
+#[cfg(esp_idf_esp_wifi_ftm_enable)]
+impl<'a> Drop for EspFtmSession<'a> {
+    fn drop(&mut self) {
+        let completed = self.report.get(|report| report.is_some());
 
-        let mut ap_count: u16 = ap_infos_raw.len() as u16;
+        if !completed {
+            if let Err(err) = esp!(unsafe { esp_wifi_ftm_end_session() }) {
+                warn!("Failed to end FTM session: {}", err);
+            }
+        }
 
-        esp!(unsafe { esp_wifi_scan_get_ap_records(&mut ap_count, ap_infos_raw.as_mut_ptr(),) })?;
+        if let Err(err) = esp!(unsafe {
+            esp_event_handler_unregister(
+                WIFI_EVENT,
+                wifi_event_t_WIFI_EVENT_FTM_REPORT as _,
+                Option::Some(Self::event_handler),
+            )
+        }) {
+            error!(
+                "Failed to unregister the FTM event handler, leaking its report: {}",
+                err
+            );
 
-        info!("Got info for {} access points", ap_count);
+            // The handler might still be invoked with it
+            core::mem::forget(core::mem::replace(
+                &mut self.report,
+                Box::new(Waitable::new(None)),
+            ));
+        }
 
-        Ok(ap_count as usize)
+        info!("FTM session dropped");
     }
+}
 
-    fn netif_unbind(netif: Option<&mut EspNetif>) -> Result<(), EspError> {
-        if let Some(netif) = netif {
-            esp!(unsafe {
-                esp_wifi_clear_default_wifi_driver_and_handlers(netif.1 as *mut c_types::c_void)
-            })?;
-        }
+fn set_chars(buf: &mut [c_types::c_char], s: &str) {
+    let len = cmp::min(buf.len() - 1, s.len());
 
-        Ok(())
+    for (dst, src) in buf.iter_mut().zip(s.as_bytes()[..len].iter()) {
+        *dst = *src as _;
     }
 
-    fn netif_info(name: &'static str, netif: Option<&EspNetif>) -> Result<(), EspError> {
-        if let Some(netif) = netif {
-            info!(
-                "{} netif status: {:?}, index: {}, name: {}, ifkey: {}",
-                name,
-                netif,
-                netif.get_index(),
-                netif.get_name(),
-                netif.get_key()
-            );
-        } else {
-            info!("{} netif is not allocated", name);
-        }
+    buf[len] = 0;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WpsType {
+    PushButton,
+    Pin,
+}
 
-        Ok(())
+// Advertised to the registrar (the AP) during the WPS exchange; each field is truncated to the
+// length allowed by ESP-IDF
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WpsFactoryInfo {
+    pub manufacturer: String,
+    pub model_number: String,
+    pub model_name: String,
+    pub device_name: String,
+}
+
+impl Default for WpsFactoryInfo {
+    // The values of the ESP-IDF examples
+    fn default() -> Self {
+        Self {
+            manufacturer: "ESPRESSIF".into(),
+            model_number: "ESP32".into(),
+            model_name: "ESPRESSIF IOT".into(),
+            device_name: "ESP STATION".into(),
+        }
     }
+}
 
-    unsafe extern "C" fn event_handler(
-        arg: *mut c_types::c_void,
-        event_base: esp_event_base_t,
-        event_id: c_types::c_int,
-        event_data: *mut c_types::c_void,
-    ) {
-        let shared_ref = (arg as *mut Waitable<Shared>).as_mut().unwrap();
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WpsCredentials {
+    pub ssid: String,
+    pub password: String,
+}
 
-        shared_ref.modify(|shared| {
-            if event_base == WIFI_EVENT {
-                Self::on_wifi_event(shared, event_id, event_data)
-            } else if event_base == IP_EVENT {
-                Self::on_ip_event(shared, event_id, event_data)
-            } else {
-                warn!("Got unknown event base");
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WpsStatus {
+    Success(vec::Vec<WpsCredentials>),
+    Failed,
+    Timeout,
+    PbcOverlap,
+}
 
-                Ok(false)
-            }
-            .map(|notify| (notify, ()))
-            .unwrap()
-        });
-    }
+#[derive(Default)]
+struct WpsState {
+    pin: Option<String>,
+    status: Option<WpsStatus>,
+}
 
-    #[allow(non_upper_case_globals)]
-    fn on_wifi_event(
-        shared: &mut Shared,
-        event_id: c_types::c_int,
-        event_data: *mut c_types::c_void,
-    ) -> Result<bool, EspError> {
-        info!("Got wifi event: {} ", event_id);
+pub struct EspWps<'a> {
+    wifi: &'a mut EspWifi,
+    state: Box<Waitable<WpsState>>,
+}
 
-        let handled = match event_id as u32 {
-            wifi_event_t_WIFI_EVENT_STA_START => {
-                shared.status.0 = Self::reconnect_if_operating(shared.operating)?;
-                true
-            }
-            wifi_event_t_WIFI_EVENT_STA_STOP => {
-                shared.status.0 = ClientStatus::Stopped;
-                true
-            }
-            wifi_event_t_WIFI_EVENT_STA_CONNECTED => {
-                shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connected(
-                    match shared.client_ip_conf.as_ref() {
-                        None => ClientIpStatus::Disabled,
-                        Some(ipv4::ClientConfiguration::DHCP(_)) => ClientIpStatus::Waiting,
-                        Some(ipv4::ClientConfiguration::Fixed(ref status)) => {
-                            ClientIpStatus::Done(status.clone())
-                        }
-                    },
-                ));
+impl<'a> EspWps<'a> {
+    pub fn new(
+        wifi: &'a mut EspWifi,
+        wps_type: WpsType,
+        factory_info: &WpsFactoryInfo,
+    ) -> Result<Self, EspError> {
+        info!("Starting WPS: {:?}", wps_type);
 
-                true
-            }
-            wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
-                shared.status.0 = Self::reconnect_if_operating(shared.operating)?;
+        wifi.start_sta_idle()?;
 
-                true
-            }
-            wifi_event_t_WIFI_EVENT_AP_START => {
-                shared.status.1 = ApStatus::Started(ApIpStatus::Done);
-                true
-            }
-            wifi_event_t_WIFI_EVENT_AP_STOP => {
-                shared.status.1 = ApStatus::Stopped;
-                true
-            }
-            wifi_event_t_WIFI_EVENT_AP_STACONNECTED => {
-                let event = unsafe { (event_data as *const wifi_event_ap_staconnected_t).as_ref() }
-                    .unwrap();
-                info!("Station {:?} AID={} connected", event.mac, event.aid);
+        let mut wps = Self {
+            wifi,
+            state: Box::new(Waitable::new(Default::default())),
+        };
 
-                false
-            }
-            wifi_event_t_WIFI_EVENT_AP_STADISCONNECTED => {
-                let event =
-                    unsafe { (event_data as *const wifi_event_ap_stadisconnected_t).as_ref() }
-                        .unwrap();
-                info!("Station {:?} AID={} disconnected", event.mac, event.aid);
+        let state_ref: *mut _ = &mut *wps.state;
 
-                false
-            }
-            _ => false,
+        esp!(unsafe {
+            esp_event_handler_register(
+                WIFI_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+                state_ref as *mut c_types::c_void,
+            )
+        })?;
+
+        let mut config = esp_wps_config_t {
+            wps_type: match wps_type {
+                WpsType::PushButton => wps_type_t_WPS_TYPE_PBC,
+                WpsType::Pin => wps_type_t_WPS_TYPE_PIN,
+            },
+            ..Default::default()
         };
 
-        if handled {
-            info!(
-                "Wifi event {} handled, set status: {:?}",
-                event_id, shared.status
-            );
+        set_chars(
+            &mut config.factory_info.manufacturer,
+            &factory_info.manufacturer,
+        );
+        set_chars(
+            &mut config.factory_info.model_number,
+            &factory_info.model_number,
+        );
+        set_chars(
+            &mut config.factory_info.model_name,
+            &factory_info.model_name,
+        );
+        set_chars(
+            &mut config.factory_info.device_name,
+            &factory_info.device_name,
+        );
+
+        unsafe {
+            esp!(esp_wifi_wps_enable(&config))?;
+            esp!(esp_wifi_wps_start(0))?;
         }
 
-        Ok(handled)
+        info!("WPS started");
+
+        Ok(wps)
     }
 
-    #[allow(non_upper_case_globals)]
-    fn on_ip_event(
-        shared: &mut Shared,
-        event_id: c_types::c_int,
-        event_data: *mut c_types::c_void,
-    ) -> Result<bool, EspError> {
-        let event_id = event_id as u32;
+    // Only available with `WpsType::Pin`, once the PIN is generated
+    pub fn pin(&self) -> Option<String> {
+        self.state.get(|state| state.pin.clone())
+    }
 
-        let handled = if shared.sta_netif.is_some()
-            && (event_id == ip_event_t_IP_EVENT_STA_GOT_IP
-                || event_id == ip_event_t_IP_EVENT_STA_LOST_IP)
-        {
-            if event_id == ip_event_t_IP_EVENT_STA_GOT_IP {
-                let event = unsafe { (event_data as *const ip_event_got_ip_t).as_ref() }.unwrap();
+    // On success, the first received credentials are transferred into the STA configuration
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<WpsStatus, EspError> {
+        let status = if let Some(timeout) = timeout {
+            let (timed_out, status) = self.state.wait_timeout_while_and_get(
+                timeout,
+                |state| state.status.is_none(),
+                |state| state.status.clone(),
+            );
 
-                // Check the exact netif because it seems that the Eth stack mistakenly sends IP_EVENT_STA_GOT_IP instead of IP_EVENT_ETH_GOT_IP
-                if shared.sta_netif.unwrap() != (*event).esp_netif {
-                    return Ok(false);
-                }
+            if timed_out {
+                esp!(ESP_ERR_TIMEOUT as i32)?;
+            }
 
-                info!("Got IP event: {}", event_id);
+            status
+        } else {
+            self.state
+                .wait_while_and_get(|state| state.status.is_none(), |state| state.status.clone())
+        }
+        .unwrap();
 
-                shared.status.0 = ClientStatus::Started(ClientConnectionStatus::Connected(
-                    ClientIpStatus::Done(ipv4::ClientSettings {
-                        ip: ipv4::Ipv4Addr::from(Newtype((*event).ip_info.ip)),
-                        subnet: ipv4::Subnet {
-                            gateway: ipv4::Ipv4Addr::from(Newtype((*event).ip_info.gw)),
-                            mask: Newtype((*event).ip_info.netmask).try_into()?,
-                        },
-                        dns: None,           // TODO
-                        secondary_dns: None, // TODO
-                    }),
-                ));
+        info!("WPS finished: {:?}", status);
 
-                info!(
-                    "IP event {} handled, set status: {:?}",
-                    event_id, shared.status
-                );
+        if let WpsStatus::Success(credentials) = &status {
+            esp!(unsafe { esp_wifi_wps_disable() })?;
 
-                true
+            // With a single AP, ESP-IDF has already stored the credentials in the STA configuration
+            let mut conf = self.wifi.get_client_conf()?;
+
+            if let Some(credentials) = credentials.first() {
+                conf.ssid = credentials.ssid.clone();
+                conf.password = credentials.password.clone();
+            }
+
+            conf.auth_method = if conf.password.is_empty() {
+                AuthMethod::None
             } else {
-                info!("Got IP event: {}", event_id);
+                AuthMethod::WPA2Personal
+            };
 
-                shared.status.0 = Self::reconnect_if_operating(shared.operating)?;
+            if conf.ip_conf.is_none() {
+                conf.ip_conf = Some(Default::default());
+            }
 
-                info!(
-                    "IP event {} handled, set status: {:?}",
-                    event_id, shared.status
-                );
+            self.wifi.set_configuration(&Configuration::Client(conf))?;
+        }
 
-                true
+        Ok(status)
+    }
+
+    #[allow(non_upper_case_globals)]
+    unsafe extern "C" fn event_handler(
+        arg: *mut c_types::c_void,
+        _event_base: esp_event_base_t,
+        event_id: c_types::c_int,
+        event_data: *mut c_types::c_void,
+    ) {
+        let state_ref = (arg as *mut Waitable<WpsState>).as_mut().unwrap();
+
+        state_ref.modify(|state| match event_id as u32 {
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_SUCCESS => {
+                let credentials = (event_data as *const wifi_event_sta_wps_er_success_t)
+                    .as_ref()
+                    .map(|event| {
+                        event.ap_cred[..event.ap_cred_cnt as usize]
+                            .iter()
+                            .map(|cred| WpsCredentials {
+                                ssid: from_cstr(&cred.ssid).into(),
+                                password: from_cstr(&cred.passphrase).into(),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                state.status = Some(WpsStatus::Success(credentials));
+
+                (true, ())
             }
-        } else if shared.ap_netif.is_some() && event_id == ip_event_t_IP_EVENT_AP_STAIPASSIGNED {
-            info!("Got IP event: {}", event_id);
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_FAILED => {
+                state.status = Some(WpsStatus::Failed);
 
-            let event =
-                unsafe { (event_data as *const ip_event_ap_staipassigned_t).as_ref() }.unwrap();
-            info!(
-                "AP assigned IP to a station: {}",
-                ipv4::Ipv4Addr::from(Newtype((*event).ip))
-            );
+                (true, ())
+            }
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_TIMEOUT => {
+                state.status = Some(WpsStatus::Timeout);
 
-            info!("IP event {} handled", event_id);
+                (true, ())
+            }
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_PBC_OVERLAP => {
+                state.status = Some(WpsStatus::PbcOverlap);
 
-            false
-        } else {
-            false
-        };
+                (true, ())
+            }
+            wifi_event_t_WIFI_EVENT_STA_WPS_ER_PIN => {
+                let event = (event_data as *const wifi_event_sta_wps_er_pin_t)
+                    .as_ref()
+                    .unwrap();
 
-        Ok(handled)
-    }
+                let pin = String::from_utf8_lossy(&event.pin_code).into_owned();
 
-    fn reconnect_if_operating(operating: bool) -> Result<ClientStatus, EspError> {
-        Ok(if operating {
-            info!("Reconnecting");
+                info!("WPS PIN: {}", pin);
 
-            esp_nofail!(unsafe { esp_wifi_connect() });
+                state.pin = Some(pin);
 
-            ClientStatus::Started(ClientConnectionStatus::Connecting)
-        } else {
-            ClientStatus::Started(ClientConnectionStatus::Disconnected)
-        })
+                (true, ())
+            }
+            _ => (false, ()),
+        });
     }
 }
 
-impl Drop for EspWifi {
+impl<'a> Drop for EspWps<'a> {
     fn drop(&mut self) {
-        {
-            let mut taken = TAKEN.lock();
+        // Disabling twice after a successful `wait` is harmless
+        if let Err(err) = esp!(unsafe { esp_wifi_wps_disable() }) {
+            warn!("Failed to disable WPS: {}", err);
+        }
 
-            self.clear_all().unwrap();
-            *taken = false;
+        if let Err(err) = esp!(unsafe {
+            esp_event_handler_unregister(
+                WIFI_EVENT,
+                ESP_EVENT_ANY_ID,
+                Option::Some(Self::event_handler),
+            )
+        }) {
+            error!("Failed to unregister the WPS event handler: {}", err);
         }
 
-        info!("Dropped");
+        info!("WPS dropped");
     }
 }
 
@@ -877,15 +4173,27 @@ impl Wifi for EspWifi {
 
             let real_count = self.do_get_scan_infos(&mut ap_infos_raw)?;
 
-            for i in 0..real_count {
-                if ap_infos.len() == i {
+            let mut filled = 0;
+
+            for ap_info_raw in ap_infos_raw.iter().take(real_count) {
+                if ap_infos.len() == filled {
                     break;
                 }
 
-                let ap_info = Newtype(&ap_infos_raw[i]).into();
-                info!("Found access point {:?}", ap_info);
-
-                ap_infos[i] = ap_info;
+                match AccessPointInfo::try_from(Newtype(ap_info_raw)) {
+                    Ok(ap_info) => {
+                        info!("Found access point {:?}", ap_info);
+
+                        ap_infos[filled] = ap_info;
+                        filled += 1;
+                    }
+                    Err(err) => info!(
+                        "Skipping access point {:?} with auth mode {}: {}",
+                        from_cstr(&ap_info_raw.ssid),
+                        ap_info_raw.authmode,
+                        err
+                    ),
+                }
             }
         }
 
@@ -894,27 +4202,9 @@ impl Wifi for EspWifi {
 
     #[allow(non_upper_case_globals)]
     fn scan(&mut self) -> Result<vec::Vec<AccessPointInfo>, Self::Error> {
-        let total_count = self.do_scan()?;
-
-        let mut ap_infos_raw: vec::Vec<wifi_ap_record_t> =
-            vec::Vec::with_capacity(total_count as usize);
-        #[allow(clippy::uninit_vec)]
-        // ... because we are filling it in on the next line and only reading the initialized members
-        unsafe {
-            ap_infos_raw.set_len(total_count as usize)
-        };
-
-        let real_count = self.do_get_scan_infos(&mut ap_infos_raw)?;
-
-        let mut result = vec::Vec::with_capacity(real_count);
-        for ap_info_raw in ap_infos_raw.iter().take(real_count) {
-            let ap_info: AccessPointInfo = Newtype(ap_info_raw).into();
-            info!("Found access point {:?}", ap_info);
-
-            result.push(ap_info);
-        }
+        self.do_scan_start(None, 0, true)?;
 
-        Ok(result)
+        self.do_get_scan_results()
     }
 
     #[allow(non_upper_case_globals)]
@@ -947,6 +4237,19 @@ impl Wifi for EspWifi {
 
         self.stop()?;
 
+        let status = self.apply_configuration(conf)?;
+
+        self.start(status)?;
+
+        info!("Configuration set");
+
+        Ok(())
+    }
+}
+
+impl EspWifi {
+    // Sets the mode and the configuration of the stopped driver, returning the status to start with
+    fn apply_configuration(&mut self, conf: &Configuration) -> Result<Status, EspError> {
         let status = unsafe {
             match conf {
                 Configuration::None => {
@@ -980,10 +4283,68 @@ impl Wifi for EspWifi {
             }
         };
 
-        self.start(status)?;
+        Ok(status)
+    }
+}
 
-        info!("Configuration set");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn country(cc: &[u8; 3]) -> wifi_country_t {
+        wifi_country_t {
+            cc: [cc[0] as _, cc[1] as _, cc[2] as _],
+            schan: 1,
+            nchan: 13,
+            max_tx_power: 20,
+            policy: wifi_country_policy_t_WIFI_COUNTRY_POLICY_MANUAL,
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn country_environment_is_split_from_the_code() {
+        let info = CountryInfo::from(Newtype(&country(b"DEO")));
+
+        assert_eq!(info.code, "DE");
+        assert_eq!(info.environment, CountryEnvironment::Outdoor);
+        assert_eq!(info.start_channel, 1);
+        assert_eq!(info.channels, 13);
+        assert_eq!(info.policy, CountryPolicy::Manual);
+
+        let info = CountryInfo::from(Newtype(&country(b"US ")));
+
+        assert_eq!(info.code, "US");
+        assert_eq!(info.environment, CountryEnvironment::Any);
+
+        let info = CountryInfo::from(Newtype(&country(b"01\0")));
+
+        assert_eq!(info.code, "01");
+        assert_eq!(info.environment, CountryEnvironment::Any);
+    }
+
+    #[test]
+    fn country_round_trips() {
+        for cc in &[b"DEO", b"FRI", b"JPX", b"US "] {
+            let info = CountryInfo::from(Newtype(&country(cc)));
+
+            assert_eq!(Newtype::<wifi_country_t>::from(&info).0.cc, country(cc).cc);
+        }
+    }
+
+    #[test]
+    fn country_code_is_truncated_to_two_characters() {
+        let info = CountryInfo {
+            code: "DEU".into(),
+            environment: CountryEnvironment::Indoor,
+            start_channel: 1,
+            channels: 13,
+            max_tx_power: 20,
+            policy: CountryPolicy::Auto,
+        };
+
+        assert_eq!(
+            Newtype::<wifi_country_t>::from(&info).0.cc,
+            country(b"DEI").cc
+        );
     }
 }